@@ -14,3 +14,12 @@ extern "C" int luau_setfflag(const char* name, int value)
     }
     return 0;
 }
+
+// Calls the callback for every boolean fast flag along with its current value
+extern "C" void luau_enumfflags(void (*cb)(const char* name, int value, void* ctx), void* ctx)
+{
+    for (Luau::FValue<bool>* flag = Luau::FValue<bool>::list; flag; flag = flag->next)
+    {
+        cb(flag->name, flag->value, ctx);
+    }
+}
@@ -0,0 +1,205 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lext.h"
+
+#include <chrono>
+#include <string.h>
+
+static uint64_t lutec_budget_now()
+{
+    return std::chrono::duration_cast<std::chrono::microseconds>(std::chrono::steady_clock::now().time_since_epoch()).count();
+}
+
+static const char *kInterruptPrefix = "execution budget exceeded";
+
+static const char *lutec_interrupt_message(lutec_InterruptReason reason)
+{
+    switch (reason)
+    {
+    case LUTEC_INTERRUPT_INSTRUCTIONS:
+        return "execution budget exceeded: instruction limit reached";
+    case LUTEC_INTERRUPT_TIMEOUT:
+        return "execution budget exceeded: time limit reached";
+    case LUTEC_INTERRUPT_CANCELLED:
+        return "execution budget exceeded: cancelled";
+    default:
+        return "execution budget exceeded";
+    }
+}
+
+void lutec_budget_interrupt(lua_State *L, lutec_RuntimeExt *ext)
+{
+    lutec_Budget &budget = ext->budget;
+
+    // A new slice only starts on resumes from the scheduler or the host (see
+    // lutec_budget_newslice). Switching coroutines from Luau doesn't start one, or scripts could
+    // escape the budget by resuming coroutines in a loop
+    if (!budget.sliceStarted)
+    {
+        budget.sliceStarted = true;
+        budget.sliceInstructions = 0;
+        budget.sliceStartUs = budget.timeLimitUs ? lutec_budget_now() : 0;
+    }
+
+    lutec_InterruptReason reason = LUTEC_INTERRUPT_NONE;
+
+    if (budget.cancelToken && budget.cancelToken->load(std::memory_order_relaxed))
+    {
+        reason = LUTEC_INTERRUPT_CANCELLED;
+    }
+    else if (budget.instructionLimit && ++budget.sliceInstructions > budget.instructionLimit)
+    {
+        reason = LUTEC_INTERRUPT_INSTRUCTIONS;
+    }
+    else if (budget.timeLimitUs && lutec_budget_now() - budget.sliceStartUs > budget.timeLimitUs)
+    {
+        reason = LUTEC_INTERRUPT_TIMEOUT;
+    }
+
+    if (reason == LUTEC_INTERRUPT_NONE)
+    {
+        return;
+    }
+
+    // The slice counters are intentionally not reset, so a script catching the error with
+    // pcall is aborted again at the next interrupt
+    ext->interruptReason = reason;
+    budget.lastInterrupt->store(reason);
+    luaL_errorL(L, "%s", lutec_interrupt_message(reason));
+}
+
+void lutec_budget_newslice(lutec_RuntimeExt *ext)
+{
+    ext->budget.sliceStarted = false;
+    ext->reportedInterrupt = LUTEC_INTERRUPT_NONE;
+}
+
+lutec_InterruptReason lutec_budget_takereason(lutec_RuntimeExt *ext, lua_State *T)
+{
+    lutec_InterruptReason reason = ext->interruptReason;
+    lutec_InterruptReason shared = lutec_InterruptReason(ext->budget.lastInterrupt->exchange(LUTEC_INTERRUPT_NONE));
+    ext->interruptReason = LUTEC_INTERRUPT_NONE;
+
+    // The error of an aborted child VM is rethrown in the coroutine that called into it
+    if (reason == LUTEC_INTERRUPT_NONE && shared != LUTEC_INTERRUPT_NONE && T)
+    {
+        const char *message = lua_tostring(T, -1);
+        if (message && strstr(message, kInterruptPrefix))
+        {
+            reason = shared;
+        }
+    }
+
+    return reason;
+}
+
+// Starts a new budget slice for a coroutine the host is about to resume directly
+//
+// Coroutines resumed by the scheduler through lutec_run_once get a new slice automatically
+extern "C" int lutec_budget_beginslice(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    lutec_budget_newslice(ext);
+    return 0;
+}
+
+// Sets the execution budget of the runtime attached to L
+//
+// The budget applies to every slice of execution of any coroutine in the runtime and in child
+// VMs created afterwards. Passing 0 for a limit disables it
+extern "C" int lutec_setbudget(lua_State *L, uint64_t instructionLimit, uint64_t timeLimitUs)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    ext->budget.instructionLimit = instructionLimit;
+    ext->budget.timeLimitUs = timeLimitUs;
+    ext->budget.sliceStarted = false;
+
+    if (ext->budget.active())
+    {
+        lutec_installinterrupt(L);
+    }
+
+    return 0;
+}
+
+// Cancel tokens can be triggered from any thread and are shared between a runtime and its child VMs
+typedef std::shared_ptr<std::atomic<bool>> lutec_CancelToken;
+
+extern "C" lutec_CancelToken *lutec_canceltoken_new()
+{
+    return new lutec_CancelToken(std::make_shared<std::atomic<bool>>(false));
+}
+
+extern "C" lutec_CancelToken *lutec_canceltoken_clone(lutec_CancelToken *token)
+{
+    return new lutec_CancelToken(*token);
+}
+
+extern "C" void lutec_canceltoken_free(lutec_CancelToken *token)
+{
+    delete token;
+}
+
+extern "C" void lutec_canceltoken_cancel(lutec_CancelToken *token)
+{
+    (*token)->store(true);
+}
+
+extern "C" int lutec_canceltoken_iscancelled(lutec_CancelToken *token)
+{
+    return (*token)->load() ? 1 : 0;
+}
+
+// Sets the cancel token of the runtime attached to L, or clears it if token is null
+extern "C" int lutec_setcanceltoken(lua_State *L, lutec_CancelToken *token)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (token)
+    {
+        ext->budget.cancelToken = *token;
+        lutec_installinterrupt(L);
+    }
+    else
+    {
+        ext->budget.cancelToken.reset();
+    }
+
+    return 0;
+}
+
+// Returns (and clears) the reason the last coroutine of the runtime was aborted by its budget
+//
+// This is needed for errors that are not reported through lutec_run_once, such as when the
+// host resumes a coroutine directly
+extern "C" int lutec_take_interrupt(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return LUTEC_INTERRUPT_NONE;
+    }
+
+    int reason = lutec_budget_takereason(ext, nullptr);
+    if (reason == LUTEC_INTERRUPT_NONE)
+    {
+        reason = ext->reportedInterrupt;
+    }
+    ext->reportedInterrupt = LUTEC_INTERRUPT_NONE;
+    return reason;
+}
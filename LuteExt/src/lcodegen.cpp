@@ -0,0 +1,82 @@
+#include "lua.h"
+#include "luacodegen.h"
+
+#include "Luau/CodeGen.h"
+
+#include "lext.h"
+
+// Only built when the codegen feature of lute-src-rs is enabled, as it requires
+// Luau.CodeGen to be linked in
+//
+// Native compilation applies to chunks loaded through lutec_load, which includes
+// lutec_loadsource (and so module loaders and require implementations built on it).
+// Chunks Lute loads itself without going through these functions, such as the entry
+// script of the lute CLI, run in the interpreter
+
+static void lutec_codegen_create(lua_State *L)
+{
+    luau_codegen_create(L);
+}
+
+static void lutec_codegen_compile(lua_State *L, int idx)
+{
+    Luau::CodeGen::CompilationOptions options;
+    Luau::CodeGen::CompilationStats stats = {};
+    Luau::CodeGen::compile(L, idx, options, &stats);
+
+    if (lutec_RuntimeExt *ext = lutec_getext(L))
+    {
+        ext->nativeFunctions += stats.functionsCompiled;
+    }
+}
+
+extern "C" int lutec_codegen_supported()
+{
+    return luau_codegen_supported();
+}
+
+// Enables native compilation for the runtime attached to L
+//
+// Once enabled, chunks loaded through lutec_load or lutec_loadsource (in this runtime or
+// any child VM created through @lute/vm) are natively compiled
+//
+// Returns 1 on success and 0 if codegen is not supported or no runtime is loaded
+extern "C" int lutec_codegen_enable(lua_State *L)
+{
+    if (!luau_codegen_supported())
+    {
+        return 0;
+    }
+
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 0;
+    }
+
+    lutec_codegen_create_hook = lutec_codegen_create;
+    lutec_codegen_compile_hook = lutec_codegen_compile;
+
+    if (!ext->codegen)
+    {
+        luau_codegen_create(L);
+        ext->codegen = true;
+    }
+
+    return 1;
+}
+
+// Returns whether native execution is enabled for L
+extern "C" int lutec_codegen_isenabled(lua_State *L)
+{
+    return Luau::CodeGen::isNativeExecutionEnabled(L) ? 1 : 0;
+}
+
+// Returns the number of functions natively compiled by lutec_load in the runtime attached to L
+//
+// Child VMs count separately from the runtime that created them
+extern "C" uint64_t lutec_codegen_compiledfunctions(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    return ext ? ext->nativeFunctions : 0;
+}
@@ -0,0 +1,108 @@
+#include "lua.h"
+
+#include "lext.h"
+
+// Only built when the debugger feature of lute-src-rs is enabled
+
+// Pause requests can be made from any thread and are shared between a runtime and its child VMs
+typedef std::shared_ptr<std::atomic<bool>> lutec_PauseFlag;
+
+static void lutec_debug_dispatch(lua_State *L, int event)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext || !ext->debugger)
+    {
+        return;
+    }
+
+    // Keep the hooks alive even if the debugger detaches while handling the event
+    std::shared_ptr<lutec_DebugHooks> debugger = ext->debugger;
+    debugger->onevent(debugger->ctx, L, event);
+}
+
+static void lutec_debugbreak(lua_State *L, lua_Debug *ar)
+{
+    lutec_debug_dispatch(L, LUTEC_DEBUG_BREAKPOINT);
+}
+
+static void lutec_debugstep(lua_State *L, lua_Debug *ar)
+{
+    lutec_debug_dispatch(L, LUTEC_DEBUG_STEP);
+}
+
+static void lutec_debug_install(lua_State *L)
+{
+    lua_Callbacks *cb = lua_callbacks(L);
+    cb->debugbreak = lutec_debugbreak;
+    cb->debugstep = lutec_debugstep;
+}
+
+// Attaches a debugger to the runtime attached to L (and child VMs created afterwards)
+//
+// onevent is called on the thread running the Luau code whenever a breakpoint is hit, a step
+// completes or a pause was requested through pause (which may be null). Execution stays
+// suspended until onevent returns, which also suspends every other coroutine of the scheduler.
+// Passing a null onevent detaches
+//
+// release is called with ctx once the debugger is detached, replaced, or no runtime
+// references it anymore
+extern "C" int lutec_debug_attach(
+    lua_State *L, void *ctx, void (*onevent)(void *ctx, lua_State *L, int event), void (*release)(void *ctx), lutec_PauseFlag *pause)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+        return 1;
+    }
+
+    if (!onevent)
+    {
+        ext->debugger.reset();
+        lua_Callbacks *cb = lua_callbacks(L);
+        cb->debugbreak = nullptr;
+        cb->debugstep = nullptr;
+        if (release)
+        {
+            release(ctx);
+        }
+        return 0;
+    }
+
+    std::shared_ptr<lutec_DebugHooks> debugger = std::make_shared<lutec_DebugHooks>();
+    debugger->ctx = ctx;
+    debugger->onevent = onevent;
+    debugger->release = release;
+    if (pause)
+    {
+        debugger->pause = *pause;
+    }
+    ext->debugger = std::move(debugger);
+
+    lutec_debug_install_hook = lutec_debug_install;
+    lutec_debug_install(L);
+    lutec_installinterrupt(L);
+    return 0;
+}
+
+extern "C" lutec_PauseFlag *lutec_debug_pauseflag_new()
+{
+    return new lutec_PauseFlag(std::make_shared<std::atomic<bool>>(false));
+}
+
+extern "C" void lutec_debug_pauseflag_free(lutec_PauseFlag *pause)
+{
+    delete pause;
+}
+
+// Requests the runtimes the flag was attached with to pause at the next interrupt
+//
+// Unlike the other debugger functions, this may be called from any thread. It only touches the
+// flag, so it is fine to call after the runtime was destroyed
+extern "C" void lutec_debug_pause(lutec_PauseFlag *pause)
+{
+    (*pause)->store(true);
+}
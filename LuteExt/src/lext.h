@@ -0,0 +1,406 @@
+#pragma once
+
+#include "lua.h"
+#include "lute/runtime.h"
+
+#include <atomic>
+#include <memory>
+#include <stdint.h>
+#include <string>
+#include <unordered_map>
+#include <vector>
+
+struct lutec_Profiler;
+
+// Creates a stopped profiler, see lprofiler.cpp
+std::shared_ptr<lutec_Profiler> lutec_profiler_new();
+
+// Reasons passed to the debugger hook
+enum lutec_DebugEvent
+{
+    LUTEC_DEBUG_BREAKPOINT = 1, // A breakpoint set with lua_breakpoint was hit
+    LUTEC_DEBUG_STEP = 2,       // Single stepping is enabled on the running thread
+    LUTEC_DEBUG_PAUSE = 3,      // A pause was requested with lutec_debug_pause
+};
+
+// Debugger hook shared by a runtime and the child VMs created while it is attached
+struct lutec_DebugHooks
+{
+    void *ctx = nullptr;
+    void (*onevent)(void *ctx, lua_State *L, int event) = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    // Set from any thread to pause at the next interrupt, owned by the host (see lutec_debug_pause)
+    std::shared_ptr<std::atomic<bool>> pause;
+
+    ~lutec_DebugHooks()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// Reasons for a coroutine being aborted from the interrupt handler
+enum lutec_InterruptReason
+{
+    LUTEC_INTERRUPT_NONE = 0,
+    LUTEC_INTERRUPT_INSTRUCTIONS = 1, // Instruction (interrupt count) limit exceeded
+    LUTEC_INTERRUPT_TIMEOUT = 2,      // Wall-clock limit exceeded
+    LUTEC_INTERRUPT_CANCELLED = 3,    // Cancel token was triggered
+};
+
+// Execution budget applied to every slice of execution (from a resume until the next yield)
+struct lutec_Budget
+{
+    uint64_t instructionLimit = 0; // Interrupts per slice, 0 for unlimited
+    uint64_t timeLimitUs = 0;      // Wall-clock time per slice, 0 for unlimited
+    std::shared_ptr<std::atomic<bool>> cancelToken;
+
+    // Last interrupt of the runtime or any of its child VMs, shared between them so that calls
+    // into child VMs aborted by the budget are reported as interrupted by the runtime
+    std::shared_ptr<std::atomic<int>> lastInterrupt = std::make_shared<std::atomic<int>>(0);
+
+    // Tracking of the current slice, only reset when the scheduler or the host resumes a
+    // coroutine. Coroutines resumed from Luau within the slice count against it as well
+    bool sliceStarted = false;
+    uint64_t sliceInstructions = 0;
+    uint64_t sliceStartUs = 0;
+
+    bool active() const
+    {
+        return instructionLimit != 0 || timeLimitUs != 0 || cancelToken;
+    }
+};
+
+// A frame of a traceback captured when a coroutine failed
+struct lutec_TraceFrame
+{
+    std::string source;   // Chunk name of the function
+    std::string function; // Function name, empty for anonymous functions
+    int line = -1;        // Current line, -1 if unknown
+    bool native = false;  // Whether the frame is a C function
+};
+
+// Receives one traceback frame, see lutec_last_traceback
+typedef void (*lutec_TracebackCallback)(void *ctx, const char *source, const char *function, int line);
+
+// How errors of coroutines resumed by the scheduler are handled
+enum lutec_ErrorPolicy
+{
+    LUTEC_POLICY_REPORT = 0,  // Return the error from lutec_run_once (the default)
+    LUTEC_POLICY_COLLECT = 1, // Store the error until taken with lutec_take_errors
+    LUTEC_POLICY_LOG = 2,     // Only pass the error to the error handler
+    LUTEC_POLICY_ABORT = 3,   // Report the error and stop running the scheduler
+    LUTEC_POLICY_RESTART = 4, // Restart the task from its entry function
+};
+
+// Result of applying the error policy
+enum lutec_ErrorAction
+{
+    LUTEC_ERROR_REPORT = 0,  // The error must be reported to the host
+    LUTEC_ERROR_HANDLED = 1, // The error was consumed by the policy
+};
+
+// A traceback frame passed to error callbacks, see lutec_TraceFrame
+struct lutec_ErrorFrame
+{
+    const char *source;
+    const char *function; // Null for anonymous functions
+    int line;
+};
+
+// Receives an error of a scheduled coroutine, the thread only identifies the coroutine
+typedef void (*lutec_ErrorCallback)(void *ctx, lua_State *thread, const char *message, size_t size, const lutec_ErrorFrame *frames, int nframes);
+
+// A host-provided error handler shared by a runtime and all of its child VMs
+struct lutec_ErrorHandler
+{
+    lutec_ErrorCallback callback = nullptr;
+    void *ctx = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    ~lutec_ErrorHandler()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// An error stored by LUTEC_POLICY_COLLECT
+struct lutec_TaskError
+{
+    lua_State *thread;
+    std::string message;
+    std::vector<lutec_TraceFrame> traceback;
+};
+
+// Levels of the messages passed to the log sink
+enum lutec_LogLevel
+{
+    LUTEC_LOG_INFO = 0,  // print and writes to process.stdout
+    LUTEC_LOG_WARN = 1,  // warn
+    LUTEC_LOG_ERROR = 2, // Writes to process.stderr
+};
+
+// Receives a message written by a script. chunkname is the chunk of the calling function, or
+// null if it was called from C
+typedef void (*lutec_LogCallback)(void *ctx, lua_State *thread, int level, const char *chunkname, const char *message, size_t size);
+
+// A host-provided log sink shared by a runtime and all of its child VMs
+struct lutec_LogSink
+{
+    lutec_LogCallback callback = nullptr;
+    void *ctx = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    ~lutec_LogSink()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// How much of @lute/system sandboxed runtimes can access
+enum lutec_SystemAccess
+{
+    LUTEC_SYSTEM_NONE = 0,  // The library is removed
+    LUTEC_SYSTEM_BASIC = 1, // Only members that don't identify the host (os, arch, ...)
+    LUTEC_SYSTEM_FULL = 2,  // The library is kept as is
+};
+
+// Directory @lute/fs is confined to
+struct lutec_FsScope
+{
+    std::string root; // Canonical path of the root, empty if fs is not scoped
+    bool readOnly = false;
+};
+
+// Restrictions on process.run set by the host, applied to its child VMs as well
+struct lutec_ProcessPolicy
+{
+    bool enabled = false;               // Whether process.run goes through the policy
+    bool allowAll = false;              // Whether any executable may be started
+    std::vector<std::string> allow;     // Executables process.run may start, matched exactly
+    bool clearEnv = false;              // Whether the host environment is not inherited
+    std::vector<std::string> removeEnv; // Variables removed from the environment
+    std::vector<std::string> setEnv;    // Variables set in the environment, as "NAME=value"
+    std::string cwd;                    // Working directory processes are pinned to, if any
+    uint64_t timeoutMs = 0;             // Processes are killed after this long, 0 for no limit
+    uint64_t maxOutputBytes = 0;        // Processes are killed past this much output, 0 for no limit
+};
+
+// C layout of lutec_ProcessPolicy passed by the host to lutec_setprocesspolicy
+struct lutec_ProcessPolicyDesc
+{
+    int allowAll;
+    const char *const *allow;
+    int allowCount;
+    int clearEnv;
+    const char *const *removeEnv;
+    int removeEnvCount;
+    const char *const *setEnv;
+    int setEnvCount;
+    const char *cwd;
+    uint64_t timeoutMs;
+    uint64_t maxOutputBytes;
+};
+
+// Sandbox settings of a runtime, applied to its child VMs as well
+struct lutec_Sandbox
+{
+    bool enabled = false;
+    bool processEnabled = false; // Whether process.run is available at all, see lutec_ProcessPolicy
+    lutec_SystemAccess system = LUTEC_SYSTEM_NONE;
+};
+
+// Compiles and loads a module from source, pushing the chunk function (or an error message)
+//
+// Returns 0 on success or a Luau status code on failure
+typedef int (*lutec_LoadCallback)(void *ctx, lua_State *L, const char *chunkname, const char *source, size_t size);
+
+// A host-provided module loader shared by a runtime and all of its child VMs
+struct lutec_Loader
+{
+    lutec_LoadCallback load = nullptr;
+    void *ctx = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    ~lutec_Loader()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// Called right before the global state of a runtime (or of one of its child VMs) is closed,
+// while everything in it is still valid
+typedef void (*lutec_CloseCallback)(void *ctx, lua_State *L);
+
+// A host-provided close hook shared by a runtime and all of its child VMs
+struct lutec_CloseHook
+{
+    lutec_CloseCallback onclose = nullptr;
+    void *ctx = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    ~lutec_CloseHook()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// Host modules (reachable through require("@host/name")) shared by a runtime and all of its
+// child VMs. The modules are owned by the host, LuteExt only keeps them alive
+struct lutec_HostModules
+{
+    void *ctx = nullptr;
+    void (*release)(void *ctx) = nullptr;
+
+    ~lutec_HostModules()
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+    }
+};
+
+// Per-runtime state owned by LuteExt
+//
+// Lute's Runtime struct is shared with the upstream runtime and cannot be extended,
+// so any embedding-specific settings are kept in a side table keyed by the Runtime
+struct lutec_RuntimeExt
+{
+    bool codegen = false;                           // Whether loaded chunks should be natively compiled
+    uint64_t nativeFunctions = 0;                   // Functions natively compiled by lutec_load
+    std::shared_ptr<lutec_Loader> loader;           // Module loader used by lutec_loadsource, if any
+    std::shared_ptr<lutec_HostModules> hostModules; // Modules registered by the host, if any
+    std::shared_ptr<lutec_CloseHook> closeHook;     // Called before the state is closed, if any
+
+    std::shared_ptr<lutec_Profiler> profiler = lutec_profiler_new(); // Sampling profiler, shared with child VMs
+    uint64_t profilerTick = 0;                                       // Last profiler tick sampled by this runtime
+
+    std::shared_ptr<lutec_DebugHooks> debugger; // Attached debugger, if any
+
+    lutec_Budget budget;
+    lutec_InterruptReason interruptReason = LUTEC_INTERRUPT_NONE;   // Why the last coroutine was aborted
+    lutec_InterruptReason reportedInterrupt = LUTEC_INTERRUPT_NONE; // Interrupt already reported by lutec_run_once
+
+    std::vector<lutec_TraceFrame> lastTraceback; // Frames of the coroutine that last failed in the scheduler
+    bool hasTraceback = false;
+
+    lutec_ErrorPolicy errorPolicy = LUTEC_POLICY_REPORT;
+    std::shared_ptr<lutec_ErrorHandler> errorHandler; // Called for every error handled by the policy, if any
+    std::vector<lutec_TaskError> collectedErrors;
+    std::unordered_map<const void *, int> restarts; // Restarts per entry function
+    int maxRestarts = 0;
+    int restartRef = LUA_NOREF; // Registry reference to the last restarted coroutine
+    bool aborted = false; // Set once LUTEC_POLICY_ABORT stopped the scheduler
+
+    std::shared_ptr<lutec_LogSink> logSink; // Receives print, warn and stdio output, if any
+
+    lutec_FsScope fs;
+    lutec_ProcessPolicy process;
+    lutec_Sandbox sandbox;
+};
+
+// Returns the extension state for the runtime attached to L, creating it if needed
+//
+// Returns nullptr if no runtime is loaded into L
+lutec_RuntimeExt *lutec_getext(lua_State *L);
+
+// Copies the extension state of the parent runtime into the (child) runtime
+//
+// Used when Lute.VM creates a child VM so that settings apply to all VMs of a runtime
+void lutec_inheritext(lua_State *parent, Runtime *child);
+
+// Frees the extension state of the runtime, if any
+void lutec_freeext(Runtime *runtime);
+
+// Installs the LuteExt interrupt handler on the global state of L
+//
+// The interrupt is shared between all interrupt-based features (profiler, budgets, ...)
+void lutec_installinterrupt(lua_State *L);
+
+// Called from the interrupt handler to take a profiler sample if one is due
+void lutec_profiler_interrupt(lua_State *L, lutec_RuntimeExt *ext);
+
+// Called from the interrupt handler to enforce the execution budget, raising an error when exceeded
+void lutec_budget_interrupt(lua_State *L, lutec_RuntimeExt *ext);
+
+// Starts a new slice for the next coroutine resumed in the runtime, and clears the interrupt
+// reported for the previous run
+void lutec_budget_newslice(lutec_RuntimeExt *ext);
+
+// Takes the reason the failed coroutine T was aborted, if it was aborted by a budget
+//
+// Besides the runtime's own budget, this covers coroutines failing with the error of a call
+// into a child VM that was aborted by its budget
+lutec_InterruptReason lutec_budget_takereason(lutec_RuntimeExt *ext, lua_State *T);
+
+// Captures the frames of the thread T, innermost first
+void lutec_capturetraceback(lua_State *T, std::vector<lutec_TraceFrame> &frames);
+
+// Appends the frames to out as lines in the form "source:line function name"
+void lutec_formattraceback(const std::vector<lutec_TraceFrame> &frames, std::string &out);
+
+// Applies the error policy to the failed coroutine T, whose traceback must already be captured
+//
+// GL is the global state of the runtime, used to run restarted tasks. When the error must be
+// reported, T is set to the coroutine holding the error, which differs from the original one
+// if the task failed again after being restarted
+lutec_ErrorAction lutec_handleerror(lua_State *GL, lutec_RuntimeExt *ext, lua_State **T);
+
+// Replaces the function at -2 with a Luau wrapper calling it, with the check function at -1
+//
+// guard is the source of a chunk receiving the original function and the check function, and
+// returning the wrapper. Wrappers are written in Luau so that the original functions can yield
+void lutec_guard(lua_State *L, const char *guard);
+
+// Compiles and loads a module from source through the module loader of the runtime, pushing
+// the chunk function (or the error message on failure)
+extern "C" int lutec_loadsource(lua_State *L, const char *chunkname, const char *source, size_t size);
+
+// Resolves path against the root of an fs scope, returning false if it escapes the root
+bool lutec_fs_resolve(const std::string &root, const char *path, std::string &out);
+
+// Replaces the @lute/fs library table at the top of the stack with a variant confined to the scope
+void lutec_fs_wrap(lua_State *L, const lutec_FsScope &scope);
+
+// Stores the process policy described by desc in the extension state
+void lutec_process_setpolicy(lutec_RuntimeExt &ext, const lutec_ProcessPolicyDesc &desc);
+
+// Replaces process.run in the @lute/process library table at the top of the stack with a runner
+// enforcing the process policy of the runtime. When restricted, the table is replaced with one
+// only holding run
+void lutec_process_wrap(lua_State *L, bool restricted);
+
+// Replaces print, warn and the stdio writers of the Lute libraries in the globals of L with
+// functions writing to the log sink of the runtime
+void lutec_log_install(lua_State *L);
+
+// Restricts the Lute libraries in the globals of L and sandboxes it with luaL_sandbox
+//
+// doBeforeSandbox, if any, is called right before the globals become read-only
+void lutec_sandbox_apply(lua_State *L, const lutec_RuntimeExt &ext, void (*doBeforeSandbox)(lua_State *));
+
+// Installs the debugger callbacks on the global state of L, set by Luau.LuteExt.Debug when the
+// debugger feature is enabled
+extern void (*lutec_debug_install_hook)(lua_State *L);
+
+// Native code generation hooks, set by Luau.LuteExt.CodeGen when the codegen feature is enabled
+//
+// These are function pointers so that LuteExt itself never references Luau.CodeGen symbols
+extern void (*lutec_codegen_create_hook)(lua_State *L);
+extern void (*lutec_codegen_compile_hook)(lua_State *L, int idx);
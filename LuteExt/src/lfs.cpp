@@ -0,0 +1,225 @@
+#include "lua.h"
+#include "lualib.h"
+#include "luacode.h"
+
+#include "lext.h"
+
+#include <filesystem>
+#include <stdlib.h>
+#include <string.h>
+
+namespace fs = std::filesystem;
+
+enum lutec_FsAccess
+{
+    LUTEC_FS_READ,  // Only reads from the paths
+    LUTEC_FS_WRITE, // Modifies the tree, denied in read-only scopes
+    LUTEC_FS_OPEN,  // Depends on the mode passed as the second argument
+};
+
+// Functions of @lute/fs taking paths, with the number of leading path arguments
+//
+// Functions of the library not listed here (other than the handle-based ones) are not
+// available in scoped runtimes
+static const struct
+{
+    const char *name;
+    int paths;
+    lutec_FsAccess access;
+} kFsPathFunctions[] = {
+    {"open", 1, LUTEC_FS_OPEN},
+    {"readfiletostring", 1, LUTEC_FS_READ},
+    {"writestringtofile", 1, LUTEC_FS_WRITE},
+    {"readasync", 1, LUTEC_FS_READ},
+    {"remove", 1, LUTEC_FS_WRITE},
+    {"stat", 1, LUTEC_FS_READ},
+    {"mkdir", 1, LUTEC_FS_WRITE},
+    {"rmdir", 1, LUTEC_FS_WRITE},
+    {"exists", 1, LUTEC_FS_READ},
+    {"type", 1, LUTEC_FS_READ},
+    {"listdir", 1, LUTEC_FS_READ},
+    {"watch", 1, LUTEC_FS_READ},
+    {"copy", 2, LUTEC_FS_WRITE},
+    {"link", 2, LUTEC_FS_WRITE},
+    {"symlink", 2, LUTEC_FS_WRITE},
+};
+
+// Functions of @lute/fs operating on already opened handles
+static const char *kFsHandleFunctions[] = {"read", "write", "close"};
+
+// Wrappers are written in Luau so that the original functions can still yield
+static const char *kGuardOnePath = "local orig, check = ... return function(p, ...) return orig(check(p, ...), ...) end";
+static const char *kGuardTwoPaths = "local orig, check = ... return function(a, b, ...) return orig(check(a), check(b), ...) end";
+
+static bool lutec_fs_within(const fs::path &root, const fs::path &path)
+{
+    fs::path relative = path.lexically_relative(root);
+    if (relative.empty())
+    {
+        return false;
+    }
+
+    // The root itself is "."
+    auto first = relative.begin();
+    return first == relative.end() || *first != "..";
+}
+
+bool lutec_fs_resolve(const std::string &root, const char *path, std::string &out)
+{
+    fs::path rootPath = root;
+    fs::path resolved = (rootPath / fs::path(path)).lexically_normal();
+    if (!lutec_fs_within(rootPath, resolved))
+    {
+        return false;
+    }
+
+    // Follow the symlinks of the existing part of the path, so links can't point out of the
+    // root. Links can still be swapped after the check, the scope only guards against scripts
+    std::error_code ec;
+    fs::path real = fs::weakly_canonical(resolved, ec);
+    if (ec || !lutec_fs_within(rootPath, real))
+    {
+        return false;
+    }
+
+    out = resolved.string();
+    return true;
+}
+
+static bool lutec_fs_writemode(const char *mode)
+{
+    return strpbrk(mode, "wax+") != nullptr;
+}
+
+// Checks a path argument against the scope, returning the resolved path
+//
+// Upvalues are the root, whether the scope is read-only and the access of the function
+static int lutec_fs_checkpath(lua_State *L)
+{
+    const char *path = luaL_checkstring(L, 1);
+    std::string root = lua_tostring(L, lua_upvalueindex(1));
+    bool readOnly = lua_toboolean(L, lua_upvalueindex(2));
+    lutec_FsAccess access = lutec_FsAccess(lua_tointeger(L, lua_upvalueindex(3)));
+
+    if (readOnly)
+    {
+        bool writes = access == LUTEC_FS_WRITE;
+        if (access == LUTEC_FS_OPEN)
+        {
+            const char *mode = luaL_optstring(L, 2, "r");
+            writes = lutec_fs_writemode(mode);
+        }
+
+        if (writes)
+        {
+            luaL_errorL(L, "fs: '%s' is read-only", path);
+        }
+    }
+
+    std::string resolved;
+    if (!lutec_fs_resolve(root, path, resolved))
+    {
+        luaL_errorL(L, "fs: access to '%s' is outside of the allowed directory", path);
+    }
+
+    lua_pushlstring(L, resolved.data(), resolved.size());
+    return 1;
+}
+
+void lutec_guard(lua_State *L, const char *guard)
+{
+    size_t bytecodeSize = 0;
+    char *bytecode = luau_compile(guard, strlen(guard), nullptr, &bytecodeSize);
+    int status = luau_load(L, "=guard", bytecode, bytecodeSize, 0);
+    free(bytecode);
+
+    if (status != 0)
+    {
+        lua_error(L);
+    }
+
+    lua_insert(L, -3);
+    lua_call(L, 2, 1);
+}
+
+void lutec_fs_wrap(lua_State *L, const lutec_FsScope &scope)
+{
+    int original = lua_gettop(L);
+    lua_newtable(L);
+
+    for (const auto &fn : kFsPathFunctions)
+    {
+        lua_getfield(L, original, fn.name);
+        if (!lua_isfunction(L, -1))
+        {
+            lua_pop(L, 1);
+            continue;
+        }
+
+        // Read-only scopes don't expose functions that can only write
+        if (scope.readOnly && fn.access == LUTEC_FS_WRITE)
+        {
+            lua_pop(L, 1);
+            continue;
+        }
+
+        lua_pushlstring(L, scope.root.data(), scope.root.size());
+        lua_pushboolean(L, scope.readOnly);
+        lua_pushinteger(L, fn.access);
+        lua_pushcclosurek(L, lutec_fs_checkpath, "checkpath", 3, nullptr);
+        lutec_guard(L, fn.paths == 2 ? kGuardTwoPaths : kGuardOnePath);
+        lua_setfield(L, -2, fn.name);
+    }
+
+    for (const char *name : kFsHandleFunctions)
+    {
+        lua_getfield(L, original, name);
+        lua_setfield(L, -2, name);
+    }
+
+    lua_replace(L, original);
+}
+
+// Confines @lute/fs of the runtime attached to L (and child VMs created afterwards) to root
+//
+// Paths are resolved against root, and paths escaping it (through ".." or symlinks) raise
+// errors. A read-only scope removes functions that modify the tree and only allows opening
+// files for reading. Applies to fs when it's opened with lutec_openfs, and to the fs global if
+// it was already opened. Returns 0 on success, 1 if no runtime is loaded, 2 if the runtime is
+// sandboxed and 3 if the root doesn't exist
+extern "C" int lutec_setfsroot(lua_State *L, const char *root, int readOnly)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (ext->sandbox.enabled)
+    {
+        return 2; // The globals are read-only
+    }
+
+    std::error_code ec;
+    fs::path rootPath = fs::canonical(root, ec);
+    if (ec || !fs::is_directory(rootPath, ec))
+    {
+        return 3;
+    }
+
+    ext->fs.root = rootPath.string();
+    ext->fs.readOnly = readOnly != 0;
+
+    lua_getglobal(L, "fs");
+    if (lua_istable(L, -1))
+    {
+        lutec_fs_wrap(L, ext->fs);
+        lua_setglobal(L, "fs");
+    }
+    else
+    {
+        lua_pop(L, 1);
+    }
+
+    return 0;
+}
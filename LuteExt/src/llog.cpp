@@ -0,0 +1,179 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lext.h"
+
+#include <stdio.h>
+#include <string.h>
+
+// Members of the Lute libraries writing to the standard streams, either writer functions or
+// stream tables with a write function
+//
+// Writers of libraries that aren't opened are skipped
+static const struct
+{
+    const char *library;
+    const char *name;
+    lutec_LogLevel level;
+} kLogWriters[] = {
+    {"process", "stdout", LUTEC_LOG_INFO},
+    {"process", "stderr", LUTEC_LOG_ERROR},
+};
+
+// Passes the message to the log sink of the runtime, or writes it to the standard streams if the
+// sink was removed. line is whether the message is a whole line, as print and warn add newlines
+static void lutec_log_write(lua_State *L, lutec_LogLevel level, const std::string &message, bool line)
+{
+    // Keep the sink alive even if it is replaced while logging
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    std::shared_ptr<lutec_LogSink> sink = ext ? ext->logSink : nullptr;
+
+    if (!sink)
+    {
+        FILE *stream = level == LUTEC_LOG_INFO ? stdout : stderr;
+        fwrite(message.data(), 1, message.size(), stream);
+        if (line)
+        {
+            fputc('\n', stream);
+        }
+        return;
+    }
+
+    const char *chunkname = nullptr;
+    lua_Debug ar;
+    if (lua_getinfo(L, 1, "s", &ar) && strcmp(ar.what, "C") != 0)
+    {
+        chunkname = ar.source;
+    }
+
+    sink->callback(sink->ctx, L, level, chunkname, message.data(), message.size());
+}
+
+// print and warn, formatting their arguments like print. The level is upvalue 1
+static int lutec_log_print(lua_State *L)
+{
+    std::string message;
+
+    int n = lua_gettop(L);
+    for (int i = 1; i <= n; i++)
+    {
+        size_t size = 0;
+        const char *s = luaL_tolstring(L, i, &size);
+        if (i > 1)
+        {
+            message += '\t';
+        }
+        message.append(s, size);
+        lua_pop(L, 1);
+    }
+
+    lutec_log_write(L, lutec_LogLevel(lua_tointeger(L, lua_upvalueindex(1))), message, true);
+    return 0;
+}
+
+// Stdio writers, writing their string arguments as is. The level is upvalue 1
+static int lutec_log_writer(lua_State *L)
+{
+    std::string message;
+
+    // Writers of stream tables may be called as methods
+    int first = lua_istable(L, 1) ? 2 : 1;
+
+    int n = lua_gettop(L);
+    for (int i = first; i <= n; i++)
+    {
+        size_t size = 0;
+        const char *s = luaL_checklstring(L, i, &size);
+        message.append(s, size);
+    }
+
+    lutec_log_write(L, lutec_LogLevel(lua_tointeger(L, lua_upvalueindex(1))), message, false);
+    return 0;
+}
+
+// Sets the field of the table at the top of the stack to a logging function, even if the table
+// is read-only (as in sandboxed runtimes)
+static void lutec_log_set(lua_State *L, const char *name, lua_CFunction fn, lutec_LogLevel level)
+{
+    bool readonly = lua_getreadonly(L, -1);
+    lua_setreadonly(L, -1, false);
+
+    lua_pushinteger(L, level);
+    lua_pushcclosurek(L, fn, name, 1, nullptr);
+    lua_setfield(L, -2, name);
+
+    lua_setreadonly(L, -1, readonly);
+}
+
+void lutec_log_install(lua_State *L)
+{
+    lua_pushvalue(L, LUA_GLOBALSINDEX);
+
+    lutec_log_set(L, "print", lutec_log_print, LUTEC_LOG_INFO);
+    lutec_log_set(L, "warn", lutec_log_print, LUTEC_LOG_WARN);
+
+    for (const auto &writer : kLogWriters)
+    {
+        lua_getfield(L, -1, writer.library);
+        if (lua_istable(L, -1))
+        {
+            lua_getfield(L, -1, writer.name);
+            if (lua_isfunction(L, -1))
+            {
+                lua_pop(L, 1);
+                lutec_log_set(L, writer.name, lutec_log_writer, writer.level);
+            }
+            else if (lua_istable(L, -1))
+            {
+                lua_getfield(L, -1, "write");
+                bool exists = lua_isfunction(L, -1);
+                lua_pop(L, 1);
+
+                if (exists)
+                {
+                    lutec_log_set(L, "write", lutec_log_writer, writer.level);
+                }
+                lua_pop(L, 1);
+            }
+            else
+            {
+                lua_pop(L, 1);
+            }
+        }
+        lua_pop(L, 1);
+    }
+
+    lua_pop(L, 1);
+}
+
+// Sets the sink receiving print, warn, process.stdout and process.stderr output of the runtime attached to L and its
+// child VMs, or removes it if callback is null
+//
+// The functions are replaced in the globals of L and in child VMs created afterwards. Without a
+// sink, they write to the standard streams again. release is called with ctx once no runtime
+// references the sink anymore
+extern "C" int lutec_setlogsink(lua_State *L, lutec_LogCallback callback, void *ctx, void (*release)(void *ctx))
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext || !callback)
+    {
+        if (ext)
+        {
+            ext->logSink.reset();
+        }
+        if (release)
+        {
+            release(ctx);
+        }
+        return ext ? 0 : 1;
+    }
+
+    std::shared_ptr<lutec_LogSink> sink = std::make_shared<lutec_LogSink>();
+    sink->callback = callback;
+    sink->ctx = ctx;
+    sink->release = release;
+    ext->logSink = std::move(sink);
+
+    lutec_log_install(L);
+    return 0;
+}
@@ -1,4 +1,5 @@
 #include "lua.h"
+#include "luacode.h"
 
 #include "lute/time.h"
 #include "lute/task.h"
@@ -13,6 +14,15 @@
 #include "lute/clicommands.h"
 #include "uv.h"
 
+#include "lext.h"
+
+#include <atomic>
+#include <memory>
+#include <mutex>
+#include <stdlib.h>
+#include <string>
+#include <unordered_map>
+
 struct lua_State_wrapper
 {
     lua_State *parent;
@@ -57,6 +67,125 @@ extern "C" int lutec_set_runtimeinitter(lutec_setupState_init config_init)
     return 0; // Successfully set up the runtime
 }
 
+static std::mutex lutec_ext_mutex;
+static std::unordered_map<Runtime *, std::unique_ptr<lutec_RuntimeExt>> lutec_exts;
+
+void (*lutec_codegen_create_hook)(lua_State *L) = nullptr;
+void (*lutec_codegen_compile_hook)(lua_State *L, int idx) = nullptr;
+void (*lutec_debug_install_hook)(lua_State *L) = nullptr;
+
+// Bumped whenever an entry is replaced or removed, invalidating the per-thread lookup cache
+static std::atomic<uint64_t> lutec_ext_generation{1};
+
+struct lutec_ExtCache
+{
+    uint64_t generation = 0;
+    Runtime *runtime = nullptr;
+    lutec_RuntimeExt *ext = nullptr;
+};
+
+lutec_RuntimeExt *lutec_getext(lua_State *L)
+{
+    Runtime *runtime = static_cast<Runtime *>(lua_getthreaddata(L));
+    if (!runtime)
+    {
+        return nullptr;
+    }
+
+    // The interrupt handler looks up the extension state very frequently, so avoid taking
+    // the lock when the same runtime is looked up again on this thread
+    static thread_local lutec_ExtCache cache;
+    uint64_t generation = lutec_ext_generation.load(std::memory_order_acquire);
+    if (cache.runtime == runtime && cache.generation == generation)
+    {
+        return cache.ext;
+    }
+
+    std::lock_guard<std::mutex> lock(lutec_ext_mutex);
+    std::unique_ptr<lutec_RuntimeExt> &ext = lutec_exts[runtime];
+    if (!ext)
+    {
+        ext = std::make_unique<lutec_RuntimeExt>();
+    }
+
+    cache.generation = generation;
+    cache.runtime = runtime;
+    cache.ext = ext.get();
+    return ext.get();
+}
+
+void lutec_inheritext(lua_State *parent, Runtime *child)
+{
+    lutec_RuntimeExt *parentExt = parent ? lutec_getext(parent) : nullptr;
+
+    std::lock_guard<std::mutex> lock(lutec_ext_mutex);
+
+    // Child runtimes are freed by Lute.VM directly, so a new runtime may reuse the address of
+    // an old one whose entry wasn't removed (e.g. if its state was never closed). Always
+    // overwrite the entry to avoid picking up stale settings
+    if (parentExt)
+    {
+        std::unique_ptr<lutec_RuntimeExt> ext = std::make_unique<lutec_RuntimeExt>(*parentExt);
+        ext->nativeFunctions = 0;
+        ext->profilerTick = 0;
+        ext->lastTraceback.clear();
+        ext->hasTraceback = false;
+        ext->collectedErrors.clear();
+        ext->restarts.clear();
+        ext->restartRef = LUA_NOREF;
+        ext->aborted = false;
+        lutec_exts[child] = std::move(ext);
+    }
+    else
+    {
+        lutec_exts[child] = std::make_unique<lutec_RuntimeExt>();
+    }
+    lutec_ext_generation.fetch_add(1, std::memory_order_release);
+}
+
+void lutec_freeext(Runtime *runtime)
+{
+    std::lock_guard<std::mutex> lock(lutec_ext_mutex);
+    lutec_exts.erase(runtime);
+    lutec_ext_generation.fetch_add(1, std::memory_order_release);
+}
+
+static void lutec_interrupt(lua_State *L, int gc)
+{
+    // GC interrupts must not run Luau code or raise errors
+    if (gc >= 0)
+    {
+        return;
+    }
+
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return;
+    }
+
+    if (ext->profiler)
+    {
+        lutec_profiler_interrupt(L, ext);
+    }
+
+    if (ext->debugger && ext->debugger->pause && ext->debugger->pause->exchange(false))
+    {
+        ext->debugger->onevent(ext->debugger->ctx, L, LUTEC_DEBUG_PAUSE);
+    }
+
+    // Must come last as it may raise an error
+    if (ext->budget.active())
+    {
+        lutec_budget_interrupt(L, ext);
+    }
+}
+
+void lutec_installinterrupt(lua_State *L)
+{
+    lua_callbacks(L)->interrupt = lutec_interrupt;
+}
+
 /*
 static void luteopen_lib(lua_State *L, const char *name)
 {
@@ -82,7 +211,16 @@ extern "C" int lutec_opencrypto(lua_State *L)
 
 extern "C" int lutec_openfs(lua_State *L)
 {
-    return luteopen_fs(L);
+    int results = luteopen_fs(L);
+
+    // Runtimes with an fs scope only get the confined variant
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && !ext->fs.root.empty())
+    {
+        lutec_fs_wrap(L, ext->fs);
+    }
+
+    return results;
 }
 
 extern "C" int lutec_openluau(lua_State *L)
@@ -99,7 +237,16 @@ extern "C" int lutec_opennet(lua_State *L)
 
 extern "C" int lutec_openprocess(lua_State *L)
 {
-    return luteopen_process(L);
+    int results = luteopen_process(L);
+
+    // Runtimes with a process policy only start processes through it
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->process.enabled)
+    {
+        lutec_process_wrap(L, false);
+    }
+
+    return results;
 }
 
 extern "C" int lutec_opentask(lua_State *L)
@@ -114,7 +261,7 @@ extern "C" int lutec_openvm(lua_State *L)
 
 extern "C" int lutec_opensystem(lua_State *L)
 {
-    return luteopen_vm(L);
+    return luteopen_system(L);
 }
 
 extern "C" int lutec_opentime(lua_State *L)
@@ -131,6 +278,31 @@ CliModuleResult getCliModule(std::string_view path)
     return {CliModuleType::NotFound};
 }
 
+// Runs the close hook of the runtime attached to L, if any
+static void lutec_runclosehook(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->closeHook)
+    {
+        // Keep the hook alive even if it is replaced from the callback
+        std::shared_ptr<lutec_CloseHook> hook = ext->closeHook;
+        hook->onclose(hook->ctx, L);
+    }
+}
+
+// Deleter of the global state of child VMs
+static void lutec_closestate(lua_State *L)
+{
+    Runtime *runtime = static_cast<Runtime *>(lua_getthreaddata(L));
+    lutec_runclosehook(L);
+    lua_close(L);
+
+    if (runtime)
+    {
+        lutec_freeext(runtime);
+    }
+}
+
 // Needed for Lute.VM
 lua_State *setupState(lua_State *parent, Runtime &runtime, void (*doBeforeSandbox)(lua_State *))
 {
@@ -141,6 +313,10 @@ lua_State *setupState(lua_State *parent, Runtime &runtime, void (*doBeforeSandbo
     lua_state_wrapper->DC = nullptr;              // Data copy VM, initialize to nullptr explicitly as the setup_lua_state will set it
     lua_state_wrapper->runtime_to_set = &runtime; // Set the runtime to set
 
+    // Child VMs share the settings of the runtime that created them. This must happen before
+    // the host's setup so that libraries opened there (such as a scoped fs) see the settings
+    lutec_inheritext(parent, &runtime);
+
     lutec_setup->setup_lua_state(lua_state_wrapper);
 
     lua_State *L = std::move(lua_state_wrapper->L);
@@ -159,10 +335,42 @@ lua_State *setupState(lua_State *parent, Runtime &runtime, void (*doBeforeSandbo
         return nullptr; // Thread data already set, cannot set runtime
 
     runtime.dataCopy.reset(DC);
-    runtime.globalState.reset(L);
+    // Lute.VM closes child VMs on its own, so the close hook runs from the deleter
+    runtime.globalState = decltype(runtime.globalState)(L, lutec_closestate);
     L = runtime.globalState.get();
     runtime.GL = L;
 
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->codegen && lutec_codegen_create_hook)
+    {
+        lutec_codegen_create_hook(L);
+    }
+
+    if (ext && ext->logSink)
+    {
+        lutec_log_install(L);
+    }
+
+    // Child VMs of sandboxed runtimes are sandboxed as well, doBeforeSandbox is only meaningful
+    // in that case as the host's setup is responsible for everything else
+    if (ext && ext->sandbox.enabled)
+    {
+        lutec_sandbox_apply(L, *ext, doBeforeSandbox);
+    }
+
+    // The profiler can be started at any time after the child VM was created, so the interrupt
+    // handler is always installed
+    if (ext)
+    {
+        ext->budget.sliceStarted = false;
+        lutec_installinterrupt(L);
+    }
+
+    if (ext && ext->debugger && lutec_debug_install_hook)
+    {
+        lutec_debug_install_hook(L);
+    }
+
     return L;
 }
 
@@ -188,6 +396,7 @@ extern "C" void lutec_setup_runtime(lua_State *L)
     runtime->GL = L;
 
     lua_setthreaddata(L, runtime);
+    lutec_inheritext(nullptr, runtime);
     return;
 }
 
@@ -199,6 +408,7 @@ extern "C" int lutec_destroy_runtime(lua_State *L)
     if (runtime)
     {
         runtime->stop.store(true);
+        lutec_runclosehook(L);
 
         if (runtime->globalState)
         {
@@ -213,6 +423,7 @@ extern "C" int lutec_destroy_runtime(lua_State *L)
         }
 
         lua_setthreaddata(L, nullptr);
+        lutec_freeext(runtime);
         delete runtime;
 
         return 0;
@@ -223,11 +434,160 @@ extern "C" int lutec_destroy_runtime(lua_State *L)
     }
 }
 
+// Wrapper around luau_load that applies the runtime settings (such as native compilation)
+// to the loaded chunk
+//
+// Hosts should use this instead of luau_load for modules loaded into a Lute runtime, as
+// chunks loaded with luau_load directly are never natively compiled
+extern "C" int lutec_load(lua_State *L, const char *chunkname, const char *data, size_t size, int env)
+{
+    int status = luau_load(L, chunkname, data, size, env);
+    if (status != 0)
+    {
+        return status;
+    }
+
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->codegen && lutec_codegen_compile_hook)
+    {
+        lutec_codegen_compile_hook(L, -1);
+    }
+
+    return status;
+}
+
+// Sets the module loader used by lutec_loadsource for this runtime and its child VMs
+//
+// release is called with ctx once no runtime references the loader anymore
+extern "C" int lutec_setloader(lua_State *L, lutec_LoadCallback load, void *ctx, void (*release)(void *ctx))
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+        return 1; // No runtime loaded
+    }
+
+    if (!load)
+    {
+        ext->loader.reset();
+        if (release)
+        {
+            release(ctx);
+        }
+        return 0;
+    }
+
+    std::shared_ptr<lutec_Loader> loader = std::make_shared<lutec_Loader>();
+    loader->load = load;
+    loader->ctx = ctx;
+    loader->release = release;
+    ext->loader = std::move(loader);
+
+    return 0;
+}
+
+// Sets the host modules of this runtime and its child VMs, replacing the previous ones
+//
+// release is called with ctx once no runtime references the modules anymore
+extern "C" int lutec_sethostmodules(lua_State *L, void *ctx, void (*release)(void *ctx))
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+        return 1; // No runtime loaded
+    }
+
+    std::shared_ptr<lutec_HostModules> modules = std::make_shared<lutec_HostModules>();
+    modules->ctx = ctx;
+    modules->release = release;
+    ext->hostModules = std::move(modules);
+
+    return 0;
+}
+
+// Returns the ctx passed to lutec_sethostmodules for the runtime attached to L (or the runtime
+// that created it), or null if no host modules were set
+extern "C" void *lutec_gethostmodules(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    return ext && ext->hostModules ? ext->hostModules->ctx : nullptr;
+}
+
+// Sets the hook called right before the state of this runtime or of one of its child VMs is
+// closed, replacing the previous one
+//
+// For the runtime itself, the hook runs from lutec_destroy_runtime. release is called with ctx
+// once no runtime references the hook anymore
+extern "C" int lutec_setclosehook(lua_State *L, lutec_CloseCallback onclose, void *ctx, void (*release)(void *ctx))
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        if (release)
+        {
+            release(ctx);
+        }
+        return 1; // No runtime loaded
+    }
+
+    if (!onclose)
+    {
+        ext->closeHook.reset();
+        if (release)
+        {
+            release(ctx);
+        }
+        return 0;
+    }
+
+    std::shared_ptr<lutec_CloseHook> hook = std::make_shared<lutec_CloseHook>();
+    hook->onclose = onclose;
+    hook->ctx = ctx;
+    hook->release = release;
+    ext->closeHook = std::move(hook);
+
+    return 0;
+}
+
+// Compiles and loads a module from source using the runtime's module loader
+//
+// The require of lutec_openrequire loads modules through this. Hosts with their own require
+// implementation should call it as well so that module loading goes through the loader (and
+// any bytecode cache it uses). Without a loader, the source is compiled with the default options
+extern "C" int lutec_loadsource(lua_State *L, const char *chunkname, const char *source, size_t size)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->loader)
+    {
+        // Keep the loader alive even if it is replaced while loading
+        std::shared_ptr<lutec_Loader> loader = ext->loader;
+        return loader->load(loader->ctx, L, chunkname, source, size);
+    }
+
+    size_t bytecodeSize = 0;
+    char *bytecode = luau_compile(source, size, nullptr, &bytecodeSize);
+    int status = lutec_load(L, chunkname, bytecode, bytecodeSize, 0);
+    free(bytecode);
+
+    return status;
+}
+
 extern "C" const int LUTE_STATE_MISSING_ERROR = 0;
 extern "C" const int LUTE_STATE_ERROR = 1;
 extern "C" const int LUTE_STATE_SUCCESS = 2;
 extern "C" const int LUTE_STATE_EMPTY = 3;
 extern "C" const int LUTE_STATE_UNSUPPORTED_OP = 4;
+extern "C" const int LUTE_STATE_INTERRUPTED = 5; // The coroutine was aborted by its execution budget
+extern "C" const int LUTE_STATE_HANDLED = 6;     // The coroutine failed and the error was handled by the error policy
+extern "C" const int LUTE_STATE_ABORTED = 7;     // The runtime was aborted by the error policy
 
 extern "C" struct RunOnceResult
 {
@@ -238,6 +598,21 @@ extern "C" struct RunOnceResult
 // Wrapper to run one iteration of the Lute scheduler
 RunOnceResult lutec_run_once_internal(Runtime *runtime)
 {
+    lutec_RuntimeExt *runtimeExt = runtime->GL ? lutec_getext(runtime->GL) : nullptr;
+    if (runtimeExt && runtimeExt->aborted)
+    {
+        return RunOnceResult{
+            .op = LUTE_STATE_ABORTED,
+        };
+    }
+
+    // Every coroutine resumed by the scheduler gets a new budget slice, so coroutines that
+    // yield (e.g. in task.wait) aren't charged for earlier slices or the time spent waiting
+    if (runtimeExt)
+    {
+        lutec_budget_newslice(runtimeExt);
+    }
+
     auto step = runtime->runOnce();
     if (auto err = Luau::get_if<StepErr>(&step))
     {
@@ -248,9 +623,38 @@ RunOnceResult lutec_run_once_internal(Runtime *runtime)
                 .state = nullptr};
         }
 
+        // The frames of the failed coroutine are still intact at this point, so capture them
+        // before the coroutine can be collected
+        lutec_RuntimeExt *ext = lutec_getext(err->L);
+        if (ext)
+        {
+            lutec_capturetraceback(err->L, ext->lastTraceback);
+            ext->hasTraceback = true;
+        }
+
+        lua_State *failed = err->L;
+        if (ext && lutec_handleerror(runtime->GL, ext, &failed) == LUTEC_ERROR_HANDLED)
+        {
+            lutec_budget_takereason(ext, nullptr);
+            return RunOnceResult{
+                .op = LUTE_STATE_HANDLED,
+                .state = failed};
+        }
+
+        // Errors raised by the execution budget are reported separately so hosts can tell
+        // them apart from errors raised by the script itself
+        lutec_InterruptReason reason = ext ? lutec_budget_takereason(ext, failed) : LUTEC_INTERRUPT_NONE;
+        if (reason != LUTEC_INTERRUPT_NONE)
+        {
+            ext->reportedInterrupt = reason;
+            return RunOnceResult{
+                .op = LUTE_STATE_INTERRUPTED,
+                .state = failed};
+        }
+
         return RunOnceResult{
             .op = LUTE_STATE_ERROR,
-            .state = err->L};
+            .state = failed};
     }
     else if (auto success = Luau::get_if<StepSuccess>(&step))
     {
@@ -302,9 +706,31 @@ LUALIB_API int lutec_run_once_lua(lua_State *L)
     case LUTE_STATE_MISSING_ERROR:
         luaL_errorL(L, "Missing lua state");
     case LUTE_STATE_ERROR:
+    case LUTE_STATE_INTERRUPTED:
+    {
         lua_xmove(ror.state, L, 1);
+
+        // Append the traceback of the failed coroutine to string errors, as it is lost once the
+        // error is rethrown from here
+        lutec_RuntimeExt *ext = lutec_getext(L);
+        if (ext && ext->hasTraceback && lua_type(L, -1) == LUA_TSTRING)
+        {
+            std::string message = lua_tostring(L, -1);
+            message += "\nstack traceback:\n";
+            lutec_formattraceback(ext->lastTraceback, message);
+            ext->lastTraceback.clear();
+            ext->hasTraceback = false;
+
+            lua_pop(L, 1);
+            lua_pushlstring(L, message.data(), message.size());
+        }
+
         lua_error(L);
+    }
+    case LUTE_STATE_ABORTED:
+        luaL_errorL(L, "Runtime was aborted");
     case LUTE_STATE_SUCCESS:
+    case LUTE_STATE_HANDLED:
         lua_pushinteger(L, ror.op);
         lua_pushthread(ror.state);
         lua_xmove(ror.state, L, 1);
@@ -327,6 +753,12 @@ LUALIB_API int lutec_has_work(lua_State *L)
         return 0;
     }
 
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->aborted)
+    {
+        return 0; // Aborted runtimes never run again
+    }
+
     bool result = runtime->hasWork();
     if (result == true)
     {
@@ -0,0 +1,232 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lext.h"
+
+// Reads the error value at the top of T as a string without running any Luau code
+static std::string lutec_errormessage(lua_State *T)
+{
+    if (lua_gettop(T) == 0)
+    {
+        return "";
+    }
+
+    size_t size = 0;
+    if (lua_isstring(T, -1))
+    {
+        const char *message = lua_tolstring(T, -1, &size);
+        return std::string(message, size);
+    }
+
+    return std::string("(error object is a ") + luaL_typename(T, -1) + " value)";
+}
+
+static void lutec_callhandler(lutec_ErrorHandler &handler, lua_State *T, const std::string &message, const std::vector<lutec_TraceFrame> &traceback)
+{
+    // The frames point into the traceback, which outlives the call
+    std::vector<lutec_ErrorFrame> frames;
+    frames.reserve(traceback.size());
+    for (const lutec_TraceFrame &frame : traceback)
+    {
+        frames.push_back({
+            frame.native ? "=[C]" : frame.source.c_str(),
+            frame.function.empty() ? nullptr : frame.function.c_str(),
+            frame.line,
+        });
+    }
+
+    handler.callback(handler.ctx, T, message.data(), message.size(), frames.data(), int(frames.size()));
+}
+
+// Creates a coroutine that restarts the task run by T from its entry function (without arguments)
+//
+// Returns nullptr if the task can't be restarted or ran out of restarts
+static lua_State *lutec_restarttask(lua_State *GL, lutec_RuntimeExt *ext, lua_State *T)
+{
+    int depth = lua_stackdepth(T);
+    lua_Debug ar;
+    if (depth == 0 || !lua_getinfo(T, depth - 1, "f", &ar))
+    {
+        return nullptr;
+    }
+
+    if (!lua_isLfunction(T, -1))
+    {
+        lua_pop(T, 1);
+        return nullptr;
+    }
+
+    // Restarts are counted per entry function
+    int &restarts = ext->restarts[lua_topointer(T, -1)];
+    if (restarts >= ext->maxRestarts)
+    {
+        lua_pop(T, 1);
+        return nullptr;
+    }
+    restarts++;
+
+    lua_State *NT = lua_newthread(GL);
+    lua_xmove(T, NT, 1);
+
+    // Keep the coroutine alive until the next restart, so a failure can still be reported
+    if (ext->restartRef != LUA_NOREF)
+    {
+        lua_unref(GL, ext->restartRef);
+    }
+    ext->restartRef = lua_ref(GL, -1);
+    lua_pop(GL, 1);
+
+    return NT;
+}
+
+lutec_ErrorAction lutec_handleerror(lua_State *GL, lutec_RuntimeExt *ext, lua_State **T)
+{
+    if (ext->errorPolicy == LUTEC_POLICY_REPORT)
+    {
+        return LUTEC_ERROR_REPORT;
+    }
+
+    for (;;)
+    {
+        std::string message = lutec_errormessage(*T);
+
+        if (ext->errorHandler)
+        {
+            // Keep the handler alive even if it is replaced while handling the error
+            std::shared_ptr<lutec_ErrorHandler> handler = ext->errorHandler;
+            lutec_callhandler(*handler, *T, message, ext->lastTraceback);
+        }
+
+        if (ext->errorPolicy == LUTEC_POLICY_COLLECT)
+        {
+            ext->collectedErrors.push_back({*T, std::move(message), std::move(ext->lastTraceback)});
+            lua_settop(*T, 0);
+            break;
+        }
+        else if (ext->errorPolicy == LUTEC_POLICY_LOG)
+        {
+            lua_settop(*T, 0);
+            break;
+        }
+        else if (ext->errorPolicy == LUTEC_POLICY_ABORT)
+        {
+            // The error stays on T and is reported by lutec_run_once as usual
+            ext->aborted = true;
+            return LUTEC_ERROR_REPORT;
+        }
+        else if (ext->errorPolicy == LUTEC_POLICY_RESTART)
+        {
+            lua_State *NT = lutec_restarttask(GL, ext, *T);
+            if (!NT)
+            {
+                return LUTEC_ERROR_REPORT;
+            }
+
+            lua_settop(*T, 0);
+
+            int status = lua_resume(NT, nullptr, 0);
+            if (status == LUA_OK || status == LUA_YIELD)
+            {
+                *T = NT;
+                break;
+            }
+
+            // The restarted task failed right away, which goes through the policy again
+            lutec_capturetraceback(NT, ext->lastTraceback);
+            ext->hasTraceback = true;
+            *T = NT;
+        }
+        else
+        {
+            return LUTEC_ERROR_REPORT;
+        }
+    }
+
+    ext->lastTraceback.clear();
+    ext->hasTraceback = false;
+    return LUTEC_ERROR_HANDLED;
+}
+
+// Sets how errors of coroutines resumed by the scheduler of the runtime attached to L are handled
+//
+// maxRestarts is only used by LUTEC_POLICY_RESTART and limits the restarts per task
+extern "C" int lutec_seterrorpolicy(lua_State *L, int policy, int maxRestarts)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (policy < LUTEC_POLICY_REPORT || policy > LUTEC_POLICY_RESTART)
+    {
+        return 2;
+    }
+
+    ext->errorPolicy = lutec_ErrorPolicy(policy);
+    ext->maxRestarts = maxRestarts;
+    ext->restarts.clear();
+    return 0;
+}
+
+// Sets the callback invoked for every error handled by the error policy, or clears it if
+// callback is null
+//
+// release is called with ctx once no runtime references the handler anymore
+extern "C" int lutec_seterrorhandler(lua_State *L, lutec_ErrorCallback callback, void *ctx, void (*release)(void *ctx))
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext || !callback)
+    {
+        if (ext)
+        {
+            ext->errorHandler.reset();
+        }
+        if (release)
+        {
+            release(ctx);
+        }
+        return ext ? 0 : 1;
+    }
+
+    std::shared_ptr<lutec_ErrorHandler> handler = std::make_shared<lutec_ErrorHandler>();
+    handler->callback = callback;
+    handler->ctx = ctx;
+    handler->release = release;
+    ext->errorHandler = std::move(handler);
+
+    return 0;
+}
+
+// Reports (and clears) the errors collected with LUTEC_POLICY_COLLECT, oldest first
+//
+// The thread passed to callback only identifies the coroutine and may already be collected.
+// Returns the number of errors reported
+extern "C" int lutec_take_errors(lua_State *L, lutec_ErrorCallback callback, void *ctx)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 0;
+    }
+
+    std::vector<lutec_TaskError> errors = std::move(ext->collectedErrors);
+    ext->collectedErrors.clear();
+
+    lutec_ErrorHandler handler;
+    handler.callback = callback;
+    handler.ctx = ctx;
+    for (const lutec_TaskError &error : errors)
+    {
+        lutec_callhandler(handler, error.thread, error.message, error.traceback);
+    }
+
+    return int(errors.size());
+}
+
+// Returns whether the runtime attached to L was aborted by LUTEC_POLICY_ABORT
+extern "C" int lutec_isaborted(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    return ext && ext->aborted ? 1 : 0;
+}
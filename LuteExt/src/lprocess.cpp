@@ -0,0 +1,658 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lute/runtime.h"
+#include "uv.h"
+
+#include "lext.h"
+
+#include <ctype.h>
+#include <filesystem>
+#include <initializer_list>
+#include <stdlib.h>
+#include <string>
+#include <utility>
+#include <vector>
+
+#ifndef _WIN32
+#include <unistd.h>
+#endif
+
+namespace fs = std::filesystem;
+
+// Members of @lute/process kept when a policy is set (other than run, which goes through the
+// policy). Members not listed here are removed, so functions starting processes can't bypass it
+static const char *kProcessMembers[] = {"homedir", "cwd", "exit", "env", "execpath", "args", "stdin", "stdout", "stderr"};
+
+// A process started by process.run in a runtime with a process policy
+struct lutec_ProcessRun
+{
+    uv_process_t process;
+    uv_pipe_t out;
+    uv_pipe_t err;
+    uv_timer_t timer;
+    int openHandles = 0;
+
+    ResumeToken token;
+
+    std::string stdoutBuf;
+    std::string stderrBuf;
+    uint64_t maxOutput = 0;
+
+    int64_t exitStatus = 0;
+    int termSignal = 0;
+    bool exited = false;
+    int openPipes = 2;
+
+    bool timedOut = false;
+    bool outputExceeded = false;
+    uint64_t timeoutMs = 0;
+};
+
+static void lutec_process_finish(lutec_ProcessRun *run)
+{
+    ResumeToken token = std::move(run->token);
+
+    if (run->timedOut)
+    {
+        token->fail("process: command timed out after " + std::to_string(run->timeoutMs) + "ms");
+    }
+    else if (run->outputExceeded)
+    {
+        token->fail("process: command output exceeded " + std::to_string(run->maxOutput) + " bytes");
+    }
+    else
+    {
+        std::string out = std::move(run->stdoutBuf);
+        std::string err = std::move(run->stderrBuf);
+        int64_t exitStatus = run->exitStatus;
+        int termSignal = run->termSignal;
+
+        token->complete([out = std::move(out), err = std::move(err), exitStatus, termSignal](lua_State *L) {
+            lua_createtable(L, 0, 5);
+
+            lua_pushboolean(L, exitStatus == 0 && termSignal == 0);
+            lua_setfield(L, -2, "ok");
+
+            lua_pushinteger(L, int(exitStatus));
+            lua_setfield(L, -2, "exitcode");
+
+            lua_pushlstring(L, out.data(), out.size());
+            lua_setfield(L, -2, "stdout");
+
+            lua_pushlstring(L, err.data(), err.size());
+            lua_setfield(L, -2, "stderr");
+
+            if (termSignal != 0)
+            {
+                lua_pushinteger(L, termSignal);
+                lua_setfield(L, -2, "signal");
+            }
+
+            return 1;
+        });
+    }
+
+    delete run;
+}
+
+static void lutec_process_onclose(uv_handle_t *handle)
+{
+    lutec_ProcessRun *run = static_cast<lutec_ProcessRun *>(handle->data);
+    if (--run->openHandles == 0)
+    {
+        lutec_process_finish(run);
+    }
+}
+
+static void lutec_process_close(uv_handle_t *handle)
+{
+    if (!uv_is_closing(handle))
+    {
+        uv_close(handle, lutec_process_onclose);
+    }
+}
+
+// Closes everything once the process exited and both pipes reached EOF
+static void lutec_process_checkdone(lutec_ProcessRun *run)
+{
+    if (!run->exited || run->openPipes > 0)
+    {
+        return;
+    }
+
+    lutec_process_close((uv_handle_t *)&run->timer);
+    lutec_process_close((uv_handle_t *)&run->process);
+}
+
+static void lutec_process_kill(lutec_ProcessRun *run)
+{
+    if (!run->exited)
+    {
+        uv_process_kill(&run->process, SIGKILL);
+    }
+}
+
+// Stops reading the pipes that are still open
+//
+// Children of the process can keep the pipes open after it exited, so this ends the run when
+// it times out or exceeds its output limit
+static void lutec_process_closepipes(lutec_ProcessRun *run)
+{
+    for (uv_pipe_t *pipe : {&run->out, &run->err})
+    {
+        if (!uv_is_closing((uv_handle_t *)pipe))
+        {
+            uv_read_stop((uv_stream_t *)pipe);
+            lutec_process_close((uv_handle_t *)pipe);
+            run->openPipes--;
+        }
+    }
+
+    lutec_process_checkdone(run);
+}
+
+static void lutec_process_onalloc(uv_handle_t *handle, size_t suggested, uv_buf_t *buf)
+{
+    buf->base = new char[suggested];
+    buf->len = (unsigned int)suggested;
+}
+
+static void lutec_process_onread(uv_stream_t *stream, ssize_t nread, const uv_buf_t *buf)
+{
+    lutec_ProcessRun *run = static_cast<lutec_ProcessRun *>(stream->data);
+    std::string &target = stream == (uv_stream_t *)&run->out ? run->stdoutBuf : run->stderrBuf;
+
+    if (nread > 0 && !run->outputExceeded)
+    {
+        target.append(buf->base, size_t(nread));
+
+        if (run->maxOutput && run->stdoutBuf.size() + run->stderrBuf.size() > run->maxOutput)
+        {
+            run->outputExceeded = true;
+            lutec_process_kill(run);
+            delete[] buf->base;
+            lutec_process_closepipes(run);
+            return;
+        }
+    }
+
+    delete[] buf->base;
+
+    if (nread < 0)
+    {
+        uv_read_stop(stream);
+        lutec_process_close((uv_handle_t *)stream);
+        run->openPipes--;
+        lutec_process_checkdone(run);
+    }
+}
+
+static void lutec_process_onexit(uv_process_t *process, int64_t exitStatus, int termSignal)
+{
+    lutec_ProcessRun *run = static_cast<lutec_ProcessRun *>(process->data);
+    run->exited = true;
+    run->exitStatus = exitStatus;
+    run->termSignal = termSignal;
+
+    // The timer keeps running until the pipes are closed, as they may be held open by children
+    lutec_process_checkdone(run);
+}
+
+static void lutec_process_ontimeout(uv_timer_t *timer)
+{
+    lutec_ProcessRun *run = static_cast<lutec_ProcessRun *>(timer->data);
+    run->timedOut = true;
+    lutec_process_kill(run);
+    lutec_process_closepipes(run);
+}
+
+static bool lutec_process_envname(const std::string &entry, std::string &name)
+{
+    size_t eq = entry.find('=');
+    if (eq == std::string::npos || eq == 0)
+    {
+        return false;
+    }
+
+    name = entry.substr(0, eq);
+    return true;
+}
+
+// Variables scripts can't pass, as they change which executable runs or what it loads
+static bool lutec_process_envdenied(const std::string &name)
+{
+    std::string upper = name;
+    for (char &c : upper)
+    {
+        c = char(toupper((unsigned char)c));
+    }
+
+    return upper == "PATH" || upper.rfind("LD_", 0) == 0 || upper.rfind("DYLD_", 0) == 0;
+}
+
+static bool lutec_process_isexecutable(const fs::path &path)
+{
+    std::error_code ec;
+    if (!fs::is_regular_file(path, ec))
+    {
+        return false;
+    }
+
+#ifdef _WIN32
+    return true;
+#else
+    return access(path.c_str(), X_OK) == 0;
+#endif
+}
+
+// Resolves the executable to the absolute path to spawn
+//
+// Names are looked up in the host's PATH rather than the one of the process, so the environment
+// can't change which executable an allowed name refers to. Paths are relative to cwd
+static bool lutec_process_resolve(const std::string &name, const std::string &cwd, std::string &out)
+{
+    std::error_code ec;
+
+#ifdef _WIN32
+    bool isPath = name.find_first_of("/\\") != std::string::npos;
+    const char separator = ';';
+#else
+    bool isPath = name.find('/') != std::string::npos;
+    const char separator = ':';
+#endif
+
+    if (isPath)
+    {
+        fs::path path = name;
+        if (path.is_relative())
+        {
+            path = (cwd.empty() ? fs::current_path(ec) : fs::path(cwd)) / path;
+        }
+
+        if (!lutec_process_isexecutable(path))
+        {
+            return false;
+        }
+
+        out = fs::absolute(path, ec).lexically_normal().string();
+        return true;
+    }
+
+    std::vector<std::string> extensions = {""};
+#ifdef _WIN32
+    if (const char *pathext = getenv("PATHEXT"))
+    {
+        std::string list = pathext;
+        for (size_t start = 0; start <= list.size();)
+        {
+            size_t end = list.find(';', start);
+            end = end == std::string::npos ? list.size() : end;
+            if (end > start)
+            {
+                extensions.push_back(list.substr(start, end - start));
+            }
+            start = end + 1;
+        }
+    }
+#endif
+
+    const char *hostPath = getenv("PATH");
+    std::string dirs = hostPath ? hostPath : "";
+    for (size_t start = 0; start <= dirs.size();)
+    {
+        size_t end = dirs.find(separator, start);
+        end = end == std::string::npos ? dirs.size() : end;
+        std::string dir = dirs.substr(start, end - start);
+        start = end + 1;
+
+        // Empty entries would refer to the current directory, which scripts may control
+        if (dir.empty())
+        {
+            continue;
+        }
+
+        for (const std::string &extension : extensions)
+        {
+            fs::path candidate = fs::path(dir) / (name + extension);
+            if (lutec_process_isexecutable(candidate))
+            {
+                out = fs::absolute(candidate, ec).lexically_normal().string();
+                return true;
+            }
+        }
+    }
+
+    return false;
+}
+
+// Builds the environment of the process: the host's environment (unless cleared) and the
+// variables passed by the script, without the removed variables, then the injected ones
+static std::vector<std::string> lutec_process_env(lua_State *L, const lutec_ProcessPolicy &policy, int options)
+{
+    std::vector<std::pair<std::string, std::string>> vars;
+
+    auto set = [&](const std::string &name, const std::string &value) {
+        for (auto &var : vars)
+        {
+            if (var.first == name)
+            {
+                var.second = value;
+                return;
+            }
+        }
+        vars.emplace_back(name, value);
+    };
+
+    auto removed = [&](const std::string &name) {
+        for (const std::string &remove : policy.removeEnv)
+        {
+            if (remove == name)
+            {
+                return true;
+            }
+        }
+        return false;
+    };
+
+    if (!policy.clearEnv)
+    {
+        uv_env_item_t *items = nullptr;
+        int count = 0;
+        if (uv_os_environ(&items, &count) == 0)
+        {
+            for (int i = 0; i < count; i++)
+            {
+                if (!removed(items[i].name))
+                {
+                    set(items[i].name, items[i].value);
+                }
+            }
+            uv_os_free_environ(items, count);
+        }
+    }
+
+    if (options && lua_getfield(L, options, "env") == LUA_TTABLE)
+    {
+        lua_pushnil(L);
+        while (lua_next(L, -2))
+        {
+            if (lua_type(L, -2) == LUA_TSTRING && lua_type(L, -1) == LUA_TSTRING)
+            {
+                std::string name = lua_tostring(L, -2);
+                if (lutec_process_envdenied(name))
+                {
+                    luaL_errorL(L, "process: setting '%s' is not allowed", name.c_str());
+                }
+
+                if (!removed(name))
+                {
+                    set(name, lua_tostring(L, -1));
+                }
+            }
+            lua_pop(L, 1);
+        }
+    }
+    if (options)
+    {
+        lua_pop(L, 1);
+    }
+
+    for (const std::string &entry : policy.setEnv)
+    {
+        std::string name;
+        if (lutec_process_envname(entry, name))
+        {
+            set(name, entry.substr(name.size() + 1));
+        }
+    }
+
+    std::vector<std::string> env;
+    env.reserve(vars.size());
+    for (const auto &var : vars)
+    {
+        env.push_back(var.first + "=" + var.second);
+    }
+    return env;
+}
+
+// process.run for runtimes with a process policy
+//
+// Takes the same arguments as process.run of @lute/process, but commands must be argument
+// arrays and can't be run through a shell. Returns { ok, exitcode, stdout, stderr, signal? }
+static int lutec_process_run(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext || !ext->process.enabled)
+    {
+        luaL_errorL(L, "process: running commands is not allowed");
+    }
+    const lutec_ProcessPolicy &policy = ext->process;
+
+    // Command strings may be run through a shell, which would bypass the allow-list
+    if (lua_type(L, 1) != LUA_TTABLE)
+    {
+        luaL_errorL(L, "process: commands must be passed as an array of arguments");
+    }
+
+    int options = lua_type(L, 2) == LUA_TTABLE ? 2 : 0;
+    if (options)
+    {
+        lua_getfield(L, options, "shell");
+        bool shell = lua_toboolean(L, -1);
+        lua_pop(L, 1);
+
+        if (shell)
+        {
+            luaL_errorL(L, "process: running commands through a shell is not allowed");
+        }
+    }
+
+    std::vector<std::string> args;
+    for (int i = 1, n = lua_objlen(L, 1); i <= n; i++)
+    {
+        lua_rawgeti(L, 1, i);
+        if (!lua_isstring(L, -1))
+        {
+            luaL_errorL(L, "process: argument %d is not a string", i);
+        }
+        args.push_back(lua_tostring(L, -1));
+        lua_pop(L, 1);
+    }
+
+    if (args.empty())
+    {
+        luaL_errorL(L, "process: missing executable");
+    }
+
+    bool allowed = policy.allowAll;
+    for (const std::string &name : policy.allow)
+    {
+        allowed = allowed || name == args[0];
+    }
+    if (!allowed)
+    {
+        luaL_errorL(L, "process: running '%s' is not allowed", args[0].c_str());
+    }
+
+    std::string cwd = policy.cwd;
+    if (options)
+    {
+        lua_getfield(L, options, "cwd");
+        const char *requested = lua_tostring(L, -1);
+        if (requested && !policy.cwd.empty() && policy.cwd != requested)
+        {
+            luaL_errorL(L, "process: the working directory is pinned to '%s'", policy.cwd.c_str());
+        }
+        if (requested && cwd.empty())
+        {
+            cwd = requested;
+        }
+        lua_pop(L, 1);
+    }
+
+    // Resolved before the environment of the script is applied, see lutec_process_resolve
+    std::string executable;
+    if (!lutec_process_resolve(args[0], cwd, executable))
+    {
+        luaL_errorL(L, "process: '%s' was not found", args[0].c_str());
+    }
+
+    std::vector<std::string> env = lutec_process_env(L, policy, options);
+
+    std::vector<char *> argv;
+    for (std::string &arg : args)
+    {
+        argv.push_back(arg.data());
+    }
+    argv.push_back(nullptr);
+
+    std::vector<char *> envp;
+    for (std::string &entry : env)
+    {
+        envp.push_back(entry.data());
+    }
+    envp.push_back(nullptr);
+
+    lutec_ProcessRun *run = new lutec_ProcessRun();
+    run->maxOutput = policy.maxOutputBytes;
+    run->timeoutMs = policy.timeoutMs;
+
+    uv_loop_t *loop = uv_default_loop();
+    uv_pipe_init(loop, &run->out, 0);
+    uv_pipe_init(loop, &run->err, 0);
+    uv_timer_init(loop, &run->timer);
+    run->out.data = run;
+    run->err.data = run;
+    run->timer.data = run;
+    run->process.data = run;
+
+    uv_stdio_container_t stdio[3];
+    stdio[0].flags = UV_IGNORE;
+    stdio[1].flags = uv_stdio_flags(UV_CREATE_PIPE | UV_WRITABLE_PIPE);
+    stdio[1].data.stream = (uv_stream_t *)&run->out;
+    stdio[2].flags = uv_stdio_flags(UV_CREATE_PIPE | UV_WRITABLE_PIPE);
+    stdio[2].data.stream = (uv_stream_t *)&run->err;
+
+    uv_process_options_t spawnOptions = {};
+    spawnOptions.exit_cb = lutec_process_onexit;
+    spawnOptions.file = executable.c_str();
+    spawnOptions.args = argv.data();
+    spawnOptions.env = envp.data();
+    spawnOptions.cwd = cwd.empty() ? nullptr : cwd.c_str();
+    spawnOptions.stdio_count = 3;
+    spawnOptions.stdio = stdio;
+
+    int status = uv_spawn(loop, &run->process, &spawnOptions);
+    if (status != 0)
+    {
+        // Only the pipes and timer were initialized, and nothing can yield yet, so they're
+        // closed without waiting for the token
+        run->openHandles = 3;
+        run->token = nullptr;
+        auto close = [](uv_handle_t *handle) {
+            lutec_ProcessRun *run = static_cast<lutec_ProcessRun *>(handle->data);
+            if (--run->openHandles == 0)
+            {
+                delete run;
+            }
+        };
+        uv_close((uv_handle_t *)&run->out, close);
+        uv_close((uv_handle_t *)&run->err, close);
+        uv_close((uv_handle_t *)&run->timer, close);
+
+        luaL_errorL(L, "process: failed to start '%s': %s", args[0].c_str(), uv_strerror(status));
+    }
+
+    run->openHandles = 4;
+    run->token = getResumeToken(L);
+
+    uv_read_start((uv_stream_t *)&run->out, lutec_process_onalloc, lutec_process_onread);
+    uv_read_start((uv_stream_t *)&run->err, lutec_process_onalloc, lutec_process_onread);
+
+    if (policy.timeoutMs)
+    {
+        uv_timer_start(&run->timer, lutec_process_ontimeout, policy.timeoutMs, 0);
+    }
+
+    return lua_yield(L, 0);
+}
+
+void lutec_process_setpolicy(lutec_RuntimeExt &ext, const lutec_ProcessPolicyDesc &desc)
+{
+    lutec_ProcessPolicy policy;
+    policy.enabled = true;
+    policy.allowAll = desc.allowAll != 0;
+    for (int i = 0; i < desc.allowCount; i++)
+    {
+        policy.allow.push_back(desc.allow[i]);
+    }
+    policy.clearEnv = desc.clearEnv != 0;
+    for (int i = 0; i < desc.removeEnvCount; i++)
+    {
+        policy.removeEnv.push_back(desc.removeEnv[i]);
+    }
+    for (int i = 0; i < desc.setEnvCount; i++)
+    {
+        policy.setEnv.push_back(desc.setEnv[i]);
+    }
+    policy.cwd = desc.cwd ? desc.cwd : "";
+    policy.timeoutMs = desc.timeoutMs;
+    policy.maxOutputBytes = desc.maxOutputBytes;
+    ext.process = std::move(policy);
+}
+
+void lutec_process_wrap(lua_State *L, bool restricted)
+{
+    int original = lua_gettop(L);
+    lua_createtable(L, 0, 1);
+
+    // Sandboxed runtimes only get run
+    if (!restricted)
+    {
+        for (const char *name : kProcessMembers)
+        {
+            lua_getfield(L, original, name);
+            lua_setfield(L, -2, name);
+        }
+    }
+
+    lua_pushcfunction(L, lutec_process_run, "run");
+    lua_setfield(L, -2, "run");
+    lua_replace(L, original);
+}
+
+// Sets the process policy of the runtime attached to L and child VMs created afterwards
+//
+// process.run is replaced with a runner enforcing the policy, in the process library opened
+// with lutec_openprocess and in the process global if it was already opened. Other members that
+// may start processes are removed. Denied calls raise catchable errors. Returns 0 on success, 1 if no runtime is loaded and 2 if the runtime is
+// sandboxed
+extern "C" int lutec_setprocesspolicy(lua_State *L, const lutec_ProcessPolicyDesc *desc)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (ext->sandbox.enabled)
+    {
+        return 2; // The globals are read-only
+    }
+
+    lutec_process_setpolicy(*ext, *desc);
+
+    lua_getglobal(L, "process");
+    if (lua_istable(L, -1))
+    {
+        lutec_process_wrap(L, false);
+        lua_setglobal(L, "process");
+    }
+    else
+    {
+        lua_pop(L, 1);
+    }
+
+    return 0;
+}
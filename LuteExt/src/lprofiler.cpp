@@ -0,0 +1,227 @@
+#include "lua.h"
+
+#include "lext.h"
+
+#include <atomic>
+#include <chrono>
+#include <condition_variable>
+#include <functional>
+#include <mutex>
+#include <string>
+#include <thread>
+#include <vector>
+
+#include <string.h>
+
+struct lutec_ProfileSample
+{
+    std::string stack;    // Frames separated by ';', outermost first
+    uint64_t timestampUs; // Time since the profiler was started
+    uint64_t threadId;    // OS thread the sample was taken on
+    double weightUs;      // Time attributed to the sample
+};
+
+// Sampling profiler shared by a runtime and all of its child VMs
+//
+// Every runtime gets one when it is created, so child VMs created before the profiler is
+// started are sampled as well. A timer thread advances the tick at the sampling rate while
+// running, and the interrupt handler of each VM takes a sample whenever it observes a tick it
+// has not sampled yet
+struct lutec_Profiler
+{
+    std::atomic<bool> running{false};
+    std::atomic<uint64_t> tick{0};
+    std::atomic<uint64_t> startTick{0}; // Tick at which the profiler was last started
+    std::atomic<int> frequency{1000};
+    std::atomic<int64_t> startUs{0}; // Steady clock time at which the profiler was last started
+
+    std::mutex timerMutex;
+    std::condition_variable timerCv;
+    std::thread timer;
+
+    std::mutex samplesMutex;
+    std::vector<lutec_ProfileSample> samples;
+
+    ~lutec_Profiler()
+    {
+        stop();
+    }
+
+    void stop()
+    {
+        {
+            std::lock_guard<std::mutex> lock(timerMutex);
+            running.store(false);
+        }
+        timerCv.notify_all();
+
+        if (timer.joinable() && timer.get_id() != std::this_thread::get_id())
+        {
+            timer.join();
+        }
+    }
+};
+
+// Highest sampling rate, as the timer can't wake up more than once per microsecond
+static const int kMaxFrequency = 1000000;
+
+static int64_t lutec_profiler_nowus()
+{
+    return std::chrono::duration_cast<std::chrono::microseconds>(std::chrono::steady_clock::now().time_since_epoch()).count();
+}
+
+std::shared_ptr<lutec_Profiler> lutec_profiler_new()
+{
+    return std::make_shared<lutec_Profiler>();
+}
+
+static std::string lutec_profiler_frame(lua_Debug &ar)
+{
+    std::string frame = ar.name ? ar.name : (ar.what && strcmp(ar.what, "main") == 0 ? "<main>" : "<anonymous>");
+    frame += " (";
+    frame += ar.short_src ? ar.short_src : "?";
+    if (ar.currentline > 0)
+    {
+        frame += ":";
+        frame += std::to_string(ar.currentline);
+    }
+    frame += ")";
+    return frame;
+}
+
+void lutec_profiler_interrupt(lua_State *L, lutec_RuntimeExt *ext)
+{
+    lutec_Profiler *profiler = ext->profiler.get();
+    if (!profiler->running.load(std::memory_order_acquire))
+    {
+        return;
+    }
+
+    uint64_t tick = profiler->tick.load(std::memory_order_relaxed);
+    if (tick == ext->profilerTick)
+    {
+        return;
+    }
+
+    // Attribute all ticks missed since the last sample (e.g. while in C code) to this one. The
+    // first sample after a start only gets one tick
+    uint64_t startTick = profiler->startTick.load(std::memory_order_relaxed);
+    uint64_t elapsedTicks = ext->profilerTick <= startTick ? 1 : tick - ext->profilerTick;
+    ext->profilerTick = tick;
+
+    std::vector<std::string> frames;
+    lua_Debug ar;
+    for (int level = 0; lua_getinfo(L, level, "sln", &ar); level++)
+    {
+        frames.push_back(lutec_profiler_frame(ar));
+    }
+
+    if (frames.empty())
+    {
+        return;
+    }
+
+    lutec_ProfileSample sample;
+    for (auto it = frames.rbegin(); it != frames.rend(); ++it)
+    {
+        if (!sample.stack.empty())
+        {
+            sample.stack += ";";
+        }
+        sample.stack += *it;
+    }
+
+    sample.timestampUs = uint64_t(lutec_profiler_nowus() - profiler->startUs.load(std::memory_order_relaxed));
+    sample.threadId = std::hash<std::thread::id>{}(std::this_thread::get_id());
+    sample.weightUs = double(elapsedTicks) * 1e6 / double(profiler->frequency.load(std::memory_order_relaxed));
+
+    std::lock_guard<std::mutex> lock(profiler->samplesMutex);
+    profiler->samples.push_back(std::move(sample));
+}
+
+// Starts sampling the runtime attached to L and all of its child VMs at the given rate, in
+// samples per second (1000 if 0 or less)
+//
+// Returns 0 on success, 1 if no runtime is loaded, 2 if the profiler is already running and 3
+// if the frequency is above 1000000
+extern "C" int lutec_profiler_start(lua_State *L, int frequency)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (frequency > kMaxFrequency)
+    {
+        return 3;
+    }
+
+    std::shared_ptr<lutec_Profiler> profiler = ext->profiler;
+    if (profiler->running.load())
+    {
+        return 2;
+    }
+
+    // Make sure the timer of the previous run is gone before reusing the profiler
+    profiler->stop();
+
+    {
+        std::lock_guard<std::mutex> lock(profiler->samplesMutex);
+        profiler->samples.clear();
+    }
+
+    profiler->frequency.store(frequency > 0 ? frequency : 1000);
+    profiler->startUs.store(lutec_profiler_nowus());
+    profiler->startTick.store(profiler->tick.load());
+    profiler->running.store(true, std::memory_order_release);
+
+    lutec_Profiler *raw = profiler.get();
+    profiler->timer = std::thread([raw]() {
+        auto interval = std::chrono::microseconds(1000000 / raw->frequency.load());
+        std::unique_lock<std::mutex> lock(raw->timerMutex);
+        while (raw->running.load())
+        {
+            raw->timerCv.wait_for(lock, interval);
+            raw->tick.fetch_add(1, std::memory_order_relaxed);
+        }
+    });
+
+    // Child VMs install the interrupt handler when they are created
+    lutec_installinterrupt(L);
+
+    return 0;
+}
+
+// Stops sampling, keeping the collected samples until the next start
+extern "C" int lutec_profiler_stop(lua_State *L)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    ext->profiler->stop();
+    return 0;
+}
+
+// Calls the callback for every sample collected by the runtime's profiler, in order
+extern "C" int lutec_profiler_samples(
+    lua_State *L, void (*cb)(void *ctx, const char *stack, uint64_t timestampUs, uint64_t threadId, double weightUs), void *ctx)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    std::shared_ptr<lutec_Profiler> profiler = ext->profiler;
+    std::lock_guard<std::mutex> lock(profiler->samplesMutex);
+    for (const lutec_ProfileSample &sample : profiler->samples)
+    {
+        cb(ctx, sample.stack.c_str(), sample.timestampUs, sample.threadId, sample.weightUs);
+    }
+
+    return 0;
+}
@@ -0,0 +1,272 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lext.h"
+
+#include <filesystem>
+#include <fstream>
+#include <iterator>
+#include <string>
+#include <string.h>
+
+// File-based require for Lute runtimes and their child VMs
+//
+// Modules are loaded through lutec_loadsource, so they go through the runtime's module loader
+// (and its bytecode cache) when one is set. Since child VMs created through @lute/vm load
+// their entry module with the global require, installing this in the runtime initter routes
+// those loads through the loader as well
+
+static const char *kModulesKey = "_LUTECMODULES";
+
+// Marks modules that are being loaded, to detect cyclic requires
+static const char kLoadingSentinel = 0;
+
+namespace fs = std::filesystem;
+
+extern "C" int lutec_openfs(lua_State *L);
+extern "C" int lutec_openluau(lua_State *L);
+extern "C" int lutec_openprocess(lua_State *L);
+extern "C" int lutec_opentask(lua_State *L);
+extern "C" int lutec_openvm(lua_State *L);
+extern "C" int lutec_opensystem(lua_State *L);
+extern "C" int lutec_opentime(lua_State *L);
+#ifndef LUTE_DISABLE_CRYPTO
+extern "C" int lutec_opencrypto(lua_State *L);
+#endif
+#ifndef LUTE_DISABLE_NET
+extern "C" int lutec_opennet(lua_State *L);
+#endif
+
+// Lute libraries, opened through the lutec_open functions so the fs scope and process policy of
+// the runtime apply to them. In sandboxed runtimes, the restricted global is returned instead
+static const struct
+{
+    const char *path;
+    const char *global;
+    lua_CFunction open;
+} kLuteLibraries[] = {
+    {"@lute/fs", "fs", lutec_openfs},
+    {"@lute/luau", "luau", lutec_openluau},
+    {"@lute/process", "process", lutec_openprocess},
+    {"@lute/task", "task", lutec_opentask},
+    {"@lute/vm", "vm", lutec_openvm},
+    {"@lute/system", "system", lutec_opensystem},
+    {"@lute/time", "time", lutec_opentime},
+#ifndef LUTE_DISABLE_CRYPTO
+    {"@lute/crypto", "crypto", lutec_opencrypto},
+#endif
+#ifndef LUTE_DISABLE_NET
+    {"@lute/net", "net", lutec_opennet},
+#endif
+};
+
+// Pushes the Lute library for path, returning false if path isn't one
+static bool lutec_require_lute(lua_State *L, const char *path)
+{
+    for (const auto &lib : kLuteLibraries)
+    {
+        if (strcmp(lib.path, path) != 0)
+        {
+            continue;
+        }
+
+        lutec_RuntimeExt *ext = lutec_getext(L);
+        if (ext && ext->sandbox.enabled)
+        {
+            // The globals of the main thread can't be replaced by scripts
+            lua_State *GL = lua_mainthread(L);
+            lua_getglobal(GL, lib.global);
+            lua_xmove(GL, L, 1);
+            if (lua_isnil(L, -1))
+            {
+                luaL_errorL(L, "could not require %s: library is not available", path);
+            }
+            return true;
+        }
+
+        // Not cached, so libraries required after the fs scope or process policy changed
+        // follow the new settings
+        lib.open(L);
+        return true;
+    }
+
+    return false;
+}
+
+// Resolves the path of a module to the file to load
+//
+// `.luau` is appended if missing. Relative paths are resolved against the directory of the
+// requiring module, or the current directory if it wasn't loaded from a file. Paths are confined
+// to the fs root of runtimes with an fs scope. Sandboxed runtimes without an fs scope can't
+// require files
+static bool lutec_require_resolve(lua_State *L, const char *path, std::string &file)
+{
+    file = path;
+    if (file.size() < 5 || file.compare(file.size() - 5, 5, ".luau") != 0)
+    {
+        file += ".luau";
+    }
+
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->sandbox.enabled && ext->fs.root.empty())
+    {
+        return false;
+    }
+
+    // Level 1 is the function calling require, missing when the host calls it (as vm.create does)
+    lua_Debug ar;
+    bool fromFile = lua_getinfo(L, 1, "s", &ar) && ar.source && ar.source[0] == '@';
+    if (fromFile && !fs::path(file).is_absolute())
+    {
+        fs::path dir = fs::path(ar.source + 1).parent_path();
+
+        // Modules of fs scoped runtimes were loaded from paths joined to the root
+        if (ext && !ext->fs.root.empty())
+        {
+            dir = dir.lexically_relative(fs::path(ext->fs.root).lexically_normal());
+        }
+
+        file = (dir / file).lexically_normal().string();
+    }
+
+    if (ext && !ext->fs.root.empty())
+    {
+        std::string resolved;
+        if (!lutec_fs_resolve(ext->fs.root, file.c_str(), resolved))
+        {
+            return false;
+        }
+        file = std::move(resolved);
+    }
+
+    return true;
+}
+
+// Pushes the module cache of the globals of L
+//
+// Modules run with the globals of the script requiring them. Script threads of sandboxed
+// runtimes have their own globals, so they get their own cache (stored in their globals, to be
+// collected with them) and can't share module values to pass data to each other
+static void lutec_require_pushcache(lua_State *L)
+{
+    // The read-only globals of the main thread are shared, so they use the registry as well
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->sandbox.enabled && !lua_getreadonly(L, LUA_GLOBALSINDEX))
+    {
+        lua_pushlightuserdata(L, (void *)kModulesKey);
+        lua_rawget(L, LUA_GLOBALSINDEX);
+        if (!lua_istable(L, -1))
+        {
+            lua_pop(L, 1);
+            lua_newtable(L);
+            lua_pushlightuserdata(L, (void *)kModulesKey);
+            lua_pushvalue(L, -2);
+            lua_rawset(L, LUA_GLOBALSINDEX);
+        }
+        return;
+    }
+
+    lua_getfield(L, LUA_REGISTRYINDEX, kModulesKey);
+    if (!lua_istable(L, -1))
+    {
+        lua_pop(L, 1);
+        lua_newtable(L);
+        lua_pushvalue(L, -1);
+        lua_setfield(L, LUA_REGISTRYINDEX, kModulesKey);
+    }
+}
+
+static int lutec_require(lua_State *L)
+{
+    const char *path = luaL_checkstring(L, 1);
+
+    // Aliases (e.g. @std/) other than the Lute libraries are left to the require this one
+    // replaced
+    if (path[0] == '@')
+    {
+        if (lutec_require_lute(L, path))
+        {
+            return 1;
+        }
+
+        if (lua_isnil(L, lua_upvalueindex(1)))
+        {
+            luaL_errorL(L, "could not require %s: unknown alias", path);
+        }
+
+        lua_pushvalue(L, lua_upvalueindex(1));
+        lua_pushvalue(L, 1);
+        lua_call(L, 1, 1);
+        return 1;
+    }
+
+    std::string file;
+    if (!lutec_require_resolve(L, path, file))
+    {
+        luaL_errorL(L, "could not require %s: module is outside of the allowed directory", path);
+    }
+
+    // Modules are cached by the file they were loaded from
+    lutec_require_pushcache(L);
+    int cache = lua_gettop(L);
+
+    lua_getfield(L, cache, file.c_str());
+    if (lua_touserdata(L, -1) == &kLoadingSentinel)
+    {
+        luaL_errorL(L, "could not require %s: cyclic require", path);
+    }
+    if (!lua_isnil(L, -1))
+    {
+        return 1;
+    }
+    lua_pop(L, 1);
+
+    std::string source;
+    {
+        std::ifstream in(file, std::ios::binary);
+        if (!in)
+        {
+            luaL_errorL(L, "could not require %s: module not found", path);
+        }
+        source.assign(std::istreambuf_iterator<char>(in), std::istreambuf_iterator<char>());
+    }
+
+    std::string chunkname = "@" + file;
+    if (lutec_loadsource(L, chunkname.c_str(), source.data(), source.size()) != 0)
+    {
+        lua_error(L);
+    }
+
+    lua_pushlightuserdata(L, (void *)&kLoadingSentinel);
+    lua_setfield(L, cache, file.c_str());
+
+    if (lua_pcall(L, 0, 1, 0) != 0)
+    {
+        // Failed modules can be required again
+        lua_pushnil(L);
+        lua_setfield(L, cache, file.c_str());
+        lua_error(L);
+    }
+
+    // Modules returning nothing are cached as true so they are only run once
+    if (lua_isnil(L, -1))
+    {
+        lua_pop(L, 1);
+        lua_pushboolean(L, 1);
+    }
+
+    lua_pushvalue(L, -1);
+    lua_setfield(L, cache, file.c_str());
+    return 1;
+}
+
+// Pushes a require function loading modules through the runtime's module loader
+//
+// Requires of aliases are forwarded to the current global require, if any. Hosts set the
+// result as the global require of the runtime and of child VMs in their runtime initter
+extern "C" int lutec_openrequire(lua_State *L)
+{
+    lua_getglobal(L, "require");
+    lua_pushcclosurek(L, lutec_require, "require", 1, nullptr);
+    return 1;
+}
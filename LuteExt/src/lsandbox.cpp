@@ -0,0 +1,199 @@
+#include "lua.h"
+#include "lualib.h"
+
+#include "lext.h"
+
+#include <filesystem>
+
+namespace fs = std::filesystem;
+
+// Members of @lute/system that don't identify the host, exposed with LUTEC_SYSTEM_BASIC
+static const char *kSystemBasicMembers[] = {"os", "arch", "threadcount"};
+
+// Replaces the library table in the global name with a new table built by fill, which is
+// called with the original table at -2 and the new one at -1. Missing libraries are skipped
+template<typename F>
+static void lutec_sandbox_replace(lua_State *L, const char *name, F fill)
+{
+    lua_getglobal(L, name);
+    if (lua_type(L, -1) != LUA_TTABLE)
+    {
+        lua_pop(L, 1);
+        return;
+    }
+
+    lua_newtable(L);
+    fill();
+    lua_setglobal(L, name);
+    lua_pop(L, 1);
+}
+
+static void lutec_sandbox_copy(lua_State *L, const char *member)
+{
+    lua_getfield(L, -2, member);
+    lua_setfield(L, -2, member);
+}
+
+static void lutec_sandbox_restrictfs(lua_State *L, const lutec_FsScope &scope)
+{
+    if (scope.root.empty())
+    {
+        lua_pushnil(L);
+        lua_setglobal(L, "fs");
+        return;
+    }
+
+    // The library may have been opened without going through lutec_openfs, so it's always
+    // wrapped here. Wrapping an already scoped library again doesn't change its behavior
+    lua_getglobal(L, "fs");
+    if (lua_istable(L, -1))
+    {
+        lutec_fs_wrap(L, scope);
+        lua_setglobal(L, "fs");
+    }
+    else
+    {
+        lua_pop(L, 1);
+    }
+}
+
+static void lutec_sandbox_restrictprocess(lua_State *L, const lutec_RuntimeExt &ext)
+{
+    if (!ext.sandbox.processEnabled || !ext.process.enabled)
+    {
+        lua_pushnil(L);
+        lua_setglobal(L, "process");
+        return;
+    }
+
+    // Only run is kept, going through the process policy of the runtime
+    lua_getglobal(L, "process");
+    if (lua_istable(L, -1))
+    {
+        lutec_process_wrap(L, true);
+        lua_setglobal(L, "process");
+    }
+    else
+    {
+        lua_pop(L, 1);
+    }
+}
+
+static void lutec_sandbox_restrictsystem(lua_State *L, const lutec_Sandbox &sandbox)
+{
+    if (sandbox.system == LUTEC_SYSTEM_NONE)
+    {
+        lua_pushnil(L);
+        lua_setglobal(L, "system");
+        return;
+    }
+
+    if (sandbox.system == LUTEC_SYSTEM_BASIC)
+    {
+        lutec_sandbox_replace(L, "system", [&]() {
+            for (const char *name : kSystemBasicMembers)
+            {
+                lutec_sandbox_copy(L, name);
+            }
+        });
+    }
+}
+
+void lutec_sandbox_apply(lua_State *L, const lutec_RuntimeExt &ext, void (*doBeforeSandbox)(lua_State *))
+{
+    const lutec_Sandbox &sandbox = ext.sandbox;
+
+    lutec_sandbox_restrictfs(L, ext.fs);
+    lutec_sandbox_restrictprocess(L, ext);
+    lutec_sandbox_restrictsystem(L, sandbox);
+
+    // Loading bytecode is not memory safe, so the compiler library is never exposed
+    lua_pushnil(L);
+    lua_setglobal(L, "luau");
+
+    // Child VMs load their entry module through the host's require, outside of the scripts'
+    // threads, so the vm library is never exposed either
+    lua_pushnil(L);
+    lua_setglobal(L, "vm");
+
+    if (doBeforeSandbox)
+    {
+        doBeforeSandbox(L);
+    }
+
+    luaL_sandbox(L);
+}
+
+// Sandboxes the runtime attached to L
+//
+// The globals (including the Lute libraries) of L become read-only and fs, process and system
+// are replaced with restricted variants:
+// - fs is confined to fsRoot (see lutec_setfsroot), or to the root set before with
+//   lutec_setfsroot if null. Without a root, fs is removed
+// - process only keeps run, going through the process policy (see lutec_setprocesspolicy), or
+//   is removed if process is null
+// - system is removed, limited to basic members or kept as is (see lutec_SystemAccess)
+// - luau and vm are removed
+//
+// require of the Lute libraries returns the restricted globals, and files can only be required
+// from the fs root. Child VMs created afterwards by the host are sandboxed the same way, and
+// scripts should be run in threads created with lutec_newscriptthread. Must be called after the
+// libraries are opened as globals
+extern "C" int lutec_sandbox(lua_State *L, const char *fsRoot, int fsReadOnly, const lutec_ProcessPolicyDesc *process, int system)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext)
+    {
+        return 1;
+    }
+
+    if (ext->sandbox.enabled)
+    {
+        return 2; // Already sandboxed
+    }
+
+    if (fsRoot)
+    {
+        std::error_code ec;
+        fs::path root = fs::canonical(fsRoot, ec);
+        if (ec || !fs::is_directory(root, ec))
+        {
+            return 3; // Invalid root
+        }
+
+        ext->fs.root = root.string();
+        ext->fs.readOnly = fsReadOnly != 0;
+    }
+
+    lutec_Sandbox sandbox;
+    sandbox.enabled = true;
+
+    sandbox.processEnabled = process != nullptr;
+    if (process)
+    {
+        lutec_process_setpolicy(*ext, *process);
+    }
+
+    sandbox.system = lutec_SystemAccess(system);
+    ext->sandbox = std::move(sandbox);
+
+    lutec_sandbox_apply(L, *ext, nullptr);
+    return 0;
+}
+
+// Pushes a new thread to run a script in
+//
+// In sandboxed runtimes, the thread gets its own globals table (falling back to the read-only
+// globals) so scripts can't affect each other
+extern "C" lua_State *lutec_newscriptthread(lua_State *L)
+{
+    lua_State *T = lua_newthread(L);
+
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (ext && ext->sandbox.enabled)
+    {
+        luaL_sandboxthread(T);
+    }
+
+    return T;
+}
@@ -0,0 +1,78 @@
+#include "lua.h"
+
+#include "lext.h"
+
+// Frames beyond this depth are dropped, deep recursion would otherwise produce huge tracebacks
+static const int kTracebackMaxFrames = 64;
+
+void lutec_capturetraceback(lua_State *T, std::vector<lutec_TraceFrame> &frames)
+{
+    frames.clear();
+
+    lua_Debug ar;
+    for (int level = 0; level < kTracebackMaxFrames && lua_getinfo(T, level, "sln", &ar); level++)
+    {
+        lutec_TraceFrame frame;
+        frame.source = ar.source ? ar.source : "";
+        frame.function = ar.name ? ar.name : "";
+        frame.line = ar.currentline;
+        frame.native = ar.what && ar.what[0] == 'C';
+        frames.push_back(std::move(frame));
+    }
+}
+
+void lutec_formattraceback(const std::vector<lutec_TraceFrame> &frames, std::string &out)
+{
+    for (const lutec_TraceFrame &frame : frames)
+    {
+        out += frame.native ? "[C]" : frame.source.c_str() + (frame.source[0] == '@' || frame.source[0] == '=');
+        if (frame.line > 0)
+        {
+            out += ":" + std::to_string(frame.line);
+        }
+        if (!frame.function.empty())
+        {
+            out += " function " + frame.function;
+        }
+        out += "\n";
+    }
+}
+
+static int lutec_reportframes(const std::vector<lutec_TraceFrame> &frames, lutec_TracebackCallback cb, void *ctx)
+{
+    for (const lutec_TraceFrame &frame : frames)
+    {
+        cb(ctx, frame.native ? "=[C]" : frame.source.c_str(), frame.function.empty() ? nullptr : frame.function.c_str(), frame.line);
+    }
+
+    return int(frames.size());
+}
+
+// Reports the traceback captured when the scheduler of the runtime attached to L last failed
+//
+// cb is called once per frame, innermost first. source is the chunk name of the frame ("=[C]"
+// for native functions), function is null for anonymous functions and line is -1 if unknown.
+// Returns the number of frames, or -1 if no failure was captured since the last call
+extern "C" int lutec_last_traceback(lua_State *L, lutec_TracebackCallback cb, void *ctx)
+{
+    lutec_RuntimeExt *ext = lutec_getext(L);
+    if (!ext || !ext->hasTraceback)
+    {
+        return -1;
+    }
+
+    int count = lutec_reportframes(ext->lastTraceback, cb, ctx);
+    ext->lastTraceback.clear();
+    ext->hasTraceback = false;
+    return count;
+}
+
+// Reports the current frames of the thread T, in the same way as lutec_last_traceback
+//
+// Meant for coroutines the host resumed directly, which keep their frames after failing
+extern "C" int lutec_thread_traceback(lua_State *T, lutec_TracebackCallback cb, void *ctx)
+{
+    std::vector<lutec_TraceFrame> frames;
+    lutec_capturetraceback(T, frames);
+    return lutec_reportframes(frames, cb, ctx);
+}
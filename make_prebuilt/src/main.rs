@@ -1,162 +1,425 @@
-use lute_src_rs_common::{cmake, cmake::Config, LConfig, commonflags::{build_cc_lute_lib, setup_lute_cmake}};
+use lute_src_rs::buildenv::{build_cc_lute_lib, setup_lute_cmake, BuildEnv};
+use lute_src_rs::chunked::ChunkOptions;
+use lute_src_rs::combine::combine_archives;
+use lute_src_rs::graph::DependencyGraph;
+use lute_src_rs::manifest::{git_revision, BuildConfig, Manifest, Sources, Toolchain};
+use lute_src_rs::triple::Triple;
+use lute_src_rs_common::LConfig;
 use std::env::current_dir;
-use std::io::{Read, Write};
 
-// Install (Linux)
-// - g++-aarch64-linux-gnu
-pub fn main() {
-    // Get first arg if present
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 && args[1] == "upload" {
-        // Upload prebuilts to git
-        #[cfg(target_os = "linux")]
-        {
-            let targets = vec!["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"];
-            upload_to_git(targets, "linux");
-        }
+mod publish;
 
-        #[cfg(target_os = "macos")]
-        {
-            let targets = vec!["aarch64-apple-macos"];
-            upload_to_git(targets, "macos");
-        }
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// Builds, packages and publishes prebuilt Lute libraries
+#[derive(Parser, Debug)]
+#[command(name = "make_prebuilt")]
+struct Cli {
+    /// Defaults to `build` for the default targets of the host
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Build prebuilts
+    Build(BuildArgs),
+    /// List the supported targets, marking the default ones of the host
+    ListTargets,
+    /// Remove built prebuilts and the publishing clone
+    Clean {
+        /// Directory the prebuilts were built into
+        #[arg(long, default_value = "prebuilts")]
+        out_dir: PathBuf,
+
+        /// Targets to remove, defaults to every target
+        #[arg(long = "target")]
+        targets: Vec<String>,
+
+        /// Publishing clone to remove along with the prebuilts
+        #[arg(long, default_value = "prebuilts-git")]
+        work_dir: PathBuf,
+    },
+    /// Export built prebuilts to a directory, or to a tarball if the output ends with .tar.zst
+    Package {
+        /// Output directory or .tar.zst file
+        #[arg(long)]
+        out: PathBuf,
+
+        #[command(flatten)]
+        export: ExportArgs,
+    },
+    /// Commit built prebuilts to a git repository and push them
+    #[command(alias = "upload")]
+    Publish {
+        /// URL or path of the repository, defaults to mluau/lute-prebuilts-<host os>
+        #[arg(long)]
+        repo: Option<String>,
+
+        #[arg(long, default_value = "main")]
+        branch: String,
+
+        /// Commit in the local clone without pushing
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Create the repository as a bare repository if it is a path that doesn't exist
+        #[arg(long)]
+        init_bare: bool,
+
+        /// Directory the repository is cloned into, which must not exist or be empty
+        #[arg(long, default_value = "prebuilts-git")]
+        work_dir: PathBuf,
+
+        /// Remove the work directory first if it isn't empty, e.g. after an earlier publish
+        #[arg(long)]
+        replace_work_dir: bool,
+
+        #[arg(long, default_value = "Update prebuilts")]
+        message: String,
+
+        #[command(flatten)]
+        export: ExportArgs,
+    },
+}
+
+#[derive(Parser, Debug)]
+struct BuildArgs {
+    /// Targets to build, defaults to the default targets of the host (see list-targets)
+    #[arg(long = "target")]
+    targets: Vec<String>,
+
+    /// Build without @lute/net
+    #[arg(long)]
+    disable_net: bool,
+
+    /// Build without @lute/crypto
+    #[arg(long)]
+    disable_crypto: bool,
+
+    /// Variants to build instead of the config given by --disable-net and --disable-crypto
+    #[arg(long = "variant", value_enum, conflicts_with_all = ["disable_net", "disable_crypto"])]
+    variants: Vec<Variant>,
+
+    /// Build every variant
+    #[arg(long, conflicts_with = "variants")]
+    all_variants: bool,
 
-        #[cfg(target_os = "windows")]
-        {
-            let targets = vec!["x86_64-pc-windows-msvc"];
-            upload_to_git(targets, "windows");
+    #[arg(long, default_value = "3")]
+    opt_level: String,
+
+    /// Directory to build the prebuilts into, as <out-dir>/<target>/<fingerprint>/build
+    #[arg(long, default_value = "prebuilts")]
+    out_dir: PathBuf,
+
+    /// Number of parallel compile jobs of each build, defaults to the number of CPUs
+    #[arg(long)]
+    jobs: Option<usize>,
+
+    /// Number of targets and variants to build at the same time, on separate threads (their
+    /// output is interleaved)
+    #[arg(long, default_value = "1")]
+    parallel: usize,
+
+    /// Also merge the libraries of every target into a single archive
+    #[arg(long)]
+    combined: bool,
+
+    /// Strip debug information (only) from the combined archive, not supported with MSVC
+    #[arg(long, requires = "combined")]
+    strip_debug: bool,
+}
+
+/// Common `LConfig`s, each built into its own directory named by its fingerprint
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Full,
+    NoNet,
+    NoCrypto,
+    Minimal,
+}
+
+impl Variant {
+    const ALL: [Variant; 4] = [Variant::Full, Variant::NoNet, Variant::NoCrypto, Variant::Minimal];
+
+    fn lcfg(self) -> LConfig {
+        LConfig {
+            disable_net: matches!(self, Variant::NoNet | Variant::Minimal),
+            disable_crypto: matches!(self, Variant::NoCrypto | Variant::Minimal),
+            ..Default::default()
         }
+    }
+}
 
-        return;
+/// Targets prebuilts can be built for, with the host OS building them and whether it does by
+/// default
+const SUPPORTED_TARGETS: &[(&str, &str, bool)] = &[
+    ("aarch64-unknown-linux-gnu", "linux", true),
+    ("x86_64-unknown-linux-gnu", "linux", true),
+    ("aarch64-unknown-linux-musl", "linux", false),
+    ("x86_64-unknown-linux-musl", "linux", false),
+    ("riscv64gc-unknown-linux-gnu", "linux", false),
+    ("aarch64-apple-darwin", "macos", true),
+    ("x86_64-apple-darwin", "macos", false),
+    ("x86_64-pc-windows-msvc", "windows", true),
+];
+
+fn host_os() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "windows"
+    } else if cfg!(target_os = "macos") {
+        "macos"
+    } else {
+        "linux"
     }
+}
 
-    // On linux, build linux prebuilts for aarch64 and x86_64
-    #[cfg(target_os = "linux")]
-    {
-        for target in vec!["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"] {
-            println!("Target: {}", target);
-            build_lute_prebuilt(LConfig::default(), target, "linux");
+fn is_supported(target: &str) -> bool {
+    SUPPORTED_TARGETS
+        .iter()
+        .any(|(supported, _, _)| *supported == target)
+}
+
+fn default_targets() -> Vec<String> {
+    SUPPORTED_TARGETS
+        .iter()
+        .filter(|(_, os, default)| *default && *os == host_os())
+        .map(|(target, _, _)| target.to_string())
+        .collect()
+}
+
+fn build(args: BuildArgs) -> Result<(), String> {
+    let targets = if args.targets.is_empty() {
+        default_targets()
+    } else {
+        args.targets
+    };
+    if let Some(target) = targets.iter().find(|target| !is_supported(target)) {
+        return Err(format!(
+            "unsupported target {} (see make_prebuilt list-targets)",
+            target
+        ));
+    }
+    if args.strip_debug {
+        for target in &targets {
+            if Triple::parse(target)?.is_windows_msvc() {
+                return Err(format!("--strip-debug is not supported with MSVC ({})", target));
+            }
         }
     }
 
-    // On macos, build macos prebuilts for aarch64
-    #[cfg(target_os = "macos")]
-    {
-        build_lute_prebuilt(LConfig::default(), "aarch64-apple-macos", "macos");
+    let configs: Vec<LConfig> = if args.all_variants {
+        Variant::ALL.iter().map(|variant| variant.lcfg()).collect()
+    } else if !args.variants.is_empty() {
+        args.variants.iter().map(|variant| variant.lcfg()).collect()
+    } else {
+        vec![LConfig {
+            disable_net: args.disable_net,
+            disable_crypto: args.disable_crypto,
+            ..Default::default()
+        }]
+    };
+    let out_dir = std::path::absolute(&args.out_dir)
+        .map_err(|e| format!("invalid out dir {}: {}", args.out_dir.display(), e))?;
+    let options = PrebuiltOptions {
+        combined: args.combined,
+        strip_debug: args.strip_debug,
+    };
+
+    // Fetching the dependencies writes to the lute checkout, so it is done once up front
+    bootstrap_lute();
+
+    let builds: Vec<(&String, LConfig)> = targets
+        .iter()
+        .flat_map(|target| configs.iter().map(move |lcfg| (target, *lcfg)))
+        .collect();
+    let parallel = args.parallel.max(1).min(builds.len());
+
+    // Every build gets its own build environment, so builds of different targets can run on
+    // threads without sharing TARGET, OUT_DIR, ... through the process environment
+    let run_build = |target: &String, lcfg: LConfig| -> Result<(), String> {
+        let env = BuildEnv {
+            target: target.clone(),
+            host: env!("HOST_VAR").to_string(),
+            out_dir: out_dir.join(target).join(variant_dir(lcfg)).join("build"),
+            opt_level: args.opt_level.clone(),
+            profile: if args.opt_level == "0" { "debug" } else { "release" }.to_string(),
+            debug: false,
+            target_cfg: Triple::parse(target)?.target_cfg(),
+            jobs: args.jobs,
+        };
+        println!("Target: {} ({})", target, variant_dir(lcfg));
+
+        // cmake panics when the build fails
+        let built = std::panic::catch_unwind(|| build_lute_prebuilt(lcfg, &env, &options));
+        built.map_err(|panic| {
+            let message = panic
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("panicked");
+            format!("build of {} failed: {}", target, message)
+        })??;
+        println!("Built {} ({})", target, variant_dir(lcfg));
+        Ok(())
+    };
+
+    let queue = std::sync::Mutex::new(builds.into_iter());
+    let errors = std::sync::Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..parallel {
+            scope.spawn(|| {
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let Some((target, lcfg)) = next else {
+                        break;
+                    };
+                    if let Err(error) = run_build(target, lcfg) {
+                        eprintln!("error: {}", error);
+                        errors.lock().unwrap().push(error);
+                    }
+                }
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    if !errors.is_empty() {
+        return Err(format!("{} of the builds failed", errors.len()));
     }
+    Ok(())
+}
 
-    #[cfg(target_os = "windows")]
-    {
-        // On windows, build windows prebuilts for x86_64
-        build_lute_prebuilt(LConfig::default(), "x86_64-pc-windows-msvc", "windows");
+/// Directory of the prebuilts of a config, see `BuildConfig::fingerprint`
+fn variant_dir(lcfg: LConfig) -> String {
+    // The codegen libraries are always built
+    BuildConfig::new(lcfg, true).fingerprint()
+}
+
+fn clean(out_dir: &std::path::Path, targets: &[String], work_dir: &std::path::Path) -> Result<(), String> {
+    let mut dirs: Vec<PathBuf> = if targets.is_empty() {
+        vec![out_dir.to_path_buf(), work_dir.to_path_buf()]
+    } else {
+        targets.iter().map(|target| out_dir.join(target)).collect()
+    };
+    dirs.retain(|dir| dir.exists());
+
+    for dir in dirs {
+        println!("Removing {}", dir.display());
+        std::fs::remove_dir_all(&dir).map_err(|e| format!("could not remove {}: {}", dir.display(), e))?;
     }
+
+    Ok(())
 }
 
-pub fn upload_to_git(targets: Vec<&str>, os: &str) {
-    // Remove the prebuilt-git directory if it exists
-    let prebuilt_git_dir = "prebuilts-git";
-    if std::path::Path::new(&prebuilt_git_dir).exists() {
-        std::fs::remove_dir_all(&prebuilt_git_dir).expect("Failed to remove prebuilts-git directory");
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// Directory the prebuilts were built into
+    #[arg(long, default_value = "prebuilts")]
+    prebuilts_dir: PathBuf,
+
+    /// Targets to export, defaults to every built target
+    #[arg(long = "target")]
+    targets: Vec<String>,
+
+    /// Compress libraries over the size limit with zstd before splitting them (not for
+    /// tarballs, which are compressed as a whole)
+    #[arg(long)]
+    compress: bool,
+}
+
+impl ExportArgs {
+    fn targets(&self) -> Result<Vec<String>, String> {
+        publish::built_targets(&self.prebuilts_dir, &self.targets)
     }
 
-    // Git clone https://github.com/mluau/lute-prebuilts-{os}.git
-    let git_url = format!("https://github.com/mluau/lute-prebuilts-{}.git", os);
-    let output = std::process::Command::new("git")
-        .arg("clone")
-        .arg(&git_url)
-        .arg(&prebuilt_git_dir)
-        .output()
-        .expect("Failed to clone lute-prebuilts repository");
-
-    if !output.status.success() {
-        panic!(
-            "Failed to clone lute-prebuilts repository with stderr: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+    fn chunk_options(&self) -> ChunkOptions {
+        ChunkOptions {
+            compress: self.compress,
+            ..Default::default()
+        }
     }
+}
 
-    // Copy the prebuilts/{target}/staticlibs directory to prebuilts-git/{target}
-    for target in targets {
-        let prebuilts_src_dir = format!("prebuilts/{}/build/staticlibs", target);
-        let prebuilts_dest_dir = format!("{}/{}", prebuilt_git_dir, prebuilts_src_dir);
-        println!("Copying from {} to {}", prebuilts_src_dir, prebuilts_dest_dir);
-        // Create the destination directory if it doesn't exist
-        std::fs::create_dir_all(&prebuilts_dest_dir).expect("Failed to create destination directory");
-
-        // Copy the contents of the source directory to the destination directory
-        for entry in std::fs::read_dir(&prebuilts_src_dir).expect("Failed to read source directory") {
-            let entry = entry.expect("Failed to read entry");
-            let src_path = entry.path();
-            let dest_path = std::path::Path::new(&prebuilts_dest_dir).join(entry.file_name());
-
-            println!("Copying {} to {}", src_path.display(), dest_path.display());
-
-            // Split the file into parts if it is larger than 100MB
-            if src_path.is_file() && src_path.metadata().unwrap().len() > 100  * 1024 * 1024 {
-                println!("Splitting file {} into  parts", src_path.display());
-                let file = std::fs::File::open(&src_path).expect("Failed to open file");
-                let mut reader = std::io::BufReader::new(file);
-                let mut buffer = vec![0; 90 * 1024 * 1024]; // 100MB buffer
-                let mut part_number = 1;
-                loop {
-                    let bytes_read = reader.read(&mut buffer).expect("Failed to read file");
-                    if bytes_read == 0 {
-                        break; // End of file
-                    }
-                    let part_file_name = format!("{}.part{}", dest_path.display(), part_number);
-                    let mut part_file = std::fs::File::create(&part_file_name).expect("Failed to create part file");
-                    part_file.write_all(&buffer[..bytes_read]).expect("Failed to write to part file");
-                    println!("Created part file: {}", part_file_name);
-                    part_number += 1;
-                }
-            } else {
-                std::fs::copy(&src_path, &dest_path).expect("Failed to copy file");
+fn default_repo() -> String {
+    format!("https://github.com/mluau/lute-prebuilts-{}.git", host_os())
+}
+
+fn run(command: Commands) -> Result<(), String> {
+    match command {
+        Commands::Build(args) => build(args)?,
+        Commands::ListTargets => {
+            for (target, os, default) in SUPPORTED_TARGETS {
+                let default = if *default && *os == host_os() {
+                    " (default)"
+                } else {
+                    ""
+                };
+                println!("{}{}", target, default);
             }
         }
+        Commands::Clean {
+            out_dir,
+            targets,
+            work_dir,
+        } => clean(&out_dir, &targets, &work_dir)?,
+        Commands::Package { out, export } => {
+            let targets = export.targets()?;
+            publish::package(&export.prebuilts_dir, &targets, &out, export.chunk_options())?;
+            println!("Packaged {} into {}", targets.join(", "), out.display());
+        }
+        Commands::Publish {
+            repo,
+            branch,
+            dry_run,
+            init_bare,
+            work_dir,
+            replace_work_dir,
+            message,
+            export,
+        } => {
+            let targets = export.targets()?;
+            publish::publish(
+                &export.prebuilts_dir,
+                &targets,
+                &publish::PublishOptions {
+                    repo: repo.unwrap_or_else(default_repo),
+                    branch,
+                    dry_run,
+                    init_bare,
+                    work_dir,
+                    replace_work_dir,
+                    message,
+                    chunk_options: export.chunk_options(),
+                },
+            )?;
+        }
     }
 
-    println!("Uploading prebuilts to {}...", prebuilt_git_dir);
-
-    // Add, commit and push the changes to the prebuilts-git repository
-    let output = std::process::Command::new("git")
-        .arg("-C")
-        .arg(&prebuilt_git_dir)
-        .arg("add")
-        .arg(".")
-        .output()
-        .expect("Failed to add changes to git");
-
-    if !output.status.success() {
-        panic!(
-            "Failed to add changes to git with stderr: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
+    Ok(())
+}
 
-    let output = std::process::Command::new("git")
-        .arg("-C")
-        .arg(&prebuilt_git_dir)
-        .arg("commit")
-        .arg("-m")
-        .arg("Update prebuilts")
-        .output()
-        .expect("Failed to commit changes to git");
-
-    if !output.status.success() {
-        panic!(
-            "Failed to commit changes to git with stderr: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+// Install (Linux)
+// - g++-aarch64-linux-gnu
+pub fn main() {
+    let cli = Cli::parse();
+    let command = cli
+        .command
+        .unwrap_or_else(|| Commands::Build(BuildArgs::parse_from(["build"])));
+
+    if let Err(error) = run(command) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
     }
+}
 
-    let output = std::process::Command::new("git")
-        .arg("-C")
-        .arg(&prebuilt_git_dir)
-        .arg("push")
-        .arg("origin")
-        .arg("main") // Assuming the main branch is called "main"
-        .output()
-        .expect("Failed to push changes to git");
+#[derive(Debug, Clone)]
+pub struct PrebuiltOptions {
+    /// Also merge every library into one archive (`liblute_all.a` or `lute_all.lib`)
+    pub combined: bool,
+    /// Strip debug information (only) from the combined archive
+    pub strip_debug: bool,
 }
 
 fn does_lute_exist(cmd: &str) -> bool {
@@ -170,35 +433,8 @@ fn does_lute_exist(cmd: &str) -> bool {
     cmd.success() // If the command exists, it should return success
 }
 
-pub fn build_lute_prebuilt(lcfg: LConfig, target: &str, os: &str) {
-    let host = env!("HOST_VAR");
-    println!("Host: {}", host);
-
-    // Make prebuilts/{target} directory if it doesn't exist
-    let prebuilts_dir = format!("prebuilts/{}/build", target);
-    std::fs::create_dir_all(&prebuilts_dir).expect("Failed to create prebuilts directory");
-
-    unsafe {
-        std::env::set_var("HOST", host);
-        std::env::set_var("TARGET", target);
-        std::env::set_var("OPT_LEVEL", "3");
-        std::env::set_var("OUT_DIR", &prebuilts_dir);
-
-        if os == "windows" {
-            std::env::set_var("CARGO_CFG_TARGET_ENV", "msvc");
-            std::env::set_var("CARGO_CFG_TARGET_OS", "windows");
-            std::env::set_var("CARGO_CFG_TARGET_ARCH", "x86_64");
-        } else if os == "macos" {
-            std::env::set_var("CARGO_CFG_TARGET_ENV", "darwin");
-            std::env::set_var("CARGO_CFG_TARGET_OS", "macos");
-            std::env::set_var("CARGO_CFG_TARGET_ARCH", "aarch64");
-        } else {
-            std::env::set_var("CARGO_CFG_TARGET_ENV", target.split('-').nth(1).unwrap_or("unknown"));
-            std::env::set_var("CARGO_CFG_TARGET_OS", target.split('-').nth(2).unwrap_or("unknown"));
-            std::env::set_var("CARGO_CFG_TARGET_ARCH", target.split('-').nth(0).unwrap_or("unknown"));
-        }
-    }
-
+/// Fetches and generates the dependencies of lute with luthier, once for every build
+fn bootstrap_lute() {
     // Switch directory to CARGO_MANIFEST_DIR
     // This is needed to run the luthier.py script
     println!(
@@ -263,28 +499,62 @@ pub fn build_lute_prebuilt(lcfg: LConfig, target: &str, os: &str) {
         // Create the .done_luthier file to indicate that luthier.py has been run
         std::fs::File::create(lute_done_path).expect("Failed to create .done_luthier file");
     }
+}
+
+/// Builds the prebuilts of a config for `env.target` into `env.out_dir`
+///
+/// The build environment is only read from `env`, so several configs can be built at the same
+/// time on different threads.
+pub fn build_lute_prebuilt(lcfg: LConfig, env: &BuildEnv, options: &PrebuiltOptions) -> Result<(), String> {
+    let target = env.target.as_str();
+    let triple = Triple::parse(target)?;
+    let prebuilts_dir = env.out_dir.display().to_string();
+    println!("Host: {}", env.host);
+
+    std::fs::create_dir_all(&prebuilts_dir).expect("Failed to create prebuilts directory");
 
     // Custom is a special library that needs to be built manually and linked in as well
     println!("Building Luau.Custom for target: {}", target);
-    
+
     build_cc_lute_lib(
         lcfg,
+        env,
         "Luau.Custom",
-        vec!["Custom/src/lextra.cpp".to_string(), "Custom/src/lflags.cpp".to_string()],
-        true // prebuilt
-    );
-    
+        &["Custom/src/lextra.cpp", "Custom/src/lflags.cpp"],
+    )?;
+
     // Also build LuteExt
     println!("Building Luau.LuteExt for target: {}", target);
 
     build_cc_lute_lib(
         lcfg,
+        env,
         "Luau.LuteExt",
-        vec!["LuteExt/src/lopen.cpp".to_string()],
-        true // prebuilt
-    );
+        &[
+            "LuteExt/src/lbudget.cpp",
+            "LuteExt/src/lfs.cpp",
+            "LuteExt/src/llog.cpp",
+            "LuteExt/src/lopen.cpp",
+            "LuteExt/src/lpolicy.cpp",
+            "LuteExt/src/lprocess.cpp",
+            "LuteExt/src/lprofiler.cpp",
+            "LuteExt/src/lrequire.cpp",
+            "LuteExt/src/lsandbox.cpp",
+            "LuteExt/src/ltrace.cpp",
+        ],
+    )?;
 
-    let dst = setup_lute_cmake(lcfg, true);
+    // Prebuilts always ship the codegen shim so consumers can opt into native compilation
+    println!("Building Luau.LuteExt.CodeGen for target: {}", target);
+
+    build_cc_lute_lib(lcfg, env, "Luau.LuteExt.CodeGen", &["LuteExt/src/lcodegen.cpp"])?;
+
+    // Same for the debugger hooks
+    println!("Building Luau.LuteExt.Debug for target: {}", target);
+
+    build_cc_lute_lib(lcfg, env, "Luau.LuteExt.Debug", &["LuteExt/src/ldebug.cpp"])?;
+
+    let dst = setup_lute_cmake(lcfg, env);
 
     // Now copy the final output files to the prebuilts directory/{target}/staticlibs
     //
@@ -292,10 +562,15 @@ pub fn build_lute_prebuilt(lcfg: LConfig, target: &str, os: &str) {
     // On macos, these will be *.a files
     // On windows, these will be *.lib files
     let staticlibs_dir = format!("{}/staticlibs", prebuilts_dir);
+
+    // Start from an empty directory so libraries of earlier builds don't end up in the manifest
+    if std::path::Path::new(&staticlibs_dir).exists() {
+        std::fs::remove_dir_all(&staticlibs_dir).expect("Failed to remove old staticlibs directory");
+    }
     std::fs::create_dir_all(&staticlibs_dir).expect("Failed to create staticlibs directory");
 
     // Now glob
-    let ending = if os == "windows" {
+    let ending = if triple.is_windows_msvc() {
         "lib"
     } else {
         "a"
@@ -307,16 +582,90 @@ pub fn build_lute_prebuilt(lcfg: LConfig, target: &str, os: &str) {
 
     std::thread::sleep(std::time::Duration::from_millis(100)); // Sleep to avoid windows issues
 
-    files
+    // Libraries are copied flat, so two libraries with the same file name in different
+    // directories would silently overwrite each other. Sort first so the error is stable
+    let mut files: Vec<std::path::PathBuf> = files
         .filter_map(Result::ok)
-        .for_each(|path| {
-            if path.display().to_string().starts_with(&staticlibs_dir) || path.display().to_string().contains("staticlibs") {
-                // Skip files that are already in the staticlibs directory
-                return;
-            }
-            let file_name = path.file_name().unwrap();
-            let dest_path = std::path::Path::new(&staticlibs_dir).join(file_name);
-            println!("Copying {} to {}", path.display(), dest_path.display());
-            std::fs::copy(path.clone(), &dest_path).expect("Failed to copy static library");
-        });
+        .filter(|path| {
+            // Skip files that are already in the staticlibs directory
+            !path.display().to_string().starts_with(&staticlibs_dir) && !path.display().to_string().contains("staticlibs")
+        })
+        .collect();
+    files.sort();
+
+    let mut copied: std::collections::HashMap<std::ffi::OsString, std::path::PathBuf> = std::collections::HashMap::new();
+    for path in &files {
+        let file_name = path.file_name().unwrap();
+        if let Some(previous) = copied.get(file_name) {
+            panic!(
+                "Static library {} exists in both {} and {}",
+                file_name.to_string_lossy(),
+                previous.display(),
+                path.display()
+            );
+        }
+        copied.insert(file_name.to_os_string(), path.clone());
+    }
+
+    for path in &files {
+        let dest_path = std::path::Path::new(&staticlibs_dir).join(path.file_name().unwrap());
+        println!("Copying {} to {}", path.display(), dest_path.display());
+        std::fs::copy(path, &dest_path).expect("Failed to copy static library");
+    }
+
+    // Link order follows the dependencies between the CMake targets
+    let graph = DependencyGraph::from_build_dir(&dst.join("build"))
+        .unwrap_or_else(|e| panic!("Failed to read the CMake dependency graph: {}", e));
+
+    // Record what the libraries were built from, their checksums and how to link them
+    let mut manifest = Manifest::generate(
+        std::path::Path::new(&staticlibs_dir),
+        target,
+        Sources {
+            lute_src_rs: git_revision(std::path::Path::new(".")),
+            lute: git_revision(std::path::Path::new("lute")),
+        },
+        BuildConfig::new(lcfg, true), // The codegen libraries are always built
+        Toolchain::detect(),
+        &graph,
+    )
+    .expect("Failed to generate prebuilt manifest");
+
+    if options.combined {
+        println!("Combining {} libraries for target: {}", manifest.link_order.len(), target);
+
+        let combined = combine_archives(
+            std::path::Path::new(&staticlibs_dir),
+            target,
+            &manifest.link_order,
+            options.strip_debug,
+        )
+        .unwrap_or_else(|e| panic!("Failed to combine static libraries: {}", e));
+        println!("Created {}", combined.display());
+
+        manifest
+            .add_combined(std::path::Path::new(&staticlibs_dir))
+            .expect("Failed to add combined library to the manifest");
+    }
+
+    manifest
+        .write(std::path::Path::new(&staticlibs_dir))
+        .expect("Failed to write prebuilt manifest");
+    println!("Wrote manifest for {} libraries", manifest.files.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supported_targets_parse() {
+        // Each target is built by a host of its own OS (see triple.rs for the parsed values)
+        for (target, os, _) in SUPPORTED_TARGETS {
+            assert!(is_supported(target), "{} is not supported", target);
+            let triple = Triple::parse(target).unwrap();
+            assert_eq!(triple.os, *os, "{}", target);
+        }
+    }
 }
@@ -0,0 +1,382 @@
+//! Exporting built prebuilts to a directory, a tarball or a git repository
+//!
+//! Every export uses the layout consumers point `LUTE_PREBUILT_DIR` at:
+//! `<target>/<fingerprint>/build/staticlibs/...`, with a directory per variant. In git
+//! repositories, it is placed under `prebuilts/`.
+
+use lute_src_rs::chunked::{self, ChunkOptions};
+use lute_src_rs::manifest::MANIFEST_FILE;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Directories of a target's libraries relative to the prebuilts directory, one per variant
+///
+/// Directories without variants (`<target>/build/staticlibs`) are included too.
+fn staticlibs_dirs(prebuilts_dir: &Path, target: &str) -> Vec<PathBuf> {
+    let staticlibs = |dir: PathBuf| dir.join("build").join("staticlibs");
+
+    let mut dirs = vec![staticlibs(PathBuf::from(target))];
+    if let Ok(entries) = std::fs::read_dir(prebuilts_dir.join(target)) {
+        dirs.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| staticlibs(Path::new(target).join(entry.file_name()))),
+        );
+    }
+
+    dirs.retain(|dir| prebuilts_dir.join(dir).join(MANIFEST_FILE).is_file());
+    dirs.sort();
+    dirs
+}
+
+/// Targets built in `prebuilts_dir` (those with a manifest), restricted to `filter` if it
+/// isn't empty
+pub fn built_targets(prebuilts_dir: &Path, filter: &[String]) -> Result<Vec<String>, String> {
+    let entries = std::fs::read_dir(prebuilts_dir)
+        .map_err(|e| format!("could not read {}: {}", prebuilts_dir.display(), e))?;
+
+    let mut targets: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|target| !staticlibs_dirs(prebuilts_dir, target).is_empty())
+        .collect();
+    targets.sort();
+
+    if !filter.is_empty() {
+        if let Some(missing) = filter.iter().find(|target| !targets.contains(target)) {
+            return Err(format!(
+                "no prebuilts for {} in {}",
+                missing,
+                prebuilts_dir.display()
+            ));
+        }
+        targets.retain(|target| filter.contains(target));
+    }
+
+    if targets.is_empty() {
+        return Err(format!("no prebuilts in {}", prebuilts_dir.display()));
+    }
+    Ok(targets)
+}
+
+/// Copies the libraries of the targets into `out`, storing large ones as parts
+pub fn export_dir(
+    prebuilts_dir: &Path,
+    targets: &[String],
+    out: &Path,
+    chunk_options: ChunkOptions,
+) -> Result<(), String> {
+    for dir in targets
+        .iter()
+        .flat_map(|target| staticlibs_dirs(prebuilts_dir, target))
+    {
+        let src = prebuilts_dir.join(&dir);
+        let dest = out.join(&dir);
+
+        // Replace the previous libraries, so stale parts aren't reassembled by consumers
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)
+                .map_err(|e| format!("could not remove {}: {}", dest.display(), e))?;
+        }
+        std::fs::create_dir_all(&dest)
+            .map_err(|e| format!("could not create {}: {}", dest.display(), e))?;
+
+        let entries = std::fs::read_dir(&src)
+            .map_err(|e| format!("could not read {}: {}", src.display(), e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            println!("Copying {} to {}", path.display(), dest.display());
+            chunked::store(&path, &dest, chunk_options)
+                .map_err(|e| format!("could not copy {}: {}", path.display(), e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the libraries of the targets into a zstd-compressed tarball
+fn export_tarball(prebuilts_dir: &Path, targets: &[String], out: &Path) -> Result<(), String> {
+    let write = || -> std::io::Result<()> {
+        let file = std::fs::File::create(out)?;
+        let encoder = zstd::stream::write::Encoder::new(file, 19)?;
+        let mut builder = tar::Builder::new(encoder);
+        builder.mode(tar::HeaderMode::Deterministic);
+
+        for dir in targets
+            .iter()
+            .flat_map(|target| staticlibs_dirs(prebuilts_dir, target))
+        {
+            println!("Adding {} to {}", dir.display(), out.display());
+            builder.append_dir_all(&dir, prebuilts_dir.join(&dir))?;
+        }
+
+        builder.into_inner()?.finish()?;
+        Ok(())
+    };
+
+    write().map_err(|e| format!("could not write {}: {}", out.display(), e))
+}
+
+/// Exports the targets to a directory, or to a tarball if `out` ends with `.tar.zst`
+///
+/// Tarballs are compressed as a whole and store libraries of any size, so they don't take
+/// chunk options.
+pub fn package(
+    prebuilts_dir: &Path,
+    targets: &[String],
+    out: &Path,
+    chunk_options: ChunkOptions,
+) -> Result<(), String> {
+    if out.to_string_lossy().ends_with(".tar.zst") {
+        if chunk_options != ChunkOptions::default() {
+            return Err(format!(
+                "{} is a tarball, which doesn't split or compress libraries on their own \
+                 (--compress only applies to directories)",
+                out.display()
+            ));
+        }
+        export_tarball(prebuilts_dir, targets, out)
+    } else {
+        export_dir(prebuilts_dir, targets, out, chunk_options)
+    }
+}
+
+/// Runs git, returning its stdout or an error naming the failed step
+fn git(dir: Option<&Path>, args: &[&str]) -> Result<String, String> {
+    let mut cmd = Command::new("git");
+    if let Some(dir) = dir {
+        cmd.arg("-C").arg(dir);
+    }
+    let output = cmd
+        .args(args)
+        .output()
+        .map_err(|e| format!("could not run git {}: {}", args.join(" "), e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed with stderr: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    /// URL or path of the repository
+    pub repo: String,
+    pub branch: String,
+    /// Commit but don't push
+    pub dry_run: bool,
+    /// Create `repo` as a bare repository if it is a path that doesn't exist, for testing
+    pub init_bare: bool,
+    /// Directory the repository is cloned into, which must not exist or be empty
+    pub work_dir: PathBuf,
+    /// Remove `work_dir` first if it isn't empty, e.g. the clone of a previous publish
+    pub replace_work_dir: bool,
+    pub message: String,
+    pub chunk_options: ChunkOptions,
+}
+
+/// Commits the libraries of the targets to `prebuilts/` of the repository's branch and pushes
+/// them
+pub fn publish(
+    prebuilts_dir: &Path,
+    targets: &[String],
+    options: &PublishOptions,
+) -> Result<(), String> {
+    if options.init_bare && !Path::new(&options.repo).exists() {
+        println!("Creating bare repository {}", options.repo);
+        git(None, &["init", "--bare", &options.repo])?;
+    }
+
+    let work_dir = options.work_dir.as_path();
+    let has_entries =
+        std::fs::read_dir(work_dir).is_ok_and(|mut entries| entries.next().is_some());
+    if has_entries {
+        if !options.replace_work_dir {
+            return Err(format!(
+                "{} is not empty, remove it or pass --replace-work-dir",
+                work_dir.display()
+            ));
+        }
+        std::fs::remove_dir_all(work_dir)
+            .map_err(|e| format!("could not remove {}: {}", work_dir.display(), e))?;
+    }
+
+    println!("Cloning {} into {}", options.repo, work_dir.display());
+    git(
+        None,
+        &["clone", &options.repo, &work_dir.to_string_lossy()],
+    )?;
+
+    // Continue the branch if it exists, otherwise start it from the default branch (or as the
+    // first branch of an empty repository)
+    let remote_branch = format!("origin/{}", options.branch);
+    let exists = !git(
+        Some(work_dir),
+        &["ls-remote", "--heads", "origin", &options.branch],
+    )?
+    .trim()
+    .is_empty();
+    if exists {
+        git(
+            Some(work_dir),
+            &["checkout", "-B", &options.branch, &remote_branch],
+        )?;
+    } else {
+        git(Some(work_dir), &["checkout", "-B", &options.branch])?;
+    }
+
+    export_dir(
+        prebuilts_dir,
+        targets,
+        &work_dir.join("prebuilts"),
+        options.chunk_options,
+    )?;
+
+    git(Some(work_dir), &["add", "-A"])?;
+    if git(Some(work_dir), &["status", "--porcelain"])?
+        .trim()
+        .is_empty()
+    {
+        println!("Prebuilts are up to date, nothing to publish");
+        return Ok(());
+    }
+    // Machines without a git identity (e.g. CI runners) commit as make_prebuilt
+    let has_identity = git(Some(work_dir), &["config", "user.email"]).is_ok();
+    let mut commit = vec!["commit", "-m", options.message.as_str()];
+    if !has_identity {
+        commit.splice(
+            0..0,
+            [
+                "-c",
+                "user.name=make_prebuilt",
+                "-c",
+                "user.email=make_prebuilt@localhost",
+            ],
+        );
+    }
+    git(Some(work_dir), &commit)?;
+
+    if options.dry_run {
+        println!(
+            "Dry run, not pushing to {} {}:\n{}",
+            options.repo,
+            options.branch,
+            git(Some(work_dir), &["show", "--stat", "--format=%s", "HEAD"])?
+        );
+        return Ok(());
+    }
+
+    println!("Pushing to {} {}", options.repo, options.branch);
+    git(Some(work_dir), &["push", "origin", &options.branch])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lute_src_rs::manifest::archive_name;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        lute_src_rs::testutil::temp_dir(&format!("make_prebuilt-{}", name))
+    }
+
+    const VARIANT: &str = "cfg-0123456789abcdef";
+
+    fn fake_prebuilts(root: &Path, target: &str) -> PathBuf {
+        let prebuilts = root.join("prebuilts");
+        let dir = prebuilts
+            .join(target)
+            .join(VARIANT)
+            .join("build")
+            .join("staticlibs");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(archive_name("Luau.VM", target)), b"!<arch>\n").unwrap();
+        std::fs::write(dir.join(MANIFEST_FILE), b"{}").unwrap();
+        prebuilts
+    }
+
+    #[test]
+    fn test_publish_to_bare_repo() {
+        let root = temp_dir("publish");
+        let target = "x86_64-unknown-linux-gnu";
+        let prebuilts = fake_prebuilts(&root, target);
+        let repo = root.join("remote.git");
+
+        let mut options = PublishOptions {
+            repo: repo.to_string_lossy().into_owned(),
+            branch: "prebuilts".to_string(),
+            dry_run: true,
+            init_bare: true,
+            work_dir: root.join("work"),
+            replace_work_dir: false,
+            message: "Update prebuilts".to_string(),
+            chunk_options: ChunkOptions::default(),
+        };
+        let targets = built_targets(&prebuilts, &[]).unwrap();
+        assert_eq!(targets, vec![target.to_string()]);
+
+        // A dry run commits locally but leaves the remote untouched
+        publish(&prebuilts, &targets, &options).unwrap();
+        assert!(git(Some(&repo), &["rev-parse", "--verify", "prebuilts"]).is_err());
+
+        // The clone of the dry run is only replaced when asked to
+        let error = publish(&prebuilts, &targets, &options).unwrap_err();
+        assert!(error.contains("--replace-work-dir"), "{}", error);
+        assert!(root.join("work").join(".git").is_dir());
+
+        options.dry_run = false;
+        options.replace_work_dir = true;
+        publish(&prebuilts, &targets, &options).unwrap();
+        let files = git(Some(&repo), &["ls-tree", "-r", "--name-only", "prebuilts"]).unwrap();
+        assert!(files.contains(&format!(
+            "prebuilts/{}/{}/build/staticlibs/{}",
+            target,
+            VARIANT,
+            archive_name("Luau.VM", target)
+        )));
+
+        // Publishing the same libraries again is a no-op
+        publish(&prebuilts, &targets, &options).unwrap();
+        let commits = git(Some(&repo), &["rev-list", "--count", "prebuilts"]).unwrap();
+        assert_eq!(commits.trim(), "1");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_target() {
+        let root = temp_dir("targets");
+        let prebuilts = fake_prebuilts(&root, "x86_64-unknown-linux-gnu");
+
+        let error = built_targets(&prebuilts, &["riscv64gc-unknown-linux-gnu".to_string()]);
+        assert!(error.unwrap_err().contains("riscv64gc-unknown-linux-gnu"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_tarball_rejects_chunk_options() {
+        let root = temp_dir("tarball");
+        let target = "x86_64-unknown-linux-gnu";
+        let prebuilts = fake_prebuilts(&root, target);
+        let targets = vec![target.to_string()];
+        let out = root.join("prebuilts.tar.zst");
+
+        let compress = ChunkOptions {
+            compress: true,
+            ..Default::default()
+        };
+        let error = package(&prebuilts, &targets, &out, compress).unwrap_err();
+        assert!(error.contains("--compress"), "{}", error);
+        assert!(!out.exists());
+
+        package(&prebuilts, &targets, &out, ChunkOptions::default()).unwrap();
+        assert!(out.is_file());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Explicit build environment for building Lute outside of a build script
+//!
+//! cc and cmake read their target, output directory and optimization level from the variables
+//! cargo sets for build scripts, unless they are set on the builder. `setup_lute_cmake` and
+//! `build_cc_lute_lib` take them from a `BuildEnv` instead, so several targets can be built at
+//! the same time on different threads of one process (see make_prebuilt).
+
+use crate::LConfig;
+use lute_src_rs_common::cmake::Config;
+use std::path::{Path, PathBuf};
+
+/// `cfg(target_*)` values of a target, as cargo exposes them to build scripts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetCfg {
+    pub arch: String,
+    pub vendor: String,
+    pub os: String,
+    pub env: String,
+    pub abi: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildEnv {
+    /// Target triple to build for
+    pub target: String,
+    /// Triple of the machine running the build
+    pub host: String,
+    /// Directory cc and cmake write their output to
+    pub out_dir: PathBuf,
+    pub opt_level: String,
+    /// `release` or `debug`, as cargo names the profile being built
+    pub profile: String,
+    /// Whether debug information is generated
+    pub debug: bool,
+    pub target_cfg: TargetCfg,
+    /// Number of parallel compile jobs, defaults to the number of CPUs
+    pub jobs: Option<usize>,
+}
+
+impl BuildEnv {
+    /// A cc build for this environment
+    pub fn cc_build(&self) -> cc::Build {
+        let mut build = cc::Build::new();
+        build
+            .target(&self.target)
+            .host(&self.host)
+            .out_dir(&self.out_dir)
+            .opt_level_str(&self.opt_level)
+            .debug(self.debug)
+            .cargo_metadata(false);
+        build
+    }
+
+    /// A cmake config for the project at `path`, building into `out_dir`
+    pub fn cmake_config(&self, path: impl AsRef<Path>) -> Config {
+        let mut config = Config::new(path);
+        config
+            .target(&self.target)
+            .host(&self.host)
+            .out_dir(&self.out_dir)
+            .profile(self.cmake_profile())
+            .init_c_cfg(self.cc_build())
+            .init_cxx_cfg(self.cc_build());
+        if let Some(jobs) = self.jobs {
+            config.env("CMAKE_BUILD_PARALLEL_LEVEL", jobs.to_string());
+        }
+        config
+    }
+
+    /// `CMAKE_BUILD_TYPE` of the profile
+    fn cmake_profile(&self) -> &'static str {
+        match (self.profile.as_str(), self.debug) {
+            ("debug", _) => "Debug",
+            (_, true) => "RelWithDebInfo",
+            _ => "Release",
+        }
+    }
+}
+
+/// Preprocessor definitions of the libraries disabled by the config
+fn lute_defines(lcfg: LConfig) -> Vec<&'static str> {
+    let mut defines = Vec::new();
+    if lcfg.disable_net {
+        defines.push("LUTE_DISABLE_NET");
+    }
+    if lcfg.disable_crypto {
+        defines.push("LUTE_DISABLE_CRYPTO");
+    }
+    defines
+}
+
+/// Configures and builds Lute with CMake, returning the directory it was built in
+///
+/// Like the `lute-src-rs-common` function of the same name, but no `cargo:` directives are
+/// printed, as there is no build script to pick them up.
+pub fn setup_lute_cmake(lcfg: LConfig, env: &BuildEnv) -> PathBuf {
+    let mut config = env.cmake_config("lute");
+    for define in lute_defines(lcfg) {
+        config.define(define, "ON");
+    }
+
+    // Only the libraries are needed, Lute has no install rules for them
+    let all = if env.target.contains("msvc") {
+        "ALL_BUILD"
+    } else {
+        "all"
+    };
+    config.build_target(all).build()
+}
+
+/// Compiles `files` into the static library `name` (`lib<name>.a` or `<name>.lib`) in `out_dir`
+///
+/// Like the `lute-src-rs-common` function of the same name for prebuilt builds, against the
+/// headers of the Lute checkout in `lute`.
+pub fn build_cc_lute_lib(
+    lcfg: LConfig,
+    env: &BuildEnv,
+    name: &str,
+    files: &[&str],
+) -> Result<(), String> {
+    let mut build = env.cc_build();
+    build.cpp(true).std("c++17").warnings(false).files(files);
+
+    for include in [
+        "lute/extern/luau/Common/include",
+        "lute/extern/luau/Compiler/include",
+        "lute/extern/luau/CodeGen/include",
+        "lute/extern/luau/VM/include",
+        "lute/extern/luau/VM/src",
+        "lute/extern/libuv/include",
+    ] {
+        build.include(include);
+    }
+
+    // Every Lute library has its headers in lute/<library>/include/lute
+    let libraries = std::fs::read_dir("lute/lute")
+        .map_err(|e| format!("could not read the Lute checkout: {}", e))?;
+    for library in libraries.filter_map(Result::ok) {
+        let include = library.path().join("include");
+        if include.is_dir() {
+            build.include(include);
+        }
+    }
+
+    for define in lute_defines(lcfg) {
+        build.define(define, None);
+    }
+
+    build
+        .try_compile(name)
+        .map_err(|e| format!("could not build {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_env(profile: &str, debug: bool) -> BuildEnv {
+        BuildEnv {
+            target: "aarch64-unknown-linux-gnu".to_string(),
+            host: "x86_64-unknown-linux-gnu".to_string(),
+            out_dir: PathBuf::from("/tmp/out"),
+            opt_level: "3".to_string(),
+            profile: profile.to_string(),
+            debug,
+            target_cfg: TargetCfg {
+                arch: "aarch64".to_string(),
+                vendor: "unknown".to_string(),
+                os: "linux".to_string(),
+                env: "gnu".to_string(),
+                abi: String::new(),
+            },
+            jobs: Some(4),
+        }
+    }
+
+    #[test]
+    fn test_cmake_profile() {
+        assert_eq!(build_env("release", false).cmake_profile(), "Release");
+        assert_eq!(build_env("release", true).cmake_profile(), "RelWithDebInfo");
+        assert_eq!(build_env("debug", true).cmake_profile(), "Debug");
+    }
+
+    #[test]
+    fn test_lute_defines() {
+        assert!(lute_defines(LConfig::default()).is_empty());
+        assert_eq!(
+            lute_defines(LConfig {
+                disable_net: true,
+                disable_crypto: true,
+                ..Default::default()
+            }),
+            ["LUTE_DISABLE_NET", "LUTE_DISABLE_CRYPTO"]
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! Chunked storage of large prebuilt libraries
+//!
+//! Git hosts limit the size of files, so libraries over `MAX_FILE_SIZE` are stored as parts.
+//! A library `<name>` is stored as:
+//!
+//! - `<name>.chunks.json`, the `ChunkIndex` describing the library and its parts
+//! - `<name>.part1` to `<name>.partN`, each `CHUNK_SIZE` bytes except for the last one
+//!
+//! With compression, the parts hold the zstd-compressed library, which usually fits under the
+//! limit without splitting (in which case there is a single part). `reassemble_dir` restores
+//! the libraries of a directory into another one (the build script's `OUT_DIR`, so prebuilt
+//! directories are never written to), verifying every part and the result.
+
+use crate::manifest::sha256_file;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Size from which files are stored as parts
+pub const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Size of every part but the last
+pub const CHUNK_SIZE: u64 = 90 * 1024 * 1024;
+
+pub const CHUNK_INDEX_SUFFIX: &str = ".chunks.json";
+
+/// Version of the chunk index format, bumped on incompatible changes
+pub const CHUNK_FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkIndex {
+    pub format: u32,
+    /// File name of the reassembled file
+    pub name: String,
+    /// Size of the reassembled file
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the reassembled file
+    pub sha256: String,
+    /// Compression of the concatenated parts
+    pub compression: Compression,
+    pub parts: Vec<ChunkPart>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkPart {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// How `store` writes files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkOptions {
+    /// Files of this size or larger are stored as parts
+    pub max_file_size: u64,
+    pub chunk_size: u64,
+    /// Compress files stored as parts with zstd (requires the `zstd` feature)
+    pub compress: bool,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        ChunkOptions {
+            max_file_size: MAX_FILE_SIZE,
+            chunk_size: CHUNK_SIZE,
+            compress: false,
+        }
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes up to `size` bytes of the reader to `path`, returning how many were written and
+/// their SHA-256
+///
+/// Reads until the part is full, so parts don't depend on how much each `read` returns.
+fn write_part(reader: &mut impl Read, path: &Path, size: u64) -> std::io::Result<(u64, String)> {
+    let mut file = std::fs::File::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut written = 0;
+
+    while written < size {
+        let want = buffer.len().min((size - written) as usize);
+        let read = reader.read(&mut buffer[..want])?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        written += read as u64;
+    }
+
+    Ok((written, hex(&hasher.finalize())))
+}
+
+#[cfg(feature = "zstd")]
+fn compressed_reader(path: &Path) -> std::io::Result<Box<dyn Read>> {
+    let file = std::fs::File::open(path)?;
+    Ok(Box::new(zstd::stream::read::Encoder::new(file, 19)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compressed_reader(_path: &Path) -> std::io::Result<Box<dyn Read>> {
+    Err(std::io::Error::other(
+        "compression requires the zstd feature of lute-src-rs",
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn decompressed_reader<'a>(reader: Box<dyn Read + 'a>) -> std::io::Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompressed_reader<'a>(_reader: Box<dyn Read + 'a>) -> std::io::Result<Box<dyn Read + 'a>> {
+    Err(std::io::Error::other(
+        "zstd-compressed parts require the zstd feature of lute-src-rs",
+    ))
+}
+
+/// Copies `src` into `dest_dir`, as parts with a chunk index if it is too large
+pub fn store(src: &Path, dest_dir: &Path, options: ChunkOptions) -> std::io::Result<()> {
+    let name = src
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("source has no file name"))?
+        .to_string_lossy()
+        .into_owned();
+
+    let size = std::fs::metadata(src)?.len();
+    if size < options.max_file_size {
+        std::fs::copy(src, dest_dir.join(&name))?;
+        return Ok(());
+    }
+
+    let (compression, mut reader): (_, Box<dyn Read>) = if options.compress {
+        (Compression::Zstd, compressed_reader(src)?)
+    } else {
+        (Compression::None, Box::new(std::fs::File::open(src)?))
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        let part_name = format!("{}.part{}", name, parts.len() + 1);
+        let part_path = dest_dir.join(&part_name);
+        let (size, sha256) = write_part(&mut reader, &part_path, options.chunk_size)?;
+
+        // The previous part ended exactly at the end of the file
+        if size == 0 && !parts.is_empty() {
+            std::fs::remove_file(&part_path)?;
+            break;
+        }
+
+        parts.push(ChunkPart {
+            name: part_name,
+            size,
+            sha256,
+        });
+        if size < options.chunk_size {
+            break;
+        }
+    }
+
+    let index = ChunkIndex {
+        format: CHUNK_FORMAT,
+        name: name.clone(),
+        size,
+        sha256: sha256_file(src)?,
+        compression,
+        parts,
+    };
+    let data = serde_json::to_vec_pretty(&index).map_err(std::io::Error::other)?;
+    std::fs::write(
+        dest_dir.join(format!("{}{}", name, CHUNK_INDEX_SUFFIX)),
+        data,
+    )
+}
+
+/// Reader over the parts of an index, verifying each part once it was fully read
+struct PartsReader<'a> {
+    dir: &'a Path,
+    parts: std::slice::Iter<'a, ChunkPart>,
+    current: Option<(&'a ChunkPart, std::fs::File, Sha256, u64)>,
+}
+
+impl PartsReader<'_> {
+    fn finish_part(&mut self) -> std::io::Result<()> {
+        let Some((part, _, hasher, read)) = self.current.take() else {
+            return Ok(());
+        };
+
+        if read != part.size || hex(&hasher.finalize()) != part.sha256 {
+            return Err(std::io::Error::other(format!(
+                "part {} is corrupted",
+                part.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Read for PartsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some((_, file, hasher, read)) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 {
+                    hasher.update(&buf[..n]);
+                    *read += n as u64;
+                    return Ok(n);
+                }
+                self.finish_part()?;
+            }
+
+            let Some(part) = self.parts.next() else {
+                return Ok(0);
+            };
+            let file = std::fs::File::open(self.dir.join(&part.name))
+                .map_err(|e| std::io::Error::other(format!("missing part {}: {}", part.name, e)))?;
+            self.current = Some((part, file, Sha256::new(), 0));
+        }
+    }
+}
+
+/// Whether the name refers to a file directly inside a directory, which every name of an index
+/// must as they come from the (untrusted) prebuilt directory
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+impl ChunkIndex {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let index: ChunkIndex = serde_json::from_slice(&data)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+
+        if index.format != CHUNK_FORMAT {
+            return Err(format!(
+                "unsupported chunk format {} in {}",
+                index.format,
+                path.display()
+            ));
+        }
+
+        let mut names =
+            std::iter::once(&index.name).chain(index.parts.iter().map(|part| &part.name));
+        if let Some(name) = names.find(|name| !is_file_name(name)) {
+            return Err(format!(
+                "invalid file name {:?} in {}",
+                name,
+                path.display()
+            ));
+        }
+        Ok(index)
+    }
+
+    /// Writes the file reassembled from the parts in `src_dir` into `dest_dir`, unless it
+    /// already exists there with the right hash
+    pub fn reassemble(&self, src_dir: &Path, dest_dir: &Path) -> Result<(), String> {
+        let path = dest_dir.join(&self.name);
+        if sha256_file(&path).is_ok_and(|sha256| sha256 == self.sha256) {
+            return Ok(());
+        }
+
+        // Write to a temporary file so an interrupted build never leaves a truncated library
+        let partial = dest_dir.join(format!("{}.partial", self.name));
+        let result = (|| {
+            let parts: Box<dyn Read + '_> = Box::new(PartsReader {
+                dir: src_dir,
+                parts: self.parts.iter(),
+                current: None,
+            });
+            let mut reader = match self.compression {
+                Compression::None => parts,
+                Compression::Zstd => decompressed_reader(parts)?,
+            };
+
+            let mut file = std::fs::File::create(&partial)?;
+            std::io::copy(&mut reader, &mut file)?;
+            file.sync_all()
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&partial);
+            return Err(format!("could not reassemble {}: {}", self.name, e));
+        }
+
+        let sha256 = sha256_file(&partial).map_err(|e| e.to_string())?;
+        if sha256 != self.sha256 {
+            let _ = std::fs::remove_file(&partial);
+            return Err(format!("checksum mismatch for reassembled {}", self.name));
+        }
+
+        std::fs::rename(&partial, &path)
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+/// Reassembles every chunked file of `src_dir` into `dest_dir`, returning their names
+///
+/// `dest_dir` is created if needed, and other files in it are removed so it only holds the
+/// files reassembled from `src_dir`.
+pub fn reassemble_dir(src_dir: &Path, dest_dir: &Path) -> Result<Vec<String>, String> {
+    let entries = std::fs::read_dir(src_dir)
+        .map_err(|e| format!("could not read {}: {}", src_dir.display(), e))?;
+
+    let mut indexes: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(CHUNK_INDEX_SUFFIX))
+        })
+        .collect();
+    indexes.sort();
+
+    std::fs::create_dir_all(dest_dir)
+        .map_err(|e| format!("could not create {}: {}", dest_dir.display(), e))?;
+
+    let mut names = Vec::new();
+    for path in indexes {
+        let index = ChunkIndex::read(&path)?;
+        index.reassemble(src_dir, dest_dir)?;
+        names.push(index.name);
+    }
+
+    // Files of a previous prebuilt directory could shadow the ones it now has unchunked
+    let entries = std::fs::read_dir(dest_dir)
+        .map_err(|e| format!("could not read {}: {}", dest_dir.display(), e))?;
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !names.contains(&name) {
+            std::fs::remove_file(entry.path())
+                .map_err(|e| format!("could not remove {}: {}", entry.path().display(), e))?;
+        }
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Temporary directory with `src` and `parts` subdirectories
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = crate::testutil::temp_dir(name);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("parts")).unwrap();
+        dir
+    }
+
+    fn options(chunk_size: u64, compress: bool) -> ChunkOptions {
+        ChunkOptions {
+            max_file_size: 1,
+            chunk_size,
+            compress,
+        }
+    }
+
+    /// Stores `data` as `libtest.a` with the options, returning the part names
+    fn store_data(dir: &Path, data: &[u8], options: ChunkOptions) -> Vec<String> {
+        let src = dir.join("src").join("libtest.a");
+        std::fs::write(&src, data).unwrap();
+        store(&src, &dir.join("parts"), options).unwrap();
+
+        let index = ChunkIndex::read(&dir.join("parts").join("libtest.a.chunks.json")).unwrap();
+        index.parts.into_iter().map(|part| part.name).collect()
+    }
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = temp_dir("round-trip");
+        let data = data(1000);
+
+        let parts = store_data(&dir, &data, options(300, false));
+        assert_eq!(parts.len(), 4);
+
+        let names = reassemble_dir(&dir.join("parts"), &dir.join("out")).unwrap();
+        assert_eq!(names, vec!["libtest.a".to_string()]);
+        assert_eq!(
+            std::fs::read(dir.join("out").join("libtest.a")).unwrap(),
+            data
+        );
+        assert!(!dir.join("parts").join("libtest.a").exists());
+
+        // Files not reassembled this time are removed
+        std::fs::write(dir.join("out").join("libstale.a"), b"stale").unwrap();
+        reassemble_dir(&dir.join("parts"), &dir.join("out")).unwrap();
+        assert!(!dir.join("out").join("libstale.a").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exact_multiple_of_chunk_size() {
+        let dir = temp_dir("exact");
+        let data = data(900);
+
+        let parts = store_data(&dir, &data, options(300, false));
+        assert_eq!(
+            parts,
+            vec!["libtest.a.part1", "libtest.a.part2", "libtest.a.part3"]
+        );
+        assert!(!dir.join("parts").join("libtest.a.part4").exists());
+
+        reassemble_dir(&dir.join("parts"), &dir.join("out")).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("out").join("libtest.a")).unwrap(),
+            data
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        let dir = temp_dir("zstd");
+        let data = data(100_000);
+
+        store_data(&dir, &data, options(CHUNK_SIZE, true));
+        let index = ChunkIndex::read(&dir.join("parts").join("libtest.a.chunks.json")).unwrap();
+        assert_eq!(index.compression, Compression::Zstd);
+        assert!(index.parts[0].size < data.len() as u64);
+
+        reassemble_dir(&dir.join("parts"), &dir.join("out")).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("out").join("libtest.a")).unwrap(),
+            data
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_part() {
+        let dir = temp_dir("corrupted");
+        let parts = store_data(&dir, &data(1000), options(300, false));
+
+        let part = dir.join("parts").join(&parts[1]);
+        let mut bytes = std::fs::read(&part).unwrap();
+        bytes[0] ^= 0xff;
+        std::fs::write(&part, bytes).unwrap();
+
+        let error = reassemble_dir(&dir.join("parts"), &dir.join("out")).unwrap_err();
+        assert!(error.contains("libtest.a.part2 is corrupted"), "{}", error);
+        assert!(!dir.join("out").join("libtest.a").exists());
+        assert!(!dir.join("out").join("libtest.a.partial").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_names_with_paths_rejected() {
+        let dir = temp_dir("names");
+        store_data(&dir, &data(10), options(300, false));
+        let path = dir.join("parts").join("libtest.a.chunks.json");
+        let index = ChunkIndex::read(&path).unwrap();
+
+        for name in ["../libtest.a", "sub/libtest.a", "sub\\libtest.a", ".."] {
+            let mut bad = index.clone();
+            bad.name = name.to_string();
+            std::fs::write(&path, serde_json::to_vec(&bad).unwrap()).unwrap();
+            assert!(ChunkIndex::read(&path)
+                .unwrap_err()
+                .contains("invalid file name"));
+
+            let mut bad = index.clone();
+            bad.parts[0].name = name.to_string();
+            std::fs::write(&path, serde_json::to_vec(&bad).unwrap()).unwrap();
+            assert!(ChunkIndex::read(&path)
+                .unwrap_err()
+                .contains("invalid file name"));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
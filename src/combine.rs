@@ -0,0 +1,207 @@
+//! Merging prebuilt static libraries into a single archive
+//!
+//! Consumers of a combined archive only link `lute_all` (`liblute_all.a`, or `lute_all.lib` with
+//! MSVC) instead of every library in order. Archives are merged with an `ar -M` script on GNU
+//! targets, `libtool -static` on Apple targets and `lib.exe` with MSVC.
+
+use crate::manifest::archive_name;
+use crate::triple::Triple;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Library name of the combined archive
+pub const COMBINED_LIBRARY: &str = "lute_all";
+
+fn run(cmd: &mut Command, input: Option<&str>) -> Result<(), String> {
+    let description = format!("{:?}", cmd);
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {}: {}", description, e))?;
+
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("could not write to {}: {}", description, e))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("could not run {}: {}", description, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed with stderr: {}",
+            description,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+/// The archiver of the current `TARGET`, as configured for cc (`AR_<target>`, `AR`, ...)
+fn archiver() -> Result<Command, String> {
+    cc::Build::new()
+        .cargo_metadata(false)
+        .try_get_archiver()
+        .map_err(|e| format!("could not find the archiver: {}", e))
+}
+
+/// `strip` matching the archiver, e.g. `aarch64-linux-gnu-strip` for `aarch64-linux-gnu-ar`
+///
+/// `gcc-ar` (optionally versioned, as `gcc-ar-13`) is a wrapper of GCC without a `gcc-strip`
+/// counterpart, so it maps to the `strip` of its prefix.
+fn strip_tool(archiver: &Command) -> Command {
+    let program = archiver.get_program().to_string_lossy().into_owned();
+    let unversioned = match program.rsplit_once('-') {
+        Some((name, version))
+            if name.ends_with("gcc-ar") && version.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            name
+        }
+        _ => program.as_str(),
+    };
+
+    let strip = match unversioned
+        .strip_suffix("gcc-ar")
+        .or_else(|| unversioned.strip_suffix("ar"))
+    {
+        Some(prefix) => format!("{}strip", prefix),
+        None => "strip".to_string(),
+    };
+    Command::new(strip)
+}
+
+/// `ar -M` script merging the inputs into the output archive
+///
+/// MRI scripts add the members of whole archives, unlike `ar rcs`.
+fn mri_script(output_name: &str, inputs: &[String]) -> String {
+    let mut script = format!("CREATE {}\n", output_name);
+    for input in inputs {
+        script += &format!("ADDLIB {}\n", input);
+    }
+    script += "SAVE\nEND\n";
+    script
+}
+
+/// Merges the libraries of `dir` named in `libraries` into the combined archive, returning
+/// its path
+///
+/// With `strip_debug`, debug information is stripped from the combined archive with
+/// `strip -S`. This is an error with MSVC, where debug information isn't part of the archives.
+pub fn combine_archives(
+    dir: &Path,
+    target: &str,
+    libraries: &[String],
+    strip_debug: bool,
+) -> Result<PathBuf, String> {
+    let triple = Triple::parse(target)?;
+    if strip_debug && triple.is_windows_msvc() {
+        return Err("stripping debug information is not supported with MSVC".to_string());
+    }
+
+    let output_name = archive_name(COMBINED_LIBRARY, target);
+    let output = dir.join(&output_name);
+    if output.exists() {
+        std::fs::remove_file(&output)
+            .map_err(|e| format!("could not remove {}: {}", output.display(), e))?;
+    }
+
+    let inputs: Vec<String> = libraries
+        .iter()
+        .map(|name| archive_name(name, target))
+        .collect();
+
+    if triple.is_windows_msvc() {
+        let mut lib = cc::windows_registry::find(target, "lib.exe")
+            .ok_or_else(|| "could not find lib.exe".to_string())?;
+        lib.current_dir(dir)
+            .arg("/NOLOGO")
+            .arg(format!("/OUT:{}", output_name))
+            .args(&inputs);
+        run(&mut lib, None)?;
+        return Ok(output);
+    }
+
+    if triple.is_apple() {
+        run(
+            Command::new("libtool")
+                .current_dir(dir)
+                .args(["-static", "-o", &output_name])
+                .args(&inputs),
+            None,
+        )?;
+    } else {
+        let mut ar = archiver()?;
+        ar.current_dir(dir).arg("-M");
+        run(&mut ar, Some(&mri_script(&output_name, &inputs)))?;
+    }
+
+    if strip_debug {
+        let mut strip = if triple.is_apple() {
+            Command::new("strip")
+        } else {
+            strip_tool(&archiver()?)
+        };
+        strip.current_dir(dir).arg("-S").arg(&output_name);
+        run(&mut strip, None)?;
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mri_script() {
+        let target = "x86_64-unknown-linux-gnu";
+        let inputs: Vec<String> = ["Luau.LuteExt", "Luau.VM", "uv_a"]
+            .iter()
+            .map(|name| archive_name(name, target))
+            .collect();
+
+        assert_eq!(
+            mri_script(&archive_name(COMBINED_LIBRARY, target), &inputs),
+            "CREATE liblute_all.a
+ADDLIB libLuau.LuteExt.a
+ADDLIB libLuau.VM.a
+ADDLIB libuv_a.a
+SAVE
+END
+"
+        );
+    }
+
+    #[test]
+    fn test_strip_tool() {
+        let program = |archiver: &str| {
+            strip_tool(&Command::new(archiver))
+                .get_program()
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        assert_eq!(program("ar"), "strip");
+        assert_eq!(program("aarch64-linux-gnu-ar"), "aarch64-linux-gnu-strip");
+        assert_eq!(program("llvm-ar"), "llvm-strip");
+        assert_eq!(program("lib.exe"), "strip");
+        assert_eq!(program("gcc-ar"), "strip");
+        assert_eq!(program("gcc-ar-13"), "strip");
+        assert_eq!(
+            program("/usr/bin/aarch64-linux-gnu-gcc-ar-12"),
+            "/usr/bin/aarch64-linux-gnu-strip"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Dependency graph of the CMake targets, used to order prebuilt libraries for linking
+//!
+//! The graph is read from the Graphviz output of `cmake --graphviz`, where every target is a
+//! node labelled with its name and every edge goes from a target to one of its dependencies.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug, Default, Clone)]
+pub struct DependencyGraph {
+    /// Direct dependencies of each target
+    dependencies: HashMap<String, Vec<String>>,
+}
+
+/// Returns the first double-quoted string of `s` and the rest after it
+fn quoted(s: &str) -> Option<(&str, &str)> {
+    let start = s.find('"')? + 1;
+    let end = start + s[start..].find('"')?;
+    Some((&s[start..end], &s[end + 1..]))
+}
+
+impl DependencyGraph {
+    /// Parses the main file written by `cmake --graphviz`
+    pub fn parse_graphviz(dot: &str) -> Self {
+        let mut labels = HashMap::new();
+        let mut edges = Vec::new();
+
+        for line in dot.lines().map(str::trim) {
+            let Some((from, rest)) = quoted(line) else {
+                continue;
+            };
+
+            if let Some(rest) = rest.trim_start().strip_prefix("->") {
+                if let Some((to, _)) = quoted(rest) {
+                    edges.push((from.to_string(), to.to_string()));
+                }
+            } else if let Some(label) = rest.find("label").and_then(|i| quoted(&rest[i..])) {
+                labels.insert(from.to_string(), label.0.to_string());
+            }
+        }
+
+        let mut graph = DependencyGraph::default();
+        for (from, to) in edges {
+            let (Some(from), Some(to)) = (labels.get(&from), labels.get(&to)) else {
+                continue;
+            };
+            graph
+                .dependencies
+                .entry(from.clone())
+                .or_default()
+                .push(to.clone());
+        }
+        for label in labels.into_values() {
+            graph.dependencies.entry(label).or_default();
+        }
+
+        graph
+    }
+
+    /// Writes the graph of the configured CMake build directory and parses it
+    pub fn from_build_dir(build_dir: &Path) -> Result<Self, String> {
+        let dot = build_dir.join("lute-deps.dot");
+        let output = Command::new("cmake")
+            .arg(format!("--graphviz={}", dot.display()))
+            .arg(build_dir)
+            .output()
+            .map_err(|e| format!("could not run cmake: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "cmake --graphviz failed with stderr: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let dot = std::fs::read_to_string(&dot)
+            .map_err(|e| format!("could not read {}: {}", dot.display(), e))?;
+        Ok(Self::parse_graphviz(&dot))
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        self.dependencies.contains_key(target)
+    }
+
+    /// Targets `target` depends on, directly or through other targets
+    pub fn transitive_dependencies(&self, target: &str) -> HashSet<&str> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![target];
+
+        while let Some(target) = stack.pop() {
+            for dependency in self.dependencies.get(target).into_iter().flatten() {
+                if seen.insert(dependency.as_str()) {
+                    stack.push(dependency);
+                }
+            }
+        }
+
+        seen
+    }
+
+    /// Orders the libraries so that every library comes before the ones it depends on
+    ///
+    /// Libraries that are independent of each other (or not part of the graph) keep the order
+    /// of `fallback`. Fails if the libraries depend on each other in a cycle.
+    pub fn link_order(&self, fallback: Vec<String>) -> Result<Vec<String>, String> {
+        let rank: HashMap<&str, usize> = fallback
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+            .collect();
+
+        // Number of libraries that must come before each library
+        let mut before: HashMap<&str, usize> =
+            fallback.iter().map(|name| (name.as_str(), 0)).collect();
+        let mut after: HashMap<&str, Vec<&str>> = HashMap::new();
+        for name in &fallback {
+            for dependency in self.transitive_dependencies(name) {
+                if dependency != name.as_str() && rank.contains_key(dependency) {
+                    *before.get_mut(dependency).unwrap() += 1;
+                    after.entry(name.as_str()).or_default().push(dependency);
+                }
+            }
+        }
+
+        let mut ready: BTreeSet<(usize, &str)> = before
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(name, _)| (rank[name], *name))
+            .collect();
+
+        let mut order = Vec::with_capacity(fallback.len());
+        while let Some((_, name)) = ready.pop_first() {
+            order.push(name.to_string());
+            for dependency in after.get(name).into_iter().flatten() {
+                let count = before.get_mut(dependency).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert((rank[dependency], dependency));
+                }
+            }
+        }
+
+        if order.len() != fallback.len() {
+            let mut cycle: Vec<&str> = before
+                .iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(name, _)| *name)
+                .collect();
+            cycle.sort_unstable();
+            return Err(format!(
+                "libraries depend on each other in a cycle: {}",
+                cycle.join(", ")
+            ));
+        }
+
+        Ok(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed output of `cmake --graphviz`, with an external library and a comment per edge
+    const SAMPLE: &str = r#"digraph "Lute" {
+node [
+  fontsize = "12"
+];
+    "node0" [ label = "Lute.Fs", shape = octagon ];
+    "node1" [ label = "Lute.Runtime", shape = octagon ];
+    "node0" -> "node1" [ style = dotted ] // Lute.Fs -> Lute.Runtime
+    "node2" [ label = "Luau.VM", shape = octagon ];
+    "node1" -> "node2" [ style = dotted ] // Lute.Runtime -> Luau.VM
+    "node3" [ label = "uv_a", shape = octagon ];
+    "node1" -> "node3" [ style = dotted ] // Lute.Runtime -> uv_a
+    "node4" [ label = "Luau.Common", shape = octagon ];
+    "node2" -> "node4" [ style = dotted ] // Luau.VM -> Luau.Common
+    "node5" [ label = "Luau.Compiler", shape = octagon ];
+    "node6" [ label = "Luau.Ast", shape = octagon ];
+    "node5" -> "node6" [ style = dotted ] // Luau.Compiler -> Luau.Ast
+    "node6" -> "node4" [ style = dotted ] // Luau.Ast -> Luau.Common
+    "node7" [ label = "pthread", shape = septagon ];
+    "node1" -> "node7" [ style = dotted ] // Lute.Runtime -> pthread
+}
+"#;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_graphviz() {
+        let graph = DependencyGraph::parse_graphviz(SAMPLE);
+        assert!(graph.contains("Lute.Fs") && graph.contains("uv_a"));
+        assert!(!graph.contains("node0") && !graph.contains("Lute"));
+
+        let dependencies = graph.transitive_dependencies("Lute.Fs");
+        assert_eq!(
+            dependencies,
+            HashSet::from(["Lute.Runtime", "Luau.VM", "Luau.Common", "uv_a", "pthread"])
+        );
+    }
+
+    #[test]
+    fn test_dependents_before_dependencies() {
+        let graph = DependencyGraph::parse_graphviz(SAMPLE);
+
+        // Reversed, so every dependency has to move before its dependents
+        let order = graph
+            .link_order(names(&[
+                "uv_a",
+                "Luau.Common",
+                "Luau.Ast",
+                "Luau.Compiler",
+                "Luau.VM",
+                "Lute.Runtime",
+                "Lute.Fs",
+            ]))
+            .unwrap();
+        assert_eq!(
+            order,
+            names(&[
+                "Luau.Compiler",
+                "Luau.Ast",
+                "Lute.Fs",
+                "Lute.Runtime",
+                "uv_a",
+                "Luau.VM",
+                "Luau.Common",
+            ])
+        );
+
+        let position = |name: &str| order.iter().position(|n| n == name).unwrap();
+        for name in &order {
+            for dependency in graph.transitive_dependencies(name) {
+                if order.iter().any(|n| n == dependency) {
+                    assert!(
+                        position(name) < position(dependency),
+                        "{} -> {}",
+                        name,
+                        dependency
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallback_order_outside_graph() {
+        let graph = DependencyGraph::parse_graphviz(SAMPLE);
+
+        // Libraries the graph doesn't know keep their place in the fallback order
+        let order = graph
+            .link_order(names(&[
+                "Luau.LuteExt",
+                "Luau.VM",
+                "Lute.Runtime",
+                "curl",
+                "uv_a",
+            ]))
+            .unwrap();
+        assert_eq!(
+            order,
+            names(&["Luau.LuteExt", "Lute.Runtime", "Luau.VM", "curl", "uv_a"])
+        );
+
+        let order = DependencyGraph::default()
+            .link_order(names(&["b", "a", "c"]))
+            .unwrap();
+        assert_eq!(order, names(&["b", "a", "c"]));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let graph = DependencyGraph::parse_graphviz(
+            r#"digraph "Cycle" {
+    "node0" [ label = "A" ];
+    "node1" [ label = "B" ];
+    "node2" [ label = "C" ];
+    "node0" -> "node1"
+    "node1" -> "node0"
+    "node2" -> "node0"
+}"#,
+        );
+
+        let error = graph.link_order(names(&["C", "B", "A"])).unwrap_err();
+        assert!(error.ends_with("cycle: A, B"), "{}", error);
+    }
+}
@@ -6,6 +6,17 @@ use lute_src_rs_common::{
 pub use lute_src_rs_common::LConfig;
 use std::env::current_dir;
 
+pub mod buildenv;
+pub mod chunked;
+pub mod combine;
+pub mod graph;
+pub mod manifest;
+pub mod prebuilt;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod testutil;
+pub mod triple;
+
 fn does_lute_exist(cmd: &str) -> bool {
     let Ok(cmd) = std::process::Command::new(cmd)
     .arg("run")
@@ -18,6 +29,12 @@ fn does_lute_exist(cmd: &str) -> bool {
 }
 
 pub fn build_lute(lcfg: LConfig) {
+    // Link prebuilt libraries instead if LUTE_PREBUILT_DIR points to a matching set. This must
+    // happen before switching directory so relative paths are resolved against the caller
+    if prebuilt::try_link_prebuilt(lcfg) {
+        return;
+    }
+
     // Switch directory to CARGO_MANIFEST_DIR
     std::env::set_current_dir(env!("CARGO_MANIFEST_DIR")).unwrap();
     // This is needed to run the luthier.py script
@@ -99,7 +116,37 @@ pub fn build_lute(lcfg: LConfig) {
     build_cc_lute_lib(
         lcfg,
         "Luau.LuteExt",
-        vec!["LuteExt/src/lopen.cpp".to_string()],
+        vec![
+            "LuteExt/src/lbudget.cpp".to_string(),
+            "LuteExt/src/lfs.cpp".to_string(),
+            "LuteExt/src/llog.cpp".to_string(),
+            "LuteExt/src/lopen.cpp".to_string(),
+            "LuteExt/src/lpolicy.cpp".to_string(),
+            "LuteExt/src/lprocess.cpp".to_string(),
+            "LuteExt/src/lprofiler.cpp".to_string(),
+            "LuteExt/src/lrequire.cpp".to_string(),
+            "LuteExt/src/lsandbox.cpp".to_string(),
+            "LuteExt/src/ltrace.cpp".to_string(),
+        ],
+        false, // Not a prebuilt
+    );
+
+    // Native code generation is opt-in as it is not supported on all targets
+    // and pulls in Luau.CodeGen
+    #[cfg(feature = "codegen")]
+    build_cc_lute_lib(
+        lcfg,
+        "Luau.LuteExt.CodeGen",
+        vec!["LuteExt/src/lcodegen.cpp".to_string()],
+        false, // Not a prebuilt
+    );
+
+    // The debugger hooks are opt-in as well, so release builds don't ship them
+    #[cfg(feature = "debugger")]
+    build_cc_lute_lib(
+        lcfg,
+        "Luau.LuteExt.Debug",
+        vec!["LuteExt/src/ldebug.cpp".to_string()],
         false, // Not a prebuilt
     );
 
@@ -266,5 +313,11 @@ pub fn build_lute(lcfg: LConfig) {
         );
     }
 
+    // Luau.CodeGen is built as part of Lute but only linked in when codegen is enabled
+    //
+    // This must come before finalize_build so it is placed before Luau.VM in the link order
+    #[cfg(feature = "codegen")]
+    println!("cargo:rustc-link-lib=static=Luau.CodeGen");
+
     finalize_build(lcfg, false);
 }
@@ -0,0 +1,546 @@
+//! Manifest describing a directory of prebuilt static libraries
+//!
+//! make_prebuilt writes `manifest.json` into the `staticlibs` directory of every target. It
+//! records what the libraries were built from and with, a SHA-256 of every library and how they
+//! must be linked. `build_lute` verifies it before linking prebuilt libraries.
+
+use crate::combine::COMBINED_LIBRARY;
+use crate::graph::DependencyGraph;
+use crate::triple::Triple;
+use crate::LConfig;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::process::Command;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Version of the manifest format, bumped on incompatible changes
+pub const MANIFEST_FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub format: u32,
+    /// Target triple the libraries were built for
+    pub target: String,
+    pub sources: Sources,
+    pub config: BuildConfig,
+    pub toolchain: Toolchain,
+    /// Every library of the directory
+    pub files: Vec<FileEntry>,
+    /// Library names (without `lib` prefix and extension) in the order they must be linked
+    pub link_order: Vec<String>,
+    /// Name of the archive combining every library of `link_order`, if there is one
+    #[serde(default)]
+    pub combined: Option<String>,
+    /// Libraries of the system to link, as `cargo:rustc-link-lib` values (e.g. `dylib=stdc++`)
+    pub system_libraries: Vec<String>,
+}
+
+/// Revisions of the sources, with a `-dirty` suffix if they had uncommitted changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sources {
+    pub lute_src_rs: Option<String>,
+    pub lute: Option<String>,
+}
+
+/// The `LConfig` the libraries were built with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildConfig {
+    pub disable_crypto: bool,
+    pub disable_net: bool,
+    /// Whether the native code generation libraries are included
+    pub codegen: bool,
+}
+
+impl BuildConfig {
+    pub fn new(lcfg: LConfig, codegen: bool) -> Self {
+        BuildConfig {
+            disable_crypto: lcfg.disable_crypto,
+            disable_net: lcfg.disable_net,
+            codegen,
+        }
+    }
+
+    /// Whether libraries built with this config can be linked by a build wanting `wanted`
+    ///
+    /// The codegen libraries are only linked if wanted, so extra ones are fine.
+    pub fn satisfies(&self, wanted: &BuildConfig) -> bool {
+        self.disable_crypto == wanted.disable_crypto
+            && self.disable_net == wanted.disable_net
+            && (self.codegen || !wanted.codegen)
+    }
+
+    /// Stable name of the directory of prebuilts built with this config
+    ///
+    /// Only depends on the settings that change which libraries are built, so codegen isn't
+    /// part of it. Changing the fingerprint of existing configs breaks published prebuilts.
+    pub fn fingerprint(&self) -> String {
+        let canonical = format!(
+            "lute-prebuilt-config-v1\ndisable_crypto={}\ndisable_net={}\n",
+            self.disable_crypto, self.disable_net
+        );
+        let digest = Sha256::digest(canonical.as_bytes());
+        format!("cfg-{}", &hex(&digest)[..16])
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Toolchain {
+    pub rustc: Option<String>,
+    /// First line of the C++ compiler's version output
+    pub cxx: Option<String>,
+    pub cmake: Option<String>,
+    pub opt_level: Option<String>,
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
+}
+
+impl Toolchain {
+    /// Collects the versions of the tools used for the current `TARGET`
+    pub fn detect() -> Self {
+        let cxx = cc::Build::new()
+            .cpp(true)
+            .cargo_metadata(false)
+            .try_get_compiler()
+            .ok()
+            .and_then(|compiler| {
+                let mut cmd = compiler.to_command();
+                // cl.exe prints its version when run without arguments
+                if !compiler.is_like_msvc() {
+                    cmd.arg("--version");
+                }
+                first_line(&mut cmd)
+            });
+
+        Toolchain {
+            rustc: first_line(
+                Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+                    .arg("--version"),
+            ),
+            cxx,
+            cmake: first_line(Command::new("cmake").arg("--version")),
+            opt_level: std::env::var("OPT_LEVEL").ok(),
+            cflags: std::env::var("CFLAGS").ok(),
+            cxxflags: std::env::var("CXXFLAGS").ok(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// File name in the directory
+    pub name: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Runs the command, returning the first non-empty line it printed
+fn first_line(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// Revision of the git checkout at `dir`, if it is one
+pub fn git_revision(dir: &Path) -> Option<String> {
+    let revision = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let revision = String::from_utf8_lossy(&revision.stdout).trim().to_string();
+
+    let dirty = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain"])
+        .output()
+        .ok()
+        .is_some_and(|output| !output.stdout.is_empty());
+
+    Some(if dirty {
+        format!("{}-dirty", revision)
+    } else {
+        revision
+    })
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(hex(&hasher.finalize()))
+}
+
+fn is_windows_msvc(target: &str) -> bool {
+    Triple::parse(target).is_ok_and(|triple| triple.is_windows_msvc())
+}
+
+/// File name of a static library for the target
+pub fn archive_name(name: &str, target: &str) -> String {
+    if is_windows_msvc(target) {
+        format!("{}.lib", name)
+    } else {
+        format!("lib{}.a", name)
+    }
+}
+
+/// Library name of a static library file, or `None` if it isn't one for the target
+pub fn archive_stem(file_name: &str, target: &str) -> Option<String> {
+    if is_windows_msvc(target) {
+        file_name.strip_suffix(".lib").map(str::to_string)
+    } else {
+        file_name
+            .strip_prefix("lib")
+            .and_then(|name| name.strip_suffix(".a"))
+            .map(str::to_string)
+    }
+}
+
+/// Libraries in the order they must be linked: dependents first, third-party libraries last
+const KNOWN_LINK_ORDER: &[&str] = &[
+    "Luau.LuteExt.CodeGen",
+    "Luau.LuteExt.Debug",
+    "Luau.LuteExt",
+    "Luau.Custom",
+    "Lute.Runtime",
+    "Lute.VM",
+    "Lute.Std",
+    "Lute.Crypto",
+    "Lute.Net",
+    "Lute.Fs",
+    "Lute.Luau",
+    "Lute.Process",
+    "Lute.Require",
+    "Lute.System",
+    "Lute.Task",
+    "Lute.Time",
+    "Luau.Analysis",
+    "Luau.Require",
+    "Luau.Config",
+    "Luau.CodeGen",
+    "Luau.Compiler",
+    "Luau.Ast",
+    "Luau.VM",
+    "Luau.Common",
+    "curl",
+    "libcurl",
+    "ssl",
+    "crypto",
+    "uv_a",
+    "uv",
+    "libuv",
+    "z",
+    "zlibstatic",
+    "zlib",
+];
+
+/// Sorts library names into a default link order, for libraries whose dependencies aren't
+/// known. Unknown libraries go right before the third-party ones
+pub fn link_order(mut names: Vec<String>) -> Vec<String> {
+    let third_party = KNOWN_LINK_ORDER
+        .iter()
+        .position(|name| *name == "curl")
+        .unwrap_or(KNOWN_LINK_ORDER.len());
+
+    let rank = |name: &str| {
+        KNOWN_LINK_ORDER
+            .iter()
+            .position(|known| *known == name)
+            .map_or((third_party, 1), |index| (index, 0))
+    };
+
+    names.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.cmp(b)));
+    names
+}
+
+/// Libraries of the system the prebuilt libraries depend on
+pub fn system_libraries(target: &str) -> Vec<String> {
+    let triple = Triple::parse(target).ok();
+
+    let libraries: &[&str] = if triple.as_ref().is_some_and(|triple| triple.os == "windows") {
+        &[
+            "dylib=ws2_32",
+            "dylib=userenv",
+            "dylib=iphlpapi",
+            "dylib=dbghelp",
+            "dylib=psapi",
+            "dylib=advapi32",
+            "dylib=user32",
+            "dylib=shell32",
+            "dylib=ole32",
+            "dylib=crypt32",
+            "dylib=bcrypt",
+        ]
+    } else if triple.as_ref().is_some_and(Triple::is_apple) {
+        &[
+            "dylib=c++",
+            "framework=CoreFoundation",
+            "framework=CoreServices",
+            "framework=Security",
+        ]
+    } else {
+        &[
+            "dylib=stdc++",
+            "dylib=pthread",
+            "dylib=dl",
+            "dylib=m",
+            "dylib=rt",
+        ]
+    };
+
+    libraries
+        .iter()
+        .map(|library| library.to_string())
+        .collect()
+}
+
+impl Manifest {
+    /// Creates the manifest of the libraries in `dir`
+    ///
+    /// The libraries are ordered by the dependencies of `graph`, falling back to the default
+    /// order for libraries that aren't CMake targets.
+    pub fn generate(
+        dir: &Path,
+        target: &str,
+        sources: Sources,
+        config: BuildConfig,
+        toolchain: Toolchain,
+        graph: &DependencyGraph,
+    ) -> std::io::Result<Self> {
+        let mut files = Vec::new();
+        let mut names = Vec::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(stem) = archive_stem(&file_name, target) else {
+                continue;
+            };
+            if stem == COMBINED_LIBRARY {
+                continue;
+            }
+
+            files.push(FileEntry {
+                size: entry.metadata()?.len(),
+                sha256: sha256_file(&entry.path())?,
+                name: file_name,
+            });
+            names.push(stem);
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let link_order = graph
+            .link_order(link_order(names))
+            .map_err(std::io::Error::other)?;
+
+        Ok(Manifest {
+            format: MANIFEST_FORMAT,
+            target: target.to_string(),
+            sources,
+            config,
+            toolchain,
+            files,
+            link_order,
+            combined: None,
+            system_libraries: system_libraries(target),
+        })
+    }
+
+    /// Adds the combined archive in `dir` (see `combine`), which consumers link instead of the
+    /// individual libraries
+    pub fn add_combined(&mut self, dir: &Path) -> std::io::Result<()> {
+        let name = archive_name(COMBINED_LIBRARY, &self.target);
+        let path = dir.join(&name);
+
+        self.files.retain(|file| file.name != name);
+        self.files.push(FileEntry {
+            size: std::fs::metadata(&path)?.len(),
+            sha256: sha256_file(&path)?,
+            name,
+        });
+        self.files.sort_by(|a, b| a.name.cmp(&b.name));
+        self.combined = Some(COMBINED_LIBRARY.to_string());
+        Ok(())
+    }
+
+    pub fn read(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(MANIFEST_FILE);
+        let data = std::fs::read(&path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&data).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(dir.join(MANIFEST_FILE), data)
+    }
+
+    /// Checks that the libraries are the ones described and were built for the target and a
+    /// config satisfying `wanted`
+    ///
+    /// Each library is looked up in the first of `dirs` containing it.
+    pub fn verify(&self, dirs: &[&Path], target: &str, wanted: &BuildConfig) -> Result<(), String> {
+        if self.format != MANIFEST_FORMAT {
+            return Err(format!(
+                "unsupported manifest format {} (expected {})",
+                self.format, MANIFEST_FORMAT
+            ));
+        }
+        if self.target != target {
+            return Err(format!(
+                "libraries were built for {}, not {}",
+                self.target, target
+            ));
+        }
+        if !self.config.satisfies(wanted) {
+            return Err(format!(
+                "libraries were built with {:?}, which doesn't match {:?}",
+                self.config, wanted
+            ));
+        }
+
+        for file in &self.files {
+            let path = dirs
+                .iter()
+                .map(|dir| dir.join(&file.name))
+                .find(|path| path.is_file())
+                .ok_or_else(|| format!("{} is missing", file.name))?;
+            let sha256 = sha256_file(&path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            if sha256 != file.sha256 {
+                return Err(format!("checksum mismatch for {}", path.display()));
+            }
+        }
+
+        for name in self.link_order.iter().chain(&self.combined) {
+            let file_name = archive_name(name, target);
+            if !self.files.iter().any(|file| file.name == file_name) {
+                return Err(format!("{} is linked but not listed", file_name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_manifest, temp_dir};
+
+    const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+    fn config(disable_net: bool, codegen: bool) -> BuildConfig {
+        BuildConfig {
+            disable_crypto: false,
+            disable_net,
+            codegen,
+        }
+    }
+
+    fn assert_rejected(manifest: &Manifest, dir: &Path, wanted: &BuildConfig, expected: &str) {
+        let error = manifest.verify(&[dir], TARGET, wanted).unwrap_err();
+        assert!(error.contains(expected), "{}", error);
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable() {
+        // Published prebuilts are stored under these names, so they must never change
+        let expected = [
+            (false, false, "cfg-630db0dd296ffaad"),
+            (false, true, "cfg-a3536d2d4f3e5759"),
+            (true, false, "cfg-39ac0bea7a5c5ef3"),
+            (true, true, "cfg-80850eabbee44ade"),
+        ];
+
+        for (disable_crypto, disable_net, fingerprint) in expected {
+            for codegen in [false, true] {
+                let config = BuildConfig {
+                    disable_crypto,
+                    disable_net,
+                    codegen,
+                };
+                assert_eq!(config.fingerprint(), fingerprint, "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = temp_dir("verify");
+        let manifest = fake_manifest(&dir, TARGET, config(false, true), &["Luau.VM", "Luau.Ast"]);
+        manifest.write(&dir).unwrap();
+        assert_eq!(Manifest::read(&dir).unwrap(), manifest);
+
+        // Libraries built with codegen can be linked without it
+        manifest
+            .verify(&[&dir], TARGET, &config(false, true))
+            .unwrap();
+        manifest
+            .verify(&[&dir], TARGET, &config(false, false))
+            .unwrap();
+
+        let mut wrong_format = manifest.clone();
+        wrong_format.format = MANIFEST_FORMAT + 1;
+        assert_rejected(
+            &wrong_format,
+            &dir,
+            &config(false, false),
+            "unsupported manifest format",
+        );
+
+        let error = manifest
+            .verify(&[&dir], "aarch64-unknown-linux-gnu", &config(false, false))
+            .unwrap_err();
+        assert!(
+            error.contains("built for x86_64-unknown-linux-gnu"),
+            "{}",
+            error
+        );
+
+        assert_rejected(&manifest, &dir, &config(true, false), "doesn't match");
+        let mut without_codegen = manifest.clone();
+        without_codegen.config = config(false, false);
+        assert_rejected(
+            &without_codegen,
+            &dir,
+            &config(false, true),
+            "doesn't match",
+        );
+
+        let mut unlisted = manifest.clone();
+        unlisted.link_order.push("Luau.Compiler".to_string());
+        assert_rejected(
+            &unlisted,
+            &dir,
+            &config(false, false),
+            "libLuau.Compiler.a is linked but not listed",
+        );
+
+        std::fs::write(
+            dir.join(archive_name("Luau.VM", TARGET)),
+            b"!<arch>\nmodified",
+        )
+        .unwrap();
+        assert_rejected(&manifest, &dir, &config(false, false), "checksum mismatch");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+//! Linking Lute from a local directory of prebuilt static libraries
+//!
+//! When `LUTE_PREBUILT_DIR` is set, `build_lute` looks for the libraries of the target and
+//! `LConfig` in `$LUTE_PREBUILT_DIR/<target>/<fingerprint>/build/staticlibs` (the layout written
+//! by make_prebuilt) and links them instead of building Lute. Libraries stored as parts are
+//! reassembled into `OUT_DIR` (see `chunked`), and every library is checked against the
+//! directory's manifest (see `manifest`) first. If they don't match the target or the
+//! `LConfig`, a warning is printed and Lute is built from source.
+
+use crate::chunked;
+use crate::manifest::{BuildConfig, Manifest};
+use crate::LConfig;
+use std::path::{Path, PathBuf};
+
+pub const PREBUILT_DIR_VAR: &str = "LUTE_PREBUILT_DIR";
+
+/// Libraries that must be present in the prebuilt directory, each as a list of accepted names
+fn required_libraries(lcfg: LConfig) -> Vec<&'static [&'static str]> {
+    let mut libraries: Vec<&'static [&'static str]> = vec![
+        &["Luau.Custom"],
+        &["Luau.LuteExt"],
+        &["Luau.VM"],
+        &["Luau.Compiler"],
+        &["Luau.Ast"],
+        &["Lute.Runtime"],
+        &["Lute.Fs"],
+        &["Lute.Luau"],
+        &["Lute.Process"],
+        &["Lute.Require"],
+        &["Lute.Std"],
+        &["Lute.System"],
+        &["Lute.Task"],
+        &["Lute.Time"],
+        &["Lute.VM"],
+        &["uv_a", "uv", "libuv"],
+    ];
+
+    if !lcfg.disable_crypto {
+        libraries.push(&["Lute.Crypto"]);
+    }
+    if !lcfg.disable_net {
+        libraries.push(&["Lute.Net"]);
+        libraries.push(&["curl", "libcurl"]);
+        libraries.push(&["z", "zlibstatic", "zlib"]);
+    }
+    if !lcfg.disable_net || !lcfg.disable_crypto {
+        libraries.push(&["ssl"]);
+        libraries.push(&["crypto"]);
+    }
+
+    #[cfg(feature = "codegen")]
+    {
+        libraries.push(&["Luau.CodeGen"]);
+        libraries.push(&["Luau.LuteExt.CodeGen"]);
+    }
+
+    #[cfg(feature = "debugger")]
+    libraries.push(&["Luau.LuteExt.Debug"]);
+
+    libraries
+}
+
+/// Directory of the target's libraries for the config under the prebuilt root, along with the
+/// target name used by the directory
+///
+/// Libraries of each config are in `<target>/<fingerprint>/build/staticlibs` (see
+/// `BuildConfig::fingerprint`). Directories without variants (`<target>/build/staticlibs`) are
+/// used as a fallback, their manifest tells whether they match. make_prebuilt used to name the
+/// macOS target `aarch64-apple-macos`, so that name is accepted for `*-apple-darwin` too.
+fn target_dir(
+    root: &Path,
+    target: &str,
+    config: &BuildConfig,
+) -> Result<(PathBuf, String), String> {
+    let mut candidates = vec![target.to_string()];
+    if let Some(arch) = target.strip_suffix("-apple-darwin") {
+        candidates.push(format!("{}-apple-macos", arch));
+    }
+
+    let fingerprint = config.fingerprint();
+    candidates
+        .iter()
+        .flat_map(|target| {
+            [root.join(target).join(&fingerprint), root.join(target)]
+                .map(|dir| (dir.join("build").join("staticlibs"), target.clone()))
+        })
+        .find(|(dir, _)| dir.is_dir())
+        .ok_or_else(|| {
+            format!(
+                "no prebuilt libraries for target {} and variant {} in {}",
+                target,
+                fingerprint,
+                root.display()
+            )
+        })
+}
+
+/// Prebuilt libraries matching the target and `LConfig`
+#[derive(Debug, Clone)]
+pub struct PrebuiltLibraries {
+    /// Directory of the libraries under the prebuilt root
+    pub dir: PathBuf,
+    /// Directory the libraries stored as parts were reassembled into
+    pub reassembled_dir: PathBuf,
+    pub manifest: Manifest,
+}
+
+/// Checks the prebuilt libraries for the target against their manifest and the `LConfig`,
+/// reassembling the ones stored as parts into `out_dir`
+pub fn validate_prebuilt(
+    lcfg: LConfig,
+    root: &Path,
+    target: &str,
+    out_dir: &Path,
+) -> Result<PrebuiltLibraries, String> {
+    let config = BuildConfig::new(lcfg, cfg!(feature = "codegen"));
+    let (dir, dir_target) = target_dir(root, target, &config)?;
+    let reassembled_dir = out_dir.join("lute-prebuilt");
+    chunked::reassemble_dir(&dir, &reassembled_dir)?;
+    let manifest = Manifest::read(&dir)?;
+    manifest.verify(&[&reassembled_dir, &dir], &dir_target, &config)?;
+
+    let missing: Vec<&str> = required_libraries(lcfg)
+        .into_iter()
+        .filter(|names| {
+            !names
+                .iter()
+                .any(|name| manifest.link_order.iter().any(|linked| linked == name))
+        })
+        .map(|names| names[0])
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "prebuilt libraries in {} don't match the configuration, missing: {}",
+            dir.display(),
+            missing.join(", ")
+        ));
+    }
+
+    Ok(PrebuiltLibraries {
+        dir,
+        reassembled_dir,
+        manifest,
+    })
+}
+
+/// Whether a library is only needed for native code generation
+fn is_codegen_library(name: &str) -> bool {
+    name == "Luau.CodeGen" || name == "Luau.LuteExt.CodeGen"
+}
+
+/// Whether a library is needed with the enabled features
+fn is_wanted_library(name: &str) -> bool {
+    if is_codegen_library(name) {
+        return cfg!(feature = "codegen");
+    }
+    name != "Luau.LuteExt.Debug" || cfg!(feature = "debugger")
+}
+
+/// Libraries to link, in order
+///
+/// The combined archive merges every library of the link order, so it is only linked if the
+/// link order has no libraries of disabled features (e.g. prebuilts built with the codegen
+/// libraries, linked without the `codegen` feature). The libraries are linked on their own
+/// otherwise.
+fn linked_libraries(manifest: &Manifest) -> Vec<String> {
+    let all_wanted = manifest
+        .link_order
+        .iter()
+        .all(|name| is_wanted_library(name));
+    match &manifest.combined {
+        Some(combined) if all_wanted => vec![combined.clone()],
+        _ => manifest
+            .link_order
+            .iter()
+            .filter(|name| is_wanted_library(name))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Links the prebuilt libraries in `root` for the target, in the order recorded by their
+/// manifest, or only the combined archive if the manifest has one (see `linked_libraries`)
+///
+/// Libraries stored as parts are reassembled into `out_dir`.
+pub fn link_prebuilt(
+    lcfg: LConfig,
+    root: &Path,
+    target: &str,
+    out_dir: &Path,
+) -> Result<(), String> {
+    let PrebuiltLibraries {
+        dir,
+        reassembled_dir,
+        manifest,
+    } = validate_prebuilt(lcfg, root, target, out_dir)?;
+
+    println!("cargo:rerun-if-changed={}", dir.display());
+    // Reassembled libraries first, the prebuilt directory only has their parts
+    println!(
+        "cargo:rustc-link-search=native={}",
+        reassembled_dir.display()
+    );
+    println!("cargo:rustc-link-search=native={}", dir.display());
+
+    for name in linked_libraries(&manifest) {
+        println!("cargo:rustc-link-lib=static={}", name);
+    }
+    for library in &manifest.system_libraries {
+        println!("cargo:rustc-link-lib={}", library);
+    }
+
+    Ok(())
+}
+
+/// Links the libraries of `LUTE_PREBUILT_DIR` if it is set and matches the configuration
+///
+/// Returns false if Lute must be built from source. Relative paths are resolved against the
+/// current directory, which must be the manifest directory of the crate running the build
+/// script.
+pub fn try_link_prebuilt(lcfg: LConfig) -> bool {
+    println!("cargo:rerun-if-env-changed={}", PREBUILT_DIR_VAR);
+
+    let Some(root) = std::env::var_os(PREBUILT_DIR_VAR) else {
+        return false;
+    };
+    let linked = (|| {
+        let target = std::env::var("TARGET").map_err(|_| "TARGET is not set".to_string())?;
+        let out_dir = std::env::var_os("OUT_DIR").ok_or("OUT_DIR is not set")?;
+        let root = std::path::absolute(PathBuf::from(&root))
+            .map_err(|e| format!("invalid path {}: {}", Path::new(&root).display(), e))?;
+        link_prebuilt(lcfg, &root, &target, Path::new(&out_dir))
+    })();
+
+    match linked {
+        Ok(()) => true,
+        Err(error) => {
+            println!(
+                "cargo:warning={}: {}, building Lute from source",
+                PREBUILT_DIR_VAR, error
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::archive_name;
+    use crate::testutil::{fake_manifest, temp_dir};
+
+    const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+    fn lcfg() -> LConfig {
+        LConfig {
+            disable_net: true,
+            ..Default::default()
+        }
+    }
+
+    /// Writes fake libraries for `lcfg` (except `skip`) and their manifest into
+    /// `<root>/<dir_target>/<fingerprint>/build/staticlibs`, as built for `target`
+    fn fake_prebuilt(root: &Path, dir_target: &str, target: &str, skip: &str) -> PathBuf {
+        let config = BuildConfig::new(lcfg(), cfg!(feature = "codegen"));
+        let dir = root
+            .join(dir_target)
+            .join(config.fingerprint())
+            .join("build")
+            .join("staticlibs");
+
+        let libraries: Vec<&str> = required_libraries(lcfg())
+            .into_iter()
+            .map(|names| names[0])
+            .filter(|name| *name != skip)
+            .collect();
+        fake_manifest(&dir, target, config, &libraries)
+            .write(&dir)
+            .unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_matching_prebuilt() {
+        let root = temp_dir("matching");
+        let dir = fake_prebuilt(&root, TARGET, TARGET, "");
+
+        let found = validate_prebuilt(lcfg(), &root, TARGET, &root.join("out")).unwrap();
+        assert_eq!(found.dir, dir);
+        assert_eq!(
+            found.reassembled_dir,
+            root.join("out").join("lute-prebuilt")
+        );
+        assert_eq!(found.manifest.target, TARGET);
+        assert!(found
+            .manifest
+            .link_order
+            .iter()
+            .any(|name| name == "Luau.VM"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_chunked_library_reassembled_into_out_dir() {
+        let root = temp_dir("chunked");
+        let dir = fake_prebuilt(&root, TARGET, TARGET, "");
+
+        // Store a library as parts, as a published prebuilt directory would
+        let name = archive_name("Luau.VM", TARGET);
+        let original = root.join(&name);
+        std::fs::rename(dir.join(&name), &original).unwrap();
+        let options = chunked::ChunkOptions {
+            max_file_size: 1,
+            chunk_size: 4,
+            compress: false,
+        };
+        chunked::store(&original, &dir, options).unwrap();
+
+        let found = validate_prebuilt(lcfg(), &root, TARGET, &root.join("out")).unwrap();
+        assert_eq!(
+            std::fs::read(found.reassembled_dir.join(&name)).unwrap(),
+            std::fs::read(&original).unwrap()
+        );
+        assert!(!dir.join(&name).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_target() {
+        let root = temp_dir("wrong-target");
+
+        // Libraries of another target in the directory of this one
+        fake_prebuilt(&root, TARGET, "aarch64-unknown-linux-gnu", "");
+        let error = validate_prebuilt(lcfg(), &root, TARGET, &root.join("out")).unwrap_err();
+        assert!(
+            error.contains("built for aarch64-unknown-linux-gnu"),
+            "{}",
+            error
+        );
+
+        let error = validate_prebuilt(
+            lcfg(),
+            &root,
+            "riscv64gc-unknown-linux-gnu",
+            &root.join("out"),
+        )
+        .unwrap_err();
+        assert!(error.contains("no prebuilt libraries"), "{}", error);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_library() {
+        let root = temp_dir("missing");
+        fake_prebuilt(&root, TARGET, TARGET, "Lute.Fs");
+
+        let error = validate_prebuilt(lcfg(), &root, TARGET, &root.join("out")).unwrap_err();
+        assert!(error.ends_with("missing: Lute.Fs"), "{}", error);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_macos_dir() {
+        let root = temp_dir("legacy-macos");
+        let dir = fake_prebuilt(&root, "aarch64-apple-macos", "aarch64-apple-macos", "");
+
+        let found = validate_prebuilt(lcfg(), &root, "aarch64-apple-darwin", &root.join("out"));
+        assert_eq!(found.unwrap().dir, dir);
+
+        // The standard name is preferred when both exist
+        let dir = fake_prebuilt(&root, "aarch64-apple-darwin", "aarch64-apple-darwin", "");
+        let found = validate_prebuilt(lcfg(), &root, "aarch64-apple-darwin", &root.join("out"));
+        assert_eq!(found.unwrap().dir, dir);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_combined_with_disabled_features() {
+        let root = temp_dir("combined");
+        let dir = fake_prebuilt(&root, TARGET, TARGET, "");
+        let mut manifest = Manifest::read(&dir).unwrap();
+        manifest.combined = Some("lute_all".to_string());
+        assert_eq!(linked_libraries(&manifest), ["lute_all"]);
+
+        // The debugger hooks are merged into the archive, but not wanted without the feature
+        manifest.link_order.push("Luau.LuteExt.Debug".to_string());
+        if cfg!(feature = "debugger") {
+            assert_eq!(linked_libraries(&manifest), ["lute_all"]);
+        } else {
+            let linked = linked_libraries(&manifest);
+            assert!(!linked.iter().any(|name| name == "lute_all"));
+            assert!(!linked.iter().any(|name| name == "Luau.LuteExt.Debug"));
+            assert!(linked.iter().any(|name| name == "Luau.VM"));
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_link_prebuilt() {
+        let root = temp_dir("link");
+        let out_dir = root.join("out");
+        assert!(link_prebuilt(lcfg(), &root, TARGET, &out_dir).is_err());
+
+        fake_prebuilt(&root, TARGET, TARGET, "Lute.Fs");
+        assert!(link_prebuilt(lcfg(), &root, TARGET, &out_dir).is_err());
+
+        fake_prebuilt(&root, TARGET, TARGET, "");
+        link_prebuilt(lcfg(), &root, TARGET, &out_dir).unwrap();
+        assert!(out_dir.join("lute-prebuilt").is_dir());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+//! Fixtures shared by the tests of this crate and make_prebuilt
+//!
+//! Only compiled for tests and with the `test-support` feature.
+
+use crate::manifest::{
+    archive_name, sha256_file, system_libraries, BuildConfig, FileEntry, Manifest, Sources,
+    Toolchain, MANIFEST_FORMAT,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Creates an empty temporary directory, unique to the process and the call
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "lute-src-rs-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a fake archive for each library into `dir` and returns their manifest, linking them
+/// in the given order
+///
+/// The manifest isn't written, so tests can change it first.
+pub fn fake_manifest(
+    dir: &Path,
+    target: &str,
+    config: BuildConfig,
+    libraries: &[&str],
+) -> Manifest {
+    std::fs::create_dir_all(dir).unwrap();
+
+    let mut files = Vec::new();
+    for name in libraries {
+        let file_name = archive_name(name, target);
+        let path = dir.join(&file_name);
+        std::fs::write(&path, format!("!<arch>\n{}", name)).unwrap();
+        files.push(FileEntry {
+            size: std::fs::metadata(&path).unwrap().len(),
+            sha256: sha256_file(&path).unwrap(),
+            name: file_name,
+        });
+    }
+
+    Manifest {
+        format: MANIFEST_FORMAT,
+        target: target.to_string(),
+        sources: Sources {
+            lute_src_rs: None,
+            lute: None,
+        },
+        config,
+        toolchain: Toolchain {
+            rustc: None,
+            cxx: None,
+            cmake: None,
+            opt_level: None,
+            cflags: None,
+            cxxflags: None,
+        },
+        files,
+        link_order: libraries.iter().map(|name| name.to_string()).collect(),
+        combined: None,
+        system_libraries: system_libraries(target),
+    }
+}
@@ -0,0 +1,215 @@
+//! Target triple parsing
+//!
+//! Splitting a triple on `-` isn't enough to get its `cfg(target_*)` values: the vendor is
+//! optional (`aarch64-linux-android`), the environment and ABI share a component
+//! (`gnueabihf`), and some names differ from the cfg values (`darwin` is `macos`, `riscv64gc`
+//! is `riscv64`). `Triple::parse` follows the values rustc reports for its targets.
+
+use crate::buildenv::TargetCfg;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Triple {
+    /// Triple as given
+    pub triple: String,
+    /// `target_arch`, e.g. `x86_64`, `aarch64`, `riscv64`
+    pub arch: String,
+    /// `target_vendor`, `unknown` if the triple has none
+    pub vendor: String,
+    /// `target_os`, e.g. `linux`, `macos`, `windows`, `android`
+    pub os: String,
+    /// `target_env`, e.g. `gnu`, `musl`, `msvc`, or empty
+    pub env: String,
+    /// `target_abi`, e.g. `eabihf`, `sim`, or empty
+    pub abi: String,
+}
+
+/// Normalizes the architecture component to its `target_arch`
+fn arch(component: &str) -> Option<&str> {
+    let arch = match component {
+        "x86_64" | "x86_64h" => "x86_64",
+        "i386" | "i586" | "i686" => "x86",
+        "aarch64" | "arm64" | "arm64e" => "aarch64",
+        "riscv64gc" | "riscv64imac" | "riscv64" => "riscv64",
+        "riscv32i" | "riscv32imc" | "riscv32imac" | "riscv32gc" => "riscv32",
+        "powerpc64" | "powerpc64le" => "powerpc64",
+        "loongarch64" => "loongarch64",
+        "s390x" => "s390x",
+        "wasm32" => "wasm32",
+        "wasm64" => "wasm64",
+        arm if arm.starts_with("arm") || arm.starts_with("thumb") => "arm",
+        _ => return None,
+    };
+    Some(arch)
+}
+
+/// Splits the environment component into `target_env` and `target_abi`
+fn env_abi(component: &str) -> (&str, &str) {
+    for env in ["gnu", "musl", "msvc", "uclibc", "ohos"] {
+        if let Some(abi) = component.strip_prefix(env) {
+            // e.g. gnux32 and gnullvm are separate ABIs of gnu
+            return (env, abi.strip_prefix('_').unwrap_or(abi));
+        }
+    }
+    // Apple simulator and Mac Catalyst targets, or a bare ABI like eabihf
+    ("", component)
+}
+
+/// Vendors of `<arch>-<vendor>-<os>` triples, to tell them apart from `<arch>-<os>-<env>`
+const VENDORS: &[&str] = &[
+    "unknown", "pc", "apple", "nvidia", "fortanix", "sun", "wrs", "uwp",
+];
+
+impl Triple {
+    pub fn parse(triple: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid target triple {}: {}", triple, reason);
+
+        let (vendor, os, env_component) = match triple.split('-').collect::<Vec<_>>()[..] {
+            [_, vendor, os, env] => (vendor, os, env),
+            [_, vendor, os] if VENDORS.contains(&vendor) => (vendor, os, ""),
+            // e.g. aarch64-linux-android
+            [_, os, env] => ("unknown", os, env),
+            [_, os] => ("unknown", os, ""),
+            _ => return Err(invalid("expected 2 to 4 components")),
+        };
+        let arch = triple.split('-').next().and_then(arch);
+        let arch = arch.ok_or_else(|| invalid("unknown architecture"))?;
+
+        let (os, env, abi) = match (os, env_component) {
+            // `aarch64-apple-macos` was used for prebuilts before the standard name
+            ("darwin" | "macos", abi) => ("macos", "", abi),
+            ("ios" | "tvos" | "watchos" | "visionos", abi) => (os, "", abi),
+            ("linux", component) if component.starts_with("android") => {
+                ("android", "", &component["android".len()..])
+            }
+            (os, component) => {
+                let (env, abi) = env_abi(component);
+                (os, env, abi)
+            }
+        };
+        if os.is_empty() {
+            return Err(invalid("missing OS"));
+        }
+
+        Ok(Triple {
+            triple: triple.to_string(),
+            arch: arch.to_string(),
+            vendor: vendor.to_string(),
+            os: os.to_string(),
+            env: env.to_string(),
+            abi: abi.to_string(),
+        })
+    }
+
+    pub fn is_windows_msvc(&self) -> bool {
+        self.os == "windows" && self.env == "msvc"
+    }
+
+    pub fn is_apple(&self) -> bool {
+        self.vendor == "apple"
+    }
+
+    /// The `cfg(target_*)` values cargo gives build scripts for this target
+    pub fn target_cfg(&self) -> TargetCfg {
+        TargetCfg {
+            arch: self.arch.clone(),
+            vendor: self.vendor.clone(),
+            os: self.os.clone(),
+            env: self.env.clone(),
+            abi: self.abi.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_cfgs() {
+        // The values rustc reports with `--print cfg` for each target
+        let expected = [
+            (
+                "aarch64-unknown-linux-gnu",
+                "aarch64",
+                "unknown",
+                "linux",
+                "gnu",
+            ),
+            (
+                "x86_64-unknown-linux-gnu",
+                "x86_64",
+                "unknown",
+                "linux",
+                "gnu",
+            ),
+            (
+                "aarch64-unknown-linux-musl",
+                "aarch64",
+                "unknown",
+                "linux",
+                "musl",
+            ),
+            (
+                "x86_64-unknown-linux-musl",
+                "x86_64",
+                "unknown",
+                "linux",
+                "musl",
+            ),
+            (
+                "riscv64gc-unknown-linux-gnu",
+                "riscv64",
+                "unknown",
+                "linux",
+                "gnu",
+            ),
+            ("aarch64-apple-darwin", "aarch64", "apple", "macos", ""),
+            ("x86_64-apple-darwin", "x86_64", "apple", "macos", ""),
+            ("x86_64-pc-windows-msvc", "x86_64", "pc", "windows", "msvc"),
+        ];
+
+        for (target, arch, vendor, os, env) in expected {
+            let cfg = Triple::parse(target).unwrap().target_cfg();
+            assert_eq!(
+                (
+                    cfg.arch.as_str(),
+                    cfg.vendor.as_str(),
+                    cfg.os.as_str(),
+                    cfg.env.as_str()
+                ),
+                (arch, vendor, os, env),
+                "{}",
+                target
+            );
+            assert_eq!(cfg.abi, "");
+        }
+
+        let android = Triple::parse("armv7-linux-androideabi").unwrap();
+        assert_eq!(
+            (
+                android.arch.as_str(),
+                android.os.as_str(),
+                android.env.as_str(),
+                android.abi.as_str()
+            ),
+            ("arm", "android", "", "eabi")
+        );
+        assert!(Triple::parse("x86_64").is_err());
+    }
+
+    #[test]
+    fn test_prebuilt_dir_names() {
+        // Directory names make_prebuilt used for macOS before the standard ones
+        let legacy = Triple::parse("aarch64-apple-macos").unwrap();
+        let darwin = Triple::parse("aarch64-apple-darwin").unwrap();
+        assert_eq!(legacy.target_cfg(), darwin.target_cfg());
+        assert!(legacy.is_apple() && !legacy.is_windows_msvc());
+
+        assert!(Triple::parse("x86_64-pc-windows-msvc")
+            .unwrap()
+            .is_windows_msvc());
+        assert!(!Triple::parse("x86_64-pc-windows-gnu")
+            .unwrap()
+            .is_windows_msvc());
+    }
+}
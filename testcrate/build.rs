@@ -1,12 +1,9 @@
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     
-    #[cfg(not(feature = "prebuilt"))]
+    // Links the libraries in LUTE_PREBUILT_DIR instead if they match, see lute_src_rs::prebuilt
     lute_src_rs::build_lute(lute_src_rs::LConfig {
         disable_crypto: true,
         ..Default::default()
     });
-
-    #[cfg(feature = "prebuilt")]
-    lute_prebuilts_chooser::integrate();
 } // ssssasshdsdsssfhhss
\ No newline at end of file
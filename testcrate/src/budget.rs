@@ -0,0 +1,234 @@
+//! Execution budgets for embedded scripts
+//!
+//! Budgets are enforced from the Luau interrupt callback and apply to every slice of
+//! execution of a coroutine (from a resume until it yields), so a script stuck in a loop
+//! without yielding is aborted instead of blocking the scheduler forever.
+
+use std::os::raw::{c_int, c_void};
+use std::time::Duration;
+
+use crate::{
+    lutec_canceltoken_cancel, lutec_canceltoken_clone, lutec_canceltoken_free,
+    lutec_canceltoken_iscancelled, lutec_canceltoken_new,
+};
+
+/// Limits applied to each slice of execution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum number of interrupts (function calls and loop iterations) per slice
+    pub instruction_limit: Option<u64>,
+    /// Maximum wall-clock time per slice
+    pub time_limit: Option<Duration>,
+}
+
+/// Why a coroutine was aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptReason {
+    Instructions,
+    Timeout,
+    Cancelled,
+    /// Reported by the scheduler, but the reason was already taken
+    Unknown,
+}
+
+impl InterruptReason {
+    pub(crate) fn from_raw(raw: c_int) -> Option<Self> {
+        match raw {
+            1 => Some(InterruptReason::Instructions),
+            2 => Some(InterruptReason::Timeout),
+            3 => Some(InterruptReason::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Token that aborts all coroutines of the runtimes it is set on once cancelled
+///
+/// Can be cloned and cancelled from any thread.
+pub struct CancelToken {
+    token: *mut c_void,
+}
+
+// SAFETY: The underlying token is an atomic flag behind a shared pointer
+unsafe impl Send for CancelToken {}
+unsafe impl Sync for CancelToken {}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken {
+            token: unsafe { lutec_canceltoken_new() },
+        }
+    }
+
+    pub fn cancel(&self) {
+        unsafe { lutec_canceltoken_cancel(self.token) }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        unsafe { lutec_canceltoken_iscancelled(self.token) != 0 }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        self.token
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for CancelToken {
+    fn clone(&self) -> Self {
+        CancelToken {
+            token: unsafe { lutec_canceltoken_clone(self.token) },
+        }
+    }
+}
+
+impl Drop for CancelToken {
+    fn drop(&mut self) {
+        unsafe { lutec_canceltoken_free(self.token) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{LuteError, Runtime};
+
+    fn assert_interrupted(result: Result<(), LuteError>, expected: InterruptReason) {
+        match result {
+            Err(LuteError::Interrupted {
+                reason, message, ..
+            }) => {
+                assert_eq!(reason, expected);
+                assert!(message.contains("execution budget exceeded"), "{}", message);
+            }
+            other => panic!("expected interruption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_instruction_limit_main_state() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_budget(Budget {
+                instruction_limit: Some(100_000),
+                ..Default::default()
+            });
+
+            assert_interrupted(
+                runtime.spawn("=spin", b"while true do end"),
+                InterruptReason::Instructions,
+            );
+
+            // Ordinary errors are still reported as such
+            assert!(matches!(
+                runtime.spawn("=fail", b"error('boom')"),
+                Err(LuteError::Runtime { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_time_limit_scheduled_coroutine() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_budget(Budget {
+                time_limit: Some(Duration::from_millis(50)),
+                ..Default::default()
+            });
+
+            // The loop only starts once the scheduler resumes the deferred task
+            runtime
+                .spawn("=defer", b"task.defer(function() while true do end end)")
+                .unwrap();
+
+            assert_interrupted(runtime.run_until_idle(), InterruptReason::Timeout);
+        }
+    }
+
+    #[test]
+    fn test_switching_coroutines_keeps_slice() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_budget(Budget {
+                instruction_limit: Some(100_000),
+                ..Default::default()
+            });
+
+            // Resuming coroutines from Luau doesn't start a new slice
+            assert_interrupted(
+                runtime.spawn(
+                    "=pingpong",
+                    b"local co = coroutine.wrap(function() while true do coroutine.yield() end end)
+while true do co() end",
+                ),
+                InterruptReason::Instructions,
+            );
+
+            // Neither does catching the error of a new coroutine every time
+            assert_interrupted(
+                runtime.spawn(
+                    "=pcall",
+                    b"while true do pcall(coroutine.wrap(function() while true do end end)) end",
+                ),
+                InterruptReason::Instructions,
+            );
+        }
+    }
+
+    #[test]
+    fn test_budget_resets_on_yield() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_budget(Budget {
+                instruction_limit: Some(2_000),
+                time_limit: Some(Duration::from_millis(20)),
+            });
+
+            // Every slice stays within the budget, but all of them together and the time spent
+            // waiting don't
+            runtime
+                .spawn(
+                    "=yields",
+                    b"local n = 0
+for i = 1, 200 do
+    for j = 1, 500 do
+        n += 1
+    end
+    task.wait(0.001)
+end
+finished = n",
+                )
+                .unwrap();
+            runtime.run_until_idle().unwrap();
+
+            runtime.spawn("=check", b"assert(finished == 100000)").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cancel_token_from_other_thread() {
+        unsafe {
+            let runtime = Runtime::new();
+            let token = CancelToken::new();
+            runtime.set_cancel_token(&token);
+
+            let remote = token.clone();
+            let canceller = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                remote.cancel();
+            });
+
+            assert_interrupted(
+                runtime.spawn("=spin", b"while true do end"),
+                InterruptReason::Cancelled,
+            );
+            assert!(token.is_cancelled());
+            canceller.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Bytecode cache for module loading
+//!
+//! Entries are keyed by a SHA-256 over the source, the compiler options, the Luau bytecode
+//! version and the set of enabled fast flags, so changing any of them results in a miss
+//! rather than stale bytecode.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use sha2::{Digest, Sha256};
+
+use crate::compiler::{CompileError, Compiler};
+use crate::luau_enumfflags;
+
+const ENTRY_MAGIC: &[u8; 8] = b"LUTEBC01";
+const ENTRY_HEADER_SIZE: usize = 8 + 32 + 8 + 32;
+
+pub type CacheKey = [u8; 32];
+
+/// Where cached bytecode is stored
+#[derive(Debug, Clone)]
+pub enum CacheStorage {
+    /// One file per entry in the given directory
+    Directory(PathBuf),
+    /// In memory, evicting the least recently used entry once `capacity` entries are stored
+    Memory { capacity: usize },
+}
+
+/// Snapshot of the cache metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries that failed verification and were discarded
+    pub corrupted: u64,
+    pub evictions: u64,
+}
+
+pub struct BytecodeCache {
+    storage: CacheStorage,
+    memory: Mutex<MemoryCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    corrupted: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct MemoryCache {
+    entries: HashMap<CacheKey, Arc<[u8]>>,
+    // Least recently used first
+    order: VecDeque<CacheKey>,
+}
+
+impl BytecodeCache {
+    pub fn new(storage: CacheStorage) -> Self {
+        BytecodeCache {
+            storage,
+            memory: Mutex::new(MemoryCache::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            corrupted: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn storage(&self) -> &CacheStorage {
+        &self.storage
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            corrupted: self.corrupted.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Computes the cache key for the source compiled with the given compiler
+    pub fn key(compiler: &Compiler, source: &[u8]) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(b"source\0");
+        hasher.update((source.len() as u64).to_le_bytes());
+        hasher.update(source);
+        hasher.update(b"options\0");
+        hasher.update(compiler.fingerprint().as_bytes());
+        hasher.update(b"version\0");
+        hasher.update(luau_version());
+        hasher.update(b"fflags\0");
+        for flag in enabled_fflags() {
+            hasher.update(flag.as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.finalize().into()
+    }
+
+    /// Returns the cached bytecode for the source, compiling and storing it on a miss
+    pub fn get_or_compile(
+        &self,
+        compiler: &Compiler,
+        source: &[u8],
+    ) -> Result<Arc<[u8]>, CompileError> {
+        let key = Self::key(compiler, source);
+
+        if let Some(bytecode) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(bytecode);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let bytecode: Arc<[u8]> = Arc::from(compiler.compile(source)?.as_bytes());
+        self.insert(key, bytecode.clone());
+        Ok(bytecode)
+    }
+
+    /// Removes all entries from the cache
+    pub fn clear(&self) -> std::io::Result<()> {
+        match &self.storage {
+            CacheStorage::Memory { .. } => {
+                let mut memory = self.memory.lock().unwrap();
+                memory.entries.clear();
+                memory.order.clear();
+            }
+            CacheStorage::Directory(dir) => {
+                if !dir.exists() {
+                    return Ok(());
+                }
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().is_some_and(|ext| ext == "luauc") {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        match &self.storage {
+            CacheStorage::Memory { .. } => {
+                let mut memory = self.memory.lock().unwrap();
+                let bytecode = memory.entries.get(key)?.clone();
+                memory.touch(key);
+                Some(bytecode)
+            }
+            CacheStorage::Directory(_) => {
+                let path = self.entry_path(key);
+                let data = std::fs::read(&path).ok()?;
+                match decode_entry(key, &data) {
+                    Some(bytecode) => Some(Arc::from(bytecode)),
+                    None => {
+                        // Discard the entry so it is rewritten on this miss
+                        self.corrupted.fetch_add(1, Ordering::Relaxed);
+                        let _ = std::fs::remove_file(&path);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert(&self, key: CacheKey, bytecode: Arc<[u8]>) {
+        match &self.storage {
+            CacheStorage::Memory { capacity } => {
+                let mut memory = self.memory.lock().unwrap();
+                if memory.entries.insert(key, bytecode).is_none() {
+                    memory.order.push_back(key);
+                } else {
+                    memory.touch(&key);
+                }
+
+                while memory.entries.len() > (*capacity).max(1) {
+                    let Some(oldest) = memory.order.pop_front() else {
+                        break;
+                    };
+                    memory.entries.remove(&oldest);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            CacheStorage::Directory(dir) => {
+                // Failing to write the cache is not fatal, the module was compiled anyway
+                if std::fs::create_dir_all(dir).is_err() {
+                    return;
+                }
+
+                // Write to a temporary file first so concurrent readers never see a partial entry
+                let path = self.entry_path(&key);
+                let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+                if std::fs::write(&tmp, encode_entry(&key, &bytecode)).is_ok()
+                    && std::fs::rename(&tmp, &path).is_err()
+                {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+            }
+        }
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        let CacheStorage::Directory(dir) = &self.storage else {
+            unreachable!("entry_path is only used for directory storage");
+        };
+        dir.join(format!("{}.luauc", hex(key)))
+    }
+}
+
+impl MemoryCache {
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            self.order.remove(pos);
+        }
+        self.order.push_back(*key);
+    }
+}
+
+// Entry layout: magic, key, payload length (u64 LE), SHA-256 of the payload, payload
+fn encode_entry(key: &CacheKey, bytecode: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ENTRY_HEADER_SIZE + bytecode.len());
+    data.extend_from_slice(ENTRY_MAGIC);
+    data.extend_from_slice(key);
+    data.extend_from_slice(&(bytecode.len() as u64).to_le_bytes());
+    data.extend_from_slice(&Sha256::digest(bytecode));
+    data.extend_from_slice(bytecode);
+    data
+}
+
+fn decode_entry<'a>(key: &CacheKey, data: &'a [u8]) -> Option<&'a [u8]> {
+    if data.len() < ENTRY_HEADER_SIZE || &data[..8] != ENTRY_MAGIC || &data[8..40] != key {
+        return None;
+    }
+
+    let len = u64::from_le_bytes(data[40..48].try_into().ok()?) as usize;
+    let checksum = &data[48..80];
+    let payload = &data[ENTRY_HEADER_SIZE..];
+
+    if payload.len() != len || Sha256::digest(payload).as_slice() != checksum {
+        return None;
+    }
+
+    // A zero version byte is an error, which is never cached
+    if payload.first().is_none_or(|v| *v == 0) {
+        return None;
+    }
+
+    Some(payload)
+}
+
+// The bytecode (and type info) version produced by the linked Luau compiler
+fn luau_version() -> &'static [u8] {
+    static VERSION: OnceLock<Vec<u8>> = OnceLock::new();
+    VERSION.get_or_init(|| match Compiler::new().compile("") {
+        Ok(bytecode) => bytecode.as_bytes().iter().take(2).copied().collect(),
+        Err(_) => Vec::new(),
+    })
+}
+
+fn enabled_fflags() -> Vec<String> {
+    unsafe extern "C" fn collect(name: *const c_char, value: c_int, ctx: *mut c_void) {
+        if value != 0 {
+            let flags = &mut *(ctx as *mut Vec<String>);
+            flags.push(CStr::from_ptr(name).to_string_lossy().into_owned());
+        }
+    }
+
+    let mut flags: Vec<String> = Vec::new();
+    unsafe { luau_enumfflags(collect, &mut flags as *mut Vec<String> as *mut c_void) };
+    flags.sort();
+    flags
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::{load_source, ModuleLoader};
+    use crate::runtime::Runtime;
+    use crate::*;
+    use std::ptr;
+
+    #[test]
+    fn test_memory_cache_hits_and_evicts() {
+        let cache = BytecodeCache::new(CacheStorage::Memory { capacity: 1 });
+        let compiler = Compiler::new();
+
+        cache.get_or_compile(&compiler, b"return 1").unwrap();
+        cache.get_or_compile(&compiler, b"return 1").unwrap();
+        cache.get_or_compile(&compiler, b"return 2").unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 1);
+
+        // Different options must not share entries
+        cache
+            .get_or_compile(&Compiler::new().optimization_level(2), b"return 2")
+            .unwrap();
+        assert_eq!(cache.stats().misses, 3);
+    }
+
+    #[test]
+    fn test_directory_cache_discards_corrupted_entries() {
+        let dir = std::env::temp_dir().join(format!("lute-bccache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let cache = BytecodeCache::new(CacheStorage::Directory(dir.clone()));
+        let compiler = Compiler::new();
+        let source = b"return 40 + 2";
+
+        cache.get_or_compile(&compiler, source).unwrap();
+        cache.get_or_compile(&compiler, source).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+
+        // Flip a byte in the payload
+        let path = cache.entry_path(&BytecodeCache::key(&compiler, source));
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        cache.get_or_compile(&compiler, source).unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.corrupted, 1);
+        assert_eq!(stats.misses, 2);
+
+        // The entry was rewritten and is valid again
+        cache.get_or_compile(&compiler, source).unwrap();
+        assert_eq!(cache.stats().hits, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_runtime_loader_uses_cache() {
+        let cache = Arc::new(BytecodeCache::new(CacheStorage::Memory { capacity: 16 }));
+        let loader = Arc::new(ModuleLoader::new(Compiler::new()).with_cache(cache.clone()));
+
+        unsafe {
+            let state = luaL_newstate();
+            lutec_setup_runtime(state);
+            luaL_openlibs(state);
+            assert!(loader.install(state));
+
+            for _ in 0..3 {
+                assert_eq!(load_source(state, "=module", b"return 7 * 6"), 0);
+                lua_call(state, 0, 1);
+                assert_eq!(lua_tointegerx(state, -1, ptr::null_mut()), 42);
+                lua_settop(state, 0);
+            }
+
+            assert_ne!(load_source(state, "=broken", b"return +"), 0);
+            assert!(to_string(state, -1).starts_with("=broken:1:"));
+
+            lutec_destroy_runtime(state);
+            lua_close(state);
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 2);
+    }
+
+    #[test]
+    fn test_require_uses_cache() {
+        let cache = Arc::new(BytecodeCache::new(CacheStorage::Memory { capacity: 16 }));
+        let loader = Arc::new(ModuleLoader::new(Compiler::new()).with_cache(cache.clone()));
+
+        let source = b"local answer = require('fixtures/required')
+assert(answer.value == 42)
+assert(require('fixtures/required') == answer)
+";
+        for _ in 0..2 {
+            unsafe {
+                let runtime = Runtime::new();
+                assert!(loader.clone().install(runtime.state()));
+                runtime.spawn("=main", source).unwrap();
+            }
+        }
+
+        // The main chunk is loaded through the loader too. The module is only loaded once per
+        // runtime, and the second runtime gets both chunks from the cache
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.hits, 2);
+    }
+}
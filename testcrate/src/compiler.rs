@@ -0,0 +1,629 @@
+//! Safe wrapper around `luau_compile`
+//!
+//! `Compiler` fills in a `lua_CompileOptions` from owned Rust values for the duration of the
+//! compile call and returns a `Bytecode` that owns (and frees) the buffer returned by Luau.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::AssertUnwindSafe;
+use std::ptr;
+
+use crate::{free, lua_CompileOptions, luau_compile, lutec_load};
+
+extern "C" {
+    fn luau_set_compile_constant_nil(constant: *mut *mut c_void);
+    fn luau_set_compile_constant_boolean(constant: *mut *mut c_void, b: c_int);
+    fn luau_set_compile_constant_number(constant: *mut *mut c_void, n: f64);
+    fn luau_set_compile_constant_vector(
+        constant: *mut *mut c_void,
+        x: f32,
+        y: f32,
+        z: f32,
+        w: f32,
+    );
+    fn luau_set_compile_constant_string(constant: *mut *mut c_void, s: *const c_char, l: usize);
+}
+
+/// Type of a library member, as reported to the compiler (mirrors `LuauBytecodeType`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum BytecodeType {
+    Nil = 0,
+    Boolean = 1,
+    Number = 2,
+    String = 3,
+    Table = 4,
+    Function = 5,
+    Thread = 6,
+    Userdata = 7,
+    Vector = 8,
+    Buffer = 9,
+    Any = 15,
+}
+
+/// Value of a library member known at compile time
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileConstant {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Vector(f32, f32, f32, f32),
+    String(Vec<u8>),
+}
+
+type LibraryMemberTypeFn = dyn Fn(&str, &str) -> BytecodeType + Send + Sync;
+type LibraryMemberConstantFn = dyn Fn(&str, &str) -> Option<CompileConstant> + Send + Sync;
+
+/// Builder for Luau compile options
+pub struct Compiler {
+    optimization_level: u8,
+    debug_level: u8,
+    type_info_level: u8,
+    coverage_level: u8,
+    vector_lib: Option<String>,
+    vector_ctor: Option<String>,
+    vector_type: Option<String>,
+    mutable_globals: Vec<String>,
+    userdata_types: Vec<String>,
+    libraries_with_known_members: Vec<String>,
+    library_member_type_cb: Option<Box<LibraryMemberTypeFn>>,
+    library_member_constant_cb: Option<Box<LibraryMemberConstantFn>>,
+    disabled_builtins: Vec<String>,
+}
+
+impl Compiler {
+    /// Creates a compiler with the same defaults as `luau_compile` with null options
+    pub fn new() -> Self {
+        Compiler {
+            optimization_level: 1,
+            debug_level: 1,
+            type_info_level: 0,
+            coverage_level: 0,
+            vector_lib: None,
+            vector_ctor: None,
+            vector_type: None,
+            mutable_globals: Vec::new(),
+            userdata_types: Vec::new(),
+            libraries_with_known_members: Vec::new(),
+            library_member_type_cb: None,
+            library_member_constant_cb: None,
+            disabled_builtins: Vec::new(),
+        }
+    }
+
+    /// Sets the optimization level (0-2)
+    pub fn optimization_level(mut self, level: u8) -> Self {
+        self.optimization_level = level;
+        self
+    }
+
+    /// Sets the debug level (0-2)
+    pub fn debug_level(mut self, level: u8) -> Self {
+        self.debug_level = level;
+        self
+    }
+
+    /// Sets the type information level (0-1)
+    pub fn type_info_level(mut self, level: u8) -> Self {
+        self.type_info_level = level;
+        self
+    }
+
+    /// Sets the coverage level (0-2)
+    pub fn coverage_level(mut self, level: u8) -> Self {
+        self.coverage_level = level;
+        self
+    }
+
+    /// Sets the library providing the vector constructor (e.g. `vector`)
+    pub fn vector_lib(mut self, lib: impl Into<String>) -> Self {
+        self.vector_lib = Some(lib.into());
+        self
+    }
+
+    /// Sets the vector constructor function (e.g. `create`)
+    pub fn vector_ctor(mut self, ctor: impl Into<String>) -> Self {
+        self.vector_ctor = Some(ctor.into());
+        self
+    }
+
+    /// Sets the name of the vector type used in type annotations
+    pub fn vector_type(mut self, ty: impl Into<String>) -> Self {
+        self.vector_type = Some(ty.into());
+        self
+    }
+
+    /// Sets the globals that may be mutated and so must not be treated as constants
+    pub fn mutable_globals<S: Into<String>>(mut self, globals: impl IntoIterator<Item = S>) -> Self {
+        self.mutable_globals = globals.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the userdata types known to the type information pass
+    pub fn userdata_types<S: Into<String>>(mut self, types: impl IntoIterator<Item = S>) -> Self {
+        self.userdata_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the builtins that must not be inlined by the compiler (e.g. `math.floor`)
+    pub fn disabled_builtins<S: Into<String>>(
+        mut self,
+        builtins: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.disabled_builtins = builtins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the libraries whose members are described by the library member callbacks
+    pub fn libraries_with_known_members<S: Into<String>>(
+        mut self,
+        libraries: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.libraries_with_known_members = libraries.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the callback returning the type of a library member
+    pub fn library_member_type(
+        mut self,
+        cb: impl Fn(&str, &str) -> BytecodeType + Send + Sync + 'static,
+    ) -> Self {
+        self.library_member_type_cb = Some(Box::new(cb));
+        self
+    }
+
+    /// Sets the callback returning the value of a library member, if it is a constant
+    pub fn library_member_constant(
+        mut self,
+        cb: impl Fn(&str, &str) -> Option<CompileConstant> + Send + Sync + 'static,
+    ) -> Self {
+        self.library_member_constant_cb = Some(Box::new(cb));
+        self
+    }
+
+    /// Returns a stable description of the options affecting the produced bytecode
+    ///
+    /// Library member callbacks cannot be inspected, so only their presence and the list of
+    /// libraries they apply to are included. Callbacks must therefore be deterministic for
+    /// bytecode to be safely cached.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "O{} g{} t{} c{} vl={:?} vc={:?} vt={:?} mg={:?} ut={:?} lk={:?} cbt={} cbc={} db={:?}",
+            self.optimization_level,
+            self.debug_level,
+            self.type_info_level,
+            self.coverage_level,
+            self.vector_lib,
+            self.vector_ctor,
+            self.vector_type,
+            self.mutable_globals,
+            self.userdata_types,
+            self.libraries_with_known_members,
+            self.library_member_type_cb.is_some(),
+            self.library_member_constant_cb.is_some(),
+            self.disabled_builtins,
+        )
+    }
+
+    /// Compiles the source into bytecode
+    pub fn compile(&self, source: impl AsRef<[u8]>) -> Result<Bytecode, CompileError> {
+        let source = source.as_ref();
+
+        // Everything referenced by the options must outlive the luau_compile call
+        let vector_lib = self.vector_lib.as_deref().map(to_cstring).transpose()?;
+        let vector_ctor = self.vector_ctor.as_deref().map(to_cstring).transpose()?;
+        let vector_type = self.vector_type.as_deref().map(to_cstring).transpose()?;
+        let mutable_globals = CStringList::new(&self.mutable_globals)?;
+        let userdata_types = CStringList::new(&self.userdata_types)?;
+        let libraries = CStringList::new(&self.libraries_with_known_members)?;
+        let disabled_builtins = CStringList::new(&self.disabled_builtins)?;
+
+        let mut options = lua_CompileOptions {
+            optimizationLevel: self.optimization_level as c_int,
+            debugLevel: self.debug_level as c_int,
+            typeInfoLevel: self.type_info_level as c_int,
+            coverageLevel: self.coverage_level as c_int,
+            vectorLib: opt_ptr(&vector_lib),
+            vectorCtor: opt_ptr(&vector_ctor),
+            vectorType: opt_ptr(&vector_type),
+            mutableGlobals: mutable_globals.as_ptr(),
+            userdataTypes: userdata_types.as_ptr(),
+            librariesWithKnownMembers: libraries.as_ptr(),
+            libraryMemberTypeCb: self
+                .library_member_type_cb
+                .as_ref()
+                .map(|_| library_member_type as _),
+            libraryMemberConstantCb: self
+                .library_member_constant_cb
+                .as_ref()
+                .map(|_| library_member_constant as _),
+            disabledBuiltins: disabled_builtins.as_ptr(),
+        };
+
+        // The C callbacks carry no context pointer, so the active compiler is stashed in a
+        // thread local for the duration of the call
+        let mut size = 0;
+        let (data, panic) = ACTIVE_COMPILER.with(|active| {
+            let previous = active.replace(Some(ActiveCompiler {
+                compiler: self as *const Compiler,
+                strings: Vec::new(),
+                panic: None,
+            }));
+            let data = unsafe {
+                luau_compile(
+                    source.as_ptr().cast(),
+                    source.len(),
+                    &mut options,
+                    &mut size,
+                )
+            };
+            let finished = active.replace(previous);
+            (data, finished.and_then(|finished| finished.panic))
+        });
+
+        if data.is_null() {
+            return Err(CompileError {
+                message: "luau_compile returned null".to_string(),
+                line: None,
+                column: None,
+            });
+        }
+
+        let bytecode = Bytecode { data, size };
+
+        if let Some(message) = panic {
+            return Err(CompileError {
+                message: format!("library member callback panicked: {}", message),
+                line: None,
+                column: None,
+            });
+        }
+
+        // Compile errors are encoded as a zero version byte followed by the error message
+        match bytecode.as_bytes() {
+            [0, message @ ..] => Err(CompileError::decode(message)),
+            [] => Err(CompileError::decode(b"")),
+            _ => Ok(bytecode),
+        }
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler::new()
+    }
+}
+
+/// Bytecode produced by `Compiler::compile`
+///
+/// The buffer is allocated by Luau and freed on drop.
+pub struct Bytecode {
+    data: *mut c_char,
+    size: usize,
+}
+
+// SAFETY: The buffer is exclusively owned and never mutated after compilation
+unsafe impl Send for Bytecode {}
+unsafe impl Sync for Bytecode {}
+
+impl Bytecode {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data as *const u8, self.size) }
+    }
+
+    /// Loads the bytecode into the given state through `lutec_load`, pushing the chunk
+    /// function on success
+    pub unsafe fn load(&self, state: *mut c_void, chunkname: &CStr) -> c_int {
+        lutec_load(state, chunkname.as_ptr(), self.data, self.size, 0)
+    }
+}
+
+impl std::ops::Deref for Bytecode {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Drop for Bytecode {
+    fn drop(&mut self) {
+        unsafe { free(self.data.cast()) };
+    }
+}
+
+impl fmt::Debug for Bytecode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bytecode").field("size", &self.size).finish()
+    }
+}
+
+/// A syntax or compile error reported by Luau
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub message: String,
+    /// 1-based line of the error, if reported
+    pub line: Option<u32>,
+    /// 1-based column of the error, if reported
+    ///
+    /// Luau currently only reports lines, but the column is decoded if present
+    pub column: Option<u32>,
+}
+
+impl CompileError {
+    /// Decodes an error message in the `:line: message` (or `:line:column: message`) form
+    fn decode(raw: &[u8]) -> Self {
+        let raw = String::from_utf8_lossy(raw);
+
+        let mut line = None;
+        let mut column = None;
+        let mut message = raw.as_ref();
+
+        if let Some(rest) = message.strip_prefix(':') {
+            if let Some((n, rest)) = split_number(rest) {
+                line = Some(n);
+                message = rest;
+
+                if let Some((n, rest)) = split_number(rest) {
+                    column = Some(n);
+                    message = rest;
+                }
+            }
+        }
+
+        CompileError {
+            message: message.trim_start().to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+// Splits "123: rest" into (123, " rest")
+fn split_number(s: &str) -> Option<(u32, &str)> {
+    let (n, rest) = s.split_once(':')?;
+    Some((n.parse().ok()?, rest))
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: {}", line, column, self.message),
+            (Some(line), None) => write!(f, "{}: {}", line, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+struct ActiveCompiler {
+    compiler: *const Compiler,
+    // Strings handed to luau_set_compile_constant_string must stay alive until compilation ends
+    strings: Vec<Vec<u8>>,
+    // Message of the first callback that panicked, reported as a compile error
+    panic: Option<String>,
+}
+
+thread_local! {
+    static ACTIVE_COMPILER: RefCell<Option<ActiveCompiler>> = const { RefCell::new(None) };
+}
+
+/// Runs a callback of the active compiler, which can't unwind into Luau
+///
+/// The active compiler isn't borrowed while the callback runs, so it may compile as well.
+/// Panics are recorded and fail the compilation once luau_compile returns.
+fn with_active_compiler<T>(default: T, f: impl FnOnce(&Compiler) -> Option<T>) -> T {
+    let Some(compiler) =
+        ACTIVE_COMPILER.with(|active| active.borrow().as_ref().map(|active| active.compiler))
+    else {
+        return default;
+    };
+
+    // SAFETY: The compiler outlives the luau_compile call it is active for
+    match std::panic::catch_unwind(AssertUnwindSafe(|| f(unsafe { &*compiler }))) {
+        Ok(result) => result.unwrap_or(default),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("unknown panic")
+                .to_string();
+            ACTIVE_COMPILER.with(|active| {
+                if let Some(active) = active.borrow_mut().as_mut() {
+                    active.panic.get_or_insert(message);
+                }
+            });
+            default
+        }
+    }
+}
+
+unsafe extern "C" fn library_member_type(library: *const c_char, member: *const c_char) -> c_int {
+    let library = CStr::from_ptr(library).to_string_lossy();
+    let member = CStr::from_ptr(member).to_string_lossy();
+
+    with_active_compiler(BytecodeType::Any, |compiler| {
+        let cb = compiler.library_member_type_cb.as_ref()?;
+        Some(cb(&library, &member))
+    }) as c_int
+}
+
+unsafe extern "C" fn library_member_constant(
+    library: *const c_char,
+    member: *const c_char,
+    constant: *mut *mut c_void,
+) {
+    let library = CStr::from_ptr(library).to_string_lossy();
+    let member = CStr::from_ptr(member).to_string_lossy();
+
+    let value = with_active_compiler(None, |compiler| {
+        let cb = compiler.library_member_constant_cb.as_ref()?;
+        Some(cb(&library, &member))
+    });
+
+    match value {
+        None => {}
+        Some(CompileConstant::Nil) => luau_set_compile_constant_nil(constant),
+        Some(CompileConstant::Boolean(b)) => {
+            luau_set_compile_constant_boolean(constant, b as c_int)
+        }
+        Some(CompileConstant::Number(n)) => luau_set_compile_constant_number(constant, n),
+        Some(CompileConstant::Vector(x, y, z, w)) => {
+            luau_set_compile_constant_vector(constant, x, y, z, w)
+        }
+        Some(CompileConstant::String(s)) => {
+            // The heap buffer does not move when the Vec itself is moved
+            luau_set_compile_constant_string(constant, s.as_ptr().cast(), s.len());
+            ACTIVE_COMPILER.with(|active| {
+                if let Some(active) = active.borrow_mut().as_mut() {
+                    active.strings.push(s);
+                }
+            });
+        }
+    }
+}
+
+fn to_cstring(s: &str) -> Result<CString, CompileError> {
+    CString::new(s).map_err(|_| CompileError {
+        message: format!("compile option {:?} contains a nul byte", s),
+        line: None,
+        column: None,
+    })
+}
+
+fn opt_ptr(s: &Option<CString>) -> *const c_char {
+    s.as_ref().map_or(ptr::null(), |s| s.as_ptr())
+}
+
+/// A null-terminated array of C strings
+struct CStringList {
+    _strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl CStringList {
+    fn new(strings: &[String]) -> Result<Self, CompileError> {
+        let strings = strings
+            .iter()
+            .map(|s| to_cstring(s))
+            .collect::<Result<Vec<CString>, _>>()?;
+        let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        ptrs.push(ptr::null());
+        Ok(CStringList {
+            _strings: strings,
+            ptrs,
+        })
+    }
+
+    fn as_ptr(&self) -> *const *const c_char {
+        if self.ptrs.len() == 1 {
+            ptr::null()
+        } else {
+            self.ptrs.as_ptr()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_compile_and_load() {
+        let bytecode = Compiler::new()
+            .optimization_level(2)
+            .compile("local a, b = ... return a * b")
+            .expect("compile failed");
+
+        unsafe {
+            let state = luaL_newstate();
+            assert_eq!(bytecode.load(state, c"mul"), 0);
+
+            lua_pushinteger(state, 6);
+            lua_pushinteger(state, 7);
+            lua_call(state, 2, 1);
+            assert_eq!(lua_tointegerx(state, -1, ptr::null_mut()), 42);
+
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let err = Compiler::new()
+            .compile("local a = 1\nlocal = 2")
+            .expect_err("compile should fail");
+
+        assert_eq!(err.line, Some(2));
+        assert!(!err.message.is_empty());
+        assert!(err.to_string().starts_with("2: "));
+    }
+
+    #[test]
+    fn test_nul_in_option() {
+        let err = Compiler::new()
+            .mutable_globals(["ok", "bad\0global"])
+            .compile("return 1")
+            .expect_err("compile should fail");
+        assert!(err.message.contains("nul byte"));
+        assert_eq!(err.line, None);
+
+        assert!(Compiler::new().vector_lib("vec\0").compile("return 1").is_err());
+    }
+
+    #[test]
+    fn test_library_member_constant() {
+        let bytecode = Compiler::new()
+            .libraries_with_known_members(["engine"])
+            .library_member_type(|_, _| BytecodeType::Number)
+            .library_member_constant(|library, member| match (library, member) {
+                ("engine", "TICK_RATE") => Some(CompileConstant::Number(60.0)),
+                _ => None,
+            })
+            .compile("return engine.TICK_RATE")
+            .expect("compile failed");
+
+        unsafe {
+            // `engine` is never defined at runtime, so this only works if the constant was folded
+            let state = luaL_newstate();
+            assert_eq!(bytecode.load(state, c"const"), 0);
+            lua_call(state, 0, 1);
+            assert_eq!(lua_tointegerx(state, -1, ptr::null_mut()), 60);
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_default_matches_new() {
+        let compiler = Compiler::default();
+        assert_eq!(compiler.optimization_level, 1);
+        assert_eq!(compiler.debug_level, 1);
+    }
+
+    #[test]
+    fn test_library_member_callback_panics() {
+        let err = Compiler::new()
+            .libraries_with_known_members(["engine"])
+            .library_member_type(|_, _| BytecodeType::Number)
+            .library_member_constant(|_, _| panic!("no constants"))
+            .compile("return engine.TICK_RATE")
+            .expect_err("compile should fail");
+        assert!(err.message.contains("no constants"), "{}", err.message);
+
+        // Callbacks can compile as well, the active compiler isn't borrowed while they run
+        let bytecode = Compiler::new()
+            .libraries_with_known_members(["engine"])
+            .library_member_type(|_, _| BytecodeType::Number)
+            .library_member_constant(|_, _| {
+                Compiler::new().compile("return 1").unwrap();
+                Some(CompileConstant::Number(60.0))
+            })
+            .compile("return engine.TICK_RATE");
+        assert!(bytecode.is_ok());
+    }
+}
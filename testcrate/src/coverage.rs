@@ -0,0 +1,381 @@
+//! Code coverage for modules loaded through a `ModuleLoader`
+//!
+//! Modules are compiled with coverage enabled and their chunk functions are kept alive in
+//! the registry so hit counts can be read back with `lua_getcoverage` once the scheduler is
+//! idle, or right before their state is closed for child VMs. Results can be exported as LCOV
+//! or a simple JSON document.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Mutex;
+
+use crate::{
+    lua_getcoverage, lua_mainthread, lua_pushvalue, lua_rawgeti, lua_ref, lua_settop, lua_unref,
+};
+use crate::LUA_REGISTRYINDEX;
+
+#[derive(Default)]
+struct Tracked {
+    // Refs of the chunk functions of open states, per main thread and chunk name
+    open: HashMap<usize, HashMap<String, c_int>>,
+    // Counts of chunks that were reloaded or whose state was closed, per chunk name
+    released: BTreeMap<String, FileCoverage>,
+}
+
+/// Collects coverage for all modules loaded by a runtime
+#[derive(Default)]
+pub struct Coverage {
+    tracked: Mutex<Tracked>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks the chunk function at the top of the stack
+    ///
+    /// A chunk loaded again into the same state replaces the previous one, whose counts are
+    /// kept.
+    pub(crate) unsafe fn track(&self, state: *mut c_void, chunkname: &str) {
+        let main = lua_mainthread(state);
+        lua_pushvalue(state, -1);
+        let r#ref = lua_ref(state, -1);
+        lua_settop(state, -2);
+
+        let mut tracked = self.tracked.lock().unwrap();
+        let previous = tracked
+            .open
+            .entry(main as usize)
+            .or_default()
+            .insert(chunkname.to_string(), r#ref);
+
+        if let Some(previous) = previous {
+            let file = read_chunk(main, previous);
+            lua_unref(main, previous);
+            tracked
+                .released
+                .entry(chunkname.to_string())
+                .or_default()
+                .merge(file);
+        }
+    }
+
+    /// Reads the counts of the chunks loaded into the state and stops tracking them
+    ///
+    /// Called by the `ModuleLoader` right before the state is closed.
+    pub(crate) unsafe fn release(&self, state: *mut c_void) {
+        let main = lua_mainthread(state);
+
+        let mut tracked = self.tracked.lock().unwrap();
+        let Some(chunks) = tracked.open.remove(&(main as usize)) else {
+            return;
+        };
+
+        for (chunkname, r#ref) in chunks {
+            let file = read_chunk(main, r#ref);
+            lua_unref(main, r#ref);
+            tracked.released.entry(chunkname).or_default().merge(file);
+        }
+    }
+
+    /// Reads the current hit counts of all tracked modules
+    ///
+    /// Modules of closed states (such as child VMs) report the counts they had when their
+    /// state was closed. Call this from the thread driving the runtime once the scheduler has
+    /// gone idle (`lutec_has_work` returns 0) to get the final counts.
+    pub unsafe fn collect(&self) -> CoverageReport {
+        let tracked = self.tracked.lock().unwrap();
+        let mut files = tracked.released.clone();
+
+        for (&main, chunks) in &tracked.open {
+            for (chunkname, &r#ref) in chunks {
+                let file = read_chunk(main as *mut c_void, r#ref);
+                files.entry(chunkname.clone()).or_default().merge(file);
+            }
+        }
+
+        CoverageReport { files }
+    }
+}
+
+unsafe fn read_chunk(state: *mut c_void, r#ref: c_int) -> FileCoverage {
+    let mut file = FileCoverage::default();
+
+    lua_rawgeti(state, LUA_REGISTRYINDEX, r#ref);
+    lua_getcoverage(
+        state,
+        -1,
+        &mut file as *mut FileCoverage as *mut c_void,
+        coverage_callback,
+    );
+    lua_settop(state, -2);
+
+    file
+}
+
+unsafe extern "C" fn coverage_callback(
+    context: *mut c_void,
+    function: *const c_char,
+    linedefined: c_int,
+    _depth: c_int,
+    hits: *const c_int,
+    size: usize,
+) {
+    let file = &mut *(context as *mut FileCoverage);
+    let hits = std::slice::from_raw_parts(hits, size);
+
+    let name = if function.is_null() {
+        if linedefined == 0 {
+            "<main>".to_string()
+        } else {
+            format!("<anonymous:{}>", linedefined)
+        }
+    } else {
+        CStr::from_ptr(function).to_string_lossy().into_owned()
+    };
+
+    let mut function_hits = None;
+    for (line, &count) in hits.iter().enumerate() {
+        if count < 0 {
+            continue; // No code on this line
+        }
+        *file.lines.entry(line as u32).or_default() += count as u64;
+        function_hits.get_or_insert(count as u64);
+    }
+
+    file.functions.push(FunctionCoverage {
+        name,
+        line: linedefined.max(0) as u32,
+        hits: function_hits.unwrap_or(0),
+    });
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub line: u32,
+    /// Hits of the first line with code in the function
+    pub hits: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub functions: Vec<FunctionCoverage>,
+    /// Hit count per line, only for lines with code
+    pub lines: BTreeMap<u32, u64>,
+}
+
+impl FileCoverage {
+    /// Adds the counts of another load of the same chunk
+    fn merge(&mut self, other: FileCoverage) {
+        for (line, hits) in other.lines {
+            *self.lines.entry(line).or_default() += hits;
+        }
+        for function in other.functions {
+            match self
+                .functions
+                .iter_mut()
+                .find(|f| f.name == function.name && f.line == function.line)
+            {
+                Some(existing) => existing.hits += function.hits,
+                None => self.functions.push(function),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    /// Coverage per chunk name
+    pub files: BTreeMap<String, FileCoverage>,
+}
+
+impl CoverageReport {
+    /// Exports the report in the LCOV tracefile format
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        out.push_str("TN:\n");
+
+        for (name, file) in &self.files {
+            let _ = writeln!(out, "SF:{}", source_path(name));
+
+            for function in &file.functions {
+                let _ = writeln!(out, "FN:{},{}", function.line, function.name);
+            }
+            for function in &file.functions {
+                let _ = writeln!(out, "FNDA:{},{}", function.hits, function.name);
+            }
+            let _ = writeln!(out, "FNF:{}", file.functions.len());
+            let _ = writeln!(
+                out,
+                "FNH:{}",
+                file.functions.iter().filter(|f| f.hits > 0).count()
+            );
+
+            for (line, hits) in &file.lines {
+                let _ = writeln!(out, "DA:{},{}", line, hits);
+            }
+            let _ = writeln!(out, "LF:{}", file.lines.len());
+            let _ = writeln!(out, "LH:{}", file.lines.values().filter(|h| **h > 0).count());
+            out.push_str("end_of_record\n");
+        }
+
+        out
+    }
+
+    /// Exports the report as JSON in the form
+    /// `{"files": {"<chunk>": {"lines": {"<line>": hits}, "functions": [...]}}}`
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"files\":{");
+
+        for (i, (name, file)) in self.files.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}:{{\"lines\":{{", json_string(name));
+            for (j, (line, hits)) in file.lines.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "\"{}\":{}", line, hits);
+            }
+            out.push_str("},\"functions\":[");
+            for (j, function) in file.functions.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                let _ = write!(
+                    out,
+                    "{{\"name\":{},\"line\":{},\"hits\":{}}}",
+                    json_string(&function.name),
+                    function.line,
+                    function.hits
+                );
+            }
+            out.push_str("]}");
+        }
+
+        out.push_str("}}");
+        out
+    }
+}
+
+// Chunk names use Luau's conventions (`@path` for files, `=name` for custom names)
+fn source_path(chunkname: &str) -> &str {
+    chunkname
+        .strip_prefix('@')
+        .or_else(|| chunkname.strip_prefix('='))
+        .unwrap_or(chunkname)
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::loader::{load_source, ModuleLoader};
+    use crate::*;
+    use std::sync::Arc;
+
+    const FIXTURE: &str = include_str!("../fixtures/coverage.luau");
+
+    #[test]
+    fn test_coverage_fixture() {
+        let coverage = Arc::new(Coverage::new());
+        let loader = Arc::new(ModuleLoader::new(Compiler::new()).with_coverage(coverage.clone()));
+
+        unsafe {
+            let state = luaL_newstate();
+            lutec_setup_runtime(state);
+            luaL_openlibs(state);
+            assert!(loader.install(state));
+
+            assert_eq!(load_source(state, "@fixtures/coverage.luau", FIXTURE.as_bytes()), 0);
+            lua_call(state, 0, 1);
+            assert_eq!(lua_tointegerx(state, -1, std::ptr::null_mut()), 3);
+            lua_settop(state, 0);
+
+            // Drain the scheduler before reading the counts
+            while lutec_has_work(state) != 0 {
+                if lutec_run_once(state).op != LUTE_STATE_SUCCESS {
+                    break;
+                }
+            }
+
+            let report = coverage.collect();
+            let file = &report.files["@fixtures/coverage.luau"];
+
+            // `classify` is called three times, only the negative branch is never taken
+            let classify = file.functions.iter().find(|f| f.name == "classify").unwrap();
+            assert_eq!(classify.line, 1);
+            assert_eq!(file.lines.get(&2), Some(&3));
+            assert_eq!(file.lines.get(&3), Some(&0));
+
+            let lcov = report.to_lcov();
+            assert!(lcov.contains("SF:fixtures/coverage.luau\n"));
+            assert!(lcov.contains("FN:1,classify\n"));
+            assert!(lcov.contains("DA:3,0\n"));
+            assert!(lcov.ends_with("end_of_record\n"));
+
+            let json = report.to_json();
+            assert!(json.starts_with("{\"files\":{\"@fixtures/coverage.luau\":"));
+            assert!(json.contains("\"3\":0"));
+
+            lutec_destroy_runtime(state);
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_coverage_reload_and_close() {
+        let coverage = Arc::new(Coverage::new());
+        let loader = Arc::new(ModuleLoader::new(Compiler::new()).with_coverage(coverage.clone()));
+
+        unsafe {
+            let state = luaL_newstate();
+            lutec_setup_runtime(state);
+            luaL_openlibs(state);
+            assert!(loader.install(state));
+
+            // Reloading the chunk replaces the tracked function but keeps its counts
+            for _ in 0..2 {
+                assert_eq!(load_source(state, "@fixtures/coverage.luau", FIXTURE.as_bytes()), 0);
+                lua_call(state, 0, 1);
+                lua_settop(state, 0);
+            }
+
+            // The counts are read by the close hook, before the state is gone
+            lutec_destroy_runtime(state);
+            lua_close(state);
+        }
+
+        let report = unsafe { coverage.collect() };
+        let file = &report.files["@fixtures/coverage.luau"];
+        let classify: Vec<_> = file.functions.iter().filter(|f| f.name == "classify").collect();
+        assert_eq!(classify.len(), 1);
+        assert_eq!(file.lines.get(&2), Some(&6));
+        assert_eq!(file.lines.get(&3), Some(&0));
+    }
+}
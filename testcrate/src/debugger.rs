@@ -0,0 +1,892 @@
+//! Debug Adapter Protocol server for scripts running in an embedded Lute runtime
+//!
+//! The debugger is driven from two places:
+//!
+//! - A transport thread reads DAP requests (from a TCP connection or stdio) and queues them.
+//! - The thread running the runtime handles the queue, either from `Debugger::poll` between
+//!   scheduler steps or while suspended at a breakpoint. As the scheduler runs on that
+//!   thread, every other coroutine stays paused while the debugger is stopped.
+//!
+//! Breakpoints are applied to chunks loaded through a `ModuleLoader` created
+//! `with_debugger`, so modules should be compiled with `debug_level(2)` to get local names.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+
+use crate::compiler::Compiler;
+use crate::*;
+
+const LUTEC_DEBUG_BREAKPOINT: c_int = 1;
+const LUTEC_DEBUG_STEP: c_int = 2;
+const LUTEC_DEBUG_PAUSE: c_int = 3;
+
+// DAP thread id used for the runtime, as all coroutines are suspended together
+const THREAD_ID: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepKind {
+    In,
+    Over,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StepRequest {
+    kind: StepKind,
+    depth: c_int,
+    line: c_int,
+}
+
+struct Chunk {
+    state: usize, // Main thread of the state the chunk was loaded into
+    r#ref: c_int,
+    path: String,
+}
+
+#[derive(Default)]
+struct DebugState {
+    // Requested breakpoint lines per normalized source path
+    breakpoints: HashMap<String, Vec<c_int>>,
+    chunks: Vec<Chunk>,
+    step: Option<StepRequest>,
+    configured: bool,
+    disconnected: bool,
+}
+
+/// What to do after handling a request
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Stay,
+    Resume,
+}
+
+pub struct Debugger {
+    state: Mutex<DebugState>,
+    requests: Mutex<Receiver<Value>>,
+    output: Mutex<Box<dyn Write + Send>>,
+    seq: AtomicI64,
+    // Shared with the runtimes the debugger is attached to, so the transport thread can request
+    // pauses without touching them
+    pause: PauseFlag,
+}
+
+struct PauseFlag(*mut c_void);
+
+// SAFETY: The flag is an atomic behind a shared pointer, only ever set through lutec_debug_pause
+unsafe impl Send for PauseFlag {}
+unsafe impl Sync for PauseFlag {}
+
+impl PauseFlag {
+    fn request(&self) {
+        unsafe { lutec_debug_pause(self.0) }
+    }
+}
+
+impl Drop for PauseFlag {
+    fn drop(&mut self) {
+        unsafe { lutec_debug_pauseflag_free(self.0) }
+    }
+}
+
+impl Debugger {
+    fn new(requests: Receiver<Value>, output: Box<dyn Write + Send>) -> Arc<Self> {
+        Arc::new(Debugger {
+            state: Mutex::new(DebugState::default()),
+            requests: Mutex::new(requests),
+            output: Mutex::new(output),
+            seq: AtomicI64::new(1),
+            pause: PauseFlag(unsafe { lutec_debug_pauseflag_new() }),
+        })
+    }
+
+    /// Listens for a single DAP client on the given address
+    ///
+    /// Returns immediately with the bound address, the client is accepted in the background.
+    pub fn listen_tcp(addr: impl ToSocketAddrs) -> std::io::Result<(Arc<Self>, SocketAddr)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = channel();
+        let writer = SharedWriter::default();
+        let debugger = Debugger::new(rx, Box::new(writer.clone()));
+
+        let weak = Arc::downgrade(&debugger);
+        std::thread::spawn(move || {
+            let Ok((stream, _)) = listener.accept() else {
+                let _ = tx.send(json!({ "type": "request", "command": "disconnect", "seq": 0 }));
+                return;
+            };
+            if let Ok(write_half) = stream.try_clone() {
+                writer.set(Box::new(write_half));
+            }
+            read_messages(stream, tx, weak);
+        });
+
+        Ok((debugger, local_addr))
+    }
+
+    /// Speaks DAP over the process stdin/stdout
+    pub fn stdio() -> Arc<Self> {
+        let (tx, rx) = channel();
+        let debugger = Debugger::new(rx, Box::new(std::io::stdout()));
+
+        let weak = Arc::downgrade(&debugger);
+        std::thread::spawn(move || read_messages(std::io::stdin(), tx, weak));
+
+        debugger
+    }
+
+    /// Attaches the debugger to the runtime attached to the state (and child VMs created
+    /// afterwards)
+    ///
+    /// The runtime keeps a reference to the debugger until it is destroyed or `detach` is called.
+    pub unsafe fn attach(self: &Arc<Self>, state: *mut c_void) -> bool {
+        let ctx = Arc::into_raw(self.clone()) as *mut c_void;
+        lutec_debug_attach(
+            state,
+            ctx,
+            Some(on_event),
+            Some(release_debugger),
+            self.pause.0,
+        ) == 0
+    }
+
+    /// Detaches the debugger from the runtime attached to the state
+    pub unsafe fn detach(&self, state: *mut c_void) -> bool {
+        lutec_debug_attach(state, ptr::null_mut(), None, None, ptr::null_mut()) == 0
+    }
+
+    /// Handles requests from the client until it sends `configurationDone`
+    ///
+    /// Call this before running any script so initial breakpoints are in place.
+    pub unsafe fn wait_for_configuration(&self, state: *mut c_void) {
+        loop {
+            {
+                let debug_state = self.state.lock().unwrap();
+                if debug_state.configured || debug_state.disconnected {
+                    return;
+                }
+            }
+
+            let Some(request) = self.recv() else {
+                return;
+            };
+            self.handle(state, None, &request);
+        }
+    }
+
+    /// Handles all pending requests without blocking
+    ///
+    /// Call this regularly (e.g. between scheduler steps) so breakpoints changed while the
+    /// script is running are applied.
+    pub unsafe fn poll(&self, state: *mut c_void) {
+        loop {
+            let request = match self.requests.lock().unwrap().try_recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            self.handle(state, None, &request);
+        }
+    }
+
+    /// Tracks a loaded chunk (at the top of the stack) so breakpoints can be set in it
+    pub(crate) unsafe fn track(&self, state: *mut c_void, chunkname: &str) {
+        let path = normalize_path(chunkname);
+
+        lua_pushvalue(state, -1);
+        let r#ref = lua_ref(state, -1);
+
+        let mut debug_state = self.state.lock().unwrap();
+        if let Some(lines) = debug_state.breakpoints.get(&path) {
+            for line in lines {
+                lua_breakpoint(state, -1, *line, 1);
+            }
+        }
+        lua_settop(state, -2);
+
+        debug_state.chunks.push(Chunk {
+            state: lua_mainthread(state) as usize,
+            r#ref,
+            path,
+        });
+    }
+
+    /// Stops tracking the chunks loaded into the state, which is about to be closed
+    pub(crate) unsafe fn untrack(&self, state: *mut c_void) {
+        let main = lua_mainthread(state);
+
+        let mut debug_state = self.state.lock().unwrap();
+        debug_state.chunks.retain(|chunk| {
+            if chunk.state != main as usize {
+                return true;
+            }
+            lua_unref(main, chunk.r#ref);
+            false
+        });
+    }
+
+    fn recv(&self) -> Option<Value> {
+        self.requests.lock().unwrap().recv().ok()
+    }
+
+    fn send(&self, mut message: Value) {
+        message["seq"] = json!(self.seq.fetch_add(1, Ordering::Relaxed));
+        let body = message.to_string();
+
+        let mut output = self.output.lock().unwrap();
+        let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = output.flush();
+    }
+
+    fn respond(&self, request: &Value, body: Result<Value, String>) {
+        let (success, body, message) = match body {
+            Ok(body) => (true, body, None),
+            Err(message) => (false, Value::Null, Some(message)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+        });
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        self.send(response);
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    /// Called on the runtime thread by LuteExt
+    unsafe fn on_event(&self, state: *mut c_void, event: c_int) {
+        let reason = match event {
+            LUTEC_DEBUG_BREAKPOINT => "breakpoint",
+            LUTEC_DEBUG_PAUSE => "pause",
+            LUTEC_DEBUG_STEP => {
+                let step = self.state.lock().unwrap().step;
+                let Some(step) = step else {
+                    lua_singlestep(state, 0);
+                    return;
+                };
+
+                let depth = lua_stackdepth(state);
+                let line = current_line(state, 0);
+                let done = match step.kind {
+                    StepKind::In => depth != step.depth || line != step.line,
+                    StepKind::Over => {
+                        depth < step.depth || (depth == step.depth && line != step.line)
+                    }
+                    StepKind::Out => depth < step.depth,
+                };
+                if !done {
+                    return;
+                }
+
+                lua_singlestep(state, 0);
+                self.state.lock().unwrap().step = None;
+                "step"
+            }
+            _ => return,
+        };
+
+        if self.state.lock().unwrap().disconnected {
+            return;
+        }
+
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        );
+
+        // Everything on this thread (including the scheduler) is suspended until resumed
+        while let Some(request) = self.recv() {
+            if self.handle(state, Some(state), &request) == Flow::Resume {
+                break;
+            }
+        }
+    }
+
+    /// Handles a request, `paused` is the suspended thread when stopped
+    unsafe fn handle(&self, state: *mut c_void, paused: Option<*mut c_void>, request: &Value) -> Flow {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+
+        match command {
+            "initialize" => {
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                    })),
+                );
+                self.event("initialized", json!({}));
+            }
+            "launch" | "attach" => self.respond(request, Ok(Value::Null)),
+            "configurationDone" => {
+                self.state.lock().unwrap().configured = true;
+                self.respond(request, Ok(Value::Null));
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(state, args);
+                self.respond(request, Ok(body));
+            }
+            "threads" => self.respond(
+                request,
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "lute" }] })),
+            ),
+            "pause" => {
+                if paused.is_none() {
+                    self.pause.request();
+                }
+                self.respond(request, Ok(Value::Null));
+            }
+            "stackTrace" | "scopes" | "variables" | "evaluate" => {
+                let Some(thread) = paused else {
+                    self.respond(request, Err("not paused".to_string()));
+                    return Flow::Stay;
+                };
+                let body = match command {
+                    "stackTrace" => Ok(stack_trace(thread)),
+                    "scopes" => Ok(scopes(args)),
+                    "variables" => Ok(variables(thread, args)),
+                    _ => evaluate(thread, args),
+                };
+                self.respond(request, body);
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let Some(thread) = paused else {
+                    self.respond(request, Err("not paused".to_string()));
+                    return Flow::Stay;
+                };
+
+                let kind = match command {
+                    "next" => Some(StepKind::Over),
+                    "stepIn" => Some(StepKind::In),
+                    "stepOut" => Some(StepKind::Out),
+                    _ => None,
+                };
+                self.state.lock().unwrap().step = kind.map(|kind| StepRequest {
+                    kind,
+                    depth: lua_stackdepth(thread),
+                    line: current_line(thread, 0),
+                });
+                lua_singlestep(thread, kind.is_some() as c_int);
+
+                let body = if kind.is_none() {
+                    json!({ "allThreadsContinued": true })
+                } else {
+                    Value::Null
+                };
+                self.respond(request, Ok(body));
+                return Flow::Resume;
+            }
+            "disconnect" => {
+                self.clear_breakpoints(state);
+                {
+                    let mut debug_state = self.state.lock().unwrap();
+                    debug_state.step = None;
+                    debug_state.disconnected = true;
+                }
+                if let Some(thread) = paused {
+                    lua_singlestep(thread, 0);
+                }
+                self.respond(request, Ok(Value::Null));
+                return Flow::Resume;
+            }
+            _ => self.respond(request, Err(format!("unsupported request '{}'", command))),
+        }
+
+        Flow::Stay
+    }
+
+    unsafe fn set_breakpoints(&self, state: *mut c_void, args: &Value) -> Value {
+        let path = normalize_path(args["source"]["path"].as_str().unwrap_or_default());
+        let lines: Vec<c_int> = args["breakpoints"]
+            .as_array()
+            .map(|bps| {
+                bps.iter()
+                    .filter_map(|bp| bp["line"].as_i64())
+                    .map(|line| line as c_int)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut debug_state = self.state.lock().unwrap();
+        let old = debug_state
+            .breakpoints
+            .insert(path.clone(), lines.clone())
+            .unwrap_or_default();
+
+        // Only chunks of the calling VM can be updated from this thread, others pick up the
+        // breakpoints when their chunks are loaded
+        let main = lua_mainthread(state) as usize;
+        let mut actual: Vec<Option<c_int>> = vec![None; lines.len()];
+
+        for chunk in debug_state.chunks.iter().filter(|c| c.path == path && c.state == main) {
+            let chunk_state = chunk.state as *mut c_void;
+            lua_rawgeti(chunk_state, LUA_REGISTRYINDEX, chunk.r#ref);
+            for line in &old {
+                lua_breakpoint(chunk_state, -1, *line, 0);
+            }
+            for (i, line) in lines.iter().enumerate() {
+                let set = lua_breakpoint(chunk_state, -1, *line, 1);
+                if set > 0 {
+                    actual[i] = Some(set);
+                }
+            }
+            lua_settop(chunk_state, -2);
+        }
+
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .zip(actual)
+            .map(|(line, actual)| match actual {
+                Some(actual) => json!({ "verified": true, "line": actual }),
+                None => json!({ "verified": false, "line": line }),
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    unsafe fn clear_breakpoints(&self, state: *mut c_void) {
+        let mut debug_state = self.state.lock().unwrap();
+        let main = lua_mainthread(state) as usize;
+
+        for chunk in debug_state.chunks.iter().filter(|c| c.state == main) {
+            let Some(lines) = debug_state.breakpoints.get(&chunk.path) else {
+                continue;
+            };
+            let chunk_state = chunk.state as *mut c_void;
+            lua_rawgeti(chunk_state, LUA_REGISTRYINDEX, chunk.r#ref);
+            for line in lines {
+                lua_breakpoint(chunk_state, -1, *line, 0);
+            }
+            lua_settop(chunk_state, -2);
+        }
+
+        debug_state.breakpoints.clear();
+    }
+}
+
+unsafe extern "C-unwind" fn on_event(ctx: *mut c_void, state: *mut c_void, event: c_int) {
+    let debugger = &*(ctx as *const Debugger);
+    debugger.on_event(state, event);
+}
+
+unsafe extern "C" fn release_debugger(ctx: *mut c_void) {
+    drop(Arc::from_raw(ctx as *const Debugger));
+}
+
+// Chunk names use `@path`, clients send plain (possibly absolute or `./`-prefixed) paths
+fn normalize_path(path: &str) -> String {
+    let path = path.strip_prefix('@').unwrap_or(path);
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let path = std::fs::canonicalize(path)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| path.to_string());
+    path.replace('\\', "/")
+}
+
+unsafe fn getinfo(state: *mut c_void, level: c_int, what: &CStr) -> Option<lua_Debug> {
+    let mut ar: lua_Debug = std::mem::zeroed();
+    (lua_getinfo(state, level, what.as_ptr(), &mut ar) != 0).then_some(ar)
+}
+
+unsafe fn current_line(state: *mut c_void, level: c_int) -> c_int {
+    getinfo(state, level, c"l").map_or(-1, |ar| ar.currentline)
+}
+
+unsafe fn cstr(ptr: *const c_char) -> Option<String> {
+    (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_string_lossy().into_owned())
+}
+
+// Renders the value at the index without modifying the stack
+unsafe fn display(state: *mut c_void, idx: c_int) -> (String, String) {
+    let ty = cstr(lua_typename(state, lua_type(state, idx))).unwrap_or_default();
+
+    let mut len = 0;
+    let ptr = luaL_tolstring(state, idx, &mut len);
+    let value = String::from_utf8_lossy(std::slice::from_raw_parts(ptr as *const u8, len))
+        .into_owned();
+    lua_settop(state, -2);
+
+    (value, ty)
+}
+
+unsafe fn stack_trace(state: *mut c_void) -> Value {
+    let mut frames = Vec::new();
+
+    let mut level = 0;
+    while let Some(ar) = getinfo(state, level, c"sln") {
+        let what = cstr(ar.what).unwrap_or_default();
+        if what != "C" {
+            let source = cstr(ar.source).unwrap_or_default();
+            let path = source.strip_prefix('@').unwrap_or(&source).to_string();
+            let name = cstr(ar.name).unwrap_or_else(|| {
+                if what == "main" {
+                    "<main>".to_string()
+                } else {
+                    "<anonymous>".to_string()
+                }
+            });
+
+            frames.push(json!({
+                "id": level + 1,
+                "name": name,
+                "source": { "name": cstr(ar.short_src), "path": path },
+                "line": ar.currentline,
+                "column": 1,
+            }));
+        }
+        level += 1;
+    }
+
+    json!({ "stackFrames": frames, "totalFrames": frames.len() })
+}
+
+// Each frame has two scopes, locals (2 * id) and upvalues (2 * id + 1)
+fn scopes(args: &Value) -> Value {
+    let frame = args["frameId"].as_i64().unwrap_or(1);
+    json!({
+        "scopes": [
+            { "name": "Locals", "variablesReference": frame * 2, "expensive": false },
+            { "name": "Upvalues", "variablesReference": frame * 2 + 1, "expensive": false },
+        ]
+    })
+}
+
+unsafe fn variables(state: *mut c_void, args: &Value) -> Value {
+    let reference = args["variablesReference"].as_i64().unwrap_or(0);
+    let level = (reference / 2 - 1) as c_int;
+    let mut variables = Vec::new();
+
+    let mut push = |name: String, state: *mut c_void| {
+        let (value, ty) = display(state, -1);
+        variables.push(json!({
+            "name": name,
+            "value": value,
+            "type": ty,
+            "variablesReference": 0,
+        }));
+    };
+
+    if reference % 2 == 0 {
+        let mut n = 1;
+        loop {
+            let name = lua_getlocal(state, level, n);
+            if name.is_null() {
+                break;
+            }
+            push(cstr(name).unwrap_or_default(), state);
+            lua_settop(state, -2);
+            n += 1;
+        }
+    } else if getinfo_push_function(state, level) {
+        let mut n = 1;
+        loop {
+            let name = lua_getupvalue(state, -1, n);
+            if name.is_null() {
+                break;
+            }
+            push(cstr(name).unwrap_or_default(), state);
+            lua_settop(state, -2);
+            n += 1;
+        }
+        lua_settop(state, -2);
+    }
+
+    json!({ "variables": variables })
+}
+
+// Pushes the function running at the level
+unsafe fn getinfo_push_function(state: *mut c_void, level: c_int) -> bool {
+    let mut ar: lua_Debug = std::mem::zeroed();
+    lua_getinfo(state, level, c"f".as_ptr(), &mut ar) != 0
+}
+
+/// Evaluates an expression with access to the locals and upvalues of the frame
+unsafe fn evaluate(state: *mut c_void, args: &Value) -> Result<Value, String> {
+    let expression = args["expression"].as_str().unwrap_or_default();
+    let level = (args["frameId"].as_i64().unwrap_or(1) - 1) as c_int;
+
+    let bytecode = Compiler::new()
+        .compile(format!("return {}", expression))
+        .map_err(|e| e.to_string())?;
+
+    let top = lua_gettop(state);
+
+    // Environment with the locals and upvalues of the frame, falling back to the globals
+    lua_createtable(state, 0, 0);
+    if getinfo_push_function(state, level) {
+        let mut n = 1;
+        loop {
+            let name = lua_getupvalue(state, -1, n);
+            if name.is_null() {
+                break;
+            }
+            let name = CString::from(CStr::from_ptr(name));
+            lua_setfield(state, -3, name.as_ptr());
+            n += 1;
+        }
+        lua_settop(state, -2);
+    }
+    let mut n = 1;
+    loop {
+        let name = lua_getlocal(state, level, n);
+        if name.is_null() {
+            break;
+        }
+        // Skip internal locals such as `(for index)`
+        if *name == b'(' as c_char {
+            lua_settop(state, -2);
+        } else {
+            let name = CString::from(CStr::from_ptr(name));
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        n += 1;
+    }
+    lua_createtable(state, 0, 1);
+    lua_pushvalue(state, LUA_GLOBALSINDEX);
+    lua_setfield(state, -2, c"__index".as_ptr());
+    lua_setmetatable(state, -2);
+
+    if bytecode.load(state, c"=evaluate") != 0 {
+        let (message, _) = display(state, -1);
+        lua_settop(state, top);
+        return Err(message);
+    }
+    lua_pushvalue(state, -2);
+    lua_setfenv(state, -2);
+
+    let result = if lua_pcall(state, 0, 1, 0) == 0 {
+        let (value, ty) = display(state, -1);
+        Ok(json!({ "result": value, "type": ty, "variablesReference": 0 }))
+    } else {
+        Err(display(state, -1).0)
+    };
+
+    lua_settop(state, top);
+    result
+}
+
+/// Writer that can be connected after the debugger is created
+#[derive(Clone, Default)]
+struct SharedWriter(Arc<Mutex<Option<Box<dyn Write + Send>>>>);
+
+impl SharedWriter {
+    fn set(&self, writer: Box<dyn Write + Send>) {
+        *self.0.lock().unwrap() = Some(writer);
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(writer) => writer.write(buf),
+            None => Ok(buf.len()), // No client yet, drop the output
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads `Content-Length` framed messages until the stream closes
+fn read_messages(stream: impl Read, tx: Sender<Value>, debugger: std::sync::Weak<Debugger>) {
+    let mut reader = BufReader::new(stream);
+
+    while let Some(message) = read_message(&mut reader) {
+        // Pauses must be delivered while the runtime is running, so they bypass the queue
+        if message["command"] == "pause" {
+            if let Some(debugger) = debugger.upgrade() {
+                debugger.pause.request();
+            }
+        }
+
+        if tx.send(message).is_err() {
+            return;
+        }
+    }
+
+    // Treat a closed connection as a disconnect so a stopped runtime resumes
+    let _ = tx.send(json!({ "type": "request", "command": "disconnect", "seq": 0 }));
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::ModuleLoader;
+    use crate::runtime::Runtime;
+    use std::net::TcpStream;
+
+    const FIXTURE: &str = include_str!("../fixtures/debug.luau");
+
+    struct Client {
+        stream: BufReader<TcpStream>,
+        seq: i64,
+    }
+
+    impl Client {
+        fn send(&mut self, command: &str, arguments: Value) -> i64 {
+            self.seq += 1;
+            let body = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            write!(
+                self.stream.get_mut(),
+                "Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            self.seq
+        }
+
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            let seq = self.send(command, arguments);
+            loop {
+                let message = read_message(&mut self.stream).expect("connection closed");
+                if message["type"] == "response" && message["request_seq"] == seq {
+                    assert_eq!(message["success"], true, "{}", message);
+                    return message["body"].clone();
+                }
+            }
+        }
+
+        fn wait_event(&mut self, event: &str) -> Value {
+            loop {
+                let message = read_message(&mut self.stream).expect("connection closed");
+                if message["type"] == "event" && message["event"] == event {
+                    return message["body"].clone();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dap_breakpoint_locals_and_evaluate() {
+        let (debugger, addr) = Debugger::listen_tcp("127.0.0.1:0").unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = Client {
+                stream: BufReader::new(TcpStream::connect(addr).unwrap()),
+                seq: 0,
+            };
+
+            client.request("initialize", json!({ "adapterID": "lute" }));
+            client.wait_event("initialized");
+
+            let body = client.request(
+                "setBreakpoints",
+                json!({
+                    "source": { "path": "fixtures/debug.luau" },
+                    "breakpoints": [{ "line": 3 }],
+                }),
+            );
+            assert_eq!(body["breakpoints"][0]["line"], 3);
+            client.request("configurationDone", json!({}));
+
+            let stopped = client.wait_event("stopped");
+            assert_eq!(stopped["reason"], "breakpoint");
+
+            let trace = client.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            let top = &trace["stackFrames"][0];
+            assert_eq!(top["name"], "double");
+            assert_eq!(top["line"], 3);
+
+            let frame = top["id"].as_i64().unwrap();
+            let scopes = client.request("scopes", json!({ "frameId": frame }));
+            let locals = scopes["scopes"][0]["variablesReference"].clone();
+            let vars = client.request("variables", json!({ "variablesReference": locals }));
+            let vars: HashMap<String, String> = vars["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| {
+                    (
+                        v["name"].as_str().unwrap().to_string(),
+                        v["value"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect();
+            assert_eq!(vars["x"], "21");
+            assert_eq!(vars["y"], "42");
+
+            let result = client.request(
+                "evaluate",
+                json!({ "expression": "x + y", "frameId": frame }),
+            );
+            assert_eq!(result["result"], "63");
+
+            // Step over the return, landing back in the main chunk
+            client.request("next", json!({ "threadId": THREAD_ID }));
+            let stopped = client.wait_event("stopped");
+            assert_eq!(stopped["reason"], "step");
+            let trace = client.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            assert_eq!(trace["stackFrames"][0]["name"], "<main>");
+
+            client.request("continue", json!({ "threadId": THREAD_ID }));
+            client.request("disconnect", json!({}));
+        });
+
+        unsafe {
+            let runtime = Runtime::new();
+            let loader = Arc::new(
+                ModuleLoader::new(Compiler::new().debug_level(2)).with_debugger(debugger.clone()),
+            );
+            assert!(loader.install(runtime.state()));
+            assert!(debugger.attach(runtime.state()));
+
+            debugger.wait_for_configuration(runtime.state());
+            runtime.spawn("@fixtures/debug.luau", FIXTURE.as_bytes()).unwrap();
+            runtime.run_until_idle().unwrap();
+        }
+
+        client.join().unwrap();
+    }
+}
@@ -1,7 +1,25 @@
 #![allow(clippy::missing_safety_doc)]
 
+use std::borrow::Cow;
 use std::io::Write;
 use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::ptr;
+
+pub mod budget;
+pub mod cache;
+pub mod compiler;
+pub mod coverage;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod loader;
+pub mod log;
+pub mod module;
+pub mod policy;
+pub mod process;
+pub mod profiler;
+pub mod runtime;
+pub mod sandbox;
+pub mod value;
 
 #[repr(C)]
 #[allow(non_snake_case, non_camel_case_types)]
@@ -47,6 +65,7 @@ extern "C" {
     pub fn lua_getfield(state: *mut c_void, index: c_int, k: *const c_char) -> c_int;
     pub fn lua_setfield(state: *mut c_void, index: c_int, k: *const c_char);
     pub fn lua_tolstring(state: *mut c_void, index: c_int, len: *mut c_long) -> *const c_char;
+    pub fn lua_pushlstring(state: *mut c_void, s: *const c_char, len: usize);
     pub fn lua_call(state: *mut c_void, nargs: c_int, nresults: c_int);
     pub fn lua_pcall(state: *mut c_void, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
     pub fn lua_newthread(state: *mut c_void) -> *mut c_void;
@@ -76,6 +95,31 @@ extern "C" {
     pub fn lua_getmetatablepointer(state: *mut c_void, index: c_int) -> *const c_void;
     pub fn lua_topointer(state: *mut c_void, index: c_int) -> *const c_void;
 
+    pub fn lua_pushnil(state: *mut c_void);
+    pub fn lua_pushboolean(state: *mut c_void, b: c_int);
+    pub fn lua_pushnumber(state: *mut c_void, n: f64);
+    pub fn lua_pushvector(state: *mut c_void, x: f32, y: f32, z: f32);
+    pub fn lua_toboolean(state: *mut c_void, index: c_int) -> c_int;
+    pub fn lua_tonumberx(state: *mut c_void, index: c_int, isnum: *mut c_int) -> f64;
+    pub fn lua_tovector(state: *mut c_void, index: c_int) -> *const f32;
+    pub fn lua_newbuffer(state: *mut c_void, size: usize) -> *mut c_void;
+    pub fn lua_tobuffer(state: *mut c_void, index: c_int, len: *mut usize) -> *mut c_void;
+    pub fn lua_newuserdatadtor(
+        state: *mut c_void,
+        size: usize,
+        dtor: unsafe extern "C" fn(data: *mut c_void),
+    ) -> *mut c_void;
+    pub fn lua_touserdata(state: *mut c_void, index: c_int) -> *mut c_void;
+    pub fn luaL_newmetatable(state: *mut c_void, tname: *const c_char) -> c_int;
+    pub fn lua_rawequal(state: *mut c_void, a: c_int, b: c_int) -> c_int;
+    pub fn lua_rawset(state: *mut c_void, index: c_int);
+    pub fn lua_rawseti(state: *mut c_void, index: c_int, n: c_int);
+    pub fn lua_next(state: *mut c_void, index: c_int) -> c_int;
+    pub fn lua_objlen(state: *mut c_void, index: c_int) -> c_int;
+    pub fn lua_insert(state: *mut c_void, index: c_int);
+    pub fn lua_setreadonly(state: *mut c_void, index: c_int, enabled: c_int);
+    pub fn lua_getreadonly(state: *mut c_void, index: c_int) -> c_int;
+
     pub fn luau_compile(
         source: *const c_char,
         size: usize,
@@ -89,13 +133,251 @@ extern "C" {
         size: usize,
         env: c_int,
     ) -> c_int;
+    pub fn lutec_load(
+        state: *mut c_void,
+        chunkname: *const c_char,
+        data: *const c_char,
+        size: usize,
+        env: c_int,
+    ) -> c_int;
+
+    pub fn lutec_setloader(
+        state: *mut c_void,
+        load: Option<lutec_LoadCallback>,
+        ctx: *mut c_void,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    ) -> c_int;
+    pub fn lutec_sethostmodules(
+        state: *mut c_void,
+        ctx: *mut c_void,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    ) -> c_int;
+    pub fn lutec_gethostmodules(state: *mut c_void) -> *mut c_void;
+    pub fn lutec_loadsource(
+        state: *mut c_void,
+        chunkname: *const c_char,
+        source: *const c_char,
+        size: usize,
+    ) -> c_int;
+    pub fn lutec_openrequire(state: *mut c_void) -> c_int;
+    pub fn lutec_setclosehook(
+        state: *mut c_void,
+        onclose: Option<unsafe extern "C-unwind" fn(ctx: *mut c_void, state: *mut c_void)>,
+        ctx: *mut c_void,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    ) -> c_int;
+
+    pub fn luau_setfflag(name: *const c_char, value: c_int) -> c_int;
+    pub fn luau_enumfflags(
+        cb: unsafe extern "C" fn(name: *const c_char, value: c_int, ctx: *mut c_void),
+        ctx: *mut c_void,
+    );
 
     pub fn lutec_set_runtimeinitter(callback: lutec_setupState_init) -> c_int;
     pub fn lua_checkstack(state: *mut c_void, extra: c_int) -> c_int;
     pub fn lua_tothread(state: *mut c_void, idx: c_int) -> *mut c_void;
+    pub fn lua_mainthread(state: *mut c_void) -> *mut c_void;
+
+    pub fn lua_ref(state: *mut c_void, idx: c_int) -> c_int;
+    pub fn lua_unref(state: *mut c_void, r#ref: c_int);
+    pub fn lua_rawgeti(state: *mut c_void, idx: c_int, n: c_int) -> c_int;
+
+    pub fn lua_setthreaddata(state: *mut c_void, data: *mut c_void);
+
+    pub fn lutec_setbudget(state: *mut c_void, instruction_limit: u64, time_limit_us: u64) -> c_int;
+    pub fn lutec_setcanceltoken(state: *mut c_void, token: *mut c_void) -> c_int;
+    pub fn lutec_take_interrupt(state: *mut c_void) -> c_int;
+    pub fn lutec_budget_beginslice(state: *mut c_void) -> c_int;
+
+    pub fn lutec_seterrorpolicy(state: *mut c_void, policy: c_int, max_restarts: c_int) -> c_int;
+    pub fn lutec_seterrorhandler(
+        state: *mut c_void,
+        callback: Option<lutec_ErrorCallback>,
+        ctx: *mut c_void,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    ) -> c_int;
+    pub fn lutec_setlogsink(
+        state: *mut c_void,
+        callback: Option<lutec_LogCallback>,
+        ctx: *mut c_void,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    ) -> c_int;
+    pub fn lutec_take_errors(
+        state: *mut c_void,
+        callback: lutec_ErrorCallback,
+        ctx: *mut c_void,
+    ) -> c_int;
+    pub fn lutec_isaborted(state: *mut c_void) -> c_int;
+
+    pub fn lutec_setfsroot(state: *mut c_void, root: *const c_char, read_only: c_int) -> c_int;
+    pub fn lutec_setprocesspolicy(
+        state: *mut c_void,
+        policy: *const lutec_ProcessPolicyDesc,
+    ) -> c_int;
+    pub fn lutec_sandbox(
+        state: *mut c_void,
+        fs_root: *const c_char,
+        fs_read_only: c_int,
+        process: *const lutec_ProcessPolicyDesc,
+        system: c_int,
+    ) -> c_int;
+    pub fn lutec_newscriptthread(state: *mut c_void) -> *mut c_void;
+
+    pub fn lutec_last_traceback(
+        state: *mut c_void,
+        callback: lutec_TracebackCallback,
+        ctx: *mut c_void,
+    ) -> c_int;
+    pub fn lutec_thread_traceback(
+        thread: *mut c_void,
+        callback: lutec_TracebackCallback,
+        ctx: *mut c_void,
+    ) -> c_int;
+    pub fn lutec_canceltoken_new() -> *mut c_void;
+    pub fn lutec_canceltoken_clone(token: *mut c_void) -> *mut c_void;
+    pub fn lutec_canceltoken_free(token: *mut c_void);
+    pub fn lutec_canceltoken_cancel(token: *mut c_void);
+    pub fn lutec_canceltoken_iscancelled(token: *mut c_void) -> c_int;
+
+    pub fn lutec_profiler_start(state: *mut c_void, frequency: c_int) -> c_int;
+    pub fn lutec_profiler_stop(state: *mut c_void) -> c_int;
+    pub fn lutec_profiler_samples(
+        state: *mut c_void,
+        cb: unsafe extern "C" fn(
+            ctx: *mut c_void,
+            stack: *const c_char,
+            timestamp_us: u64,
+            thread_id: u64,
+            weight_us: f64,
+        ),
+        ctx: *mut c_void,
+    ) -> c_int;
+
+    pub fn lua_getinfo(
+        state: *mut c_void,
+        level: c_int,
+        what: *const c_char,
+        ar: *mut lua_Debug,
+    ) -> c_int;
+    pub fn lua_getlocal(state: *mut c_void, level: c_int, n: c_int) -> *const c_char;
+    pub fn lua_getupvalue(state: *mut c_void, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_stackdepth(state: *mut c_void) -> c_int;
+    pub fn lua_breakpoint(state: *mut c_void, funcindex: c_int, line: c_int, enabled: c_int) -> c_int;
+    pub fn lua_singlestep(state: *mut c_void, enabled: c_int);
+    pub fn lua_setfenv(state: *mut c_void, idx: c_int) -> c_int;
+    pub fn luaL_tolstring(state: *mut c_void, idx: c_int, len: *mut usize) -> *const c_char;
+
+    #[cfg(feature = "debugger")]
+    pub fn lutec_debug_attach(
+        state: *mut c_void,
+        ctx: *mut c_void,
+        onevent: Option<unsafe extern "C-unwind" fn(ctx: *mut c_void, state: *mut c_void, event: c_int)>,
+        release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+        pause: *mut c_void,
+    ) -> c_int;
+    #[cfg(feature = "debugger")]
+    pub fn lutec_debug_pauseflag_new() -> *mut c_void;
+    #[cfg(feature = "debugger")]
+    pub fn lutec_debug_pauseflag_free(pause: *mut c_void);
+    #[cfg(feature = "debugger")]
+    pub fn lutec_debug_pause(pause: *mut c_void);
+
+    pub fn lua_getcoverage(
+        state: *mut c_void,
+        funcindex: c_int,
+        context: *mut c_void,
+        callback: lua_Coverage,
+    );
+}
+
+pub const LUA_REGISTRYINDEX: c_int = -1002000;
+pub const LUA_GLOBALSINDEX: c_int = -1002002;
+
+pub const LUA_IDSIZE: usize = 256;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct lua_Debug {
+    pub name: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub short_src: *const c_char,
+    pub linedefined: c_int,
+    pub currentline: c_int,
+    pub nupvals: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub userdata: *mut c_void,
+    pub ssbuf: [c_char; LUA_IDSIZE],
+}
+
+// Receives per-line hit counts for a function, -1 for lines without code.
+#[allow(non_camel_case_types)]
+pub type lua_Coverage = unsafe extern "C" fn(
+    context: *mut c_void,
+    function: *const c_char,
+    linedefined: c_int,
+    depth: c_int,
+    hits: *const c_int,
+    size: usize,
+);
+
+// Receives one traceback frame, innermost first. function is null for anonymous functions.
+#[allow(non_camel_case_types)]
+pub type lutec_TracebackCallback = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    source: *const c_char,
+    function: *const c_char,
+    line: c_int,
+);
 
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct lutec_ErrorFrame {
+    pub source: *const c_char,
+    pub function: *const c_char,
+    pub line: c_int,
 }
 
+// Receives a message written by a script. chunkname is null if it was written from C.
+#[allow(non_camel_case_types)]
+pub type lutec_LogCallback = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    thread: *mut c_void,
+    level: c_int,
+    chunkname: *const c_char,
+    message: *const c_char,
+    size: usize,
+);
+
+// Process policy passed to lutec_setprocesspolicy and lutec_sandbox
+#[repr(C)]
+#[allow(non_snake_case, non_camel_case_types)]
+pub struct lutec_ProcessPolicyDesc {
+    pub allowAll: c_int,
+    pub allow: *const *const c_char,
+    pub allowCount: c_int,
+    pub clearEnv: c_int,
+    pub removeEnv: *const *const c_char,
+    pub removeEnvCount: c_int,
+    pub setEnv: *const *const c_char,
+    pub setEnvCount: c_int,
+    pub cwd: *const c_char,
+    pub timeoutMs: u64,
+    pub maxOutputBytes: u64,
+}
+
+// Receives an error of a scheduled coroutine, the thread only identifies the coroutine.
+#[allow(non_camel_case_types)]
+pub type lutec_ErrorCallback = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    thread: *mut c_void,
+    message: *const c_char,
+    size: usize,
+    frames: *const lutec_ErrorFrame,
+    nframes: c_int,
+);
+
 /*
 extern "C" const int LUTE_STATE_MISSING_ERROR = 0;
 extern "C" const int LUTE_STATE_ERROR = 1;
@@ -115,6 +397,9 @@ pub const LUTE_STATE_ERROR: c_int = 1;
 pub const LUTE_STATE_SUCCESS: c_int = 2;
 pub const LUTE_STATE_EMPTY: c_int = 3;
 pub const LUTE_STATE_UNSUPPORTED_OP: c_int = 4;
+pub const LUTE_STATE_INTERRUPTED: c_int = 5;
+pub const LUTE_STATE_HANDLED: c_int = 6;
+pub const LUTE_STATE_ABORTED: c_int = 7;
 
 #[repr(C)]
 #[allow(non_camel_case_types)]
@@ -129,6 +414,7 @@ extern "C-unwind" {
     pub fn lutec_has_work(state: *mut c_void) -> c_int;
     pub fn lutec_has_threads(state: *mut c_void) -> c_int;
     pub fn lutec_has_continuation(state: *mut c_void) -> c_int;
+    pub fn lua_error(state: *mut c_void) -> !;
 }
 
 /*
@@ -155,11 +441,25 @@ pub struct lutec_setupState {
 // Populates function pointers in the given lutec_setupState.
 pub type lutec_setupState_init = unsafe extern "C" fn(config: *mut lutec_setupState);
 
+// Compiles and loads a module, pushing the chunk function (or an error message).
+#[allow(non_camel_case_types)]
+pub type lutec_LoadCallback = unsafe extern "C-unwind" fn(
+    ctx: *mut c_void,
+    state: *mut c_void,
+    chunkname: *const c_char,
+    source: *const c_char,
+    size: usize,
+) -> c_int;
+
 #[cfg(all(feature = "codegen", not(target_os = "emscripten")))]
 extern "C" {
     pub fn luau_codegen_supported() -> c_int;
     pub fn luau_codegen_create(state: *mut c_void);
     pub fn luau_codegen_compile(state: *mut c_void, idx: c_int);
+    pub fn lutec_codegen_supported() -> c_int;
+    pub fn lutec_codegen_enable(state: *mut c_void) -> c_int;
+    pub fn lutec_codegen_isenabled(state: *mut c_void) -> c_int;
+    pub fn lutec_codegen_compiledfunctions(state: *mut c_void) -> u64;
 }
 
 pub unsafe fn lua_getglobal(state: *mut c_void, k: *const c_char) {
@@ -170,32 +470,93 @@ pub unsafe fn lua_setglobal(state: *mut c_void, k: *const c_char) {
     lua_setfield(state, -1002002 /* LUA_GLOBALSINDEX */, k);
 }
 
-pub unsafe fn to_string<'a>(state: *mut c_void, index: c_int) -> &'a str {
+/// Reads the string (or number) at the index, replacing invalid UTF-8 sequences
+///
+/// Returns an empty string for other values. Use `value::FromLua` to get type errors instead.
+pub unsafe fn to_string<'a>(state: *mut c_void, index: c_int) -> Cow<'a, str> {
     let mut len: c_long = 0;
     let ptr = lua_tolstring(state, index, &mut len);
 
-    if ptr.is_null() {
-        println!("Error: lua_tolstring returned null");
-        return "";
-    }
-    if len < 0 {
-        println!("Error: length is negative");
-        return "";
+    if ptr.is_null() || len < 0 {
+        return Cow::Borrowed("");
     }
 
     let bytes = std::slice::from_raw_parts(ptr as *const u8, len as usize);
-    std::str::from_utf8(bytes).unwrap()
+    String::from_utf8_lossy(bytes)
+}
+
+/// Opens the Lute libraries (except crypto and net) as globals
+pub unsafe fn open_lute_globals(state: *mut c_void) {
+    lutec_openfs(state);
+    lua_setglobal(state, c"fs".as_ptr());
+
+    lutec_openluau(state);
+    lua_setglobal(state, c"luau".as_ptr());
+
+    lutec_openprocess(state);
+    lua_setglobal(state, c"process".as_ptr());
+
+    lutec_opentask(state);
+    lua_setglobal(state, c"task".as_ptr());
+
+    lutec_openvm(state);
+    lua_setglobal(state, c"vm".as_ptr());
+
+    lutec_opensystem(state);
+    lua_setglobal(state, c"system".as_ptr());
+
+    lutec_opentime(state);
+    lua_setglobal(state, c"time".as_ptr());
+}
+
+// Creates a state with the Luau standard libraries, as both initters need
+unsafe fn new_lua_state() -> Option<*mut c_void> {
+    let state = luaL_newstate();
+    if state.is_null() {
+        return None;
+    }
+    luaL_openlibs(state);
+    Some(state)
 }
 
 pub unsafe fn set_lute_state_initter() -> c_int {
     pub unsafe extern "C" fn init_config(config: *mut lutec_setupState) {
         unsafe extern "C-unwind" fn setup_lua_state(wrapper: *mut lua_State_wrapper) {
-            let state = luaL_newstate();
-            if state.is_null() {
-                return;
+            if let Some(state) = new_lua_state() {
+                (*wrapper).L = state;
             }
-            luaL_openlibs(state);
+        }
+
+        (*config).setup_lua_state = setup_lua_state;
+    }
+
+    lutec_set_runtimeinitter(init_config)
+}
+
+/// Sets a runtime initter giving child VMs created through `@lute/vm` the same environment as
+/// `Runtime::new`: the Lute libraries as globals, and a `require` loading modules through the
+/// runtime's loader that also resolves `@host/` modules
+///
+/// Like `set_lute_state_initter`, this only has an effect if no initter was set before, as the
+/// initter is process-wide.
+pub unsafe fn set_lute_vm_initter() -> c_int {
+    pub unsafe extern "C" fn init_config(config: *mut lutec_setupState) {
+        unsafe extern "C-unwind" fn setup_lua_state(wrapper: *mut lua_State_wrapper) {
+            let Some(state) = new_lua_state() else {
+                return;
+            };
+
+            // setupState requires the runtime to be attached and a data copy VM to be provided
+            lua_setthreaddata(state, (*wrapper).runtime_to_set);
+            open_lute_globals(state);
+
+            // Loads modules (and the entry module of vm.create) through the runtime's loader
+            lutec_openrequire(state);
+            lua_setglobal(state, c"require".as_ptr());
+            module::install_require(state);
+
             (*wrapper).L = state;
+            (*wrapper).DC = luaL_newstate();
         }
 
         (*config).setup_lua_state = setup_lua_state;
@@ -206,8 +567,6 @@ pub unsafe fn set_lute_state_initter() -> c_int {
 
 #[cfg(test)]
 mod tests {
-    use std::ptr;
-
     use super::*;
 
     #[test]
@@ -364,6 +723,49 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(all(
+        feature = "codegen",
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn test_codegen() {
+        unsafe {
+            assert_ne!(luau_codegen_supported(), 0);
+            assert_ne!(lutec_codegen_supported(), 0);
+
+            let state = luaL_newstate();
+            assert!(!state.is_null());
+            lutec_setup_runtime(state);
+            luaL_openlibs(state);
+
+            assert_eq!(lutec_codegen_enable(state), 1);
+            assert_eq!(lutec_codegen_isenabled(state), 1);
+            assert_eq!(lutec_codegen_compiledfunctions(state), 0);
+
+            let code = "local n = ... local sum = 0 for i = 1, n do sum += i end return sum";
+            let mut bytecode_size = 0;
+            let bytecode = luau_compile(
+                code.as_ptr().cast(),
+                code.len(),
+                ptr::null_mut(),
+                &mut bytecode_size,
+            );
+            // lutec_load natively compiles the chunk as codegen is enabled for the runtime
+            let result = lutec_load(state, c"sum".as_ptr(), bytecode, bytecode_size, 0);
+            assert_eq!(result, 0);
+            free(bytecode.cast());
+            assert!(lutec_codegen_compiledfunctions(state) > 0);
+
+            lua_pushinteger(state, 100);
+            lua_call(state, 1, 1);
+            assert_eq!(lua_tointegerx(state, -1, ptr::null_mut()), 5050);
+
+            lutec_destroy_runtime(state);
+            lua_close(state);
+        }
+    }
+
     #[test]
     fn test_metatablepointer() {
         unsafe {
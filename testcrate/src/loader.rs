@@ -0,0 +1,203 @@
+//! Module loading for the embedded Lute runtime
+//!
+//! A `ModuleLoader` is installed per runtime with `lutec_setloader` and is shared with every
+//! child VM created through `@lute/vm`. The `require` of `lutec_openrequire` (the global
+//! `require` of `Runtime` and of child VMs) loads modules through it, and hosts with their own
+//! `require` call `load_source` (or `lutec_loadsource` from C) so that all module loads go
+//! through the same compile options and bytecode cache.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Arc;
+
+use crate::cache::BytecodeCache;
+use crate::compiler::{CompileError, Compiler};
+use crate::coverage::Coverage;
+#[cfg(feature = "debugger")]
+use crate::debugger::Debugger;
+use crate::{lua_pushlstring, lutec_load, lutec_loadsource, lutec_setclosehook, lutec_setloader};
+
+const LUA_ERRSYNTAX: c_int = 3;
+
+/// Compiles (or fetches from the cache) and loads modules for a runtime
+pub struct ModuleLoader {
+    compiler: Compiler,
+    cache: Option<Arc<BytecodeCache>>,
+    coverage: Option<Arc<Coverage>>,
+    #[cfg(feature = "debugger")]
+    debugger: Option<Arc<Debugger>>,
+}
+
+impl ModuleLoader {
+    pub fn new(compiler: Compiler) -> Self {
+        ModuleLoader {
+            compiler,
+            cache: None,
+            coverage: None,
+            #[cfg(feature = "debugger")]
+            debugger: None,
+        }
+    }
+
+    /// Uses the given cache for all modules loaded through this loader
+    pub fn with_cache(mut self, cache: Arc<BytecodeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Compiles all modules with coverage enabled and tracks them in the given collector
+    pub fn with_coverage(mut self, coverage: Arc<Coverage>) -> Self {
+        self.compiler = std::mem::take(&mut self.compiler).coverage_level(2);
+        self.coverage = Some(coverage);
+        self
+    }
+
+    /// Registers all modules with the debugger so breakpoints can be set in them
+    #[cfg(feature = "debugger")]
+    pub fn with_debugger(mut self, debugger: Arc<Debugger>) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    pub fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+
+    pub fn cache(&self) -> Option<&Arc<BytecodeCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Compiles and loads the source into the state, pushing the chunk function
+    pub unsafe fn load(
+        &self,
+        state: *mut c_void,
+        chunkname: &CStr,
+        source: &[u8],
+    ) -> Result<(), LoadError> {
+        let status = match &self.cache {
+            Some(cache) => {
+                let bytecode = cache.get_or_compile(&self.compiler, source)?;
+                lutec_load(
+                    state,
+                    chunkname.as_ptr(),
+                    bytecode.as_ptr().cast(),
+                    bytecode.len(),
+                    0,
+                )
+            }
+            None => self.compiler.compile(source)?.load(state, chunkname),
+        };
+
+        if status != 0 {
+            return Err(LoadError::Load(status));
+        }
+
+        if let Some(coverage) = &self.coverage {
+            coverage.track(state, &chunkname.to_string_lossy());
+        }
+
+        #[cfg(feature = "debugger")]
+        if let Some(debugger) = &self.debugger {
+            debugger.track(state, &chunkname.to_string_lossy());
+        }
+
+        Ok(())
+    }
+
+    /// Installs the loader into the runtime attached to the state
+    ///
+    /// The loader is shared with all child VMs of the runtime and dropped once the runtime
+    /// and all of its child VMs are gone.
+    pub unsafe fn install(self: Arc<Self>, state: *mut c_void) -> bool {
+        // Chunks tracked for coverage or debugging must be released before their state closes
+        #[cfg(not(feature = "debugger"))]
+        let tracks = self.coverage.is_some();
+        #[cfg(feature = "debugger")]
+        let tracks = self.coverage.is_some() || self.debugger.is_some();
+
+        if tracks {
+            let ctx = Arc::into_raw(self.clone()) as *mut c_void;
+            if lutec_setclosehook(state, Some(close_callback), ctx, Some(release_callback)) != 0 {
+                return false;
+            }
+        }
+
+        let ctx = Arc::into_raw(self) as *mut c_void;
+        lutec_setloader(state, Some(load_callback), ctx, Some(release_callback)) == 0
+    }
+}
+
+/// Loads a module through the runtime's loader, pushing the chunk function on success and
+/// the error message on failure
+pub unsafe fn load_source(state: *mut c_void, chunkname: &str, source: &[u8]) -> c_int {
+    let chunkname = CString::new(chunkname).expect("chunkname contains a nul byte");
+    lutec_loadsource(
+        state,
+        chunkname.as_ptr(),
+        source.as_ptr().cast(),
+        source.len(),
+    )
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Compile(CompileError),
+    /// `luau_load` failed with the given status, the error message is on the stack
+    Load(c_int),
+}
+
+impl From<CompileError> for LoadError {
+    fn from(err: CompileError) -> Self {
+        LoadError::Compile(err)
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Compile(err) => write!(f, "{}", err),
+            LoadError::Load(status) => write!(f, "luau_load failed with status {}", status),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+unsafe extern "C-unwind" fn load_callback(
+    ctx: *mut c_void,
+    state: *mut c_void,
+    chunkname: *const c_char,
+    source: *const c_char,
+    size: usize,
+) -> c_int {
+    let loader = &*(ctx as *const ModuleLoader);
+    let chunkname = CStr::from_ptr(chunkname);
+    let source = std::slice::from_raw_parts(source as *const u8, size);
+
+    match loader.load(state, chunkname, source) {
+        Ok(()) => 0,
+        Err(LoadError::Compile(err)) => {
+            let message = format!("{}:{}", chunkname.to_string_lossy(), err);
+            lua_pushlstring(state, message.as_ptr().cast(), message.len());
+            LUA_ERRSYNTAX
+        }
+        Err(LoadError::Load(status)) => status,
+    }
+}
+
+unsafe extern "C-unwind" fn close_callback(ctx: *mut c_void, state: *mut c_void) {
+    let loader = &*(ctx as *const ModuleLoader);
+
+    if let Some(coverage) = &loader.coverage {
+        coverage.release(state);
+    }
+
+    #[cfg(feature = "debugger")]
+    if let Some(debugger) = &loader.debugger {
+        debugger.untrack(state);
+    }
+}
+
+unsafe extern "C" fn release_callback(ctx: *mut c_void) {
+    drop(Arc::from_raw(ctx as *const ModuleLoader));
+}
@@ -0,0 +1,146 @@
+//! Redirection of script output to the host
+//!
+//! With a log sink, `print`, `warn`, `process.stdout` and `process.stderr` no longer write to
+//! the process' standard streams. Each message is passed to the sink with its level and the
+//! chunk it was written from, including messages of child VMs created through `@lute/vm`.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Level of a message written by a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// `print` and writes to `process.stdout`
+    Info,
+    /// `warn`
+    Warn,
+    /// Writes to `process.stderr`
+    Error,
+}
+
+impl LogLevel {
+    pub(crate) fn from_raw(raw: c_int) -> Self {
+        match raw {
+            1 => LogLevel::Warn,
+            2 => LogLevel::Error,
+            _ => LogLevel::Info,
+        }
+    }
+}
+
+/// A message written by a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// The thread that wrote the message, only usable to tell threads apart
+    pub thread: *mut c_void,
+    /// Chunk of the function that wrote the message, `None` if it was written from C
+    pub chunkname: Option<String>,
+    /// The message, without the newline added by `print` and `warn`
+    pub message: String,
+}
+
+pub(crate) type LogSink = Box<dyn Fn(&LogRecord) + Send + Sync>;
+
+pub(crate) unsafe extern "C" fn sink_callback(
+    ctx: *mut c_void,
+    thread: *mut c_void,
+    level: c_int,
+    chunkname: *const c_char,
+    message: *const c_char,
+    size: usize,
+) {
+    let sink = &*(ctx as *const LogSink);
+    let message = std::slice::from_raw_parts(message as *const u8, size);
+
+    sink(&LogRecord {
+        level: LogLevel::from_raw(level),
+        thread,
+        chunkname: (!chunkname.is_null())
+            .then(|| CStr::from_ptr(chunkname).to_string_lossy().into_owned()),
+        message: String::from_utf8_lossy(message).into_owned(),
+    });
+}
+
+pub(crate) unsafe extern "C" fn release_sink(ctx: *mut c_void) {
+    drop(Box::from_raw(ctx as *mut LogSink));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+    use crate::sandbox::Sandbox;
+    use std::sync::{Arc, Mutex};
+
+    fn capture(runtime: &Runtime) -> Arc<Mutex<Vec<LogRecord>>> {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        unsafe { runtime.set_log_sink(move |record| sink.lock().unwrap().push(record.clone())) };
+        records
+    }
+
+    #[test]
+    fn test_print_and_warn() {
+        unsafe {
+            let runtime = Runtime::new();
+            let records = capture(&runtime);
+
+            runtime
+                .spawn(
+                    "@scripts/hello.luau",
+                    b"print('hello', 1, true) warn('careful')",
+                )
+                .unwrap();
+
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].level, LogLevel::Info);
+            assert_eq!(records[0].message, "hello\t1\ttrue");
+            assert_eq!(records[0].chunkname.as_deref(), Some("@scripts/hello.luau"));
+            assert_eq!(records[1].level, LogLevel::Warn);
+            assert_eq!(records[1].message, "careful");
+        }
+    }
+
+    #[test]
+    fn test_stdio_writers() {
+        unsafe {
+            let runtime = Runtime::new();
+            let records = capture(&runtime);
+
+            // The streams are either writers or tables with a write function
+            runtime
+                .spawn(
+                    "@scripts/stdio.luau",
+                    b"local function write(stream, s)
+    if type(stream) == 'function' then stream(s) else stream.write(stream, s) end
+end
+write(process.stdout, 'to stdout')
+write(process.stderr, 'to stderr')",
+                )
+                .unwrap();
+
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].level, LogLevel::Info);
+            assert_eq!(records[0].message, "to stdout");
+            assert_eq!(records[0].chunkname.as_deref(), Some("@scripts/stdio.luau"));
+            assert_eq!(records[1].level, LogLevel::Error);
+            assert_eq!(records[1].message, "to stderr");
+        }
+    }
+
+    #[test]
+    fn test_sandboxed_runtime() {
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox::default()).unwrap();
+            let records = capture(&runtime);
+
+            runtime
+                .spawn("=sandboxed", b"print('from sandbox')")
+                .unwrap();
+            assert_eq!(records.lock().unwrap()[0].message, "from sandbox");
+        }
+    }
+}
@@ -0,0 +1,631 @@
+//! Rust functions and userdata types exposed to scripts as modules
+//!
+//! A `Module` registered on a runtime can be loaded with `require("@host/name")` from the
+//! runtime and from child VMs created through `@lute/vm`. Functions are Rust closures taking
+//! their arguments as `FromArgs` and returning `ToReturns` values. Conversion errors, errors
+//! returned by fallible closures and panics become Luau errors, raised only once every Rust
+//! value of the call was dropped, so no Luau error unwinds through Rust frames.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::os::raw::{c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+
+use crate::value::{
+    borrow_userdata, to_bytes, userdata_type_name, ConversionError, FromLua, ToLua, UserData,
+    LUA_TNIL, LUA_TTABLE,
+};
+use crate::*;
+
+const LUA_MULTRET: c_int = -1;
+const HOST_PREFIX: &str = "@host/";
+
+fn upvalue_index(i: c_int) -> c_int {
+    LUA_GLOBALSINDEX - i
+}
+
+/// Arguments of a host function, read starting at a stack index
+///
+/// Implemented for every `FromLua` value (the first argument) and for tuples of them.
+pub trait FromArgs: Sized {
+    unsafe fn from_args(state: *mut c_void, start: c_int) -> Result<Self, ConversionError>;
+}
+
+/// Values returned by a host function
+///
+/// Implemented for every `ToLua` value and for tuples of them.
+pub trait ToReturns {
+    /// Pushes the values, returning how many were pushed
+    unsafe fn push_returns(self, state: *mut c_void) -> c_int;
+}
+
+unsafe fn arg<T: FromLua>(state: *mut c_void, index: c_int) -> Result<T, ConversionError> {
+    T::from_lua(state, index).map_err(|error| error.in_field(format_args!("argument #{}", index)))
+}
+
+impl<T: FromLua> FromArgs for T {
+    unsafe fn from_args(state: *mut c_void, start: c_int) -> Result<Self, ConversionError> {
+        arg(state, start)
+    }
+}
+
+impl<T: ToLua> ToReturns for T {
+    unsafe fn push_returns(self, state: *mut c_void) -> c_int {
+        lua_checkstack(state, 1);
+        self.push(state);
+        1
+    }
+}
+
+macro_rules! impl_tuples {
+    ($($name:ident $offset:literal),+) => {
+        impl<$($name: FromLua),+> FromArgs for ($($name,)+) {
+            unsafe fn from_args(state: *mut c_void, start: c_int) -> Result<Self, ConversionError> {
+                Ok(($(arg::<$name>(state, start + $offset)?,)+))
+            }
+        }
+
+        impl<$($name: ToLua),+> ToReturns for ($($name,)+) {
+            #[allow(non_snake_case)]
+            unsafe fn push_returns(self, state: *mut c_void) -> c_int {
+                let ($($name,)+) = self;
+                let mut count = 0;
+                lua_checkstack(state, 8);
+                $(
+                    $name.push(state);
+                    count += 1;
+                )+
+                count
+            }
+        }
+    };
+}
+
+impl_tuples!(A 0, B 1);
+impl_tuples!(A 0, B 1, C 2);
+impl_tuples!(A 0, B 1, C 2, D 3);
+impl_tuples!(A 0, B 1, C 2, D 3, E 4);
+impl_tuples!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// A type-erased host function, returning its number of results or an error message
+type HostFn = dyn Fn(*mut c_void) -> Result<c_int, String> + Send + Sync;
+
+fn host_fn<A, R, E>(
+    name: &str,
+    self_args: c_int,
+    f: impl Fn(*mut c_void, A) -> Result<R, E> + Send + Sync + 'static,
+) -> Arc<HostFn>
+where
+    A: FromArgs,
+    R: ToReturns,
+    E: Display,
+{
+    let name = name.to_string();
+    Arc::new(move |state| unsafe {
+        let args =
+            A::from_args(state, self_args + 1).map_err(|error| format!("{}: {}", name, error))?;
+        match f(state, args) {
+            Ok(results) => Ok(results.push_returns(state)),
+            Err(error) => Err(format!("{}: {}", name, error)),
+        }
+    })
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "host function panicked".to_string(),
+        },
+    }
+}
+
+// Calls the host function in upvalue 1
+unsafe extern "C-unwind" fn call_host(state: *mut c_void) -> c_int {
+    let result = {
+        let f = &**(lua_touserdata(state, upvalue_index(1)) as *const *const Arc<HostFn>);
+        catch_unwind(AssertUnwindSafe(|| f(state))).unwrap_or_else(|panic| {
+            Err(format!("host function panicked: {}", panic_message(panic)))
+        })
+    };
+
+    match result {
+        Ok(n) => n,
+        Err(message) => {
+            lua_pushlstring(state, message.as_ptr().cast(), message.len());
+            drop(message);
+            lua_error(state)
+        }
+    }
+}
+
+unsafe fn push_host_fn(state: *mut c_void, name: &str, f: &Arc<HostFn>) {
+    lua_checkstack(state, 2);
+    UserData(f.clone()).push(state);
+    let name = CString::new(name).unwrap_or_default();
+    lua_pushcclosurek(state, call_host, name.as_ptr(), 1, ptr::null());
+}
+
+/// A userdata type with methods and metamethods, for values pushed as `UserData<T>`
+///
+/// Methods are called with `:` and receive the value as `&mut T`. Metamethods (such as
+/// `__tostring`, `__eq` or `__len`) receive it the same way, so binary metamethods are only
+/// called when the value is the left operand.
+pub struct UserDataType<T> {
+    name: String,
+    methods: Vec<(String, Arc<HostFn>)>,
+    metamethods: Vec<(String, Arc<HostFn>)>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> UserDataType<T> {
+    /// Creates a type named `name`, which `typeof` returns for its values
+    pub fn new(name: impl Into<String>) -> Self {
+        UserDataType {
+            name: name.into(),
+            methods: Vec::new(),
+            metamethods: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn wrap<A, R, E>(
+        name: &str,
+        f: impl Fn(&mut T, A) -> Result<R, E> + Send + Sync + 'static,
+    ) -> Arc<HostFn>
+    where
+        A: FromArgs,
+        R: ToReturns,
+        E: Display,
+    {
+        host_fn(name, 1, move |state, args| unsafe {
+            let value = match borrow_userdata::<T>(state, 1) {
+                Ok(value) => value,
+                Err(error) => return Err(error.in_field("self").to_string()),
+            };
+            f(value, args).map_err(|error| error.to_string())
+        })
+    }
+
+    pub fn method<A: FromArgs, R: ToReturns>(
+        mut self,
+        name: &str,
+        f: impl Fn(&mut T, A) -> R + Send + Sync + 'static,
+    ) -> Self {
+        let f = Self::wrap(name, move |value, args| Ok::<_, String>(f(value, args)));
+        self.methods.push((name.to_string(), f));
+        self
+    }
+
+    /// Adds a method whose errors are raised as Luau errors
+    pub fn try_method<A: FromArgs, R: ToReturns, E: Display>(
+        mut self,
+        name: &str,
+        f: impl Fn(&mut T, A) -> Result<R, E> + Send + Sync + 'static,
+    ) -> Self {
+        let f = Self::wrap(name, f);
+        self.methods.push((name.to_string(), f));
+        self
+    }
+
+    /// Adds a metamethod such as `__tostring`. `__index`, `__metatable` and `__type` are
+    /// reserved
+    pub fn metamethod<A: FromArgs, R: ToReturns>(
+        mut self,
+        name: &str,
+        f: impl Fn(&mut T, A) -> R + Send + Sync + 'static,
+    ) -> Self {
+        let f = Self::wrap(name, move |value, args| Ok::<_, String>(f(value, args)));
+        self.metamethods.push((name.to_string(), f));
+        self
+    }
+}
+
+/// Type-erased `UserDataType`, installed into the metatable of the Rust type in each VM
+struct TypeDef {
+    name: String,
+    metatable: CString,
+    methods: Vec<(String, Arc<HostFn>)>,
+    metamethods: Vec<(String, Arc<HostFn>)>,
+}
+
+impl TypeDef {
+    unsafe fn install(&self, state: *mut c_void) {
+        lua_checkstack(state, 4);
+        luaL_newmetatable(state, self.metatable.as_ptr());
+
+        for (name, f) in &self.metamethods {
+            if matches!(name.as_str(), "__index" | "__metatable" | "__type") {
+                continue;
+            }
+            push_host_fn(state, name, f);
+            let name = CString::new(name.as_str()).unwrap_or_default();
+            lua_setfield(state, -2, name.as_ptr());
+        }
+
+        lua_createtable(state, 0, self.methods.len() as c_int);
+        for (name, f) in &self.methods {
+            push_host_fn(state, name, f);
+            let name = CString::new(name.as_str()).unwrap_or_default();
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        lua_setreadonly(state, -1, 1);
+        lua_setfield(state, -2, c"__index".as_ptr());
+
+        lua_pushlstring(state, self.name.as_ptr().cast(), self.name.len());
+        lua_setfield(state, -2, c"__type".as_ptr());
+
+        // Scripts can't get or replace the metatable
+        lua_pushboolean(state, 0);
+        lua_setfield(state, -2, c"__metatable".as_ptr());
+
+        lua_settop(state, -2);
+    }
+}
+
+/// A module of Rust functions and userdata types, loaded with `require("@host/<name>")`
+pub struct Module {
+    name: String,
+    functions: Vec<(String, Arc<HostFn>)>,
+    types: Vec<TypeDef>,
+}
+
+impl Module {
+    pub fn new(name: impl Into<String>) -> Self {
+        Module {
+            name: name.into(),
+            functions: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds a function, taking a single value or a tuple of values as arguments
+    pub fn function<A: FromArgs, R: ToReturns>(
+        mut self,
+        name: &str,
+        f: impl Fn(A) -> R + Send + Sync + 'static,
+    ) -> Self {
+        let f = host_fn(name, 0, move |_, args| Ok::<_, String>(f(args)));
+        self.functions.push((name.to_string(), f));
+        self
+    }
+
+    /// Adds a function whose errors are raised as Luau errors
+    pub fn try_function<A: FromArgs, R: ToReturns, E: Display>(
+        mut self,
+        name: &str,
+        f: impl Fn(A) -> Result<R, E> + Send + Sync + 'static,
+    ) -> Self {
+        let f = host_fn(name, 0, move |_, args| f(args));
+        self.functions.push((name.to_string(), f));
+        self
+    }
+
+    /// Adds a userdata type, whose values are created by the functions of the module
+    pub fn userdata<T: 'static>(mut self, ty: UserDataType<T>) -> Self {
+        self.types.push(TypeDef {
+            name: ty.name,
+            metatable: userdata_type_name::<T>(),
+            methods: ty.methods,
+            metamethods: ty.metamethods,
+        });
+        self
+    }
+
+    /// Pushes a new read-only table with the functions of the module
+    unsafe fn push(&self, state: *mut c_void) {
+        for ty in &self.types {
+            ty.install(state);
+        }
+
+        lua_checkstack(state, 2);
+        lua_createtable(state, 0, self.functions.len() as c_int);
+        for (name, f) in &self.functions {
+            push_host_fn(state, name, f);
+            let name = CString::new(name.as_str()).unwrap_or_default();
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        lua_setreadonly(state, -1, 1);
+    }
+}
+
+/// Modules of a runtime, shared with its child VMs
+#[derive(Default)]
+pub(crate) struct HostModules {
+    modules: RwLock<HashMap<String, Arc<Module>>>,
+}
+
+unsafe extern "C" fn release_modules(ctx: *mut c_void) {
+    drop(Box::from_raw(ctx as *mut HostModules));
+}
+
+/// Registers the module on the runtime attached to the state, replacing a module of the same
+/// name for VMs that haven't loaded it yet
+pub(crate) unsafe fn register(state: *mut c_void, module: Module) -> bool {
+    let mut modules = lutec_gethostmodules(state) as *const HostModules;
+    if modules.is_null() {
+        let ctx = Box::into_raw(Box::<HostModules>::default());
+        if lutec_sethostmodules(state, ctx as *mut c_void, Some(release_modules)) != 0 {
+            return false;
+        }
+        modules = ctx;
+    }
+
+    (*modules)
+        .modules
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(module.name.clone(), Arc::new(module));
+    true
+}
+
+/// Pushes the module table, loading it on first use in this VM
+unsafe fn load(state: *mut c_void, name: &str) -> Result<(), String> {
+    lua_checkstack(state, 3);
+
+    lua_getfield(state, LUA_REGISTRYINDEX, c"_HOSTMODULES".as_ptr());
+    if lua_type(state, -1) != LUA_TTABLE {
+        lua_settop(state, -2);
+        lua_createtable(state, 0, 0);
+        lua_pushvalue(state, -1);
+        lua_setfield(state, LUA_REGISTRYINDEX, c"_HOSTMODULES".as_ptr());
+    }
+
+    let key = CString::new(name).map_err(|_| format!("invalid module name '{}'", name))?;
+    lua_getfield(state, -1, key.as_ptr());
+    if lua_type(state, -1) != LUA_TNIL {
+        lua_remove(state, -2);
+        return Ok(());
+    }
+    lua_settop(state, -2);
+
+    let modules = lutec_gethostmodules(state) as *const HostModules;
+    let module = if modules.is_null() {
+        None
+    } else {
+        (*modules)
+            .modules
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(name)
+            .cloned()
+    };
+    let Some(module) = module else {
+        lua_settop(state, -2);
+        return Err(format!(
+            "could not require {}{}: module not found",
+            HOST_PREFIX, name
+        ));
+    };
+
+    module.push(state);
+    lua_pushvalue(state, -1);
+    lua_setfield(state, -3, key.as_ptr());
+    lua_remove(state, -2);
+    Ok(())
+}
+
+// require resolving `@host/` modules, deferring other paths to the previous require (upvalue 1)
+unsafe extern "C-unwind" fn require_host(state: *mut c_void) -> c_int {
+    let result = {
+        let path = to_bytes(state, 1)
+            .ok()
+            .map(|path| String::from_utf8_lossy(path).into_owned());
+
+        match path
+            .as_deref()
+            .and_then(|path| path.strip_prefix(HOST_PREFIX))
+        {
+            Some(name) => load(state, name).map(|_| true),
+            None => Ok(false),
+        }
+    };
+
+    match result {
+        Ok(true) => return 1,
+        Ok(false) => {}
+        Err(message) => {
+            lua_pushlstring(state, message.as_ptr().cast(), message.len());
+            drop(message);
+            lua_error(state)
+        }
+    }
+
+    if lua_type(state, upvalue_index(1)) == LUA_TNIL {
+        luaL_errorL(state, c"require is not available".as_ptr());
+    }
+
+    // Errors of the previous require are raised from here, after every Rust value was dropped
+    let nargs = lua_gettop(state);
+    lua_pushvalue(state, upvalue_index(1));
+    lua_insert(state, 1);
+    if lua_pcall(state, nargs, LUA_MULTRET, 0) != 0 {
+        lua_error(state);
+    }
+    lua_gettop(state)
+}
+
+/// Replaces the global `require` of the state with one that also resolves `@host/` modules
+///
+/// Other paths go through the previous `require`, if any. Read-only globals (as in sandboxed
+/// runtimes) are updated as well.
+pub unsafe fn install_require(state: *mut c_void) {
+    lua_checkstack(state, 4);
+
+    // Already installed in this VM
+    lua_getglobal(state, c"require".as_ptr());
+    lua_getfield(state, LUA_REGISTRYINDEX, c"_HOSTREQUIRE".as_ptr());
+    let installed = lua_rawequal(state, -1, -2) != 0;
+    lua_settop(state, -2);
+    if installed {
+        lua_settop(state, -2);
+        return;
+    }
+
+    lua_pushcclosurek(state, require_host, c"require".as_ptr(), 1, ptr::null());
+    lua_pushvalue(state, -1);
+    lua_setfield(state, LUA_REGISTRYINDEX, c"_HOSTREQUIRE".as_ptr());
+
+    lua_pushvalue(state, LUA_GLOBALSINDEX);
+    let readonly = lua_getreadonly(state, -1);
+    lua_setreadonly(state, -1, 0);
+    lua_insert(state, -2);
+    lua_setfield(state, -2, c"require".as_ptr());
+    lua_setreadonly(state, -1, readonly);
+    lua_settop(state, -2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{LuteError, Runtime};
+    use crate::sandbox::Sandbox;
+
+    struct Counter {
+        count: i64,
+    }
+
+    fn counter_module() -> Module {
+        Module::new("counter")
+            .function("new", |start: Option<i64>| {
+                UserData(Counter {
+                    count: start.unwrap_or(0),
+                })
+            })
+            .userdata(
+                UserDataType::<Counter>::new("Counter")
+                    .method("increment", |counter, by: Option<i64>| {
+                        counter.count += by.unwrap_or(1);
+                    })
+                    .method("get", |counter, ()| counter.count)
+                    .try_method("decrement", |counter, by: i64| {
+                        if by > counter.count {
+                            return Err("counter can't go below zero");
+                        }
+                        counter.count -= by;
+                        Ok(())
+                    })
+                    .metamethod("__tostring", |counter, ()| {
+                        format!("Counter({})", counter.count)
+                    }),
+            )
+    }
+
+    fn math_module() -> Module {
+        Module::new("math")
+            .function("add", |(a, b): (f64, f64)| a + b)
+            .function("divmod", |(a, b): (i64, i64)| (a / b, a % b))
+            .try_function("parse", |s: String| {
+                s.parse::<i64>().map_err(|e| e.to_string())
+            })
+            .function("boom", |()| -> bool { panic!("boom") })
+    }
+
+    fn assert_fails(runtime: &Runtime, source: &str, expected: &str) {
+        match unsafe { runtime.spawn("=host", source.as_bytes()) } {
+            Err(LuteError::Runtime { message, .. }) => {
+                assert!(message.contains(expected), "{}", message)
+            }
+            other => panic!("expected `{}` to fail, got {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(runtime.register_module(math_module()));
+
+            runtime
+                .spawn(
+                    "=host",
+                    b"local math = require('@host/math')
+assert(math.add(1, 2.5) == 3.5)
+local q, r = math.divmod(7, 2)
+assert(q == 3 and r == 1)
+assert(math.parse('42') == 42)
+assert(require('@host/math') == math)",
+                )
+                .unwrap();
+
+            assert_fails(
+                &runtime,
+                "require('@host/math').add(1, 'x')",
+                "add: argument #2: expected number, got string",
+            );
+            assert_fails(
+                &runtime,
+                "require('@host/math').parse('x')",
+                "parse: invalid digit",
+            );
+            assert_fails(&runtime, "require('@host/math').boom()", "panicked: boom");
+            assert_fails(&runtime, "require('@host/math').add = nil", "readonly");
+            assert_fails(&runtime, "require('@host/missing')", "module not found");
+
+            // Errors are catchable and the runtime keeps working afterwards
+            runtime
+                .spawn(
+                    "=pcall",
+                    b"local math = require('@host/math')
+assert(not pcall(math.parse, 'x'))
+assert(math.add(1, 1) == 2)",
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_userdata() {
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(runtime.register_module(counter_module()));
+
+            runtime
+                .spawn(
+                    "=userdata",
+                    b"local counter = require('@host/counter')
+local c = counter.new(5)
+c:increment()
+c:increment(2)
+assert(c:get() == 8)
+c:decrement(3)
+assert(tostring(c) == 'Counter(5)')
+assert(typeof(c) == 'Counter')
+assert(getmetatable(c) == false)
+assert(not pcall(c.decrement, c, 10))
+assert(c:get() == 5)",
+                )
+                .unwrap();
+
+            assert_fails(
+                &runtime,
+                "local c = require('@host/counter').new() c.get({})",
+                "get: self: expected",
+            );
+        }
+    }
+
+    #[test]
+    fn test_sandboxed_runtime() {
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox::default()).unwrap();
+            assert!(runtime.register_module(math_module()));
+
+            runtime
+                .spawn(
+                    "=sandboxed",
+                    b"assert(require('@host/math').add(1, 2) == 3)",
+                )
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Handling of errors raised by coroutines resumed by the scheduler
+//!
+//! By default, the scheduler reports each error from `lutec_run_once`, so a host that stops
+//! stepping loses the errors of background tasks. An `ErrorPolicy` lets the runtime deal with
+//! them instead, optionally notifying an error handler.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+
+use crate::lutec_ErrorFrame;
+use crate::runtime::{TraceFrame, Traceback};
+
+/// What the scheduler does when a coroutine it resumed fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Report the error from the next scheduler step
+    #[default]
+    Report,
+    /// Keep running and store the error until it's taken with `Runtime::take_errors`
+    Collect,
+    /// Keep running, errors are only passed to the error handler
+    Log,
+    /// Report the error, then stop running the scheduler for good
+    Abort,
+    /// Restart the task from its entry function, without its original arguments
+    ///
+    /// Once a task ran out of restarts, its error is reported.
+    Restart { max_restarts: u32 },
+}
+
+impl ErrorPolicy {
+    pub(crate) fn to_raw(self) -> (c_int, c_int) {
+        match self {
+            ErrorPolicy::Report => (0, 0),
+            ErrorPolicy::Collect => (1, 0),
+            ErrorPolicy::Log => (2, 0),
+            ErrorPolicy::Abort => (3, 0),
+            ErrorPolicy::Restart { max_restarts } => {
+                (4, max_restarts.min(c_int::MAX as u32) as c_int)
+            }
+        }
+    }
+}
+
+/// An error raised by a scheduled coroutine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskError {
+    /// The failed coroutine, only usable to tell tasks apart as it may already be collected
+    pub thread: *mut c_void,
+    pub message: String,
+    pub traceback: Traceback,
+}
+
+pub(crate) type ErrorHandler = Box<dyn Fn(&TaskError) + Send + Sync>;
+
+pub(crate) unsafe fn task_error(
+    thread: *mut c_void,
+    message: *const c_char,
+    size: usize,
+    frames: *const lutec_ErrorFrame,
+    nframes: c_int,
+) -> TaskError {
+    let message = std::slice::from_raw_parts(message as *const u8, size);
+    let frames = if nframes > 0 {
+        std::slice::from_raw_parts(frames, nframes as usize)
+    } else {
+        &[]
+    };
+
+    TaskError {
+        thread,
+        message: String::from_utf8_lossy(message).into_owned(),
+        traceback: Traceback {
+            frames: frames
+                .iter()
+                .map(|frame| TraceFrame {
+                    source: CStr::from_ptr(frame.source).to_string_lossy().into_owned(),
+                    function: (!frame.function.is_null())
+                        .then(|| CStr::from_ptr(frame.function).to_string_lossy().into_owned()),
+                    line: u32::try_from(frame.line).ok().filter(|line| *line > 0),
+                })
+                .collect(),
+        },
+    }
+}
+
+pub(crate) unsafe extern "C" fn handler_callback(
+    ctx: *mut c_void,
+    thread: *mut c_void,
+    message: *const c_char,
+    size: usize,
+    frames: *const lutec_ErrorFrame,
+    nframes: c_int,
+) {
+    let handler = &*(ctx as *const ErrorHandler);
+    handler(&task_error(thread, message, size, frames, nframes));
+}
+
+pub(crate) unsafe extern "C" fn release_handler(ctx: *mut c_void) {
+    drop(Box::from_raw(ctx as *mut ErrorHandler));
+}
+
+pub(crate) unsafe extern "C" fn collect_callback(
+    ctx: *mut c_void,
+    thread: *mut c_void,
+    message: *const c_char,
+    size: usize,
+    frames: *const lutec_ErrorFrame,
+    nframes: c_int,
+) {
+    let errors = &mut *(ctx as *mut Vec<TaskError>);
+    errors.push(task_error(thread, message, size, frames, nframes));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{LuteError, Runtime};
+    use std::sync::{Arc, Mutex};
+
+    const FAILING_TASKS: &[u8] = b"for i = 1, 3 do
+    task.defer(function()
+        error('task failed')
+    end)
+end
+";
+
+    #[test]
+    fn test_collect_keeps_running() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_error_policy(ErrorPolicy::Collect);
+
+            runtime.spawn("@tasks/failing.luau", FAILING_TASKS).unwrap();
+            runtime.run_until_idle().unwrap();
+
+            let errors = runtime.take_errors();
+            assert_eq!(errors.len(), 3);
+            for error in &errors {
+                assert!(error.message.contains("task failed"), "{}", error.message);
+                assert_eq!(error.traceback.frames.iter().find_map(|f| f.line), Some(3));
+            }
+            assert!(runtime.take_errors().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_log_calls_handler() {
+        unsafe {
+            let runtime = Runtime::new();
+            let logged = Arc::new(Mutex::new(Vec::new()));
+
+            let sink = logged.clone();
+            runtime.set_error_handler(move |error| sink.lock().unwrap().push(error.clone()));
+            runtime.set_error_policy(ErrorPolicy::Log);
+
+            runtime.spawn("@tasks/failing.luau", FAILING_TASKS).unwrap();
+            runtime.run_until_idle().unwrap();
+
+            assert_eq!(logged.lock().unwrap().len(), 3);
+            // Logged errors are not collected
+            assert!(runtime.take_errors().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_abort_stops_runtime() {
+        unsafe {
+            let runtime = Runtime::new();
+            runtime.set_error_policy(ErrorPolicy::Abort);
+
+            runtime.spawn("@tasks/failing.luau", FAILING_TASKS).unwrap();
+            assert!(matches!(
+                runtime.run_until_idle(),
+                Err(LuteError::Runtime { .. })
+            ));
+
+            // The remaining tasks never run
+            assert!(runtime.is_aborted());
+            assert_eq!(runtime.step(), Err(LuteError::Aborted));
+            assert_eq!(runtime.run_until_idle(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_restart_until_limit() {
+        unsafe {
+            let runtime = Runtime::new();
+            let logged = Arc::new(Mutex::new(0));
+
+            let sink = logged.clone();
+            runtime.set_error_handler(move |_| *sink.lock().unwrap() += 1);
+            runtime.set_error_policy(ErrorPolicy::Restart { max_restarts: 2 });
+
+            runtime
+                .spawn(
+                    "=restart",
+                    b"attempts = 0
+task.defer(function()
+    attempts += 1
+    error('attempt ' .. attempts)
+end)",
+                )
+                .unwrap();
+
+            // The first failure and both restarts go through the handler, then the error is
+            // reported once the task ran out of restarts
+            match runtime.run_until_idle() {
+                Err(LuteError::Runtime { message, .. }) => {
+                    assert!(message.contains("attempt 3"), "{}", message)
+                }
+                other => panic!("expected the last attempt to fail, got {:?}", other),
+            }
+            assert_eq!(*logged.lock().unwrap(), 3);
+        }
+    }
+}
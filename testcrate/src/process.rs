@@ -0,0 +1,274 @@
+//! Host-controlled restrictions on `@lute/process`
+//!
+//! With a `ProcessPolicy`, `process.run` is replaced with a runner that only starts allowed
+//! executables, in a pinned working directory and with an environment chosen by the host, and
+//! kills processes that run for too long or print too much. Other members of the library that
+//! could start processes are removed. Denied calls raise errors scripts can catch with `pcall`.
+//! The policy applies to child VMs created afterwards as well.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{lutec_ProcessPolicyDesc, lutec_setprocesspolicy};
+
+/// Restrictions on `process.run`, nothing can be started by default
+///
+/// Commands must be passed as argument arrays and can't use a shell, so the allow-list can't be
+/// bypassed. For the same reason, executables are looked up in the host's `PATH`, and scripts
+/// can't pass `PATH`, `LD_*` or `DYLD_*` variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessPolicy {
+    /// Executables that may be started, matched exactly against the first argument
+    pub allow: Vec<String>,
+    /// Allows any executable, ignoring `allow`
+    pub allow_any: bool,
+    /// Starts processes with an empty environment instead of the host's
+    pub clear_env: bool,
+    /// Variables removed from the environment, including the ones passed by the script
+    pub remove_env: Vec<String>,
+    /// Variables set in the environment, overriding the ones passed by the script
+    pub set_env: Vec<(String, String)>,
+    /// Working directory of every process, scripts can't pass a different one
+    pub cwd: Option<PathBuf>,
+    /// Processes running longer are killed and `process.run` fails
+    pub timeout: Option<Duration>,
+    /// Processes printing more (stdout and stderr combined) are killed and `process.run` fails
+    pub max_output: Option<u64>,
+}
+
+fn c_strings(strings: impl Iterator<Item = String>) -> Option<Vec<CString>> {
+    strings.map(CString::new).collect::<Result<_, _>>().ok()
+}
+
+fn pointers(strings: &[CString]) -> Vec<*const c_char> {
+    strings.iter().map(|s| s.as_ptr()).collect()
+}
+
+impl ProcessPolicy {
+    /// Calls f with the C layout of the policy, or returns `None` if a string contains a nul
+    pub(crate) fn with_desc<R>(&self, f: impl FnOnce(&lutec_ProcessPolicyDesc) -> R) -> Option<R> {
+        let allow = c_strings(self.allow.iter().cloned())?;
+        let remove_env = c_strings(self.remove_env.iter().cloned())?;
+        let set_env = c_strings(
+            self.set_env
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        )?;
+        let cwd = match &self.cwd {
+            Some(cwd) => Some(CString::new(cwd.to_string_lossy().into_owned()).ok()?),
+            None => None,
+        };
+
+        let allow_ptrs = pointers(&allow);
+        let remove_env_ptrs = pointers(&remove_env);
+        let set_env_ptrs = pointers(&set_env);
+
+        let desc = lutec_ProcessPolicyDesc {
+            allowAll: self.allow_any as c_int,
+            allow: allow_ptrs.as_ptr(),
+            allowCount: allow_ptrs.len() as c_int,
+            clearEnv: self.clear_env as c_int,
+            removeEnv: remove_env_ptrs.as_ptr(),
+            removeEnvCount: remove_env_ptrs.len() as c_int,
+            setEnv: set_env_ptrs.as_ptr(),
+            setEnvCount: set_env_ptrs.len() as c_int,
+            cwd: cwd.as_ref().map_or(std::ptr::null(), |cwd| cwd.as_ptr()),
+            timeoutMs: self
+                .timeout
+                .map_or(0, |timeout| (timeout.as_millis() as u64).max(1)),
+            maxOutputBytes: self.max_output.unwrap_or(0),
+        };
+
+        Some(f(&desc))
+    }
+
+    /// Sets the policy of the runtime attached to the state and its child VMs
+    pub(crate) unsafe fn apply(&self, state: *mut c_void) -> bool {
+        self.with_desc(|desc| lutec_setprocesspolicy(state, desc) == 0)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::runtime::{LuteError, Runtime};
+
+    unsafe fn runtime_with(policy: ProcessPolicy) -> Runtime {
+        let runtime = Runtime::new();
+        assert!(runtime.set_process_policy(&policy));
+        runtime
+    }
+
+    unsafe fn run(runtime: &Runtime, source: &str) -> Result<(), LuteError> {
+        runtime.spawn("=process", source.as_bytes())?;
+        runtime.run_until_idle()
+    }
+
+    #[test]
+    fn test_denied_executable_is_catchable() {
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["echo".to_string()],
+                ..Default::default()
+            });
+
+            run(
+                &runtime,
+                "local ok, err = pcall(process.run, {'rm', '-rf', 'x'})
+assert(not ok and string.find(err, 'not allowed'), err)
+assert(process.run({'echo', 'hi'}).stdout == 'hi\\n')",
+            )
+            .unwrap();
+
+            match run(&runtime, "process.run({'echo', 'hi'}, { shell = true })") {
+                Err(LuteError::Runtime { message, .. }) => {
+                    assert!(message.contains("shell"), "{}", message)
+                }
+                other => panic!("expected the shell to be denied, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cwd_pinned() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["pwd".to_string()],
+                cwd: Some(dir.clone()),
+                ..Default::default()
+            });
+
+            run(
+                &runtime,
+                &format!(
+                    "assert(process.run({{'pwd'}}).stdout == {:?})
+assert(not pcall(process.run, {{'pwd'}}, {{ cwd = '/' }}))",
+                    format!("{}\n", dir.to_string_lossy())
+                ),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_env_scrubbed_and_injected() {
+        std::env::set_var("LUTE_PROCESS_TEST_SECRET", "secret");
+
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["env".to_string()],
+                remove_env: vec![
+                    "LUTE_PROCESS_TEST_SECRET".to_string(),
+                    "FROM_SCRIPT".to_string(),
+                ],
+                set_env: vec![(
+                    "LUTE_PROCESS_TEST_INJECTED".to_string(),
+                    "injected".to_string(),
+                )],
+                ..Default::default()
+            });
+
+            run(
+                &runtime,
+                "local out = process.run({'env'}, { env = { FROM_SCRIPT = 'x', KEPT = 'kept' } }).stdout
+assert(not string.find(out, 'LUTE_PROCESS_TEST_SECRET'), out)
+assert(not string.find(out, 'FROM_SCRIPT'), out)
+assert(string.find(out, 'KEPT=kept'), out)
+assert(string.find(out, 'LUTE_PROCESS_TEST_INJECTED=injected'), out)",
+            )
+            .unwrap();
+
+            // Executables are still looked up in the host's PATH without an environment
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["env".to_string()],
+                clear_env: true,
+                ..Default::default()
+            });
+            run(&runtime, "assert(process.run({'env'}).stdout == '')").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_env_cannot_redirect_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // An `echo` that would run instead of the host's if PATH was taken from the script
+        let dir = std::env::temp_dir().join(format!("lute-process-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fake = dir.join("echo");
+        std::fs::write(&fake, "#!/bin/sh\necho fake\n").unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["echo".to_string()],
+                ..Default::default()
+            });
+
+            run(
+                &runtime,
+                &format!(
+                    "local ok, err = pcall(process.run, {{'echo', 'hi'}}, {{ env = {{ PATH = {:?} }} }})
+assert(not ok and string.find(err, 'not allowed'), err)
+ok, err = pcall(process.run, {{'echo', 'hi'}}, {{ env = {{ LD_PRELOAD = 'evil.so' }} }})
+assert(not ok and string.find(err, 'not allowed'), err)
+ok, err = pcall(process.run, {{'echo', 'hi'}}, {{ env = {{ DYLD_INSERT_LIBRARIES = 'evil.dylib' }} }})
+assert(not ok and string.find(err, 'not allowed'), err)
+assert(process.run({{'echo', 'hi'}}).stdout == 'hi\\n')
+assert(process.exit ~= nil)",
+                    dir.to_string_lossy()
+                ),
+            )
+            .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_timeout_and_output_limit() {
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["sleep".to_string(), "yes".to_string()],
+                timeout: Some(Duration::from_millis(100)),
+                max_output: Some(1024),
+                ..Default::default()
+            });
+
+            run(
+                &runtime,
+                "local ok, err = pcall(process.run, {'sleep', '10'})
+assert(not ok and string.find(err, 'timed out'), err)
+ok, err = pcall(process.run, {'yes'})
+assert(not ok and string.find(err, 'output exceeded'), err)",
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_timeout_with_background_child() {
+        unsafe {
+            let runtime = runtime_with(ProcessPolicy {
+                allow: vec!["sh".to_string()],
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            });
+
+            // The shell exits right away, but the backgrounded sleep keeps its stdout open
+            let started = std::time::Instant::now();
+            run(
+                &runtime,
+                "local ok, err = pcall(process.run, {'sh', '-c', 'sleep 10 & echo started'})
+assert(not ok and string.find(err, 'timed out'), err)",
+            )
+            .unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Sampling profiler for scripts running in an embedded Lute runtime
+//!
+//! Samples are taken from the Luau interrupt callback at the configured rate on every VM of
+//! the runtime (including child VMs created through `@lute/vm` while profiling) and can be
+//! exported as folded stacks for flamegraph tools or as a Chrome trace.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::os::raw::{c_char, c_void};
+
+use crate::coverage::json_string;
+use crate::{lutec_profiler_samples, lutec_profiler_start, lutec_profiler_stop};
+
+/// A running profiler, stopped with `Profiler::stop` or when dropped
+///
+/// The profiler must be stopped or dropped before the runtime is destroyed.
+pub struct Profiler {
+    state: *mut c_void,
+}
+
+impl Profiler {
+    /// Starts sampling the runtime attached to the state and all of its child VMs (including
+    /// ones created before the profiler was started) at `frequency` samples per second
+    ///
+    /// Returns `None` if the runtime is already being profiled or the frequency is above
+    /// 1000000.
+    pub unsafe fn start(state: *mut c_void, frequency: u32) -> Option<Profiler> {
+        match lutec_profiler_start(state, frequency.min(i32::MAX as u32) as i32) {
+            0 => Some(Profiler { state }),
+            _ => None,
+        }
+    }
+
+    /// Stops sampling and returns the collected profile
+    pub unsafe fn stop(self) -> Profile {
+        lutec_profiler_stop(self.state);
+
+        let mut profile = Profile::default();
+        lutec_profiler_samples(
+            self.state,
+            collect_sample,
+            &mut profile as *mut Profile as *mut c_void,
+        );
+        profile
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        // Stopping twice is a no-op, so this is fine after `stop`
+        unsafe {
+            lutec_profiler_stop(self.state);
+        }
+    }
+}
+
+unsafe extern "C" fn collect_sample(
+    ctx: *mut c_void,
+    stack: *const c_char,
+    timestamp_us: u64,
+    thread_id: u64,
+    weight_us: f64,
+) {
+    let profile = &mut *(ctx as *mut Profile);
+    let stack = CStr::from_ptr(stack).to_string_lossy();
+
+    profile.samples.push(Sample {
+        stack: stack.split(';').map(str::to_string).collect(),
+        timestamp_us,
+        thread_id,
+        weight_us,
+    });
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Frames as `name (chunk:line)`, outermost first
+    pub stack: Vec<String>,
+    /// Time since the profiler was started
+    pub timestamp_us: u64,
+    /// OS thread the sample was taken on
+    pub thread_id: u64,
+    /// Time attributed to this sample
+    pub weight_us: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub samples: Vec<Sample>,
+}
+
+impl Profile {
+    /// Total time attributed to each function (innermost frame), in microseconds
+    pub fn self_time(&self) -> HashMap<&str, f64> {
+        let mut times = HashMap::new();
+        for sample in &self.samples {
+            if let Some(frame) = sample.stack.last() {
+                *times.entry(frame.as_str()).or_default() += sample.weight_us;
+            }
+        }
+        times
+    }
+
+    /// Exports the profile as folded stacks (`frame;frame;frame microseconds` per line),
+    /// as consumed by `flamegraph.pl` and inferno
+    pub fn to_folded(&self) -> String {
+        let mut stacks: BTreeMap<String, f64> = BTreeMap::new();
+        for sample in &self.samples {
+            *stacks.entry(sample.stack.join(";")).or_default() += sample.weight_us;
+        }
+
+        let mut out = String::new();
+        for (stack, weight) in stacks {
+            let _ = writeln!(out, "{} {}", stack, weight.round().max(1.0) as u64);
+        }
+        out
+    }
+
+    /// Exports the profile in the Chrome trace event format
+    ///
+    /// Consecutive samples sharing a stack prefix are merged into a single slice per frame,
+    /// giving one flame chart track per OS thread.
+    pub fn to_chrome_trace(&self) -> String {
+        let mut threads: BTreeMap<u64, Vec<&Sample>> = BTreeMap::new();
+        for sample in &self.samples {
+            threads.entry(sample.thread_id).or_default().push(sample);
+        }
+
+        let mut events = Vec::new();
+        for (tid, (_, mut samples)) in threads.into_iter().enumerate() {
+            samples.sort_by_key(|s| s.timestamp_us);
+
+            // Open frames and the time they were entered
+            let mut open: Vec<(&str, f64)> = Vec::new();
+            let mut end = 0.0;
+
+            for sample in samples {
+                let ts = sample.timestamp_us as f64;
+                let common = open
+                    .iter()
+                    .zip(&sample.stack)
+                    .take_while(|((open, _), frame)| *open == frame.as_str())
+                    .count();
+
+                while open.len() > common {
+                    let (name, start) = open.pop().unwrap();
+                    events.push(chrome_event(name, start, ts - start, tid));
+                }
+                for frame in &sample.stack[common..] {
+                    open.push((frame, ts));
+                }
+
+                end = ts + sample.weight_us;
+            }
+
+            while let Some((name, start)) = open.pop() {
+                events.push(chrome_event(name, start, end - start, tid));
+            }
+        }
+
+        format!("{{\"traceEvents\":[{}]}}", events.join(","))
+    }
+}
+
+fn chrome_event(name: &str, ts: f64, dur: f64, tid: usize) -> String {
+    format!(
+        "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{}}}",
+        json_string(name),
+        ts,
+        dur.max(0.0),
+        tid
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::load_source;
+    use crate::runtime::Runtime;
+    use crate::*;
+
+    #[test]
+    fn test_profiler_samples_busy_function() {
+        unsafe {
+            let state = luaL_newstate();
+            lutec_setup_runtime(state);
+            luaL_openlibs(state);
+
+            let profiler = Profiler::start(state, 1000).expect("failed to start profiler");
+
+            let code = "local function busy() local t = os.clock() while os.clock() - t < 0.1 do end end busy()";
+            assert_eq!(load_source(state, "=profiled", code.as_bytes()), 0);
+            lua_call(state, 0, 0);
+
+            let profile = profiler.stop();
+            assert!(!profile.samples.is_empty());
+            assert!(profile
+                .samples
+                .iter()
+                .all(|s| s.stack.iter().any(|f| f.starts_with("busy (profiled:"))));
+
+            let folded = profile.to_folded();
+            assert!(folded.lines().all(|l| l.starts_with("<main> (profiled:1);busy (profiled:1)")));
+
+            let trace = profile.to_chrome_trace();
+            assert!(trace.starts_with("{\"traceEvents\":[{\"name\":"));
+            assert!(trace.contains("\"ph\":\"X\""));
+
+            lutec_destroy_runtime(state);
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_profiler_rejects_high_frequency() {
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(Profiler::start(runtime.state(), 2_000_000).is_none());
+
+            let profiler = Profiler::start(runtime.state(), 1_000_000).expect("failed to start");
+            // Dropping stops the profiler, so it can be started again
+            drop(profiler);
+            assert!(Profiler::start(runtime.state(), 1000).is_some());
+        }
+    }
+}
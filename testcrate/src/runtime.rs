@@ -0,0 +1,463 @@
+//! Safe(r) wrapper around a Lute runtime and its scheduler
+//!
+//! `Runtime` owns a `lua_State` with the Lute runtime attached. Scripts are started with
+//! `spawn`, and the scheduler is driven with `step` (one `lutec_run_once`) or
+//! `run_until_idle`.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_void};
+
+use crate::budget::{Budget, CancelToken, InterruptReason};
+use crate::loader::load_source;
+use crate::log::{self, LogRecord, LogSink};
+use crate::module::{self, Module};
+use crate::policy::{self, ErrorHandler, ErrorPolicy, TaskError};
+use crate::process::ProcessPolicy;
+use crate::sandbox::{FsScope, Sandbox};
+use crate::*;
+
+const LUA_YIELD: c_int = 1;
+
+pub struct Runtime {
+    state: *mut c_void,
+}
+
+/// Result of a successful scheduler step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// A coroutine was resumed
+    Resumed(*mut c_void),
+    /// A coroutine failed and its error was handled by the runtime's error policy
+    Handled(*mut c_void),
+    /// There was nothing to run
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LuteError {
+    /// A coroutine raised an error
+    Runtime {
+        message: String,
+        traceback: Traceback,
+    },
+    /// A coroutine was aborted by the runtime's execution budget
+    Interrupted {
+        reason: InterruptReason,
+        message: String,
+        traceback: Traceback,
+    },
+    /// A script could not be compiled or loaded
+    Load { message: String },
+    /// The runtime was stopped by `ErrorPolicy::Abort` and can't run anymore
+    Aborted,
+    /// The scheduler reported an error without a coroutine to read it from
+    MissingState,
+    /// The scheduler returned an unknown result
+    Unsupported,
+}
+
+impl fmt::Display for LuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuteError::Runtime { message, traceback }
+            | LuteError::Interrupted {
+                message, traceback, ..
+            } => {
+                write!(f, "{}", message)?;
+                if !traceback.is_empty() {
+                    write!(f, "\nstack traceback:\n{}", traceback)?;
+                }
+                Ok(())
+            }
+            LuteError::Load { message } => write!(f, "{}", message),
+            LuteError::Aborted => write!(f, "runtime was aborted"),
+            LuteError::MissingState => write!(f, "scheduler error without a lua state"),
+            LuteError::Unsupported => write!(f, "unsupported response from scheduler"),
+        }
+    }
+}
+
+impl std::error::Error for LuteError {}
+
+impl LuteError {
+    /// Frames of the failed coroutine at the point of failure, if the error came from one
+    pub fn traceback(&self) -> Option<&Traceback> {
+        match self {
+            LuteError::Runtime { traceback, .. } | LuteError::Interrupted { traceback, .. } => {
+                Some(traceback)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Chunk name of the function (`=[C]` for native functions)
+    pub source: String,
+    /// `None` for anonymous functions
+    pub function: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Frames of a failed coroutine, innermost first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Traceback {
+    pub frames: Vec<TraceFrame>,
+}
+
+impl Traceback {
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Takes the traceback captured by the scheduler when it last reported a failure
+    unsafe fn take_last(state: *mut c_void) -> Self {
+        let mut traceback = Traceback::default();
+        lutec_last_traceback(
+            state,
+            traceback_callback,
+            &mut traceback as *mut Traceback as *mut c_void,
+        );
+        traceback
+    }
+
+    /// Reads the current frames of a coroutine that failed while resumed directly
+    unsafe fn of_thread(thread: *mut c_void) -> Self {
+        let mut traceback = Traceback::default();
+        lutec_thread_traceback(
+            thread,
+            traceback_callback,
+            &mut traceback as *mut Traceback as *mut c_void,
+        );
+        traceback
+    }
+}
+
+impl fmt::Display for Traceback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for frame in &self.frames {
+            // Chunk names use Luau's conventions (`@path` for files, `=name` for custom names)
+            let source = frame
+                .source
+                .strip_prefix('@')
+                .or_else(|| frame.source.strip_prefix('='))
+                .unwrap_or(&frame.source);
+
+            write!(f, "{}", source)?;
+            if let Some(line) = frame.line {
+                write!(f, ":{}", line)?;
+            }
+            if let Some(function) = &frame.function {
+                write!(f, " function {}", function)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+unsafe extern "C" fn traceback_callback(
+    ctx: *mut c_void,
+    source: *const c_char,
+    function: *const c_char,
+    line: c_int,
+) {
+    let traceback = &mut *(ctx as *mut Traceback);
+    traceback.frames.push(TraceFrame {
+        source: CStr::from_ptr(source).to_string_lossy().into_owned(),
+        function: (!function.is_null())
+            .then(|| CStr::from_ptr(function).to_string_lossy().into_owned()),
+        line: u32::try_from(line).ok().filter(|line| *line > 0),
+    });
+}
+
+impl Runtime {
+    /// Creates a state with the standard libraries and the Lute libraries (as globals) opened
+    ///
+    /// `require` loads `<path>.luau` files through the runtime's module loader. Relative paths are
+    /// resolved against the directory of the requiring module.
+    pub unsafe fn new() -> Self {
+        let state = luaL_newstate();
+        assert!(!state.is_null(), "luaL_newstate failed");
+
+        lutec_setup_runtime(state);
+        luaL_openlibs(state);
+        open_lute_globals(state);
+
+        lutec_openrequire(state);
+        lua_setglobal(state, c"require".as_ptr());
+
+        Runtime { state }
+    }
+
+    /// Creates a runtime like `new`, then sandboxes it
+    ///
+    /// Returns `None` if the sandbox settings are invalid (e.g. the fs root doesn't exist).
+    pub unsafe fn new_sandboxed(sandbox: &Sandbox) -> Option<Self> {
+        let runtime = Self::new();
+        sandbox.apply(runtime.state).then_some(runtime)
+    }
+
+    pub fn state(&self) -> *mut c_void {
+        self.state
+    }
+
+    /// Sets the execution budget applied to every coroutine of the runtime and its child VMs
+    pub unsafe fn set_budget(&self, budget: Budget) {
+        lutec_setbudget(
+            self.state,
+            budget.instruction_limit.unwrap_or(0),
+            budget
+                .time_limit
+                .map_or(0, |d| (d.as_micros() as u64).max(1)),
+        );
+    }
+
+    /// Aborts every coroutine of the runtime (and its child VMs) once the token is cancelled
+    pub unsafe fn set_cancel_token(&self, token: &CancelToken) {
+        lutec_setcanceltoken(self.state, token.as_ptr());
+    }
+
+    /// Confines `@lute/fs` of the runtime (and child VMs created afterwards) to a directory
+    ///
+    /// Returns false if the root doesn't exist or the runtime is sandboxed, in which case the
+    /// scope must be part of the `Sandbox`.
+    pub unsafe fn set_fs_scope(&self, scope: &FsScope) -> bool {
+        scope.apply(self.state)
+    }
+
+    /// Restricts `process.run` of the runtime (and child VMs created afterwards) to the policy
+    ///
+    /// Returns false if a string of the policy contains a nul or the runtime is sandboxed, in
+    /// which case the policy must be part of the `Sandbox`.
+    pub unsafe fn set_process_policy(&self, policy: &ProcessPolicy) -> bool {
+        policy.apply(self.state)
+    }
+
+    /// Registers a module loadable with `require("@host/<name>")` from the runtime and its child
+    /// VMs, replacing a module with the same name for VMs that haven't loaded it yet
+    pub unsafe fn register_module(&self, module: Module) -> bool {
+        if !module::register(self.state, module) {
+            return false;
+        }
+        module::install_require(self.state);
+        true
+    }
+
+    /// Sets how errors of coroutines resumed by the scheduler are handled
+    pub unsafe fn set_error_policy(&self, policy: ErrorPolicy) {
+        let (policy, max_restarts) = policy.to_raw();
+        lutec_seterrorpolicy(self.state, policy, max_restarts);
+    }
+
+    /// Sets the handler called for every error handled by the error policy
+    ///
+    /// The handler isn't called for errors reported with `ErrorPolicy::Report`.
+    pub unsafe fn set_error_handler(&self, handler: impl Fn(&TaskError) + Send + Sync + 'static) {
+        let handler: Box<ErrorHandler> = Box::new(Box::new(handler));
+        lutec_seterrorhandler(
+            self.state,
+            Some(policy::handler_callback),
+            Box::into_raw(handler) as *mut c_void,
+            Some(policy::release_handler),
+        );
+    }
+
+    /// Redirects `print`, `warn`, `process.stdout` and `process.stderr` of the runtime and its
+    /// child VMs to a sink
+    pub unsafe fn set_log_sink(&self, sink: impl Fn(&LogRecord) + Send + Sync + 'static) {
+        let sink: Box<LogSink> = Box::new(Box::new(sink));
+        lutec_setlogsink(
+            self.state,
+            Some(log::sink_callback),
+            Box::into_raw(sink) as *mut c_void,
+            Some(log::release_sink),
+        );
+    }
+
+    /// Removes the log sink, scripts write to the standard streams again
+    pub unsafe fn clear_log_sink(&self) {
+        lutec_setlogsink(self.state, None, ptr::null_mut(), None);
+    }
+
+    /// Takes the errors stored by `ErrorPolicy::Collect`, oldest first
+    pub unsafe fn take_errors(&self) -> Vec<TaskError> {
+        let mut errors = Vec::new();
+        lutec_take_errors(
+            self.state,
+            policy::collect_callback,
+            &mut errors as *mut Vec<TaskError> as *mut c_void,
+        );
+        errors
+    }
+
+    /// Whether the runtime was stopped by `ErrorPolicy::Abort`
+    pub unsafe fn is_aborted(&self) -> bool {
+        lutec_isaborted(self.state) != 0
+    }
+
+    /// Loads the source into a new coroutine and runs it until it first yields or finishes
+    ///
+    /// Coroutines that yield on Lute operations are resumed by the scheduler. In sandboxed
+    /// runtimes, each script gets its own globals.
+    pub unsafe fn spawn(&self, chunkname: &str, source: &[u8]) -> Result<(), LuteError> {
+        let thread = lutec_newscriptthread(self.state);
+
+        if load_source(thread, chunkname, source) != 0 {
+            let message = to_string(thread, -1).to_string();
+            lua_settop(self.state, -2);
+            return Err(LuteError::Load { message });
+        }
+
+        lutec_budget_beginslice(self.state);
+        let status = lua_resume(thread, ptr::null_mut(), 0);
+        let result = if status == 0 || status == LUA_YIELD {
+            Ok(())
+        } else {
+            Err(self.error_from(thread, false, Traceback::of_thread(thread)))
+        };
+
+        lua_settop(self.state, -2);
+        result
+    }
+
+    /// Runs one iteration of the scheduler
+    pub unsafe fn step(&self) -> Result<Step, LuteError> {
+        let result = lutec_run_once(self.state);
+
+        match result.op {
+            LUTE_STATE_SUCCESS => Ok(Step::Resumed(result.state)),
+            LUTE_STATE_HANDLED => Ok(Step::Handled(result.state)),
+            LUTE_STATE_EMPTY => Ok(Step::Empty),
+            LUTE_STATE_ABORTED => Err(LuteError::Aborted),
+            LUTE_STATE_ERROR => Err(self.error_from(
+                result.state,
+                false,
+                Traceback::take_last(self.state),
+            )),
+            LUTE_STATE_INTERRUPTED => Err(self.error_from(
+                result.state,
+                true,
+                Traceback::take_last(self.state),
+            )),
+            LUTE_STATE_MISSING_ERROR => Err(LuteError::MissingState),
+            _ => Err(LuteError::Unsupported),
+        }
+    }
+
+    /// Drives the scheduler until there is no more work, stopping at the first error
+    pub unsafe fn run_until_idle(&self) -> Result<(), LuteError> {
+        while lutec_has_work(self.state) != 0 {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Builds the error for the failed coroutine, popping the error value
+    unsafe fn error_from(
+        &self,
+        thread: *mut c_void,
+        interrupted: bool,
+        traceback: Traceback,
+    ) -> LuteError {
+        let message = if lua_gettop(thread) > 0 {
+            let message = to_string(thread, -1).to_string();
+            lua_settop(thread, -2);
+            message
+        } else {
+            String::new()
+        };
+
+        // Coroutines resumed directly are not reported through lutec_run_once, so the
+        // interrupt reason is also checked here
+        let reason = InterruptReason::from_raw(lutec_take_interrupt(self.state));
+        match (interrupted, reason) {
+            (_, Some(reason)) => LuteError::Interrupted {
+                reason,
+                message,
+                traceback,
+            },
+            (true, None) => LuteError::Interrupted {
+                reason: InterruptReason::Unknown,
+                message,
+                traceback,
+            },
+            (false, None) => LuteError::Runtime { message, traceback },
+        }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        unsafe {
+            lutec_destroy_runtime(self.state);
+            lua_close(self.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &[u8] = b"local function explode()
+    error('boom')
+end
+task.defer(function()
+    explode()
+end)
+explode()
+";
+
+    fn find_explode(error: &LuteError) -> &TraceFrame {
+        let traceback = error.traceback().expect("missing traceback");
+        traceback
+            .frames
+            .iter()
+            .find(|frame| frame.function.as_deref() == Some("explode"))
+            .unwrap_or_else(|| panic!("no frame for explode in {:?}", traceback))
+    }
+
+    #[test]
+    fn test_traceback_spawn() {
+        unsafe {
+            let runtime = Runtime::new();
+            let error = runtime.spawn("@tasks/explode.luau", SOURCE).unwrap_err();
+
+            let frame = find_explode(&error);
+            assert_eq!(frame.source, "@tasks/explode.luau");
+            assert_eq!(frame.line, Some(2));
+
+            // The main chunk called explode on line 7
+            let traceback = error.traceback().unwrap();
+            assert!(traceback.frames.iter().any(|f| f.line == Some(7)), "{:?}", traceback);
+        }
+    }
+
+    #[test]
+    fn test_traceback_scheduled_task() {
+        unsafe {
+            let runtime = Runtime::new();
+
+            // Only the deferred task fails
+            let source = std::str::from_utf8(SOURCE).unwrap().replace("\nexplode()\n", "\n");
+            runtime.spawn("@tasks/explode.luau", source.as_bytes()).unwrap();
+
+            let error = runtime.run_until_idle().unwrap_err();
+            let frame = find_explode(&error);
+            assert_eq!(frame.source, "@tasks/explode.luau");
+            assert_eq!(frame.line, Some(2));
+
+            let rendered = error.to_string();
+            assert!(rendered.contains("boom"), "{}", rendered);
+            assert!(rendered.contains("\nstack traceback:\n"), "{}", rendered);
+            assert!(
+                rendered.contains("tasks/explode.luau:2 function explode\n"),
+                "{}",
+                rendered
+            );
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Sandboxing of runtimes running untrusted scripts
+//!
+//! A sandboxed runtime has read-only globals (`luaL_sandbox`), runs every script in its own
+//! thread with separate globals (`luaL_sandboxthread`) and only exposes restricted variants of
+//! the Lute libraries with access to the host. `require` returns the same restricted libraries,
+//! and only loads files from the fs scope, caching modules per script. `@lute/vm` is not
+//! available, as child VMs load their entry module through the host's `require`.
+//!
+//! An `FsScope` can also be used on its own to confine `@lute/fs` of an unsandboxed runtime.
+
+use std::ffi::CString;
+use std::os::raw::{c_int, c_void};
+use std::path::PathBuf;
+
+use crate::process::ProcessPolicy;
+use crate::{lutec_ProcessPolicyDesc, lutec_sandbox, lutec_setfsroot};
+
+/// Directory `@lute/fs` is confined to
+///
+/// Relative paths are resolved against the root, and paths escaping it through `..` or
+/// symlinks raise errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsScope {
+    pub root: PathBuf,
+    /// Removes functions that modify the tree and only allows opening files for reading
+    pub read_only: bool,
+}
+
+impl FsScope {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsScope {
+            root: root.into(),
+            read_only: false,
+        }
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    fn root_cstring(&self) -> Option<CString> {
+        CString::new(self.root.to_string_lossy().into_owned()).ok()
+    }
+
+    /// Confines the fs library of the runtime attached to the state and its child VMs
+    pub(crate) unsafe fn apply(&self, state: *mut c_void) -> bool {
+        let Some(root) = self.root_cstring() else {
+            return false;
+        };
+        lutec_setfsroot(state, root.as_ptr(), self.read_only as c_int) == 0
+    }
+}
+
+/// How much of `@lute/system` scripts can access
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SystemAccess {
+    /// The library is not available
+    #[default]
+    None,
+    /// Only members that don't identify the host, such as `os` and `arch`
+    Basic,
+    /// The whole library
+    Full,
+}
+
+/// Capabilities of a sandboxed runtime, nothing is granted by default
+///
+/// `@lute/luau` is never available, as loading bytecode is not memory safe, and neither is
+/// `@lute/vm`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Directory `@lute/fs` and `require` are confined to
+    ///
+    /// `fs` and requiring files are not available without a scope, unless one was set on the
+    /// runtime before.
+    pub fs: Option<FsScope>,
+    /// Policy `process.run` goes through, the only member of `process` left
+    ///
+    /// `process` is not available when `None`.
+    pub process: Option<ProcessPolicy>,
+    pub system: SystemAccess,
+}
+
+impl Sandbox {
+    /// Sandboxes the runtime attached to the state, whose libraries must already be opened
+    pub(crate) unsafe fn apply(&self, state: *mut c_void) -> bool {
+        let fs_root = match &self.fs {
+            Some(scope) => match scope.root_cstring() {
+                Some(root) => Some(root),
+                None => return false,
+            },
+            None => None,
+        };
+
+        let system = match self.system {
+            SystemAccess::None => 0,
+            SystemAccess::Basic => 1,
+            SystemAccess::Full => 2,
+        };
+
+        let sandbox = |process: *const lutec_ProcessPolicyDesc| {
+            lutec_sandbox(
+                state,
+                fs_root.as_ref().map_or(std::ptr::null(), |root| root.as_ptr()),
+                self.fs.as_ref().is_some_and(|scope| scope.read_only) as c_int,
+                process,
+                system,
+            ) == 0
+        };
+
+        match &self.process {
+            Some(policy) => policy.with_desc(|desc| sandbox(desc)).unwrap_or(false),
+            None => sandbox(std::ptr::null()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{LuteError, Runtime};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Creates `<tmp>/<unique>/root` with a data file, and a secret file next to the root
+    fn temp_root() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "lute-sandbox-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data.txt"), "hello").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        root
+    }
+
+    fn assert_fails(runtime: &Runtime, source: &str, expected: &str) {
+        match runtime.spawn("=escape", source.as_bytes()) {
+            Err(LuteError::Runtime { message, .. }) => {
+                assert!(message.contains(expected), "{}", message)
+            }
+            other => panic!("expected `{}` to fail, got {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn test_globals_read_only() {
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox::default()).unwrap();
+
+            // Globals written by a script are only visible to that script
+            runtime.spawn("=first", b"shared = 1 assert(shared == 1)").unwrap();
+            runtime.spawn("=second", b"assert(shared == nil)").unwrap();
+
+            assert_fails(&runtime, "string.len = nil", "readonly");
+            assert_fails(&runtime, "task.defer = print", "readonly");
+            assert_fails(&runtime, "setmetatable(_G, nil)", "readonly");
+
+            // Nothing is granted by default
+            runtime
+                .spawn(
+                    "=libs",
+                    b"assert(fs == nil and process == nil and system == nil and luau == nil)",
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fs_confined_to_root() {
+        let root = temp_root();
+
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new(&root)),
+                ..Default::default()
+            })
+            .unwrap();
+
+            runtime
+                .spawn("=read", b"assert(fs.readfiletostring('data.txt') == 'hello')")
+                .unwrap();
+
+            assert_fails(&runtime, "fs.readfiletostring('../secret.txt')", "outside");
+            assert_fails(&runtime, "fs.readfiletostring('a/../../secret.txt')", "outside");
+
+            let absolute = root.parent().unwrap().join("secret.txt");
+            assert_fails(
+                &runtime,
+                &format!("fs.readfiletostring({:?})", absolute.to_string_lossy()),
+                "outside",
+            );
+            assert_fails(&runtime, "fs.open = nil", "readonly");
+
+            // Denied calls are catchable
+            runtime
+                .spawn(
+                    "=pcall",
+                    b"assert(not pcall(fs.readfiletostring, '../secret.txt'))",
+                )
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_process_allow_list() {
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                process: Some(ProcessPolicy {
+                    allow: vec!["echo".to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+
+            assert_fails(&runtime, "process.run({'rm', '-rf', 'x'})", "not allowed");
+            assert_fails(&runtime, "process.run('echo hi; rm -rf x')", "array of arguments");
+            assert_fails(
+                &runtime,
+                "process.run({'echo', 'hi'}, { shell = true })",
+                "shell",
+            );
+            runtime.spawn("=exit", b"assert(process.exit == nil)").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_vm_and_require_confined() {
+        let root = temp_root();
+        std::fs::write(root.join("module.luau"), "return 42").unwrap();
+        std::fs::write(root.parent().unwrap().join("escape.luau"), "return 'escaped'").unwrap();
+
+        unsafe {
+            // Without a scope, files can't be required, even from the current directory
+            let runtime = Runtime::new_sandboxed(&Sandbox::default()).unwrap();
+            runtime.spawn("=vm", b"assert(vm == nil)").unwrap();
+            assert_fails(&runtime, "require('@lute/vm')", "not available");
+            assert_fails(&runtime, "require('fixtures/required')", "outside");
+
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new(&root)),
+                ..Default::default()
+            })
+            .unwrap();
+            runtime
+                .spawn("=module", b"assert(require('module') == 42)")
+                .unwrap();
+            assert_fails(&runtime, "require('../escape')", "outside");
+            assert_fails(&runtime, "require('@lute/vm').create('../escape')", "not available");
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_scripts_do_not_share_modules() {
+        let root = temp_root();
+        std::fs::write(root.join("shared.luau"), "return { value = 1 }").unwrap();
+
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new(&root)),
+                ..Default::default()
+            })
+            .unwrap();
+
+            runtime
+                .spawn(
+                    "=writer",
+                    b"local shared = require('shared') shared.value = 'leaked'",
+                )
+                .unwrap();
+            runtime
+                .spawn(
+                    "=reader",
+                    b"local shared = require('shared') assert(shared.value == 1, shared.value)
+assert(require('shared') == shared)",
+                )
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_require_relative_to_module() {
+        let root = temp_root();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("lib/util.luau"), "return require('./helper') + 1").unwrap();
+        std::fs::write(root.join("lib/helper.luau"), "return 41").unwrap();
+        std::fs::write(root.join("lib/escape.luau"), "return require('../../secret')").unwrap();
+        std::fs::write(root.join("a.luau"), "return require('./b')").unwrap();
+        std::fs::write(root.join("b.luau"), "return require('./a')").unwrap();
+
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new(&root)),
+                ..Default::default()
+            })
+            .unwrap();
+
+            runtime
+                .spawn("=relative", b"assert(require('lib/util') == 42)")
+                .unwrap();
+            assert_fails(&runtime, "require('lib/escape')", "outside");
+            assert_fails(&runtime, "require('a')", "cyclic require");
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_require_restricted_libraries() {
+        let root = temp_root();
+
+        unsafe {
+            let runtime = Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new(&root).read_only()),
+                process: Some(ProcessPolicy {
+                    allow: vec!["echo".to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+
+            runtime
+                .spawn(
+                    "=libs",
+                    b"assert(require('@lute/fs') == fs and require('@lute/process') == process)
+assert(require('@lute/fs').writestringtofile == nil)
+assert(require('@lute/process').exit == nil)",
+                )
+                .unwrap();
+            assert_fails(
+                &runtime,
+                "require('@lute/fs').readfiletostring('../secret.txt')",
+                "outside",
+            );
+            assert_fails(&runtime, "require('@lute/process').run({'rm', 'x'})", "not allowed");
+            assert_fails(&runtime, "require('@lute/luau')", "not available");
+            assert_fails(&runtime, "require('@lute/system')", "not available");
+
+            // Unsandboxed runtimes get the scoped library as well
+            let runtime = Runtime::new();
+            assert!(runtime.set_fs_scope(&FsScope::new(&root)));
+            assert_fails(
+                &runtime,
+                "require('@lute/fs').readfiletostring('../secret.txt')",
+                "outside",
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_root() {
+        unsafe {
+            assert!(Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new("bad\0root")),
+                ..Default::default()
+            })
+            .is_none());
+            assert!(Runtime::new_sandboxed(&Sandbox {
+                fs: Some(FsScope::new("/does/not/exist")),
+                ..Default::default()
+            })
+            .is_none());
+        }
+    }
+
+    #[test]
+    fn test_fs_scope_writes() {
+        let root = temp_root();
+
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(runtime.set_fs_scope(&FsScope::new(&root)));
+
+            runtime
+                .spawn(
+                    "=write",
+                    b"fs.mkdir('out')
+fs.writestringtofile('out/result.txt', 'written')
+assert(fs.readfiletostring('out/result.txt') == 'written')",
+                )
+                .unwrap();
+            assert_eq!(
+                std::fs::read_to_string(root.join("out/result.txt")).unwrap(),
+                "written"
+            );
+
+            assert_fails(&runtime, "fs.writestringtofile('../escaped.txt', 'x')", "outside");
+            assert!(!root.parent().unwrap().join("escaped.txt").exists());
+        }
+
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[test]
+    fn test_fs_scope_read_only() {
+        let root = temp_root();
+
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(runtime.set_fs_scope(&FsScope::new(&root).read_only()));
+
+            runtime
+                .spawn("=read", b"assert(fs.readfiletostring('data.txt') == 'hello')")
+                .unwrap();
+            runtime
+                .spawn("=removed", b"assert(fs.writestringtofile == nil and fs.remove == nil)")
+                .unwrap();
+            assert_fails(&runtime, "fs.open('data.txt', 'w')", "read-only");
+            assert_fails(&runtime, "fs.open('new.txt', 'a+')", "read-only");
+        }
+
+        assert_eq!(std::fs::read_to_string(root.join("data.txt")).unwrap(), "hello");
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fs_scope_symlink_escape() {
+        let root = temp_root();
+        std::os::unix::fs::symlink(root.parent().unwrap().join("secret.txt"), root.join("link"))
+            .unwrap();
+        std::os::unix::fs::symlink(root.parent().unwrap(), root.join("parent")).unwrap();
+
+        unsafe {
+            let runtime = Runtime::new();
+            assert!(runtime.set_fs_scope(&FsScope::new(&root)));
+
+            assert_fails(&runtime, "fs.readfiletostring('link')", "outside");
+            assert_fails(&runtime, "fs.readfiletostring('parent/secret.txt')", "outside");
+            assert_fails(&runtime, "fs.writestringtofile('parent/new.txt', 'x')", "outside");
+        }
+
+        assert!(!root.parent().unwrap().join("new.txt").exists());
+        let _ = std::fs::remove_dir_all(root.parent().unwrap());
+    }
+}
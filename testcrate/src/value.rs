@@ -0,0 +1,588 @@
+//! Conversions between Rust values and Luau values on the stack
+//!
+//! `ToLua` pushes a value onto the stack and `FromLua` reads the value at a stack index, failing
+//! with a `ConversionError` naming the Luau type (from `lua_typename`) that was found instead.
+//! Strings are UTF-8 checked, `Bytes` reads and pushes strings as raw bytes. With the `serde`
+//! feature, any `Serialize`/`Deserialize` value can be converted with `to_lua` and `from_lua`.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::hash::Hash;
+use std::os::raw::{c_int, c_long, c_void};
+
+use crate::*;
+
+pub(crate) const LUA_TNONE: c_int = -1;
+pub(crate) const LUA_TNIL: c_int = 0;
+pub(crate) const LUA_TBOOLEAN: c_int = 1;
+pub(crate) const LUA_TNUMBER: c_int = 3;
+pub(crate) const LUA_TVECTOR: c_int = 4;
+pub(crate) const LUA_TSTRING: c_int = 5;
+pub(crate) const LUA_TTABLE: c_int = 6;
+pub(crate) const LUA_TUSERDATA: c_int = 8;
+pub(crate) const LUA_TBUFFER: c_int = 10;
+
+/// Error raised when a Luau value can't be converted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The value has the wrong Luau type
+    Type { expected: String, found: String },
+    /// A string is not valid UTF-8
+    Utf8 { valid_up_to: usize },
+    /// A number doesn't fit in the target type (or isn't an integer)
+    OutOfRange { value: String, target: &'static str },
+    /// A table key or value failed to convert
+    Field {
+        field: String,
+        error: Box<ConversionError>,
+    },
+    /// Raised by a serde implementation
+    Message(String),
+}
+
+impl ConversionError {
+    /// Builds a type error for the value at the index
+    pub unsafe fn type_mismatch(state: *mut c_void, index: c_int, expected: &str) -> Self {
+        ConversionError::Type {
+            expected: expected.to_string(),
+            found: type_name(state, index),
+        }
+    }
+
+    pub(crate) fn in_field(self, field: impl fmt::Display) -> Self {
+        ConversionError::Field {
+            field: field.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::Type { expected, found } => {
+                write!(f, "expected {}, got {}", expected, found)
+            }
+            ConversionError::Utf8 { valid_up_to } => {
+                write!(f, "invalid UTF-8 in string after {} bytes", valid_up_to)
+            }
+            ConversionError::OutOfRange { value, target } => {
+                write!(f, "number {} doesn't fit in {}", value, target)
+            }
+            ConversionError::Field { field, error } => write!(f, "{}: {}", field, error),
+            ConversionError::Message(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Name of the Luau type of the value at the index, as returned by `typeof` for builtin types
+pub unsafe fn type_name(state: *mut c_void, index: c_int) -> String {
+    CStr::from_ptr(lua_typename(state, lua_type(state, index)))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Converts the stack index to an absolute one, so it stays valid after pushing values
+pub(crate) unsafe fn abs_index(state: *mut c_void, index: c_int) -> c_int {
+    if index < 0 && index > LUA_REGISTRYINDEX {
+        lua_gettop(state) + index + 1
+    } else {
+        index
+    }
+}
+
+/// A Rust value that can be pushed onto the stack
+pub trait ToLua {
+    /// Pushes exactly one value
+    unsafe fn push(self, state: *mut c_void);
+}
+
+/// A Rust value that can be read from the stack, without popping it
+pub trait FromLua: Sized {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError>;
+}
+
+impl ToLua for () {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushnil(state);
+    }
+}
+
+impl FromLua for () {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        // Missing arguments are none rather than nil
+        match lua_type(state, index) {
+            LUA_TNONE | LUA_TNIL => Ok(()),
+            _ => Err(ConversionError::type_mismatch(state, index, "nil")),
+        }
+    }
+}
+
+impl ToLua for bool {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushboolean(state, self as c_int);
+    }
+}
+
+impl FromLua for bool {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        match lua_type(state, index) {
+            LUA_TBOOLEAN => Ok(lua_toboolean(state, index) != 0),
+            _ => Err(ConversionError::type_mismatch(state, index, "boolean")),
+        }
+    }
+}
+
+unsafe fn to_number(state: *mut c_void, index: c_int) -> Result<f64, ConversionError> {
+    // lua_tonumberx would also accept numeric strings
+    if lua_type(state, index) != LUA_TNUMBER {
+        return Err(ConversionError::type_mismatch(state, index, "number"));
+    }
+    Ok(lua_tonumberx(state, index, ptr::null_mut()))
+}
+
+// Luau numbers are doubles, so integers beyond 2^53 lose precision when pushed
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {$(
+        impl ToLua for $ty {
+            unsafe fn push(self, state: *mut c_void) {
+                lua_pushnumber(state, self as f64);
+            }
+        }
+
+        impl FromLua for $ty {
+            unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+                let value = to_number(state, index)?;
+                if value.fract() != 0.0 || value < <$ty>::MIN as f64 || value > <$ty>::MAX as f64 {
+                    return Err(ConversionError::OutOfRange {
+                        value: value.to_string(),
+                        target: stringify!($ty),
+                    });
+                }
+                Ok(value as $ty)
+            }
+        }
+    )*};
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToLua for f64 {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushnumber(state, self);
+    }
+}
+
+impl FromLua for f64 {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        to_number(state, index)
+    }
+}
+
+impl ToLua for f32 {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushnumber(state, self as f64);
+    }
+}
+
+impl FromLua for f32 {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        to_number(state, index).map(|value| value as f32)
+    }
+}
+
+/// Reads the string at the index as bytes, without converting numbers like `lua_tolstring`
+pub(crate) unsafe fn to_bytes<'a>(
+    state: *mut c_void,
+    index: c_int,
+) -> Result<&'a [u8], ConversionError> {
+    if lua_type(state, index) != LUA_TSTRING {
+        return Err(ConversionError::type_mismatch(state, index, "string"));
+    }
+
+    let mut len: c_long = 0;
+    let data = lua_tolstring(state, index, &mut len);
+    Ok(std::slice::from_raw_parts(data as *const u8, len as usize))
+}
+
+impl ToLua for &str {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushlstring(state, self.as_ptr() as *const _, self.len());
+    }
+}
+
+impl ToLua for String {
+    unsafe fn push(self, state: *mut c_void) {
+        self.as_str().push(state);
+    }
+}
+
+impl FromLua for String {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        let bytes = to_bytes(state, index)?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_string()),
+            Err(error) => Err(ConversionError::Utf8 {
+                valid_up_to: error.valid_up_to(),
+            }),
+        }
+    }
+}
+
+/// A Luau string read or pushed as raw bytes, which don't have to be UTF-8
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+impl ToLua for Bytes {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_pushlstring(state, self.0.as_ptr() as *const _, self.0.len());
+    }
+}
+
+impl FromLua for Bytes {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        to_bytes(state, index).map(|bytes| Bytes(bytes.to_vec()))
+    }
+}
+
+/// A Luau `buffer`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Buffer(pub Vec<u8>);
+
+impl ToLua for Buffer {
+    unsafe fn push(self, state: *mut c_void) {
+        let data = lua_newbuffer(state, self.0.len());
+        std::ptr::copy_nonoverlapping(self.0.as_ptr(), data as *mut u8, self.0.len());
+    }
+}
+
+impl FromLua for Buffer {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        if lua_type(state, index) != LUA_TBUFFER {
+            return Err(ConversionError::type_mismatch(state, index, "buffer"));
+        }
+
+        let mut len = 0;
+        let data = lua_tobuffer(state, index, &mut len);
+        Ok(Buffer(
+            std::slice::from_raw_parts(data as *const u8, len).to_vec(),
+        ))
+    }
+}
+
+/// A Luau `vector`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vector(pub [f32; 3]);
+
+impl ToLua for Vector {
+    unsafe fn push(self, state: *mut c_void) {
+        let [x, y, z] = self.0;
+        lua_pushvector(state, x, y, z);
+    }
+}
+
+impl FromLua for Vector {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        if lua_type(state, index) != LUA_TVECTOR {
+            return Err(ConversionError::type_mismatch(state, index, "vector"));
+        }
+
+        let v = lua_tovector(state, index);
+        Ok(Vector([*v, *v.add(1), *v.add(2)]))
+    }
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    unsafe fn push(self, state: *mut c_void) {
+        match self {
+            Some(value) => value.push(state),
+            None => lua_pushnil(state),
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        match lua_type(state, index) {
+            LUA_TNIL => Ok(None),
+            _ => T::from_lua(state, index).map(Some),
+        }
+    }
+}
+
+impl<T: ToLua> ToLua for Vec<T> {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_checkstack(state, 2);
+        lua_createtable(state, self.len().min(c_int::MAX as usize) as c_int, 0);
+        for (i, value) in self.into_iter().enumerate() {
+            value.push(state);
+            lua_rawseti(state, -2, i as c_int + 1);
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        if lua_type(state, index) != LUA_TTABLE {
+            return Err(ConversionError::type_mismatch(state, index, "table"));
+        }
+
+        let index = abs_index(state, index);
+        let len = lua_objlen(state, index);
+        let mut values = Vec::with_capacity(len.max(0) as usize);
+        lua_checkstack(state, 1);
+
+        for i in 1..=len {
+            lua_rawgeti(state, index, i);
+            let value = T::from_lua(state, -1);
+            lua_settop(state, -2);
+            values.push(value.map_err(|error| error.in_field(format_args!("[{}]", i)))?);
+        }
+
+        Ok(values)
+    }
+}
+
+impl<K: ToLua, V: ToLua, S> ToLua for HashMap<K, V, S> {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_checkstack(state, 3);
+        lua_createtable(state, 0, self.len().min(c_int::MAX as usize) as c_int);
+        for (key, value) in self {
+            key.push(state);
+            value.push(state);
+            lua_rawset(state, -3);
+        }
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        if lua_type(state, index) != LUA_TTABLE {
+            return Err(ConversionError::type_mismatch(state, index, "table"));
+        }
+
+        let index = abs_index(state, index);
+        let mut map = HashMap::new();
+        lua_checkstack(state, 2);
+
+        lua_pushnil(state);
+        while lua_next(state, index) != 0 {
+            let entry = K::from_lua(state, -2)
+                .map_err(|error| error.in_field("key"))
+                .and_then(|key| {
+                    V::from_lua(state, -1)
+                        .map(|value| (key, value))
+                        .map_err(|error| error.in_field(key_name(state, -2)))
+                });
+
+            match entry {
+                Ok((key, value)) => {
+                    map.insert(key, value);
+                    lua_settop(state, -2);
+                }
+                Err(error) => {
+                    lua_settop(state, -3);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+/// Describes a table key in errors, without converting it in place (which would break lua_next)
+unsafe fn key_name(state: *mut c_void, index: c_int) -> String {
+    match lua_type(state, index) {
+        LUA_TSTRING => {
+            String::from_utf8_lossy(to_bytes(state, index).unwrap_or_default()).into_owned()
+        }
+        LUA_TNUMBER => format!("[{}]", lua_tonumberx(state, index, ptr::null_mut())),
+        _ => format!("[{}]", type_name(state, index)),
+    }
+}
+
+/// A Rust value owned by a Luau userdata, dropped when the userdata is collected
+///
+/// Userdata of different Rust types are told apart by their metatable, so reading a userdata
+/// created for another type fails with a type error.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserData<T>(pub T);
+
+unsafe extern "C" fn drop_userdata<T>(data: *mut c_void) {
+    let value = std::ptr::read(data as *mut *mut T);
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+pub(crate) fn userdata_type_name<T>() -> CString {
+    CString::new(std::any::type_name::<T>()).unwrap_or_default()
+}
+
+impl<T: 'static> ToLua for UserData<T> {
+    unsafe fn push(self, state: *mut c_void) {
+        lua_checkstack(state, 2);
+
+        // The value is boxed so that its alignment doesn't depend on the userdata layout
+        let data = lua_newuserdatadtor(state, std::mem::size_of::<*mut T>(), drop_userdata::<T>)
+            as *mut *mut T;
+        std::ptr::write(data, Box::into_raw(Box::new(self.0)));
+
+        let name = userdata_type_name::<T>();
+        luaL_newmetatable(state, name.as_ptr());
+        lua_setmetatable(state, -2);
+    }
+}
+
+/// Borrows the Rust value of a userdata pushed with `UserData<T>`
+///
+/// The reference is only valid while the userdata is alive.
+pub unsafe fn borrow_userdata<'a, T: 'static>(
+    state: *mut c_void,
+    index: c_int,
+) -> Result<&'a mut T, ConversionError> {
+    let expected = || ConversionError::Type {
+        expected: std::any::type_name::<T>().to_string(),
+        found: type_name(state, index),
+    };
+
+    if lua_type(state, index) != LUA_TUSERDATA {
+        return Err(expected());
+    }
+
+    let index = abs_index(state, index);
+    lua_checkstack(state, 2);
+    if lua_getmetatable(state, index) == 0 {
+        return Err(expected());
+    }
+
+    let name = userdata_type_name::<T>();
+    lua_getfield(state, LUA_REGISTRYINDEX, name.as_ptr());
+    let matches = lua_rawequal(state, -1, -2) != 0;
+    lua_settop(state, -3);
+
+    if !matches {
+        return Err(expected());
+    }
+
+    let data = lua_touserdata(state, index) as *mut *mut T;
+    Ok(&mut **data)
+}
+
+impl<T: Clone + 'static> FromLua for UserData<T> {
+    unsafe fn from_lua(state: *mut c_void, index: c_int) -> Result<Self, ConversionError> {
+        borrow_userdata::<T>(state, index).map(|value| UserData(value.clone()))
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+#[cfg(feature = "serde")]
+pub use serde_impl::{from_lua, to_lua};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn roundtrip<T: ToLua + FromLua>(state: *mut c_void, value: T) -> T {
+        value.push(state);
+        let result = T::from_lua(state, -1).unwrap();
+        lua_settop(state, -2);
+        result
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        unsafe {
+            let state = luaL_newstate();
+
+            assert!(roundtrip(state, true));
+            assert_eq!(roundtrip(state, -42i32), -42);
+            assert_eq!(roundtrip(state, 1.5f64), 1.5);
+            assert_eq!(roundtrip(state, "héllo".to_string()), "héllo");
+            assert_eq!(roundtrip(state, Bytes(vec![0xff, 0])), Bytes(vec![0xff, 0]));
+            assert_eq!(
+                roundtrip(state, Buffer(vec![1, 2, 3])),
+                Buffer(vec![1, 2, 3])
+            );
+            assert_eq!(
+                roundtrip(state, Vector([1.0, 2.0, 3.0])),
+                Vector([1.0, 2.0, 3.0])
+            );
+            assert_eq!(roundtrip(state, Some(3u8)), Some(3));
+            assert_eq!(roundtrip(state, None::<u8>), None);
+            assert_eq!(roundtrip(state, vec![1u32, 2, 3]), vec![1, 2, 3]);
+
+            let map = HashMap::from([("a".to_string(), vec![true]), ("b".to_string(), vec![])]);
+            assert_eq!(roundtrip(state, map.clone()), map);
+
+            assert_eq!(lua_gettop(state), 0);
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_type_errors() {
+        unsafe {
+            let state = luaL_newstate();
+
+            "1".push(state);
+            assert_eq!(
+                i32::from_lua(state, -1),
+                Err(ConversionError::Type {
+                    expected: "number".to_string(),
+                    found: "string".to_string(),
+                })
+            );
+            lua_settop(state, 0);
+
+            1.5f64.push(state);
+            assert!(matches!(
+                u8::from_lua(state, -1),
+                Err(ConversionError::OutOfRange { .. })
+            ));
+            lua_settop(state, 0);
+
+            // Non-UTF-8 strings are errors for String, but not for Bytes
+            Bytes(vec![b'a', 0xff]).push(state);
+            assert_eq!(
+                String::from_lua(state, -1),
+                Err(ConversionError::Utf8 { valid_up_to: 1 })
+            );
+            assert_eq!(to_string(state, -1), "a\u{fffd}");
+            lua_settop(state, 0);
+
+            vec![1.0, 2.5].push(state);
+            let error = Vec::<u8>::from_lua(state, -1).unwrap_err();
+            assert_eq!(error.to_string(), "[2]: number 2.5 doesn't fit in u8");
+            assert_eq!(lua_gettop(state), 1);
+
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_userdata() {
+        unsafe {
+            let state = luaL_newstate();
+
+            UserData(vec![1, 2]).push(state);
+            borrow_userdata::<Vec<i32>>(state, -1).unwrap().push(3);
+            assert_eq!(
+                UserData::<Vec<i32>>::from_lua(state, -1).unwrap(),
+                UserData(vec![1, 2, 3])
+            );
+            assert!(matches!(
+                borrow_userdata::<String>(state, -1),
+                Err(ConversionError::Type { .. })
+            ));
+            assert_eq!(lua_gettop(state), 1);
+
+            lua_close(state);
+        }
+    }
+}
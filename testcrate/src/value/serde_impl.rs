@@ -0,0 +1,858 @@
+//! Conversions of `Serialize` and `Deserialize` values
+//!
+//! Structs and maps become tables with keys, sequences and tuples become arrays, `None` and unit
+//! values become `nil` and enum variants follow serde's externally tagged representation: unit
+//! variants are strings and other variants are single-key tables like `{ Variant = value }`.
+
+use std::fmt;
+use std::os::raw::{c_int, c_void};
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use super::{
+    abs_index, to_bytes, type_name, ConversionError, LUA_TBOOLEAN, LUA_TBUFFER, LUA_TNIL,
+    LUA_TNUMBER, LUA_TSTRING, LUA_TTABLE, LUA_TVECTOR,
+};
+use crate::*;
+
+impl ser::Error for ConversionError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConversionError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for ConversionError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConversionError::Message(msg.to_string())
+    }
+}
+
+/// Pushes the value onto the stack, leaving the stack unchanged on failure
+pub unsafe fn to_lua<T: Serialize + ?Sized>(
+    state: *mut c_void,
+    value: &T,
+) -> Result<(), ConversionError> {
+    let top = lua_gettop(state);
+    let result = value.serialize(Serializer { state });
+    if result.is_err() {
+        lua_settop(state, top);
+    }
+    result
+}
+
+/// Reads the value at the index, without popping it
+pub unsafe fn from_lua<T: DeserializeOwned>(
+    state: *mut c_void,
+    index: c_int,
+) -> Result<T, ConversionError> {
+    let index = abs_index(state, index);
+    let top = lua_gettop(state);
+    let result = T::deserialize(Deserializer { state, index });
+    lua_settop(state, top);
+    result
+}
+
+// Every compound value uses at most a table, a key and a value on top of its parent
+unsafe fn reserve(state: *mut c_void) -> Result<(), ConversionError> {
+    if lua_checkstack(state, 4) == 0 {
+        return Err(ConversionError::Message(
+            "value is nested too deeply".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+unsafe fn push_str(state: *mut c_void, s: &str) {
+    lua_pushlstring(state, s.as_ptr() as *const _, s.len());
+}
+
+struct Serializer {
+    state: *mut c_void,
+}
+
+/// Serializes the elements of a table at the top of the stack, or of a table nested in a
+/// variant table as `{ Variant = table }` when variant is set
+struct TableSerializer {
+    state: *mut c_void,
+    next: c_int,
+    variant: bool,
+}
+
+impl TableSerializer {
+    unsafe fn new(
+        state: *mut c_void,
+        variant: Option<&str>,
+        len: usize,
+    ) -> Result<Self, ConversionError> {
+        reserve(state)?;
+        if let Some(variant) = variant {
+            lua_createtable(state, 0, 1);
+            push_str(state, variant);
+        }
+        lua_createtable(state, len.min(c_int::MAX as usize) as c_int, 0);
+
+        Ok(TableSerializer {
+            state,
+            next: 1,
+            variant: variant.is_some(),
+        })
+    }
+
+    unsafe fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        value.serialize(Serializer { state: self.state })?;
+        lua_rawseti(self.state, -2, self.next);
+        self.next += 1;
+        Ok(())
+    }
+
+    unsafe fn field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        push_str(self.state, key);
+        value.serialize(Serializer { state: self.state })?;
+        lua_rawset(self.state, -3);
+        Ok(())
+    }
+
+    unsafe fn finish(self) -> Result<(), ConversionError> {
+        if self.variant {
+            lua_rawset(self.state, -3);
+        }
+        Ok(())
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    type SerializeSeq = TableSerializer;
+    type SerializeTuple = TableSerializer;
+    type SerializeTupleStruct = TableSerializer;
+    type SerializeTupleVariant = TableSerializer;
+    type SerializeMap = TableSerializer;
+    type SerializeStruct = TableSerializer;
+    type SerializeStructVariant = TableSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<(), ConversionError> {
+        unsafe { lua_pushboolean(self.state, v as c_int) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), ConversionError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), ConversionError> {
+        unsafe { lua_pushnumber(self.state, v) };
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), ConversionError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), ConversionError> {
+        unsafe { push_str(self.state, v) };
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), ConversionError> {
+        unsafe { lua_pushlstring(self.state, v.as_ptr() as *const _, v.len()) };
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), ConversionError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), ConversionError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), ConversionError> {
+        unsafe { lua_pushnil(self.state) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), ConversionError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), ConversionError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        unsafe {
+            reserve(self.state)?;
+            lua_createtable(self.state, 0, 1);
+            push_str(self.state, variant);
+            value.serialize(Serializer { state: self.state })?;
+            lua_rawset(self.state, -3);
+        }
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, None, len.unwrap_or(0)) }
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, None, len) }
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, None, len) }
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, Some(variant), len) }
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, None, 0) }
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, None, 0) }
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<TableSerializer, ConversionError> {
+        unsafe { TableSerializer::new(self.state, Some(variant), 0) }
+    }
+}
+
+impl ser::SerializeSeq for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        unsafe { self.element(value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeTuple for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        unsafe { self.element(value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeTupleStruct for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        unsafe { self.element(value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeTupleVariant for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        unsafe { self.element(value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeMap for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ConversionError> {
+        key.serialize(Serializer { state: self.state })?;
+        if unsafe { lua_type(self.state, -1) } == LUA_TNIL {
+            return Err(ConversionError::Message(
+                "map keys can't be nil".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        value.serialize(Serializer { state: self.state })?;
+        unsafe { lua_rawset(self.state, -3) };
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeStruct for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        unsafe { self.field(key, value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+impl ser::SerializeStructVariant for TableSerializer {
+    type Ok = ();
+    type Error = ConversionError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ConversionError> {
+        unsafe { self.field(key, value) }
+    }
+
+    fn end(self) -> Result<(), ConversionError> {
+        unsafe { self.finish() }
+    }
+}
+
+/// Deserializes the value at an absolute stack index
+///
+/// Values pushed while deserializing are left on the stack, `from_lua` restores it once done.
+struct Deserializer {
+    state: *mut c_void,
+    index: c_int,
+}
+
+impl Deserializer {
+    unsafe fn type_error(&self, expected: &str) -> ConversionError {
+        ConversionError::type_mismatch(self.state, self.index, expected)
+    }
+
+    unsafe fn is_array(&self) -> bool {
+        let len = lua_objlen(self.state, self.index);
+        if len == 0 {
+            return false;
+        }
+
+        // Tables with keys besides 1..=len are maps
+        let mut count = 0;
+        lua_pushnil(self.state);
+        while lua_next(self.state, self.index) != 0 {
+            lua_settop(self.state, -2);
+            count += 1;
+        }
+        count == len
+    }
+
+    unsafe fn seq<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        reserve(self.state)?;
+        visitor.visit_seq(SeqAccess {
+            state: self.state,
+            table: self.index,
+            next: 1,
+            len: lua_objlen(self.state, self.index),
+        })
+    }
+
+    unsafe fn map<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        reserve(self.state)?;
+        lua_pushnil(self.state);
+        visitor.visit_map(MapAccess {
+            state: self.state,
+            table: self.index,
+            key: None,
+        })
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = ConversionError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        unsafe {
+            match lua_type(self.state, self.index) {
+                LUA_TNIL => visitor.visit_unit(),
+                LUA_TBOOLEAN => visitor.visit_bool(lua_toboolean(self.state, self.index) != 0),
+                LUA_TNUMBER => {
+                    let n = lua_tonumberx(self.state, self.index, ptr::null_mut());
+                    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                        visitor.visit_i64(n as i64)
+                    } else {
+                        visitor.visit_f64(n)
+                    }
+                }
+                LUA_TSTRING => {
+                    let bytes = to_bytes(self.state, self.index)?;
+                    match std::str::from_utf8(bytes) {
+                        Ok(s) => visitor.visit_str(s),
+                        Err(_) => visitor.visit_bytes(bytes),
+                    }
+                }
+                LUA_TTABLE => {
+                    if self.is_array() {
+                        self.seq(visitor)
+                    } else {
+                        self.map(visitor)
+                    }
+                }
+                LUA_TBUFFER => {
+                    let mut len = 0;
+                    let data = lua_tobuffer(self.state, self.index, &mut len);
+                    visitor.visit_bytes(std::slice::from_raw_parts(data as *const u8, len))
+                }
+                LUA_TVECTOR => {
+                    let v = lua_tovector(self.state, self.index);
+                    let components = [*v, *v.add(1), *v.add(2)];
+                    visitor.visit_seq(de::value::SeqDeserializer::new(components.into_iter()))
+                }
+                _ => Err(self.type_error("serializable value")),
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        match unsafe { lua_type(self.state, self.index) } {
+            LUA_TNIL => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        unsafe {
+            match lua_type(self.state, self.index) {
+                LUA_TSTRING => visitor.visit_bytes(to_bytes(self.state, self.index)?),
+                LUA_TBUFFER => self.deserialize_any(visitor),
+                _ => Err(self.type_error("string")),
+            }
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        unsafe {
+            match lua_type(self.state, self.index) {
+                // Empty tables are maps for deserialize_any
+                LUA_TTABLE => self.seq(visitor),
+                _ => self.deserialize_any(visitor),
+            }
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        unsafe {
+            match lua_type(self.state, self.index) {
+                LUA_TTABLE => self.map(visitor),
+                _ => Err(self.type_error("table")),
+            }
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        unsafe {
+            match lua_type(self.state, self.index) {
+                LUA_TSTRING => {
+                    let variant =
+                        String::from_utf8_lossy(to_bytes(self.state, self.index)?).into_owned();
+                    visitor.visit_enum(variant.into_deserializer())
+                }
+                LUA_TTABLE => {
+                    reserve(self.state)?;
+                    lua_pushnil(self.state);
+                    if lua_next(self.state, self.index) == 0 {
+                        return Err(ConversionError::Message(
+                            "expected a table with a single variant key".to_string(),
+                        ));
+                    }
+
+                    let top = lua_gettop(self.state);
+                    visitor.visit_enum(EnumAccess {
+                        state: self.state,
+                        key: top - 1,
+                        value: top,
+                    })
+                }
+                _ => Err(self.type_error("string or table")),
+            }
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        match unsafe { lua_type(self.state, self.index) } {
+            LUA_TNIL => visitor.visit_unit(),
+            _ => Err(unsafe { self.type_error("nil") }),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        self.deserialize_unit(visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        identifier ignored_any
+    }
+}
+
+struct SeqAccess {
+    state: *mut c_void,
+    table: c_int,
+    next: c_int,
+    len: c_int,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = ConversionError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ConversionError> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+
+        let i = self.next;
+        self.next += 1;
+
+        unsafe {
+            lua_rawgeti(self.state, self.table, i);
+            let index = lua_gettop(self.state);
+            let value = seed
+                .deserialize(Deserializer {
+                    state: self.state,
+                    index,
+                })
+                .map_err(|error| error.in_field(format_args!("[{}]", i)))?;
+            lua_settop(self.state, index - 1);
+            Ok(Some(value))
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.next + 1).max(0) as usize)
+    }
+}
+
+/// Iterates a table with lua_next, whose key stays on the stack between entries
+struct MapAccess {
+    state: *mut c_void,
+    table: c_int,
+    key: Option<String>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = ConversionError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ConversionError> {
+        unsafe {
+            if lua_next(self.state, self.table) == 0 {
+                return Ok(None);
+            }
+
+            let index = lua_gettop(self.state) - 1;
+            self.key = Some(match lua_type(self.state, index) {
+                LUA_TSTRING => String::from_utf8_lossy(to_bytes(self.state, index)?).into_owned(),
+                _ => format!("[{}]", type_name(self.state, index)),
+            });
+
+            seed.deserialize(Deserializer {
+                state: self.state,
+                index,
+            })
+            .map(Some)
+            .map_err(|error| error.in_field("key"))
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ConversionError> {
+        unsafe {
+            let index = lua_gettop(self.state);
+            let value = seed
+                .deserialize(Deserializer {
+                    state: self.state,
+                    index,
+                })
+                .map_err(|error| error.in_field(self.key.take().unwrap_or_default()))?;
+
+            // Keep the key for the next call to lua_next
+            lua_settop(self.state, index - 1);
+            Ok(value)
+        }
+    }
+}
+
+struct EnumAccess {
+    state: *mut c_void,
+    key: c_int,
+    value: c_int,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = ConversionError;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer), ConversionError> {
+        let variant = seed.deserialize(Deserializer {
+            state: self.state,
+            index: self.key,
+        })?;
+
+        Ok((
+            variant,
+            Deserializer {
+                state: self.state,
+                index: self.value,
+            },
+        ))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = ConversionError;
+
+    fn unit_variant(self) -> Result<(), ConversionError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, ConversionError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: BTreeMap<String, i32>,
+        origin: (i32, i32),
+        parent: Option<String>,
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        unsafe {
+            let state = luaL_newstate();
+
+            let scene = Scene {
+                name: "main".to_string(),
+                shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+                tags: BTreeMap::from([("layer".to_string(), 1)]),
+                origin: (-1, 4),
+                parent: None,
+            };
+
+            to_lua(state, &scene).unwrap();
+            assert_eq!(lua_gettop(state), 1);
+            assert_eq!(from_lua::<Scene>(state, -1).unwrap(), scene);
+            assert_eq!(lua_gettop(state), 1);
+
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn test_serde_errors() {
+        unsafe {
+            let state = luaL_newstate();
+
+            to_lua(state, &BTreeMap::from([("name", 1)])).unwrap();
+            let error = from_lua::<Scene>(state, -1).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "name: invalid type: integer `1`, expected a string"
+            );
+            assert_eq!(lua_gettop(state), 1);
+
+            lua_close(state);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Tests for child VMs created through `@lute/vm`
+//!
+//! The runtime initter is process-wide and the first one set wins, so these tests run in their
+//! own process where every test sets up the initter child VMs need.
+
+use std::sync::{Arc, Mutex, Once};
+
+use testcrate::budget::{Budget, InterruptReason};
+use testcrate::log::LogRecord;
+use testcrate::module::Module;
+use testcrate::profiler::Profiler;
+use testcrate::runtime::{LuteError, Runtime};
+use testcrate::set_lute_vm_initter;
+
+fn setup() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        set_lute_vm_initter();
+    });
+}
+
+#[test]
+fn test_instruction_limit() {
+    setup();
+    unsafe {
+        let runtime = Runtime::new();
+        runtime.set_budget(Budget {
+            instruction_limit: Some(100_000),
+            ..Default::default()
+        });
+
+        // The child VM inherits the budget, and its error surfaces in the parent coroutine
+        let result = runtime
+            .spawn(
+                "=parent",
+                b"local child = vm.create('fixtures/budget_child') child.spin()",
+            )
+            .and_then(|_| runtime.run_until_idle());
+
+        match result {
+            Err(LuteError::Interrupted {
+                reason, message, ..
+            }) => {
+                assert_eq!(reason, InterruptReason::Instructions);
+                assert!(message.contains("execution budget exceeded"), "{}", message);
+            }
+            other => panic!("expected interruption, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_log() {
+    setup();
+    unsafe {
+        let runtime = Runtime::new();
+        let records = Arc::new(Mutex::new(Vec::<LogRecord>::new()));
+        let sink = records.clone();
+        runtime.set_log_sink(move |record| sink.lock().unwrap().push(record.clone()));
+
+        runtime
+            .spawn(
+                "=parent",
+                b"local child = vm.create('fixtures/log_child') child.greet()",
+            )
+            .and_then(|_| runtime.run_until_idle())
+            .unwrap();
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, "hello from the child");
+        assert!(records[0]
+            .chunkname
+            .as_deref()
+            .is_some_and(|chunk| chunk.contains("log_child")));
+    }
+}
+
+#[test]
+fn test_host_module() {
+    setup();
+    unsafe {
+        let runtime = Runtime::new();
+        let math = Module::new("math").function("add", |(a, b): (f64, f64)| a + b);
+        assert!(runtime.register_module(math));
+
+        runtime
+            .spawn(
+                "=parent",
+                b"local child = vm.create('fixtures/host_child') assert(child.sum(20, 22) == 42)",
+            )
+            .and_then(|_| runtime.run_until_idle())
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_profiler_samples_existing_child() {
+    setup();
+    unsafe {
+        let runtime = Runtime::new();
+        runtime
+            .spawn("=parent", b"child = vm.create('fixtures/profile_child')")
+            .unwrap();
+        runtime.run_until_idle().unwrap();
+
+        // The child VM was created before the profiler was started
+        let profiler = Profiler::start(runtime.state(), 1000).expect("failed to start");
+        runtime.spawn("=parent", b"assert(child.busy())").unwrap();
+        runtime.run_until_idle().unwrap();
+
+        let profile = profiler.stop();
+        assert!(
+            profile
+                .samples
+                .iter()
+                .any(|s| s.stack.iter().any(|f| f.contains("fixtures/profile_child"))),
+            "{:?}",
+            profile.samples
+        );
+    }
+}